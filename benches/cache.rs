@@ -0,0 +1,115 @@
+//! `Cache` on its own: `get` and `set` on one thread, and a mixed workload
+//! of gets and sets from several threads at once, the way connections
+//! share it.
+//!
+//! Run with `cargo bench --bench cache`. Save a baseline on one branch with
+//! `-- --save-baseline main` and compare another against it with
+//! `-- --baseline main`.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on;
+use sidica::internals::Cache;
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 10_000;
+const VALUE_SIZES: [usize; 3] = [10, 1000, 100_000];
+/// Of the requests in the mixed workload, one in this many is a set
+const SET_EVERY: u64 = 10;
+
+fn key(n: usize) -> Bytes {
+    Bytes::from(format!("key:{}", n))
+}
+
+/// A cache with `KEYS` keys, each holding `size` bytes.
+fn filled(size: usize) -> Cache {
+    let cache = Cache::builder().build();
+    let value = Bytes::from(vec![b'x'; size]);
+    for n in 0..KEYS {
+        block_on(cache.set(key(n), 0, None, value.clone()));
+    }
+    cache
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache/get");
+    group.throughput(Throughput::Elements(1));
+    for size in VALUE_SIZES {
+        let cache = filled(size);
+        let keys: Vec<Bytes> = (0..KEYS).map(key).collect();
+        let mut n = 0;
+        group.bench_function(BenchmarkId::new("hit", size), |b| {
+            b.iter(|| {
+                n = (n + 1) % KEYS;
+                black_box(block_on(cache.get(&keys[n])))
+            })
+        });
+    }
+    let cache = filled(10);
+    group.bench_function("miss", |b| b.iter(|| black_box(block_on(cache.get(b"missing")))));
+    let keys: Vec<Bytes> = (0..10).map(key).collect();
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.bench_function("multi/10", |b| b.iter(|| black_box(block_on(cache.get_multi(&keys)))));
+    group.finish();
+}
+
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache/set");
+    group.throughput(Throughput::Elements(1));
+    for size in VALUE_SIZES {
+        let cache = filled(size);
+        let keys: Vec<Bytes> = (0..KEYS).map(key).collect();
+        let value = Bytes::from(vec![b'y'; size]);
+        let mut n = 0;
+        // Over keys already there, so the cache stays the same size
+        group.bench_function(BenchmarkId::new("replace", size), |b| {
+            b.iter(|| {
+                n = (n + 1) % KEYS;
+                block_on(cache.set(keys[n].clone(), 0, None, value.clone()))
+            })
+        });
+    }
+    group.finish();
+}
+
+/// `requests` gets and sets spread over `threads` threads, each going
+/// through the keys from a different place. Returns how long they took.
+fn mixed_run(cache: &Cache, threads: u64, requests: u64) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for thread in 0..threads {
+            let cache = cache.clone();
+            scope.spawn(move || {
+                let value = Bytes::from_static(b"0123456789");
+                let mut n = thread as usize * KEYS / threads as usize;
+                for request in 0..requests / threads {
+                    n = (n + 7) % KEYS;
+                    let key = key(n);
+                    if request % SET_EVERY == 0 {
+                        block_on(cache.set(key, 0, None, value.clone()));
+                    } else {
+                        black_box(block_on(cache.get(&key)));
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn mixed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache/mixed");
+    group.throughput(Throughput::Elements(1));
+    let cache = filled(10);
+    for threads in [1, 2, 4, 8] {
+        group.bench_function(BenchmarkId::from_parameter(threads), |b| {
+            b.iter_custom(|requests| mixed_run(&cache, threads, requests.max(threads)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get, set, mixed);
+criterion_main!(benches);
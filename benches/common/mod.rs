@@ -0,0 +1,39 @@
+//! Runs the server binary for the benchmarks that measure it over TCP.
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Kills the server when dropped.
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start a server with `args` on a free port, returning once it accepts
+/// connections.
+pub fn start(args: &[&str]) -> (Server, u16) {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let server = Command::new(env!("CARGO_BIN_EXE_sidica"))
+        .args(["-l", "127.0.0.1", "-p", &port.to_string()])
+        .args(args)
+        .spawn()
+        .unwrap();
+    let server = Server(server);
+
+    let start = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(start.elapsed() < Duration::from_secs(5), "server did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+    (server, port)
+}
@@ -0,0 +1,96 @@
+//! Compares the default work-stealing runtime with `--core-pinned` on a
+//! multiget heavy workload.
+//!
+//! Run with `cargo bench --bench core_pinned`. Every mode starts its own
+//! server, preloads `KEYS` keys, then `CLIENTS` connections each send
+//! `get` requests for `KEYS_PER_GET` keys for `DURATION`. Prints the keys
+//! fetched per second for each mode.
+
+mod common;
+
+use common::start;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const KEYS: usize = 1000;
+const KEYS_PER_GET: usize = 20;
+const CLIENTS: usize = 32;
+const DURATION: Duration = Duration::from_secs(5);
+
+fn preload(port: u16) {
+    let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+    let mut line = String::new();
+    for key in 0..KEYS {
+        write!(stream.get_mut(), "set key{} 0 0 5\r\nvalue\r\n", key).unwrap();
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "STORED\r\n");
+    }
+}
+
+/// Send multigets until `stop` is set, counting the keys returned in `hits`.
+fn client(port: u16, id: usize, stop: &AtomicBool, hits: &AtomicU64) {
+    let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+    let mut request = String::new();
+    let mut line = String::new();
+    let mut next = id * KEYS_PER_GET;
+
+    while !stop.load(Ordering::Relaxed) {
+        request.clear();
+        request.push_str("get");
+        for _ in 0..KEYS_PER_GET {
+            request.push_str(&format!(" key{}", next % KEYS));
+            next += 1;
+        }
+        request.push_str("\r\n");
+        stream.get_mut().write_all(request.as_bytes()).unwrap();
+
+        let mut found = 0;
+        loop {
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+            if line == "END\r\n" {
+                break;
+            }
+            assert!(line.starts_with("VALUE "), "unexpected response {:?}", line);
+            // Skip the data block
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+            found += 1;
+        }
+        hits.fetch_add(found, Ordering::Relaxed);
+    }
+}
+
+fn bench(name: &str, args: &[&str]) {
+    let (_server, port) = start(args);
+    preload(port);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let hits = Arc::new(AtomicU64::new(0));
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|id| {
+            let stop = stop.clone();
+            let hits = hits.clone();
+            thread::spawn(move || client(port, id, &stop, &hits))
+        })
+        .collect();
+
+    thread::sleep(DURATION);
+    stop.store(true, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    let per_second = hits.load(Ordering::Relaxed) as f64 / DURATION.as_secs_f64();
+    println!("{:<12} {:>12.0} keys/s", name, per_second);
+}
+
+fn main() {
+    bench("multi-thread", &[]);
+    bench("core-pinned", &["--core-pinned"]);
+}
@@ -0,0 +1,85 @@
+//! Compares the hit rates of the eviction policies on a zipfian workload.
+//!
+//! Run with `cargo bench --bench eviction`. Every policy starts its own
+//! server with `--memory-limit`, too small for the `KEYS` keys, and replays
+//! the same trace of `REQUESTS` reads: a `get`, followed by a `set` on a
+//! miss, like a read-through client. Key popularity follows Zipf's law with
+//! exponent `SKEW`. Prints the hit rate for each policy.
+
+mod common;
+
+use common::start;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+const KEYS: usize = 20_000;
+const VALUE_SIZE: usize = 1000;
+const MEMORY_LIMIT_MB: &str = "4";
+const REQUESTS: usize = 200_000;
+const SKEW: f64 = 0.9;
+
+/// Key indexes drawn from a Zipf distribution over `KEYS` keys, the same on
+/// every run.
+fn trace() -> Vec<usize> {
+    let mut cumulative = Vec::with_capacity(KEYS);
+    let mut total = 0.0;
+    for rank in 1..=KEYS {
+        total += 1.0 / (rank as f64).powf(SKEW);
+        cumulative.push(total);
+    }
+
+    // xorshift64*, good enough to sample with
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..REQUESTS)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            let random = state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+            let target = random as f64 / (1u64 << 53) as f64 * total;
+            cumulative.partition_point(|sum| *sum < target).min(KEYS - 1)
+        })
+        .collect()
+}
+
+/// Replay `trace`, returning the fraction of gets that hit.
+fn replay(port: u16, trace: &[usize]) -> f64 {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut set = Vec::new();
+    let mut line = String::new();
+    let mut hits = 0;
+
+    for key in trace {
+        write!(stream.get_mut(), "get key{}\r\n", key).unwrap();
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        if line.starts_with("VALUE ") {
+            hits += 1;
+            // Skip the data block and END
+            stream.read_line(&mut line).unwrap();
+            stream.read_line(&mut line).unwrap();
+            continue;
+        }
+        assert_eq!(line, "END\r\n");
+        set.clear();
+        write!(set, "set key{} 0 0 {} noreply\r\n", key, VALUE_SIZE).unwrap();
+        set.resize(set.len() + VALUE_SIZE, b'x');
+        set.extend_from_slice(b"\r\n");
+        stream.get_mut().write_all(&set).unwrap();
+    }
+    hits as f64 / trace.len() as f64
+}
+
+fn bench(policy: &str, trace: &[usize]) {
+    let (_server, port) = start(&["-m", MEMORY_LIMIT_MB, "--eviction-policy", policy]);
+    let hit_rate = replay(port, trace);
+    println!("{:<6} {:>6.1}% hits", policy, hit_rate * 100.0);
+}
+
+fn main() {
+    let trace = trace();
+    bench("lru", &trace);
+    bench("lfu", &trace);
+}
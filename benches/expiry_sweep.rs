@@ -0,0 +1,111 @@
+//! Compares the ways of sweeping expired items, against how many items there
+//! are and how many of them expire.
+//!
+//! Run with `cargo bench --bench expiry_sweep`. Linux only, as it reads the
+//! server's CPU time and memory from `/proc`. For each case and
+//! `--expiry-sweep`, a server is started, given items of which some, spread
+//! evenly over the keys, expire in `TTL` seconds and the rest never do, and
+//! left alone for `WINDOW`. Prints
+//! the CPU time the server took meanwhile, how many expired items it still
+//! held at the end, and its resident memory then.
+
+mod common;
+
+use common::{start, Server};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+/// (items, of which expiring)
+const CASES: [(usize, usize); 4] = [
+    (100_000, 10_000),
+    (1_000_000, 10_000),
+    (1_000_000, 100_000),
+    (1_000_000, 500_000),
+];
+const SWEEPS: [&str; 3] = ["scan", "index", "sampled"];
+const TTL: u64 = 5;
+const WINDOW: Duration = Duration::from_secs(TTL + 3);
+const SETS_PER_WRITE: usize = 1000;
+/// Clock ticks per second in `/proc/<pid>/stat`, `USER_HZ`
+const TICKS_PER_SECOND: u64 = 100;
+
+/// CPU time `server` has taken so far, user and system.
+fn cpu_time(server: &Server) -> Duration {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", server.0.id())).unwrap();
+    // Fields after the command, which is in parentheses and may hold spaces
+    let fields: Vec<&str> = stat.rsplit_once(')').unwrap().1.split_whitespace().collect();
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    Duration::from_millis(ticks * 1000 / TICKS_PER_SECOND)
+}
+
+/// Resident memory of `server`, in megabytes.
+fn resident(server: &Server) -> u64 {
+    let status = fs::read_to_string(format!("/proc/{}/status", server.0.id())).unwrap();
+    let line = status.lines().find(|line| line.starts_with("VmRSS:")).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap() / 1024
+}
+
+/// Store `items` keys, every `items / expiring`th expiring in `TTL`
+/// seconds.
+fn load(stream: &mut BufReader<TcpStream>, items: usize, expiring: usize) {
+    let mut request = Vec::new();
+    for chunk in (0..items).collect::<Vec<_>>().chunks(SETS_PER_WRITE) {
+        request.clear();
+        for key in chunk {
+            let ttl = if key % (items / expiring) == 0 { TTL } else { 0 };
+            write!(request, "set key{} 0 {} 8 noreply\r\nxxxxxxxx\r\n", key, ttl).unwrap();
+        }
+        stream.get_mut().write_all(&request).unwrap();
+    }
+    // Answered once every set before it is done
+    curr_items(stream);
+}
+
+fn curr_items(stream: &mut BufReader<TcpStream>) -> usize {
+    stream.get_mut().write_all(b"stats\r\n").unwrap();
+    let mut items = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        if line == "END\r\n" {
+            return items.unwrap();
+        }
+        if let Some(value) = line.strip_prefix("STAT curr_items ") {
+            items = Some(value.trim_end().parse().unwrap());
+        }
+    }
+}
+
+fn bench(sweep: &str, items: usize, expiring: usize) {
+    let (server, port) = start(&["--expiry-sweep", sweep]);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut stream = BufReader::new(stream);
+    load(&mut stream, items, expiring);
+
+    let before = cpu_time(&server);
+    thread::sleep(WINDOW);
+    let cpu = cpu_time(&server) - before;
+    let left = curr_items(&mut stream).saturating_sub(items - expiring);
+    println!(
+        "{:<8} {:>9} items {:>7} expiring  {:>6} ms CPU  {:>7} expired left  {:>5} MB",
+        sweep,
+        items,
+        expiring,
+        cpu.as_millis(),
+        left,
+        resident(&server)
+    );
+}
+
+fn main() {
+    for (items, expiring) in CASES {
+        for sweep in SWEEPS {
+            bench(sweep, items, expiring);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Compares the throughput of `Generator::gen`, one atomic updated with
+//! compare and swap, with that of the generator it replaced, which kept the
+//! timestamp and the count in two atomics and could hand out an id twice.
+//!
+//! Run with `cargo bench --bench id_generator`. For 1 and `THREADS`
+//! threads, each generator hands out `IDS` ids per thread. Prints the ids
+//! per second, and how many of them were duplicates.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+// Built with its tests under `cargo clippy --all-targets`, which go unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/id_generator.rs"]
+mod id_generator;
+#[path = "../src/sync.rs"]
+mod sync;
+
+const IDS: usize = 2_000_000;
+const THREADS: usize = 8;
+
+/// The generator before ids were one atomic.
+mod two_atomics {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    pub struct Generator {
+        ts: AtomicU32,
+        count: AtomicU32,
+    }
+
+    impl Generator {
+        pub fn new() -> Generator {
+            Generator {
+                ts: AtomicU32::new(current_ts()),
+                count: AtomicU32::new(0),
+            }
+        }
+
+        pub fn gen(&self) -> u64 {
+            let now = current_ts();
+            let last_ts = self.ts.swap(now, Ordering::SeqCst);
+            let count = if now == last_ts {
+                self.count.fetch_add(1, Ordering::SeqCst)
+            } else {
+                self.count.store(0, Ordering::SeqCst);
+                0
+            };
+            u64::from(now) << 32 | u64::from(count)
+        }
+    }
+
+    fn current_ts() -> u32 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("getting time since unix epoch")
+            .as_secs() as u32
+    }
+}
+
+/// Hand out `IDS` ids on each of `threads` threads with `gen`. Returns the
+/// ids per second and how many were handed out before.
+fn run(threads: usize, gen: impl Fn() -> u64 + Send + Sync + 'static) -> (f64, usize) {
+    let gen = Arc::new(gen);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let gen = gen.clone();
+            thread::spawn(move || (0..IDS).map(|_| gen()).collect::<Vec<u64>>())
+        })
+        .collect();
+    let ids: Vec<u64> = handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+    let elapsed = start.elapsed();
+    let unique: HashSet<u64> = ids.iter().copied().collect();
+    (ids.len() as f64 / elapsed.as_secs_f64(), ids.len() - unique.len())
+}
+
+fn main() {
+    println!("{:<12} {:>8} {:>14} {:>11}", "generator", "threads", "ids/s", "duplicates");
+    for threads in [1, THREADS] {
+        let gen = id_generator::Generator::new();
+        let (rate, duplicates) = run(threads, move || gen.gen());
+        println!("{:<12} {:>8} {:>14.0} {:>11}", "one atomic", threads, rate, duplicates);
+        let gen = two_atomics::Generator::new();
+        let (rate, duplicates) = run(threads, move || gen.gen());
+        println!("{:<12} {:>8} {:>14.0} {:>11}", "two atomics", threads, rate, duplicates);
+    }
+}
@@ -0,0 +1,89 @@
+//! Measures how gets scale while other clients insert and delete keys.
+//!
+//! Run with `cargo bench --bench index_contention`. Starts a server, then
+//! `CLIENTS` connections each send requests for `DURATION`: `get`s, with a
+//! `set` of a new key and a `delete` of an old one every `WRITE_EVERY` of
+//! them, so the index keeps changing. Prints the requests served per second.
+
+mod common;
+
+use common::start;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const KEYS: usize = 10_000;
+const CLIENTS: usize = 8;
+const WRITE_EVERY: usize = 5;
+const DURATION: Duration = Duration::from_secs(5);
+
+/// Send requests until `stop` is set, counting them in `served`.
+///
+/// Every request waits for its answer; a `set` and a `delete`, both
+/// `noreply`, go out together with every `WRITE_EVERY`th `get`.
+fn client(port: u16, id: usize, stop: &AtomicBool, served: &AtomicU64) {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut request = Vec::new();
+    let mut line = String::new();
+    let mut next = 0;
+
+    while !stop.load(Ordering::Relaxed) {
+        next += 1;
+        request.clear();
+        if next % WRITE_EVERY == 0 {
+            // Keys of their own, so every set inserts
+            write!(request, "set c{}-{} 0 0 1 noreply\r\nx\r\n", id, next).unwrap();
+            write!(request, "delete c{}-{} noreply\r\n", id, next - WRITE_EVERY).unwrap();
+            served.fetch_add(2, Ordering::Relaxed);
+        }
+        write!(request, "get key{}\r\n", (next * 7919 + id) % KEYS).unwrap();
+        stream.get_mut().write_all(&request).unwrap();
+
+        loop {
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+            if line == "END\r\n" {
+                break;
+            }
+        }
+        served.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn main() {
+    let (_server, port) = start(&[]);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    for key in 0..KEYS {
+        let set = format!("set key{} 0 0 5\r\nvalue\r\n", key);
+        stream.get_mut().write_all(set.as_bytes()).unwrap();
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "STORED\r\n");
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let served = Arc::new(AtomicU64::new(0));
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|id| {
+            let stop = stop.clone();
+            let served = served.clone();
+            thread::spawn(move || client(port, id, &stop, &served))
+        })
+        .collect();
+    thread::sleep(DURATION);
+    stop.store(true, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    let per_second = served.load(Ordering::Relaxed) as f64 / DURATION.as_secs_f64();
+    println!("{} clients: {:.0} requests/s", CLIENTS, per_second);
+}
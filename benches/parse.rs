@@ -0,0 +1,55 @@
+//! Parsing requests: taking a frame off the read buffer with
+//! `RequestFrame::check` and `parse`, then the command with
+//! `Command::from_frame`, as a connection does for each request.
+//!
+//! Run with `cargo bench --bench parse`. Save a baseline on one branch
+//! with `-- --save-baseline main` and compare another against it with
+//! `-- --baseline main`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sidica::internals::{Command, RequestFrame};
+use std::hint::black_box;
+use std::io::Cursor;
+
+/// Value sizes of the `set`s parsed.
+const VALUE_SIZES: [usize; 3] = [10, 1000, 100_000];
+
+fn parse(request: &[u8]) -> Command {
+    let mut cursor = Cursor::new(request);
+    RequestFrame::check(&mut cursor).unwrap();
+    cursor.set_position(0);
+    Command::from_frame(RequestFrame::parse(&mut cursor).unwrap()).unwrap()
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse/get");
+    for keys in [1, 10, 100] {
+        let mut request = b"get".to_vec();
+        for key in 0..keys {
+            request.extend_from_slice(format!(" key:{}", key).as_bytes());
+        }
+        request.extend_from_slice(b"\r\n");
+        group.throughput(Throughput::Bytes(request.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(keys), &request, |b, request| {
+            b.iter(|| parse(black_box(request)))
+        });
+    }
+    group.finish();
+}
+
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse/set");
+    for size in VALUE_SIZES {
+        let mut request = format!("set key:1234 0 0 {}\r\n", size).into_bytes();
+        request.extend(std::iter::repeat_n(b'x', size));
+        request.extend_from_slice(b"\r\n");
+        group.throughput(Throughput::Bytes(request.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &request, |b, request| {
+            b.iter(|| parse(black_box(request)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get, set);
+criterion_main!(benches);
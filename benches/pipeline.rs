@@ -0,0 +1,90 @@
+//! Pipelined `get`s, answered in batches rather than one write each.
+//!
+//! Run with `cargo bench --bench pipeline`. Starts a server, preloads `KEYS`
+//! keys, then `CLIENTS` connections each send `DEPTH` `get` requests in one
+//! write and read every response before sending the next ones, for
+//! `DURATION`. Prints the requests answered per second, and for comparison
+//! the same without pipelining.
+
+mod common;
+
+use common::start;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const KEYS: usize = 1000;
+const DEPTH: usize = 64;
+const CLIENTS: usize = 8;
+const DURATION: Duration = Duration::from_secs(5);
+
+fn preload(port: u16) {
+    let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+    let mut line = String::new();
+    for key in 0..KEYS {
+        write!(stream.get_mut(), "set key{} 0 0 5\r\nvalue\r\n", key).unwrap();
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "STORED\r\n");
+    }
+}
+
+/// Send `depth` gets at a time until `stop` is set, counting the requests
+/// answered in `answered`.
+fn client(port: u16, id: usize, depth: usize, stop: &AtomicBool, answered: &AtomicU64) {
+    let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+    stream.get_ref().set_nodelay(true).unwrap();
+    let mut request = String::new();
+    let mut line = String::new();
+    let mut next = id * depth;
+
+    while !stop.load(Ordering::Relaxed) {
+        request.clear();
+        for _ in 0..depth {
+            request.push_str(&format!("get key{}\r\n", next % KEYS));
+            next += 1;
+        }
+        stream.get_mut().write_all(request.as_bytes()).unwrap();
+
+        for _ in 0..depth {
+            // A value, its data block and `END`
+            for _ in 0..3 {
+                line.clear();
+                stream.read_line(&mut line).unwrap();
+            }
+            assert_eq!(line, "END\r\n");
+        }
+        answered.fetch_add(depth as u64, Ordering::Relaxed);
+    }
+}
+
+fn bench(port: u16, depth: usize) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let answered = Arc::new(AtomicU64::new(0));
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|id| {
+            let stop = stop.clone();
+            let answered = answered.clone();
+            thread::spawn(move || client(port, id, depth, &stop, &answered))
+        })
+        .collect();
+
+    thread::sleep(DURATION);
+    stop.store(true, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    let per_second = answered.load(Ordering::Relaxed) as f64 / DURATION.as_secs_f64();
+    println!("depth {:<6} {:>12.0} requests/s", depth, per_second);
+}
+
+fn main() {
+    let (_server, port) = start(&[]);
+    preload(port);
+    bench(port, 1);
+    bench(port, DEPTH);
+}
@@ -0,0 +1,72 @@
+//! Writing `VALUE` responses, the bulk of what a server sends, of several
+//! sizes, with and without the CAS: `Connection::write` and a flush to a
+//! socket that takes every byte at once.
+//!
+//! Run with `cargo bench --bench serialize`. Save a baseline on one branch
+//! with `-- --save-baseline main` and compare another against it with
+//! `-- --baseline main`.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on;
+use sidica::internals::{Connection, ResponseFrame};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const VALUE_SIZES: [usize; 4] = [10, 100, 1000, 100_000];
+
+/// A socket that is never read from and drops what is written to it.
+#[derive(Debug)]
+struct Discard;
+
+impl AsyncRead for Discard {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Discard {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn value(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize/value");
+    let mut connection = Connection::new(Discard);
+    for size in VALUE_SIZES {
+        let data = Bytes::from(vec![b'x'; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+        for (name, cas) in [("get", None), ("gets", Some(u64::MAX))] {
+            group.bench_with_input(BenchmarkId::new(name, size), &data, |b, data| {
+                b.iter(|| {
+                    let frame = ResponseFrame::Value {
+                        key: Bytes::from_static(b"key:1234"),
+                        flags: 0,
+                        data_length: data.len(),
+                        cas,
+                        data: data.clone(),
+                    };
+                    block_on(async {
+                        connection.write(frame).await.unwrap();
+                        connection.flush().await.unwrap();
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, value);
+criterion_main!(benches);
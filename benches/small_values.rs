@@ -0,0 +1,87 @@
+//! Compares the memory taken by values short enough to be kept in the item
+//! with that taken by values just too long for it.
+//!
+//! Run with `cargo bench --bench small_values`. Linux only, as it reads the
+//! server's memory from `/proc`. For each of `VALUE_SIZES`, a server is
+//! started and given `ITEMS` items with values of that size. Prints how many
+//! it holds, its resident memory and how much it grew, and the bytes it
+//! counts for the items.
+
+mod common;
+
+use common::{start, Server};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+const ITEMS: usize = 1_000_000;
+/// Up to 23 bytes are kept in the item
+const VALUE_SIZES: [usize; 4] = [8, 23, 24, 32];
+const SETS_PER_WRITE: usize = 1000;
+
+/// Resident memory of `server`, in megabytes.
+fn resident(server: &Server) -> u64 {
+    let status = fs::read_to_string(format!("/proc/{}/status", server.0.id())).unwrap();
+    let line = status.lines().find(|line| line.starts_with("VmRSS:")).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap() / 1024
+}
+
+/// Store `ITEMS` keys, each with a `size` byte value.
+fn load(stream: &mut BufReader<TcpStream>, size: usize) {
+    let value = "x".repeat(size);
+    let mut request = Vec::new();
+    for chunk in (0..ITEMS).collect::<Vec<_>>().chunks(SETS_PER_WRITE) {
+        request.clear();
+        for key in chunk {
+            write!(request, "set key{} 0 0 {} noreply\r\n{}\r\n", key, size, value).unwrap();
+        }
+        stream.get_mut().write_all(&request).unwrap();
+    }
+}
+
+/// The `stats` counter `name`. Answered once every command sent before it
+/// is done.
+fn stat(stream: &mut BufReader<TcpStream>, name: &str) -> u64 {
+    stream.get_mut().write_all(b"stats\r\n").unwrap();
+    let prefix = format!("STAT {} ", name);
+    let mut value = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        if line == "END\r\n" {
+            return value.unwrap();
+        }
+        if let Some(found) = line.strip_prefix(&prefix) {
+            value = Some(found.trim_end().parse().unwrap());
+        }
+    }
+}
+
+fn bench(size: usize) {
+    let (server, port) = start(&[]);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut stream = BufReader::new(stream);
+    stat(&mut stream, "curr_items");
+    let before = resident(&server);
+
+    load(&mut stream, size);
+    let items = stat(&mut stream, "curr_items");
+    let bytes = stat(&mut stream, "bytes");
+    let after = resident(&server);
+    println!(
+        "{:>3} byte values  {:>7} items  {:>5} MB resident  {:>5} MB grown  {:>5} MB counted",
+        size,
+        items,
+        after,
+        after - before,
+        bytes / (1024 * 1024)
+    );
+}
+
+fn main() {
+    for size in VALUE_SIZES {
+        bench(size);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// An address block in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare
+/// address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Error {
+    #[error("invalid address in `{0}`")]
+    Address(String),
+    #[error("invalid prefix length in `{0}`")]
+    Prefix(String),
+}
+
+impl Cidr {
+    /// Returns true if `ip` is in this block. IPv4-mapped IPv6 addresses
+    /// match the IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr, Error> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| Error::Address(s.to_string()))?;
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| Error::Prefix(s.to_string()))?,
+            None => max,
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Returns true if a client at `ip` may connect.
+///
+/// A match in `deny` always refuses. Otherwise an empty `allow` list admits
+/// everyone, and a non-empty one only the addresses it matches.
+pub fn permits(allow: &[Cidr], deny: &[Cidr], ip: IpAddr) -> bool {
+    if deny.iter().any(|cidr| cidr.contains(ip)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|cidr| cidr.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.prefix, 8);
+        assert_eq!(cidr.to_string(), "10.1.2.3/8");
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert_eq!("0.0.0.0/0".parse::<Cidr>().unwrap().prefix, 0);
+
+        assert_eq!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(Error::Prefix("10.0.0.0/33".to_string()))
+        );
+        assert_eq!(
+            "10.0.0.0/x".parse::<Cidr>(),
+            Err(Error::Prefix("10.0.0.0/x".to_string()))
+        );
+        assert_eq!(
+            "localhost/8".parse::<Cidr>(),
+            Err(Error::Address("localhost/8".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_contains() {
+        let net: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("192.168.10.1")));
+        assert!(!net.contains(ip("192.169.0.1")));
+        assert!(net.contains(ip("::ffff:192.168.1.1")));
+        assert!(!net.contains(ip("fd00::1")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(ip("fd12::1")));
+        assert!(!"::1".parse::<Cidr>().unwrap().contains(ip("::2")));
+    }
+
+    #[test]
+    fn test_precedence() {
+        // No lists: everyone is admitted
+        assert!(permits(&[], &[], ip("1.2.3.4")));
+
+        // An allow list admits only its members
+        let allow = cidrs(&["10.0.0.0/8"]);
+        assert!(permits(&allow, &[], ip("10.1.1.1")));
+        assert!(!permits(&allow, &[], ip("11.1.1.1")));
+
+        // Deny wins over allow
+        let deny = cidrs(&["10.0.0.66"]);
+        assert!(!permits(&allow, &deny, ip("10.0.0.66")));
+        assert!(permits(&allow, &deny, ip("10.0.0.67")));
+
+        // Deny alone refuses only its members
+        assert!(!permits(&[], &deny, ip("10.0.0.66")));
+        assert!(permits(&[], &deny, ip("11.0.0.66")));
+    }
+}
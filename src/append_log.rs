@@ -0,0 +1,722 @@
+//! Append-only log: every change to the cache written to `--data-dir` as it
+//! is made, so changes acknowledged since the last snapshot survive a crash.
+//! Replayed on startup on top of the snapshot, and emptied whenever a
+//! snapshot has been saved.
+//!
+//! Each record is the length of its body and the CRC-32 of the body, both
+//! u32, and then the body: a tag and the change.
+//!
+//! ```text
+//! STORE   the item as it is now, laid out as in snapshots
+//! DELETE  key length u32, key
+//! TOUCH   key length u32, key, expiration as in snapshots
+//! FLUSH   cas u64: items up to it are flushed, at u64: when, 0 for now
+//! RESET   nothing: every item goes, the rest of the log holds the cache
+//! ```
+//!
+//! A log only grows, by a record for every change, however few items there
+//! are. It is rewritten to hold one record for each item once it has grown
+//! to `--log-rewrite-ratio` times their size, or on `rewrite_log`; see
+//! `AppendLog::rewrite`.
+//!
+//! Numbers are big endian.
+
+use crate::cache::{Cache, Item};
+use crate::checksum;
+use crate::settings::Settings;
+use crate::snapshot::{read_item, write_item};
+
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use bytes::{BufMut, Bytes, BytesMut};
+use parking_lot::{Condvar, Mutex};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use tokio::time;
+use tracing::{error, info, warn};
+
+/// Name of the log in `--data-dir`.
+pub(crate) const FILE: &str = "log";
+
+/// Most bytes of records waiting to be written. Changes wait for room
+/// beyond it.
+const QUEUE_CAPACITY: usize = 16 * 1024 * 1024;
+
+/// How often written records are synced to disk. A crash of the machine, not
+/// just of the server, loses at most this much.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Smallest log rewritten for having grown, see `rewrite_when_grown`.
+const REWRITE_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How often `rewrite_when_grown` checks the size of the log.
+const REWRITE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most bytes of records queued during a rewrite that are added to the
+/// rewritten log while changes wait. Those before are added while they go
+/// on.
+const REWRITE_CATCH_UP: usize = 64 * 1024;
+
+const STORE: u8 = 1;
+const DELETE: u8 = 2;
+const TOUCH: u8 = 3;
+const FLUSH: u8 = 4;
+const RESET: u8 = 5;
+
+/// A change to the cache, see the module documentation for how each is
+/// written.
+#[derive(Debug)]
+pub(crate) enum Record<'a> {
+    Store(&'a Item),
+    Delete(&'a [u8]),
+    Touch(&'a [u8], Option<u64>),
+    Flush { cas: u64, at: Option<u64> },
+    Reset,
+}
+
+impl Record<'_> {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Record::Store(item) => {
+                out.write_all(&[STORE])?;
+                write_item(out, item)
+            }
+            Record::Delete(key) => {
+                out.write_all(&[DELETE])?;
+                out.write_all(&(key.len() as u32).to_be_bytes())?;
+                out.write_all(key)
+            }
+            Record::Touch(key, expiration) => {
+                out.write_all(&[TOUCH])?;
+                out.write_all(&(key.len() as u32).to_be_bytes())?;
+                out.write_all(key)?;
+                write_expiration(out, *expiration)
+            }
+            Record::Flush { cas, at } => {
+                out.write_all(&[FLUSH])?;
+                out.write_all(&cas.to_be_bytes())?;
+                out.write_all(&at.unwrap_or(0).to_be_bytes())
+            }
+            Record::Reset => out.write_all(&[RESET]),
+        }
+    }
+
+    /// Apply the change written in `body` to `cache`.
+    fn replay(body: &[u8], cache: &Cache) -> Result<()> {
+        fn key(reader: &mut &[u8]) -> io::Result<Bytes> {
+            let len = u32::from_be_bytes(bytes(reader)?);
+            let mut key = vec![0; len as usize];
+            reader.read_exact(&mut key)?;
+            Ok(Bytes::from(key))
+        }
+        fn bytes<const N: usize>(reader: &mut &[u8]) -> io::Result<[u8; N]> {
+            let mut buf = [0; N];
+            reader.read_exact(&mut buf)?;
+            Ok(buf)
+        }
+
+        let reader = &mut &body[..];
+        match bytes::<1>(reader)? {
+            [STORE] => match read_item(reader)? {
+                Some(item) => cache.restore(item),
+                None => bail!("empty store record"),
+            },
+            [DELETE] => {
+                cache.remove(&key(reader)?);
+            }
+            [TOUCH] => {
+                let key = key(reader)?;
+                let [has_expiration, expiration @ ..] = bytes::<9>(reader)?;
+                let expiration = (has_expiration != 0).then(|| u64::from_be_bytes(expiration));
+                cache.set_expiration(&key, expiration);
+            }
+            [FLUSH] => {
+                let cas = u64::from_be_bytes(bytes(reader)?);
+                let at = u64::from_be_bytes(bytes(reader)?);
+                cache.replay_flush(cas, (at != 0).then_some(at));
+            }
+            [RESET] => cache.clear_now(),
+            [tag] => bail!("unknown record type {}", tag),
+        }
+        Ok(())
+    }
+}
+
+fn write_expiration(out: &mut impl Write, expiration: Option<u64>) -> io::Result<()> {
+    match expiration {
+        Some(expiration) => {
+            out.write_all(&[1])?;
+            out.write_all(&expiration.to_be_bytes())
+        }
+        None => out.write_all(&[0; 9]),
+    }
+}
+
+/// Records waiting to be written.
+#[derive(Debug, Default)]
+struct Queue {
+    records: BytesMut,
+    /// Number of the last record queued, counting from 1
+    last: u64,
+    /// Set by `close`, nothing is queued until started again
+    closed: bool,
+    /// While the log is rewritten, the records queued since it started, to
+    /// add to the rewritten log
+    rewrite: Option<BytesMut>,
+}
+
+/// The append-only log of a cache, see `Cache::with_append_log`.
+///
+/// Changes are queued under the lock of the item they change, so the log has
+/// them in the order they were made, and written by a thread of their own.
+/// The cache waits for its changes to be written before reporting them
+/// done, so what a client was told is stored is in the file even if the
+/// server is killed right after. Writes are synced to disk every
+/// `SYNC_INTERVAL`.
+#[derive(Debug)]
+pub(crate) struct AppendLog {
+    path: PathBuf,
+    /// Locked while records are taken from the queue and written, and while
+    /// a rewritten log replaces it
+    file: Mutex<File>,
+    /// Bytes in the file
+    size: AtomicU64,
+    queue: Mutex<Queue>,
+    /// Wakes the writer when records are queued or the log is closed
+    queued: Condvar,
+    /// Wakes changes waiting for room in the queue
+    room: Notify,
+    /// Number of the last record written
+    written: watch::Sender<u64>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AppendLog {
+    /// Open the log in `dir` to append to, creating it if there is none.
+    /// Nothing is written until `start`.
+    pub(crate) fn open(dir: &Path) -> Result<Arc<AppendLog>> {
+        let path = dir.join(FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("opening log {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Arc::new(AppendLog {
+            path,
+            file: Mutex::new(file),
+            size: AtomicU64::new(size),
+            queue: Mutex::new(Queue::default()),
+            queued: Condvar::new(),
+            room: Notify::new(),
+            written: watch::Sender::new(0),
+            writer: Mutex::new(None),
+        }))
+    }
+
+    /// Apply the records in the log to `cache`, returning how many there
+    /// were. Call before any record is queued.
+    ///
+    /// A record cut short at the end, as left by a crash while writing it,
+    /// is dropped. A damaged record anywhere else is an error.
+    pub(crate) fn replay(&self, cache: &Cache) -> Result<usize> {
+        let context = || format!("replaying log {}", self.path.display());
+        let file = self.file.lock();
+        let (replayed, end) = replay(&file, cache).with_context(context)?;
+        let len = file.metadata().with_context(context)?.len();
+        if end < len {
+            warn!(
+                "dropping the last {} bytes of log {}, a record cut short",
+                len - end,
+                self.path.display()
+            );
+            file.set_len(end).with_context(context)?;
+            self.size.store(end, Ordering::Relaxed);
+        }
+        Ok(replayed)
+    }
+
+    /// Start writing records, on a thread of its own. Also after `close`,
+    /// to start again.
+    pub(crate) fn start(self: &Arc<Self>) -> Result<()> {
+        self.queue.lock().closed = false;
+        let log = self.clone();
+        let handle = thread::Builder::new()
+            .name("append-log".to_string())
+            .spawn(move || log.write())?;
+        *self.writer.lock() = Some(handle);
+        Ok(())
+    }
+
+    /// Bytes in the log file.
+    pub(crate) fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Whether the log is being rewritten.
+    pub(crate) fn rewriting(&self) -> bool {
+        self.queue.lock().rewrite.is_some()
+    }
+
+    /// Wait until there is room to queue a record. Call before taking any
+    /// lock `push` is called under.
+    pub(crate) async fn reserve(&self) {
+        loop {
+            let room = self.room.notified();
+            if self.queue.lock().records.len() < QUEUE_CAPACITY {
+                return;
+            }
+            room.await;
+        }
+    }
+
+    /// Queue `record`. Does nothing once closed.
+    pub(crate) fn push(&self, record: Record<'_>) {
+        let mut queue = self.queue.lock();
+        if queue.closed {
+            return;
+        }
+        let start = queue.records.len();
+        encode(&record, &mut queue.records);
+        let queue = &mut *queue;
+        if let Some(rewrite) = &mut queue.rewrite {
+            rewrite.extend_from_slice(&queue.records[start..]);
+        }
+        queue.last += 1;
+        self.queued.notify_one();
+    }
+
+    /// Wait until every record queued so far has been written.
+    pub(crate) async fn written(&self) {
+        let last = self.queue.lock().last;
+        let mut written = self.written.subscribe();
+        let _ = written.wait_for(|written| *written >= last).await;
+    }
+
+    /// Write and sync what is queued, then stop. Records pushed after are
+    /// dropped, and a rewrite under way is given up. Blocks until done.
+    pub(crate) fn close(&self) {
+        self.queue.lock().closed = true;
+        self.queued.notify_one();
+        if let Some(writer) = self.writer.lock().take() {
+            let _ = writer.join();
+        }
+    }
+
+    /// Empty the log, once closed and its changes saved in a snapshot.
+    pub(crate) fn clear(&self) -> Result<()> {
+        self.file
+            .lock()
+            .set_len(0)
+            .with_context(|| format!("emptying log {}", self.path.display()))?;
+        self.size.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Write queued records to the file until closed.
+    ///
+    /// A failed write is logged and the records are given up on, so changes
+    /// are not held up forever; they only live in memory then.
+    fn write(&self) {
+        let mut synced = Instant::now();
+        let mut unsynced = false;
+        loop {
+            let mut queue = self.queue.lock();
+            if queue.records.is_empty() && !queue.closed {
+                self.queued.wait_for(&mut queue, SYNC_INTERVAL);
+            }
+            drop(queue);
+            // Taken before the records, so they go to the file they were
+            // queued for even if the log was rewritten meanwhile
+            let mut file = self.file.lock();
+            let mut queue = self.queue.lock();
+            let records = queue.records.split().freeze();
+            let (last, closed) = (queue.last, queue.closed);
+            drop(queue);
+            self.room.notify_waiters();
+
+            if !records.is_empty() {
+                match file.write_all(&records) {
+                    Ok(()) => {
+                        self.size.fetch_add(records.len() as u64, Ordering::Relaxed);
+                        unsynced = true;
+                    }
+                    Err(err) => error!("failed to write log {}: {}", self.path.display(), err),
+                }
+            }
+            self.written.send_replace(last);
+
+            if unsynced && (closed || synced.elapsed() >= SYNC_INTERVAL) {
+                if let Err(err) = file.sync_data() {
+                    error!("failed to sync log {}: {}", self.path.display(), err);
+                }
+                synced = Instant::now();
+                unsynced = false;
+            }
+            if closed {
+                info!("closed log {}", self.path.display());
+                return;
+            }
+        }
+    }
+
+    /// Rewrite the log from what is in `cache`, on a thread of its own, so
+    /// it only holds a record for each item. Returns false if a rewrite is
+    /// already under way or the log is closed.
+    ///
+    /// The rewritten log starts with a `RESET` and is written under a
+    /// temporary name, then renamed over the log once complete, so a crash
+    /// at any point leaves either log whole. Changes go on meanwhile; they
+    /// are written to both.
+    pub(crate) fn rewrite(self: &Arc<Self>, cache: &Cache) -> bool {
+        {
+            let mut queue = self.queue.lock();
+            if queue.closed || queue.rewrite.is_some() {
+                return false;
+            }
+            queue.rewrite = Some(BytesMut::new());
+        }
+        let (log, cache) = (self.clone(), cache.clone());
+        let spawned = thread::Builder::new()
+            .name("append-log-rewrite".to_string())
+            .spawn(move || {
+                let temporary = log.path.with_extension("tmp");
+                match log.write_rewritten(&temporary, &cache) {
+                    Ok(Some(size)) => info!(bytes = size, "rewrote log {}", log.path.display()),
+                    Ok(None) => info!("log closed, gave up rewriting it"),
+                    Err(err) => error!("failed to rewrite log {}: {:#}", log.path.display(), err),
+                }
+                log.queue.lock().rewrite = None;
+                let _ = fs::remove_file(&temporary);
+            });
+        if let Err(err) = spawned {
+            error!("failed to start rewriting log {}: {}", self.path.display(), err);
+            self.queue.lock().rewrite = None;
+            return false;
+        }
+        true
+    }
+
+    /// Write the log rewritten from `cache` to `temporary`, add the records
+    /// queued meanwhile and put it in place of the log. Returns its size,
+    /// or `None` if the log was closed first.
+    fn write_rewritten(&self, temporary: &Path, cache: &Cache) -> Result<Option<u64>> {
+        let closed = || self.queue.lock().closed;
+        let mut out = BufWriter::new(File::create(temporary)?);
+        let mut record = BytesMut::new();
+        encode(&Record::Reset, &mut record);
+        if let Some(at) = cache.pending_flush() {
+            encode(&Record::Flush { cas: cache.current_cas(), at: Some(at) }, &mut record);
+        }
+        out.write_all(&record)?;
+        for (n, item) in cache.snapshot_iter().enumerate() {
+            if n % 1024 == 0 && closed() {
+                return Ok(None);
+            }
+            record.clear();
+            encode(&Record::Store(&item), &mut record);
+            out.write_all(&record)?;
+        }
+
+        // Until few enough are left to add while changes wait
+        loop {
+            let records = match &mut self.queue.lock().rewrite {
+                Some(records) if records.len() > REWRITE_CATCH_UP => records.split(),
+                _ => break,
+            };
+            out.write_all(&records)?;
+        }
+        let mut temporary_file = out.into_inner().map_err(|err| err.into_error())?;
+
+        let mut file = self.file.lock();
+        let mut queue = self.queue.lock();
+        if queue.closed {
+            return Ok(None);
+        }
+        if let Some(records) = queue.rewrite.take() {
+            temporary_file.write_all(&records)?;
+        }
+        temporary_file.sync_all()?;
+        fs::rename(temporary, &self.path)?;
+        sync_dir(&self.path)?;
+        let size = temporary_file.metadata()?.len();
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        self.size.store(size, Ordering::Relaxed);
+        // Already in the rewritten log
+        queue.records.clear();
+        self.written.send_replace(queue.last);
+        drop(queue);
+        self.room.notify_waiters();
+        Ok(Some(size))
+    }
+}
+
+/// Append `record`, with its length and checksum, to `out`.
+fn encode(record: &Record<'_>, out: &mut BytesMut) {
+    let start = out.len();
+    // Length and checksum, filled in once the body is written
+    out.put_bytes(0, 8);
+    // Writing to memory cannot fail
+    let _ = record.write(&mut out.writer());
+    let body = &out[start + 8..];
+    let (len, crc) = (body.len() as u32, checksum::update(0, body));
+    out[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    out[start + 4..start + 8].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// Make a rename of `path` durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Rewrite the log of `cache` every time it has grown to
+/// `--log-rewrite-ratio` times the size of the items, and is at least
+/// `REWRITE_MIN_SIZE`. Checks every `REWRITE_CHECK_INTERVAL`, for as long as
+/// the server runs.
+pub(crate) async fn rewrite_when_grown(cache: Cache, settings: Arc<ArcSwap<Settings>>) {
+    let Some(log) = cache.append_log() else {
+        return;
+    };
+    let mut interval = time::interval(REWRITE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ratio = settings.load().log_rewrite_ratio;
+        let size = log.size();
+        if ratio != 0 && size >= REWRITE_MIN_SIZE && size / u64::from(ratio) >= cache.bytes() as u64 {
+            info!(bytes = size, "the log has grown, rewriting it");
+            log.rewrite(&cache);
+        }
+    }
+}
+
+/// Apply the records in `file` to `cache`. Returns how many there were and
+/// where the last complete one ends.
+fn replay(file: &File, cache: &Cache) -> Result<(usize, u64)> {
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let (mut replayed, mut end) = (0, 0);
+    loop {
+        let mut header = [0; 8];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        let body_len = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let crc = u32::from_be_bytes(header[4..].try_into().unwrap());
+        let next = end + 8 + u64::from(body_len);
+        if next > len {
+            break;
+        }
+        let mut body = vec![0; body_len as usize];
+        reader.read_exact(&mut body)?;
+        if checksum::update(0, &body) != crc {
+            // Only the last record can have been cut short
+            if next == len {
+                break;
+            }
+            bail!("checksum mismatch in the record at byte {}, the log is corrupt", end);
+        }
+        Record::replay(&body, cache).with_context(|| format!("record at byte {}", end))?;
+        replayed += 1;
+        end = next;
+    }
+    Ok((replayed, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sidica-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Log a few changes to a cache in `dir`, returning the cache.
+    async fn log_changes(dir: &Path) -> Cache {
+        let log = AppendLog::open(dir).unwrap();
+        log.start().unwrap();
+        let cache = Cache::new().with_append_log(log.clone());
+        let expiration = Some(cache.now() + 60);
+        cache.set(Bytes::from("foo"), 5, None, Bytes::from("bar")).await;
+        cache.append(b"foo", Bytes::from("baz")).await;
+        cache.set(Bytes::from("n"), 0, None, Bytes::from("1")).await;
+        cache.add_delta(b"n", 41, crate::cache::Direction::Incr).await;
+        cache.touch(b"n", expiration).await;
+        cache.set(Bytes::from("gone"), 0, None, Bytes::from("x")).await;
+        cache.delete(b"gone").await;
+        log.close();
+        cache
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let dir = dir("replay");
+        let logged = log_changes(&dir).await;
+
+        let cache = Cache::new();
+        assert_eq!(AppendLog::open(&dir).unwrap().replay(&cache).unwrap(), 7);
+        for key in [&b"foo"[..], b"n"] {
+            let (item, logged) = (cache.get(key).await.unwrap(), logged.get(key).await.unwrap());
+            assert_eq!(
+                (item.flags, item.cas, item.expiration, item.data),
+                (logged.flags, logged.cas, logged.expiration, logged.data)
+            );
+        }
+        assert!(cache.get(b"gone").await.is_none());
+
+        // Items up to the flush go
+        let log = AppendLog::open(&dir).unwrap();
+        let flushed = Cache::new();
+        log.replay(&flushed).unwrap();
+        log.start().unwrap();
+        let flushed = flushed.with_append_log(log.clone());
+        flushed.flush(None).await;
+        log.close();
+        let cache = Cache::new();
+        AppendLog::open(&dir).unwrap().replay(&cache).unwrap();
+        assert!(cache.get(b"foo").await.is_none());
+        cache.set(Bytes::from("foo"), 0, None, Bytes::new()).await;
+        assert!(cache.get(b"foo").await.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_clear() {
+        let dir = dir("replay-clear");
+        log_changes(&dir).await;
+        let log = AppendLog::open(&dir).unwrap();
+        let cleared = Cache::new();
+        log.replay(&cleared).unwrap();
+        log.start().unwrap();
+        let cleared = cleared.with_append_log(log.clone());
+        cleared.clear().await;
+        cleared.set(Bytes::from("after"), 0, None, Bytes::from("x")).await;
+        log.close();
+
+        let cache = Cache::new();
+        AppendLog::open(&dir).unwrap().replay(&cache).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(b"after").await.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn wait_for_rewrite(log: &AppendLog) {
+        while log.rewriting() {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() {
+        let dir = dir("rewrite");
+        let log = AppendLog::open(&dir).unwrap();
+        log.start().unwrap();
+        let cache = Cache::new().with_append_log(log.clone());
+        for n in 0..100 {
+            cache.set(Bytes::from("foo"), 0, None, Bytes::from(n.to_string())).await;
+        }
+        cache.set(Bytes::from("gone"), 0, None, Bytes::from("x")).await;
+        cache.delete(b"gone").await;
+        let grown = log.size();
+        assert!(log.rewrite(&cache));
+        wait_for_rewrite(&log);
+        assert!(log.size() < grown / 10, "{} of {}", log.size(), grown);
+        // Appended to the rewritten log
+        cache.set(Bytes::from("bar"), 0, None, Bytes::from("baz")).await;
+        log.close();
+
+        // The rewritten log holds the whole cache, whatever was there
+        let replayed = Cache::new();
+        replayed.set(Bytes::from("stale"), 0, None, Bytes::new()).await;
+        assert_eq!(AppendLog::open(&dir).unwrap().replay(&replayed).unwrap(), 3);
+        assert_eq!(&replayed.get(b"foo").await.unwrap().data[..], b"99");
+        assert_eq!(&replayed.get(b"bar").await.unwrap().data[..], b"baz");
+        assert!(replayed.get(b"gone").await.is_none());
+        assert!(replayed.get(b"stale").await.is_none());
+        assert!(!dir.join("log.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_keeps_changes_made_meanwhile() {
+        let dir = dir("rewrite-meanwhile");
+        let log = AppendLog::open(&dir).unwrap();
+        log.start().unwrap();
+        let cache = Cache::new().with_append_log(log.clone());
+        let key = |n: usize| Bytes::from(format!("key{}", n));
+        for n in 0..20_000 {
+            cache.set(key(n), 0, None, Bytes::from("old")).await;
+        }
+        assert!(log.rewrite(&cache));
+        for n in (0..20_000).step_by(7) {
+            if n % 2 == 0 {
+                cache.set(key(n), 0, None, Bytes::from("new")).await;
+            } else {
+                cache.delete(&key(n)).await;
+            }
+        }
+        wait_for_rewrite(&log);
+        log.close();
+
+        let replayed = Cache::new();
+        AppendLog::open(&dir).unwrap().replay(&replayed).unwrap();
+        let items = |cache: &Cache| {
+            cache.items().map(|item| (item.key, item.cas, item.data)).collect::<Vec<_>>()
+        };
+        assert_eq!(items(&replayed), items(&cache));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drops_a_record_cut_short() {
+        let dir = dir("cut-short");
+        log_changes(&dir).await;
+        let path = dir.join(FILE);
+        let bytes = fs::read(&path).unwrap();
+        // The delete is the last record, 17 bytes long
+        for cut in [3, 10] {
+            fs::write(&path, &bytes[..bytes.len() - cut]).unwrap();
+            let cache = Cache::new();
+            assert_eq!(AppendLog::open(&dir).unwrap().replay(&cache).unwrap(), 6);
+            // The delete was lost
+            assert!(cache.get(b"gone").await.is_some());
+            assert_eq!(fs::metadata(&path).unwrap().len(), bytes.len() as u64 - 17);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refuses_a_damaged_record() {
+        let dir = dir("damaged");
+        log_changes(&dir).await;
+        let path = dir.join(FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        // In the first record
+        bytes[8 + 30] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let error = AppendLog::open(&dir).unwrap().replay(&Cache::new()).unwrap_err();
+        assert!(format!("{:#}", error).contains("checksum mismatch"), "{:#}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
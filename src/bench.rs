@@ -0,0 +1,406 @@
+//! `sidica bench`: a load generator for sidica and other memcached servers.
+//!
+//! Requests are built from the server's own commands (`Get::into_frame`,
+//! `Set::into_frame`) and responses are checked by a minimal parser, so a run
+//! against a server doubles as a check of protocol compatibility.
+
+use crate::commands::{Get, Set};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+
+/// Options of `sidica bench`.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Args {
+    /// Server to benchmark
+    #[arg(default_value = "127.0.0.1:8080")]
+    pub target: String,
+
+    /// Concurrent connections
+    #[arg(short = 'c', long = "connections", default_value_t = 50)]
+    pub connections: usize,
+
+    /// How long to run, in seconds
+    #[arg(short = 't', long = "time", value_name = "SECONDS", default_value_t = 10)]
+    pub time: u64,
+
+    /// Number of distinct keys requests pick from
+    #[arg(short = 'k', long = "keys", default_value_t = 10_000)]
+    pub keys: u32,
+
+    /// Size of set values in bytes, either fixed (`100`) or picked uniformly
+    /// from a range (`32-1024`)
+    #[arg(long = "value-size", default_value = "100")]
+    pub value_size: SizeRange,
+
+    /// Relative weights of set, get and multiget requests
+    #[arg(long = "ratio", value_name = "SET:GET:MULTIGET", default_value = "1:9:0")]
+    pub ratio: Ratio,
+
+    /// Keys per multiget
+    #[arg(long = "multiget-keys", default_value_t = 10)]
+    pub multiget_keys: u32,
+
+    /// Requests sent on a connection before waiting for their responses
+    #[arg(long = "pipeline", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub pipeline: u32,
+
+    /// Set every key once before starting, so gets find them
+    #[arg(long = "preload")]
+    pub preload: bool,
+}
+
+/// Value sizes, see `--value-size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeRange {
+    min: usize,
+    max: usize,
+}
+
+impl FromStr for SizeRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SizeRange> {
+        let (min, max) = s.split_once('-').unwrap_or((s, s));
+        let range = SizeRange {
+            min: min.parse()?,
+            max: max.parse()?,
+        };
+        if range.min > range.max {
+            bail!("{} is larger than {}", range.min, range.max);
+        }
+        Ok(range)
+    }
+}
+
+/// Request mix, see `--ratio`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ratio {
+    set: u32,
+    get: u32,
+    multiget: u32,
+}
+
+impl FromStr for Ratio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Ratio> {
+        let weights = s
+            .split(':')
+            .map(str::parse)
+            .collect::<Result<Vec<u32>, _>>()?;
+        let ratio = match weights[..] {
+            [set, get, multiget] => Ratio { set, get, multiget },
+            _ => bail!("expected SET:GET:MULTIGET"),
+        };
+        if ratio.set + ratio.get + ratio.multiget == 0 {
+            bail!("at least one weight must be positive");
+        }
+        Ok(ratio)
+    }
+}
+
+/// Results of a run.
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    /// Requests answered as expected
+    pub(crate) requests: u64,
+    /// Keys asked for by gets and multigets
+    pub(crate) keys_requested: u64,
+    /// Keys found by gets and multigets
+    pub(crate) hits: u64,
+    /// Requests answered with an error or something unexpected
+    pub(crate) errors: u64,
+    /// Per request latencies, in microseconds
+    latencies: Vec<u32>,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.requests += other.requests;
+        self.keys_requested += other.keys_requested;
+        self.hits += other.hits;
+        self.errors += other.errors;
+        self.latencies.extend(other.latencies);
+    }
+
+    /// The latency at `percentile`, in microseconds. `latencies` must be
+    /// sorted.
+    fn percentile(&self, percentile: f64) -> u32 {
+        if self.latencies.is_empty() {
+            return 0;
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+
+    fn print(&self) {
+        let seconds = self.elapsed.as_secs_f64();
+        println!("requests     {}", self.requests);
+        println!("errors       {}", self.errors);
+        println!("throughput   {:.0} requests/s", self.requests as f64 / seconds);
+        if self.keys_requested > 0 {
+            println!(
+                "hit rate     {:.1}%",
+                100.0 * self.hits as f64 / self.keys_requested as f64
+            );
+        }
+        for percentile in [50.0, 90.0, 99.0, 99.9] {
+            println!("p{:<11} {}us", percentile, self.percentile(percentile));
+        }
+        println!("max          {}us", self.latencies.last().copied().unwrap_or(0));
+    }
+}
+
+/// Run `sidica bench` and print the report.
+pub fn main(args: Args) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime.block_on(run(&args))?;
+    report.print();
+    Ok(())
+}
+
+pub(crate) async fn run(args: &Args) -> Result<Report> {
+    if args.preload {
+        preload(args)
+            .await
+            .with_context(|| format!("preloading {}", args.target))?;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(args.time);
+    let start = Instant::now();
+    let clients: Vec<_> = (0..args.connections)
+        .map(|id| {
+            let args = args.clone();
+            tokio::spawn(async move { client(&args, id as u64, deadline).await })
+        })
+        .collect();
+
+    let mut report = Report::default();
+    for client in clients {
+        report.merge(client.await?.with_context(|| format!("benchmarking {}", args.target))?);
+    }
+    report.elapsed = start.elapsed();
+    report.latencies.sort_unstable();
+    Ok(report)
+}
+
+async fn connect(target: &str) -> Result<TcpStream> {
+    let stream = TcpStream::connect(target).await?;
+    // Requests go out as soon as they are written, like a real client's
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+async fn preload(args: &Args) -> Result<()> {
+    let mut rng = Rng::new(u64::MAX);
+    let (reader, mut writer) = connect(&args.target).await?.into_split();
+    let mut reader = BufReader::new(reader);
+    for key in 0..args.keys {
+        let mut request = BytesMut::new();
+        set(&mut rng, args, key).into_frame().encode(&mut request);
+        writer.write_all(&request).await?;
+        expect_stored(&mut reader).await?;
+    }
+    Ok(())
+}
+
+/// The requests a client sends in one round trip.
+enum Request {
+    Set,
+    Get(u32),
+}
+
+async fn client(args: &Args, id: u64, deadline: Instant) -> Result<Report> {
+    let mut rng = Rng::new(id);
+    let (reader, mut writer) = connect(&args.target).await?.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut report = Report::default();
+    let mut request = BytesMut::new();
+    let mut pending = Vec::with_capacity(args.pipeline as usize);
+    let total = args.ratio.set + args.ratio.get + args.ratio.multiget;
+
+    while Instant::now() < deadline {
+        for _ in 0..args.pipeline {
+            let pick = rng.below(total);
+            let (frame, kind) = if pick < args.ratio.set {
+                let key = rng.below(args.keys);
+                (set(&mut rng, args, key).into_frame(), Request::Set)
+            } else {
+                let count = if pick < args.ratio.set + args.ratio.get {
+                    1
+                } else {
+                    args.multiget_keys
+                };
+                let keys = (0..count).map(|_| key_name(rng.below(args.keys))).collect();
+                (Get::new(keys).into_frame(), Request::Get(count))
+            };
+            frame.encode(&mut request);
+            pending.push(kind);
+        }
+
+        let sent = Instant::now();
+        writer.write_all(&request).await?;
+        request.clear();
+
+        for kind in pending.drain(..) {
+            let ok = match kind {
+                Request::Set => expect_stored(&mut reader).await.is_ok(),
+                Request::Get(count) => match read_values(&mut reader).await {
+                    Ok(found) => {
+                        report.keys_requested += u64::from(count);
+                        report.hits += found;
+                        true
+                    }
+                    Err(_) => false,
+                },
+            };
+            if ok {
+                report.requests += 1;
+                let latency = sent.elapsed().as_micros();
+                report.latencies.push(latency.try_into().unwrap_or(u32::MAX));
+            } else {
+                // The stream can no longer be trusted to line up with the
+                // requests.
+                report.errors += 1;
+                return Ok(report);
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn key_name(key: u32) -> Bytes {
+    Bytes::from(format!("key:{}", key))
+}
+
+fn set(rng: &mut Rng, args: &Args, key: u32) -> Set {
+    let size = args.value_size.min
+        + rng.below((args.value_size.max - args.value_size.min + 1) as u32) as usize;
+    Set::new(key_name(key), 0, 0, Bytes::from(vec![b'x'; size]))
+}
+
+async fn read_line(reader: &mut BufReader<OwnedReadHalf>) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        bail!("connection closed");
+    }
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(line.to_string()),
+        None => bail!("response line not ended by CRLF: {:?}", line),
+    }
+}
+
+async fn expect_stored(reader: &mut BufReader<OwnedReadHalf>) -> Result<()> {
+    match read_line(reader).await?.as_str() {
+        "STORED" => Ok(()),
+        other => bail!("unexpected response to set: {:?}", other),
+    }
+}
+
+/// Read the `VALUE` blocks up to `END`, returning how many there were.
+async fn read_values(reader: &mut BufReader<OwnedReadHalf>) -> Result<u64> {
+    let mut found = 0;
+    loop {
+        let line = read_line(reader).await?;
+        if line == "END" {
+            return Ok(found);
+        }
+        // VALUE <key> <flags> <bytes> [<cas>]
+        let fields: Vec<_> = line.split(' ').collect();
+        let len: usize = match fields[..] {
+            ["VALUE", _, _, len] | ["VALUE", _, _, len, _] => len.parse()?,
+            _ => bail!("unexpected response to get: {:?}", line),
+        };
+        let mut data = vec![0; len + 2];
+        reader.read_exact(&mut data).await?;
+        if !data.ends_with(b"\r\n") {
+            bail!("value not ended by CRLF");
+        }
+        found += 1;
+    }
+}
+
+/// xorshift64*, plenty for picking keys and sizes.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Any non-zero state works
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    /// Returns a number in `0..bound`, `bound` must not be 0.
+    fn below(&mut self, bound: u32) -> u32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32 % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::settings::Settings;
+    use clap::Parser;
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(
+            "32-1024".parse::<SizeRange>().unwrap(),
+            SizeRange { min: 32, max: 1024 }
+        );
+        assert_eq!("7".parse::<SizeRange>().unwrap(), SizeRange { min: 7, max: 7 });
+        assert!("9-3".parse::<SizeRange>().is_err());
+        assert_eq!(
+            "1:2:3".parse::<Ratio>().unwrap(),
+            Ratio {
+                set: 1,
+                get: 2,
+                multiget: 3
+            }
+        );
+        assert!("1:2".parse::<Ratio>().is_err());
+        assert!("0:0:0".parse::<Ratio>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_against_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        tokio::spawn(crate::server::run(
+            vec![listener],
+            None,
+            None,
+            Default::default(),
+            Cache::new(),
+            Settings::parse_from(["sidica"]),
+            std::future::pending::<()>(),
+        ));
+
+        let args = Settings::parse_from([
+            "sidica", "bench", &target, "-c", "4", "-t", "1", "-k", "50",
+            "--value-size", "1-64", "--ratio", "1:1:1", "--pipeline", "8", "--preload",
+        ]);
+        let args = match args.command {
+            Some(crate::settings::Subcommands::Bench(args)) => args,
+            None => panic!("bench not parsed"),
+        };
+        let report = run(&args).await.unwrap();
+
+        assert_eq!(report.errors, 0);
+        assert!(report.requests > 0);
+        // Every key was preloaded
+        assert_eq!(report.hits, report.keys_requested);
+        assert_eq!(report.latencies.len() as u64, report.requests);
+    }
+}
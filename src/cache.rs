@@ -1,100 +1,5525 @@
-use crate::id_generator::Generator;
-use bytes::Bytes;
+use crate::append_log::{AppendLog, Record};
+use crate::chunks::{Chunked, Chunks};
+use crate::clock::{self, Clock, Jitter};
+use crate::expiry::{Deadlines, ExpirySweep};
+use crate::id_generator::{Generator, Layout};
+use crate::overflow::{Location, Overflow, Spilled};
+use crate::quota::{Quota, Quotas};
+use crate::stats::{CacheStats, Integrity, TtlHistogram};
+use crate::watermark::Watermark;
+use bytes::{Bytes, BytesMut};
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use nohash_hasher::NoHashHasher;
-use parking_lot::RwLock;
-use std::collections::BTreeMap;
-use std::hash::BuildHasherDefault;
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::ops::Bound;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, warn};
 use std::sync::Arc;
 
+/// Bytes counted per item besides its key and value: the item itself, and
+/// the key and id in the index and the id in the map. What the allocator and
+/// the maps add on top is not counted.
+const ITEM_OVERHEAD: usize = mem::size_of::<MemoryItem>() + mem::size_of::<Bytes>() + 2 * mem::size_of::<u64>();
+
+/// Bytes counted for an item with a `key_len` key and a `data_len` value.
+pub(crate) fn footprint(key_len: usize, data_len: usize) -> usize {
+    key_len + held(data_len) + ITEM_OVERHEAD
+}
+
+/// Bytes counted for a `len` byte value besides the item: none if it is
+/// kept in the item, see `Value`.
+fn held(len: usize) -> usize {
+    if len <= INLINE_MAX {
+        0
+    } else {
+        len
+    }
+}
+
+/// Longest value kept in the item itself, see `Value`. The most that fits
+/// beside the length in the space of a `Bytes`, so no item grows for it.
+const INLINE_MAX: usize = 23;
+
+/// Most reads `MemoryItem` counts; enough to tell items read once from
+/// those read again.
+const MAX_FETCHES: u32 = 3;
+
+/// Keys looked at for each item evicted, see `Cache::make_room`.
+const EVICTION_SAMPLE: usize = 16;
+
+/// Smallest value moved to disk rather than evicted, see
+/// `CacheBuilder::overflow`. Below it, too little memory is gained.
+const MIN_SPILL: usize = 64;
+
+/// Locks the index is split over unless set otherwise, see `Cache::shard`.
+const INDEX_SHARDS: usize = 64;
+
+/// Locks the keys are striped over for read-modify-write operations, see
+/// `Cache::key_lock`.
+const KEY_LOCKS: usize = 1024;
+
+/// Items the map has room for before it first grows, unless set otherwise.
+const INITIAL_CAPACITY: usize = 1000;
+
+/// How many times the slots its items need a map shard holds before it is
+/// shrunk, see `Cache::reclaim_memory`. Shrunk, it keeps room for twice its
+/// items, so it does not grow again right away.
+const SHRINK_FACTOR: usize = 4;
+
+/// Longest `Cache::reclaim_memory` goes on shrinking map shards for.
+const SHRINK_BUDGET: Duration = Duration::from_millis(10);
+
+/// Keys `SnapshotIter` takes at a time, under the lock of their index shard.
+const SNAPSHOT_BATCH: usize = 1024;
+
+/// Keys `Cache::flush_prefix` looks up at a time, see `Cache::scan`.
+const FLUSH_PREFIX_BATCH: usize = 1024;
+
+/// Items `Cache::sweep_expired` takes due at a time, from one index shard.
+const SWEEP_BATCH: usize = 1024;
+
+/// Keys looked at in an index shard for each sample, see
+/// `ExpirySweep::Sampled`.
+const EXPIRY_SAMPLE: usize = 20;
+
+/// Longest a sweep samples for, see `ExpirySweep::Sampled`.
+const SWEEP_BUDGET: Duration = Duration::from_millis(25);
+
+/// Keys by the id of the item they hold.
+type IndexShard = RwLock<BTreeMap<Bytes, u64>>;
+
+/// Where the outcome of a `Cache::get_or_insert_with` computation is sent,
+/// the error as its message.
+type Computed = watch::Receiver<Option<Result<ItemView, String>>>;
+
+/// An item read from the map, holding its entry's read lock.
+type ItemRef<'a> = dashmap::mapref::one::Ref<'a, u64, MemoryItem>;
+
+/// Largest value stored unless set otherwise, 1 MiB like memcached's
+/// default item size limit.
+pub const MAX_ITEM_SIZE: usize = 1024 * 1024;
+
 // add bool for memory only
 // Maybe add to btree and add byte counter have write thread check ad if bytes is over 1mb clean out hashmap and write to disk
 
 #[derive(Debug, Clone)]
 pub struct Item {
-    pub key: String,
+    pub key: Bytes,
     pub flags: u32,
     pub cas: u64,
-    pub expiration: Option<u32>,
+    /// When the item expires, in seconds since the unix epoch. `None` for
+    /// never.
+    pub expiration: Option<u64>,
     pub data: Bytes,
 }
 
+/// An item as `Cache::get` and the other reads return it: what a response
+/// needs, without the key the caller already has.
+#[derive(Debug, Clone)]
+pub struct ItemView {
+    pub flags: u32,
+    pub cas: u64,
+    /// When the item expires, see `Item::expiration`
+    pub expiration: Option<u64>,
+    pub data: Bytes,
+}
+
+impl ItemView {
+    /// The item stored under `key`.
+    pub fn into_item(self, key: Bytes) -> Item {
+        Item { key, flags: self.flags, cas: self.cas, expiration: self.expiration, data: self.data }
+    }
+}
+
+/// How an item has been read, see `SnapshotIter::next_accessed`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    /// When the item was last read or written, in seconds since the unix
+    /// epoch
+    pub last: u64,
+    /// Whether the value has been read since it was written
+    pub fetched: bool,
+}
+
+/// What `Cache::metadata` tells of an item: all but its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meta {
+    pub key: Bytes,
+    pub expiration: Option<u64>,
+    pub cas: u64,
+    pub access: Access,
+    /// Length of the value
+    pub len: usize,
+}
+
+/// The value of a `MemoryItem`. Values up to `INLINE_MAX` bytes, counters
+/// and flags and short tokens, are copied into the item, rather than each
+/// holding on to a buffer of its own and its reference count. Reads copy
+/// them back out.
 #[derive(Debug, Clone)]
+enum Value {
+    Inline { len: u8, data: [u8; INLINE_MAX] },
+    Shared(Bytes),
+}
+
+impl Value {
+    fn new(data: Bytes) -> Value {
+        if data.len() > INLINE_MAX {
+            return Value::Shared(data);
+        }
+        let mut inline = [0; INLINE_MAX];
+        inline[..data.len()].copy_from_slice(&data);
+        Value::Inline { len: data.len() as u8, data: inline }
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        match self {
+            Value::Inline { len: 0, .. } => Bytes::new(),
+            Value::Inline { .. } => Bytes::copy_from_slice(self),
+            Value::Shared(data) => data.clone(),
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Value {
+        Value::new(Bytes::new())
+    }
+}
+
+impl std::ops::Deref for Value {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Value::Inline { len, data } => &data[..usize::from(*len)],
+            Value::Shared(data) => data,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct MemoryItem {
     flags: u32,
-    expiration: Option<u32>,
+    /// Reads since the value was written: the seconds from `stored_at` to
+    /// the last one in the upper 30 bits, how many there were, up to
+    /// `MAX_FETCHES`, in the low 2. Fills what would be padding after
+    /// `flags`, so it costs no memory.
+    access: AtomicU32,
+    expiration: Option<u64>,
     cas: u64,
-    data: Bytes,
+    /// When the value was last written, in seconds since the unix epoch
+    stored_at: u64,
+    /// What the eviction policy keeps track of, see `EvictionPolicy`
+    usage: AtomicU64,
+    /// Empty while the value is on disk or in chunks
+    data: Value,
+    /// Where the value is on disk, if it was moved there, see
+    /// `CacheBuilder::overflow`
+    spilled: Option<Spilled>,
+    /// Where the value is kept if it is past the item size limit, see
+    /// `CacheBuilder::large_item_limit`
+    chunked: Option<Chunked>,
+    /// Whether a caller of `get_stale` was told to store a new value since
+    /// the item expired
+    refresh_won: AtomicBool,
+    /// Length of the key the item is stored under, counted in its footprint.
+    /// Fills padding after `refresh_won`; kept for `Cache::verify` to
+    /// discharge an item whose key is lost.
+    key_len: u32,
 }
 
 impl MemoryItem {
-    fn from_item(item: Item) -> MemoryItem {
+    fn new(key: &[u8], flags: u32, expiration: Option<u64>, cas: u64, stored_at: u64, data: Bytes) -> MemoryItem {
         MemoryItem {
-            flags: item.flags,
-            expiration: item.expiration,
-            cas: item.cas,
-            data: item.data,
+            flags,
+            access: AtomicU32::new(0),
+            expiration,
+            cas,
+            stored_at,
+            usage: AtomicU64::new(0),
+            data: Value::new(data),
+            spilled: None,
+            chunked: None,
+            refresh_won: AtomicBool::new(false),
+            key_len: key.len() as u32,
+        }
+    }
+
+    fn from_item(item: Item, stored_at: u64) -> MemoryItem {
+        MemoryItem::new(&item.key, item.flags, item.expiration, item.cas, stored_at, item.data)
+    }
+
+    /// The item, stored under `key`, with its value read back if it is on
+    /// disk or put back together if it is in chunks. `None` if that fails.
+    fn to_item(&self, key: Bytes) -> Option<Item> {
+        Some(self.to_view(&key)?.into_item(key))
+    }
+
+    /// The item, stored under `key`, like `to_item` but without the key.
+    fn to_view(&self, key: &[u8]) -> Option<ItemView> {
+        let data = self.read_value(key)?;
+        Some(ItemView {
+            flags: self.flags,
+            cas: self.cas,
+            expiration: self.expiration,
+            data,
+        })
+    }
+
+    /// The value of the item, stored under `key`, like `to_item`.
+    fn read_value(&self, key: &[u8]) -> Option<Bytes> {
+        match (&self.spilled, &self.chunked) {
+            (Some(spilled), _) => read_spilled(&spilled.location(), key, self.cas),
+            (None, Some(chunked)) => chunked.read(),
+            (None, None) => Some(self.data.to_bytes()),
         }
     }
+
+    /// Where the value is on disk, with the rest of the item, to read it
+    /// back once its map entry lock is released. `None` if it is in memory.
+    fn on_disk(&self) -> Option<OnDisk> {
+        Some(OnDisk {
+            flags: self.flags,
+            cas: self.cas,
+            expiration: self.expiration,
+            location: self.spilled.as_ref()?.location(),
+        })
+    }
+
+    /// Length of the value, wherever it is kept.
+    fn value_len(&self) -> usize {
+        match (&self.spilled, &self.chunked) {
+            (Some(spilled), _) => spilled.len(),
+            (None, Some(chunked)) => chunked.len(),
+            (None, None) => self.data.len(),
+        }
+    }
+
+    /// Bytes of the value held in memory, its chunks included.
+    fn size(&self) -> usize {
+        self.data.len() + self.chunked.as_ref().map_or(0, Chunked::size)
+    }
+
+    /// Whether the item is expired or flushed at `now`, by `flushed`, or its
+    /// value was lost from disk.
+    fn is_dead(&self, now: u64, flushed: &Flushed) -> bool {
+        self.is_expired(now) || self.is_flushed(now, flushed)
+    }
+
+    /// Whether the item is flushed at `now`, by `flushed`, or its value was
+    /// lost from disk, whether or not it expired.
+    fn is_flushed(&self, now: u64, flushed: &Flushed) -> bool {
+        let lost = self.spilled.as_ref().is_some_and(Spilled::is_lost);
+        if lost || self.cas <= flushed.cas {
+            return true;
+        }
+        self.stored_at < flushed.before(now)
+    }
+
+    /// Record a read at `now`, counted as a fetch if `fetched`; `touch` only
+    /// counts as an access.
+    fn record_access(&self, now: u64, fetched: bool) {
+        let access = self.access.load(Ordering::Relaxed);
+        let fetches = (access & MAX_FETCHES) + u32::from(fetched);
+        let since = now.saturating_sub(self.stored_at).min(u64::from(u32::MAX >> 2)) as u32;
+        let next = since << 2 | fetches.min(MAX_FETCHES);
+        if next != access {
+            self.access.store(next, Ordering::Relaxed);
+        }
+    }
+
+    /// Forget the reads of the previous value, after writing in place at
+    /// `now`.
+    fn reset_access(&mut self, now: u64) {
+        self.stored_at = now;
+        *self.access.get_mut() = 0;
+    }
+
+    /// When the item was last read or written, in seconds since the unix
+    /// epoch.
+    fn last_access(&self) -> u64 {
+        self.stored_at + u64::from(self.access.load(Ordering::Relaxed) >> 2)
+    }
+
+    /// Reads of the value since it was written, up to `MAX_FETCHES`.
+    fn fetches(&self) -> u32 {
+        self.access.load(Ordering::Relaxed) & MAX_FETCHES
+    }
+
+    fn to_access(&self) -> Access {
+        Access {
+            last: self.last_access(),
+            fetched: self.fetches() > 0,
+        }
+    }
+
+    /// Whether the item has expired at `now`.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expiration.is_some_and(|expiration| expiration <= now)
+    }
+}
+
+/// An item whose value is on disk, copied out of the map to read the value
+/// without holding any lock, see `MemoryItem::on_disk`.
+struct OnDisk {
+    flags: u32,
+    cas: u64,
+    expiration: Option<u64>,
+    location: Location,
+}
+
+impl OnDisk {
+    /// The item, stored under `key`, with its value read back. `None` if it
+    /// was lost or cannot be read.
+    fn read(self, key: &[u8]) -> Option<ItemView> {
+        let data = read_spilled(&self.location, key, self.cas)?;
+        Some(ItemView {
+            flags: self.flags,
+            cas: self.cas,
+            expiration: self.expiration,
+            data,
+        })
+    }
+}
+
+/// The value of the item `key` with `cas` on disk at `location`. `None` if
+/// it was lost or cannot be read.
+fn read_spilled(location: &Location, key: &[u8], cas: u64) -> Option<Bytes> {
+    match location.read(key, cas) {
+        Ok(data) => data,
+        Err(err) => {
+            warn!(key = ?Bytes::copy_from_slice(key), "failed to read a value back from disk: {}", err);
+            None
+        }
+    }
+}
+
+/// How a conditional store turned out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Stored,
+    /// The key already holds an item, or holds none when one is needed
+    NotStored,
+    /// The value is, or would grow, past the item size limit, see
+    /// `CacheBuilder::max_item_size`
+    TooLarge,
+    /// The item changed since the client read its CAS
+    Exists,
+    NotFound,
+    /// Storing would go past the memory or item limit and nothing could be
+    /// evicted
+    OutOfMemory,
+}
+
+/// How fresh an item `get_stale` returns is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Freshness {
+    /// It has not expired
+    Fresh,
+    /// It expired, and the caller is the first told since: it should store
+    /// a new value
+    Won,
+    /// It expired, and another caller was told to store a new value
+    Stale,
 }
 
+/// What `get_if_modified` found under a key.
 #[derive(Debug, Clone)]
-pub struct Cache {
-    id: Arc<Generator>,
-    index: Arc<RwLock<BTreeMap<String, u64>>>,
-    cache: Arc<DashMap<u64, MemoryItem, BuildHasherDefault<NoHashHasher<u64>>>>,
+pub enum Revalidated {
+    /// The item still has the CAS the caller knows
+    Unchanged,
+    /// It has another one, or another item took its place
+    Changed(ItemView),
 }
 
-impl Cache {
-    pub fn new() -> Cache {
-        Cache {
-            id: Arc::new(Generator::new()),
-            index: Arc::new(RwLock::new(BTreeMap::new())),
-            cache: Arc::new(DashMap::with_capacity_and_hasher(
-                1000,
-                BuildHasherDefault::default(),
-            )),
-        }
-    }
-
-    pub async fn get(&self, key: &String) -> Option<Item> {
-        let index = self.index.read();
-        match index.get(key) {
-            Some(id) => {
-                let item = self.cache.get(id).unwrap().clone();
-                Some(Item {
-                    key: key.clone(),
-                    flags: item.flags,
-                    cas: item.cas,
-                    expiration: item.expiration,
-                    data: item.data,
-                })
+/// Why an item left the cache, see `CacheBuilder::removals`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// It was evicted to make room, or its value was lost from disk
+    Evicted,
+    /// Its expiration came
+    Expired,
+    /// It was flushed, by `flush`, `flush_prefix` or `clear`
+    Flushed,
+    /// A store put another item in its place before it expired
+    Replaced,
+}
+
+/// An item leaving the cache, see `CacheBuilder::removals`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Removal {
+    pub key: Bytes,
+    pub reason: RemovalReason,
+}
+
+/// Which way `add_delta` moves a number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Add, wrapping around at `u64::MAX`
+    Incr,
+    /// Subtract, stopping at 0
+    Decr,
+}
+
+/// How `add_delta` turned out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delta {
+    /// The number stored now
+    Value(u64),
+    NotFound,
+    /// The item does not hold a decimal number that fits a `u64`
+    NonNumeric,
+}
+
+/// Where `flush_all` cuts off, see `Cache::flush`.
+#[derive(Debug, Default)]
+struct Flush {
+    /// Items with this CAS or a lower one are flushed
+    cas: AtomicU64,
+    /// Items stored before this time are flushed, 0 for none
+    before: AtomicU64,
+    /// Once this time has come, items stored before it are flushed too. 0
+    /// when no flush is pending.
+    at: AtomicU64,
+}
+
+impl Flush {
+    fn load(&self) -> Flushed {
+        Flushed {
+            cas: self.cas.load(Ordering::Relaxed),
+            before: self.before.load(Ordering::Relaxed),
+            at: self.at.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The memory and item limits, which `Cache::set_limits` may change while
+/// the cache is in use. `usize::MAX` when there is none.
+#[derive(Debug)]
+struct Limits {
+    /// Most bytes to hold before evicting
+    memory: AtomicUsize,
+    /// Most items to hold before evicting
+    items: AtomicUsize,
+}
+
+impl Limits {
+    fn new(memory: Option<usize>, items: Option<usize>) -> Limits {
+        let limits = Limits {
+            memory: AtomicUsize::new(usize::MAX),
+            items: AtomicUsize::new(usize::MAX),
+        };
+        limits.set(memory, items);
+        limits
+    }
+
+    fn set(&self, memory: Option<usize>, items: Option<usize>) {
+        self.memory.store(memory.unwrap_or(usize::MAX), Ordering::Relaxed);
+        self.items.store(items.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    fn memory(&self) -> Option<usize> {
+        Some(self.memory.load(Ordering::Relaxed)).filter(|&limit| limit != usize::MAX)
+    }
+
+    fn items(&self) -> Option<usize> {
+        Some(self.items.load(Ordering::Relaxed)).filter(|&limit| limit != usize::MAX)
+    }
+}
+
+/// `Flush` as it was at one point.
+#[derive(Debug, Clone, Copy)]
+struct Flushed {
+    cas: u64,
+    before: u64,
+    at: u64,
+}
+
+impl Flushed {
+    /// Items stored before this time are flushed at `now`, a pending flush
+    /// included once it has come.
+    fn before(&self, now: u64) -> u64 {
+        if self.at != 0 && self.at <= now {
+            self.at
+        } else {
+            self.before
+        }
+    }
+}
+
+/// Items as they were when a `SnapshotIter` started, kept as they are
+/// changed, removed or replaced until it comes to them.
+#[derive(Debug)]
+struct Journal {
+    /// The CAS last handed out before the iteration started. Items with a
+    /// higher one were stored since.
+    cas: u64,
+    /// When the iteration started, and the flushes then
+    now: u64,
+    flushed: Flushed,
+    state: Mutex<JournalState>,
+}
+
+#[derive(Debug, Default)]
+struct JournalState {
+    /// Index shard the iteration is in; those before are done
+    shard: usize,
+    /// Last key of `shard` done, `None` before the first
+    done: Option<Bytes>,
+    /// Items as they were, by index shard and key, of keys not done yet
+    items: BTreeMap<(usize, Bytes), (Item, Access)>,
+}
+
+impl Journal {
+    /// Keep `item`, about to change or go, as it is if the iteration still
+    /// has to come to `key` and it is how it was at the start.
+    fn keep(&self, shard: usize, key: &[u8], item: &MemoryItem) {
+        if item.cas > self.cas || item.is_dead(self.now, &self.flushed) {
+            return;
+        }
+        let mut state = self.state.lock();
+        let done = shard < state.shard
+            || shard == state.shard && state.done.as_ref().is_some_and(|done| key <= &done[..]);
+        let key = (shard, Bytes::copy_from_slice(key));
+        if !done && !state.items.contains_key(&key) {
+            if let Some(kept) = item.to_item(key.1.clone()) {
+                state.items.insert(key, (kept, item.to_access()));
             }
-            None => None,
+        }
+    }
+}
+
+/// How to choose the items to evict once the memory limit is reached.
+///
+/// A policy keeps what it needs in each item's `usage`, updated by
+/// `on_access` on every read and write, and ranks items by it. Eviction
+/// samples a few items and takes the one ranked lowest, see
+/// `Cache::make_room`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum EvictionPolicy {
+    /// Least recently used
+    #[default]
+    Lru,
+    /// Least frequently used, with use counts halving every minute unused
+    Lfu,
+    /// Never evict; stores past the limit fail as out of memory
+    None,
+}
+
+/// LFU count of a new item, so it gets a chance to be read before eviction.
+const LFU_INITIAL: u64 = 5;
+
+/// Seconds unused after which an item's LFU count is halved.
+const LFU_DECAY: u64 = 60;
+
+impl EvictionPolicy {
+    /// Record a read or write of `item`. `cas` is the CAS most recently
+    /// handed out, `now` the time.
+    ///
+    /// LRU keeps `cas`, which orders uses across the cache. LFU keeps the
+    /// time in the upper bits and a saturating use count in the low byte.
+    fn on_access(self, item: &MemoryItem, cas: u64, now: u64) {
+        let usage = item.usage.load(Ordering::Relaxed);
+        let next = match self {
+            EvictionPolicy::Lru => usage.max(cas),
+            EvictionPolicy::Lfu if usage == 0 => now << 8 | LFU_INITIAL,
+            EvictionPolicy::Lfu => now << 8 | (lfu_count(usage, now) + 1).min(0xff),
+            EvictionPolicy::None => return,
+        };
+        // Hot items are read from every core, only write when it changes
+        if next != usage {
+            item.usage.store(next, Ordering::Relaxed);
         }
     }
 
-    pub async fn set(&self, key: String, flags: u32, expiration: Option<u32>, data: Bytes) -> bool {
-        let mut index = self.index.upgradable_read();
-        match index.get(&key) {
-            // Updates an existing `Item`
-            Some(id) => {
-                //downgrade index lock
-                // Get and increament CAS on update
-                let cas = self.cache.get_mut(id).unwrap().cas;
-                let mut mi = MemoryItem { flags, expiration, cas, data };
-                mi.cas = cas + 1;
+    /// How much `item` is worth keeping at `now`. Of the sampled items, the
+    /// lowest ranked is evicted.
+    fn rank(self, item: &MemoryItem, now: u64) -> u64 {
+        let usage = item.usage.load(Ordering::Relaxed);
+        match self {
+            EvictionPolicy::Lru => usage,
+            EvictionPolicy::Lfu => lfu_count(usage, now),
+            EvictionPolicy::None => 0,
+        }
+    }
+}
+
+/// The LFU count in `usage`, halved for every `LFU_DECAY` since the last use.
+fn lfu_count(usage: u64, now: u64) -> u64 {
+    let unused = now.saturating_sub(usage >> 8);
+    (usage & 0xff) >> (unused / LFU_DECAY).min(8)
+}
+
+/// Returned by `Cache::snapshot_iter`.
+#[derive(Debug)]
+pub struct SnapshotIter<'a> {
+    cache: &'a Cache,
+    journal: Arc<Journal>,
+    /// Items taken, not yet returned
+    batch: Vec<(Item, Access)>,
+}
+
+impl SnapshotIter<'_> {
+    /// Take the next batch of keys, with the items they had at the start,
+    /// and the items of keys removed since in between. Returns false once
+    /// every shard is done.
+    fn next_batch(&mut self) -> bool {
+        let journal = &*self.journal;
+        let cas = journal.cas;
+        let (shard, done) = {
+            let state = journal.state.lock();
+            (state.shard, state.done.clone())
+        };
+        if shard == self.cache.index.len() {
+            return false;
+        }
+
+        let index = self.cache.index[shard].read();
+        let start = done.as_ref().map_or(Bound::Unbounded, |done| Bound::Excluded(done.clone()));
+        let mut keys = Vec::with_capacity(SNAPSHOT_BATCH);
+        for (key, id) in index.range((start.clone(), Bound::Unbounded)).take(SNAPSHOT_BATCH) {
+            // Read before looking in the journal, which changes are kept in
+            // before they are made
+            let item = self
+                .cache
+                .cache
+                .get(id)
+                .filter(|item| item.cas <= cas && !item.is_dead(journal.now, &journal.flushed))
+                .and_then(|item| Some((item.to_item(key.clone())?, item.to_access())));
+            keys.push((key.clone(), item));
+        }
+        let last = (keys.len() == SNAPSHOT_BATCH).then(|| keys[keys.len() - 1].0.clone());
 
-                self.cache.insert(*id, mi);
-                false
+        let mut state = journal.state.lock();
+        for (key, item) in keys {
+            if let Some(item) = state.items.remove(&(shard, key)).or(item) {
+                self.batch.push(item);
             }
-            // Inserts a new `Item`
-            None => {
-                let new_id = self.id.gen();
-                index.with_upgraded(|index| index.insert(key, new_id));
-                self.cache.insert(new_id, MemoryItem { flags, expiration, cas: 0, data });
-                true
+        }
+        // What is left in the range is of keys removed since
+        let start = match start {
+            Bound::Excluded(done) => Bound::Excluded((shard, done)),
+            _ => Bound::Included((shard, Bytes::new())),
+        };
+        let end = match &last {
+            Some(last) => Bound::Included((shard, last.clone())),
+            None => Bound::Excluded((shard + 1, Bytes::new())),
+        };
+        let removed: Vec<_> = state.items.range((start, end)).map(|(key, _)| key.clone()).collect();
+        for key in removed {
+            self.batch.extend(state.items.remove(&key));
+        }
+        match last {
+            Some(last) => state.done = Some(last),
+            None => (state.shard, state.done) = (shard + 1, None),
+        }
+        true
+    }
+
+    /// Like `next`, along with how the item had been read. Items changed
+    /// since the start come with their reads up to the change.
+    pub fn next_accessed(&mut self) -> Option<(Item, Access)> {
+        while self.batch.is_empty() {
+            if !self.next_batch() {
+                return None;
+            }
+        }
+        self.batch.pop()
+    }
+}
+
+impl Iterator for SnapshotIter<'_> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Item> {
+        self.next_accessed().map(|(item, _)| item)
+    }
+}
+
+impl Drop for SnapshotIter<'_> {
+    fn drop(&mut self) {
+        self.cache.journaling.fetch_sub(1, Ordering::SeqCst);
+        let mut journals = self.cache.journals.write();
+        journals.retain(|journal| !Arc::ptr_eq(journal, &self.journal));
+    }
+}
+
+/// Returned by `Cache::metadata`.
+#[derive(Debug)]
+pub struct MetaIter<'a> {
+    cache: &'a Cache,
+    now: u64,
+    /// Index shard the next batch is taken from, and the last key taken
+    /// from it
+    shard: usize,
+    done: Option<Bytes>,
+    /// Taken, not yet returned
+    batch: Vec<Meta>,
+}
+
+impl MetaIter<'_> {
+    /// Take the next batch of keys and the metadata of their items. Returns
+    /// false once every shard is done.
+    fn next_batch(&mut self) -> bool {
+        let Some(index) = self.cache.index.get(self.shard) else {
+            return false;
+        };
+        let start = self.done.take().map_or(Bound::Unbounded, Bound::Excluded);
+        let keys: Vec<(Bytes, u64)> = index
+            .read()
+            .range((start, Bound::Unbounded))
+            .take(SNAPSHOT_BATCH)
+            .map(|(key, id)| (key.clone(), *id))
+            .collect();
+        if keys.len() < SNAPSHOT_BATCH {
+            self.shard += 1;
+        } else {
+            self.done = keys.last().map(|(key, _)| key.clone());
+        }
+        let flushed = self.cache.flush.load();
+        for (key, id) in keys.into_iter().rev() {
+            let Some(item) = self.cache.cache.get(&id) else {
+                continue;
+            };
+            if !item.is_dead(self.now, &flushed) {
+                self.batch.push(Meta {
+                    key,
+                    expiration: item.expiration,
+                    cas: item.cas,
+                    access: item.to_access(),
+                    len: item.value_len(),
+                });
+            }
+        }
+        true
+    }
+}
+
+impl Iterator for MetaIter<'_> {
+    type Item = Meta;
+
+    fn next(&mut self) -> Option<Meta> {
+        while self.batch.is_empty() {
+            if !self.next_batch() {
+                return None;
             }
         }
+        self.batch.pop()
+    }
+}
+
+/// A computation of `Cache::get_or_insert_with` under way.
+struct Computing<'a> {
+    cache: &'a Cache,
+    key: &'a Bytes,
+}
+
+impl Drop for Computing<'_> {
+    fn drop(&mut self) {
+        self.cache.computing.lock().remove(self.key);
+    }
+}
+
+/// Expired items are removed lazily: every lookup treats them as missing and
+/// removes those it comes across. Flushed items count as expired, so a
+/// flush only moves a cutoff and never walks the cache. Expired items no one
+/// asks for can also be swept, see `CacheBuilder::expiry_sweep`.
+///
+/// Keys map to ids in `index`, ids to items in `cache`. Whenever both are
+/// used, the lock of the key's index shard is taken first and held until the
+/// map is updated. Items go into the map before they are indexed and out of
+/// the index before they leave the map, so nothing sees the index pointing
+/// at an item that is not in the map yet or anymore. Should the two ever
+/// disagree anyway, an id missing from the map counts as a missing item
+/// rather than a broken cache.
+///
+/// Appends, increments and `cas` first take the lock of the key's stripe of
+/// `key_locks`, then read the item, work out the new value and write it
+/// back, taking the index and map locks anew for each. A key lock is always
+/// taken first and one at a time, never under an index or map lock, so it
+/// cannot be part of a cycle. Other stores do not take it; an update finding
+/// the item changed since it was read starts over.
+///
+/// All these locks are synchronous `parking_lot` ones, held for a map or
+/// index update and never longer: no guard is held across an `.await`, which
+/// clippy enforces, see `clippy.toml`. The methods are `async` only to wait
+/// for the append log, see `logged`; everything else they do runs to the end
+/// on the calling worker thread. Work that goes through many items, like
+/// sweeps and snapshots, runs on a blocking thread instead.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    id: Arc<Generator>,
+    /// Last CAS handed out. Every store takes the next one, so they are
+    /// unique and increasing across all keys.
+    cas: Arc<AtomicU64>,
+    clock: Clock,
+    flush: Arc<Flush>,
+    /// Counters and the bytes held, see `stats`
+    stats: Arc<CacheStats>,
+    /// Most bytes and items to hold before evicting, see `set_limits`
+    limits: Arc<Limits>,
+    /// Largest value to store in one piece
+    max_item_size: usize,
+    /// Largest value to store in chunks, if any, see
+    /// `CacheBuilder::large_item_limit`
+    large_item_limit: Option<usize>,
+    chunks: Option<Arc<Chunks>>,
+    policy: EvictionPolicy,
+    /// Most bytes to hold for the items of some key prefixes, see
+    /// `CacheBuilder::quotas`
+    quotas: Option<Arc<Quotas>>,
+    sweep: ExpirySweep,
+    /// Seconds expired items are kept to be served stale, see
+    /// `CacheBuilder::stale_grace`
+    stale_grace: Option<u64>,
+    /// How far deadlines are moved, and whether later too, see `deadline`
+    ttl_jitter: Option<(Jitter, bool)>,
+    /// Where items leaving are reported, see `CacheBuilder::removals`
+    removals: Option<mpsc::Sender<Removal>>,
+    /// When the items that expire do, with `ExpirySweep::Index`
+    deadlines: Option<Arc<Deadlines>>,
+    /// Index shard `ExpirySweep::Sampled` samples first, and the last key
+    /// sampled in each, see `sweep_sampled`
+    sweep_hand: Arc<Mutex<(usize, Vec<Option<Bytes>>)>>,
+    /// Index shard eviction samples, and the last key sampled in it, see
+    /// `make_room`
+    hand: Arc<Mutex<(usize, Option<Bytes>)>>,
+    /// Values evictions are writing to disk, with the hand released, see
+    /// `evict_while`
+    spilling: Arc<AtomicUsize>,
+    /// Map shard `reclaim_memory` looks at first, and the flush cutoff it
+    /// last removed flushed items up to
+    reclaim_hand: Arc<Mutex<(usize, (u64, u64))>>,
+    /// Slots the map starts with, which it is never shrunk below
+    initial_capacity: usize,
+    /// Changes made, see `changes`
+    changes: Arc<AtomicU64>,
+    /// Computations under way in `get_or_insert_with`, by key
+    computing: Arc<Mutex<HashMap<Bytes, Computed>>>,
+    /// Where changes are written as they are made, see `with_append_log`
+    log: Option<Arc<AppendLog>>,
+    /// Where values go instead of being evicted, see `CacheBuilder::overflow`
+    overflow: Option<Arc<Overflow>>,
+    /// Of the `SnapshotIter`s under way, and how many there are
+    journals: Arc<RwLock<Vec<Arc<Journal>>>>,
+    journaling: Arc<AtomicUsize>,
+    /// Held by read-modify-write operations, by key hash, see `key_lock`
+    key_locks: Arc<[Mutex<()>]>,
+    /// Picks the index shard of a key
+    hasher: RandomState,
+    index: Arc<[IndexShard]>,
+    /// Items by id. Ids are hashed: those handed out in different seconds
+    /// share their low bits, see `Generator`.
+    cache: Arc<DashMap<u64, MemoryItem>>,
+}
+
+/// Creates a `Cache` configured otherwise than `Cache::new`, see
+/// `Cache::builder`.
+#[derive(Debug)]
+pub struct CacheBuilder {
+    initial_capacity: usize,
+    shards: usize,
+    memory_limit: Option<usize>,
+    max_items: Option<usize>,
+    max_item_size: usize,
+    large_item_limit: Option<usize>,
+    policy: EvictionPolicy,
+    clock: Clock,
+    overflow: Option<Arc<Overflow>>,
+    quotas: Option<Quotas>,
+    sweep: ExpirySweep,
+    stale_grace: Option<u64>,
+    ttl_jitter: Option<Jitter>,
+    ttl_jitter_both_ways: bool,
+    id_layout: Layout,
+    removals: Option<mpsc::Sender<Removal>>,
+}
+
+impl Default for CacheBuilder {
+    fn default() -> CacheBuilder {
+        CacheBuilder {
+            initial_capacity: INITIAL_CAPACITY,
+            shards: INDEX_SHARDS,
+            memory_limit: None,
+            max_items: None,
+            max_item_size: MAX_ITEM_SIZE,
+            large_item_limit: None,
+            policy: EvictionPolicy::default(),
+            clock: Clock::default(),
+            overflow: None,
+            quotas: None,
+            sweep: ExpirySweep::default(),
+            stale_grace: None,
+            ttl_jitter: None,
+            ttl_jitter_both_ways: false,
+            id_layout: Layout::default(),
+            removals: None,
+        }
+    }
+}
+
+impl CacheBuilder {
+    /// Make room for `capacity` items up front.
+    pub fn initial_capacity(mut self, capacity: usize) -> CacheBuilder {
+        self.initial_capacity = capacity;
+        self
+    }
+
+    /// Split the index over `shards` locks, see `Cache::shard`. More let
+    /// more writers in at once, at the cost of sampling fewer keys in each
+    /// for eviction.
+    ///
+    /// # Panics
+    ///
+    /// `build` panics if `shards` is 0.
+    pub fn shards(mut self, shards: usize) -> CacheBuilder {
+        self.shards = shards;
+        self
+    }
+
+    /// Evict items to hold at most `limit` bytes, see `Cache::make_room`.
+    /// Unlimited if `None`, the default.
+    pub fn memory_limit_bytes(mut self, limit: Option<usize>) -> CacheBuilder {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Evict items to hold at most `max` of them, like
+    /// `memory_limit_bytes`.
+    pub fn max_items(mut self, max: Option<usize>) -> CacheBuilder {
+        self.max_items = max;
+        self
+    }
+
+    /// Refuse to store values larger than `size` bytes, with
+    /// `Outcome::TooLarge`. `MAX_ITEM_SIZE` by default.
+    pub fn max_item_size(mut self, size: usize) -> CacheBuilder {
+        self.max_item_size = size;
+        self
+    }
+
+    /// Store values past `max_item_size`, up to `limit` bytes, in chunks of
+    /// `max_item_size` bytes, put back together when read. Refused like
+    /// other values past the limits if `None`, the default.
+    pub fn large_item_limit(mut self, limit: Option<usize>) -> CacheBuilder {
+        self.large_item_limit = limit;
+        self
+    }
+
+    /// Choose items to evict by `policy`.
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> CacheBuilder {
+        self.policy = policy;
+        self
+    }
+
+    /// Find expired items to remove by `sweep`, see `Cache::sweep_expired`.
+    /// `ExpirySweep::None` by default, leaving them to be come across.
+    pub fn expiry_sweep(mut self, sweep: ExpirySweep) -> CacheBuilder {
+        self.sweep = sweep;
+        self
+    }
+
+    /// Keep expired items for `grace` more seconds, missed by `get` but
+    /// returned by `Cache::get_stale`, so that one caller stores a new value
+    /// while the others are served the old one. They are removed once it
+    /// ends, unless a new value is stored first. `None` by default.
+    pub fn stale_grace(mut self, grace: Option<u64>) -> CacheBuilder {
+        self.stale_grace = grace.filter(|grace| *grace > 0);
+        self
+    }
+
+    /// Move the deadlines `Cache::deadline` works out earlier by up to
+    /// `jitter`, so that items stored together with the same time to live
+    /// do not all expire together. `None` by default.
+    pub fn ttl_jitter(mut self, jitter: Option<Jitter>) -> CacheBuilder {
+        self.ttl_jitter = jitter;
+        self
+    }
+
+    /// Move deadlines later by up to `CacheBuilder::ttl_jitter` too, past
+    /// what clients asked for.
+    pub fn ttl_jitter_both_ways(mut self, both_ways: bool) -> CacheBuilder {
+        self.ttl_jitter_both_ways = both_ways;
+        self
+    }
+
+    /// Split the ids of items between time and count as `layout` has it.
+    /// `Layout::SECONDS` by default.
+    pub fn id_layout(mut self, layout: Layout) -> CacheBuilder {
+        self.id_layout = layout;
+        self
+    }
+
+    /// Report items evicted, expired, flushed or replaced to `removals`.
+    /// Items deleted are not reported, nor values moved to disk.
+    ///
+    /// Reports are sent once the locks the removal took are released, and
+    /// without waiting: those `removals` has no room for are dropped, and
+    /// counted in the `removals_dropped` stat. `None` by default.
+    pub fn removals(mut self, removals: Option<mpsc::Sender<Removal>>) -> CacheBuilder {
+        self.removals = removals;
+        self
+    }
+
+    /// Expire items by `clock` rather than the system's.
+    #[cfg(test)]
+    pub(crate) fn clock(mut self, clock: Clock) -> CacheBuilder {
+        self.clock = clock;
+        self
+    }
+
+    /// Move values to `overflow` rather than evicting their items, see
+    /// `Cache::make_room`. They come back to memory when read.
+    pub(crate) fn overflow(mut self, overflow: Option<Arc<Overflow>>) -> CacheBuilder {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Evict items of a key prefix to hold at most its quota for them, see
+    /// `Cache::make_room`. Other items are not evicted for them.
+    pub(crate) fn quotas(mut self, quotas: Option<Quotas>) -> CacheBuilder {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn build(self) -> Cache {
+        assert!(self.shards > 0, "a cache needs at least one index shard");
+        let large_item_limit = self.large_item_limit.filter(|limit| *limit > self.max_item_size);
+        Cache {
+            id: Arc::new(Generator::with_layout(self.id_layout)),
+            cas: Arc::new(AtomicU64::new(0)),
+            clock: self.clock,
+            flush: Arc::new(Flush::default()),
+            stats: Arc::new(CacheStats::default()),
+            limits: Arc::new(Limits::new(self.memory_limit, self.max_items)),
+            max_item_size: self.max_item_size,
+            large_item_limit,
+            chunks: large_item_limit.map(|_| Arc::new(Chunks::default())),
+            policy: self.policy,
+            quotas: self.quotas.map(Arc::new),
+            sweep: self.sweep,
+            stale_grace: self.stale_grace,
+            ttl_jitter: self.ttl_jitter.map(|jitter| (jitter, self.ttl_jitter_both_ways)),
+            removals: self.removals,
+            deadlines: (self.sweep == ExpirySweep::Index).then(|| Arc::new(Deadlines::new(self.shards))),
+            sweep_hand: Arc::new(Mutex::new((0, Vec::new()))),
+            hand: Arc::new(Mutex::new((0, None))),
+            spilling: Arc::new(AtomicUsize::new(0)),
+            reclaim_hand: Arc::new(Mutex::new((0, (0, 0)))),
+            initial_capacity: self.initial_capacity,
+            changes: Arc::new(AtomicU64::new(0)),
+            computing: Arc::new(Mutex::new(HashMap::new())),
+            log: None,
+            overflow: self.overflow,
+            journals: Arc::new(RwLock::new(Vec::new())),
+            journaling: Arc::new(AtomicUsize::new(0)),
+            key_locks: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+            index: (0..self.shards).map(|_| RwLock::new(BTreeMap::new())).collect(),
+            cache: Arc::new(DashMap::with_capacity(self.initial_capacity)),
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::new()
+    }
+}
+
+impl Cache {
+    /// Create a cache with the defaults of `CacheBuilder`: no limits but
+    /// `MAX_ITEM_SIZE`, evicting by LRU once limited.
+    pub fn new() -> Cache {
+        Cache::builder().build()
+    }
+
+    /// Configure a cache to create.
+    pub fn builder() -> CacheBuilder {
+        CacheBuilder::default()
+    }
+
+    pub(crate) fn quotas(&self) -> Option<&Arc<Quotas>> {
+        self.quotas.as_ref()
+    }
+
+    /// Write every change to `log` from now on. Changes are only reported
+    /// done once written; evictions and expirations are not written.
+    pub(crate) fn with_append_log(mut self, log: Arc<AppendLog>) -> Cache {
+        self.log = Some(log);
+        self
+    }
+
+    /// Largest value stored in chunks, if any are, see
+    /// `CacheBuilder::large_item_limit`.
+    pub(crate) fn large_item_limit(&self) -> Option<usize> {
+        self.large_item_limit
+    }
+
+    pub(crate) fn expiry_sweep(&self) -> ExpirySweep {
+        self.sweep
+    }
+
+    pub(crate) fn append_log(&self) -> Option<&Arc<AppendLog>> {
+        self.log.as_ref()
+    }
+
+    pub(crate) fn overflow(&self) -> Option<&Arc<Overflow>> {
+        self.overflow.as_ref()
+    }
+
+    /// Make the change `op` makes, and with an append log wait for room in
+    /// it before and until the change is written after. `op` queues its
+    /// record under the lock of the item it changes, so the log has the
+    /// changes to an item in the order they were made.
+    ///
+    /// `op` runs without yielding, so dropping the future leaves the change
+    /// either made in full or not at all: dropped while waiting for room, it
+    /// is not made; dropped while waiting for the write, it is made and will
+    /// be written, only not waited for.
+    async fn logged<T>(&self, op: impl FnOnce() -> T) -> T {
+        let Some(log) = &self.log else {
+            return op();
+        };
+        log.reserve().await;
+        let result = op();
+        log.written().await;
+        result
+    }
+
+    /// Count a change, and queue `record` of it in the append log.
+    fn log_change(&self, record: Record<'_>) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        if let Some(log) = &self.log {
+            log.push(record);
+        }
+    }
+
+    /// Count `item`, just stored under `key`, as a change and queue it in
+    /// the append log.
+    fn log_store(&self, key: &[u8], item: &MemoryItem) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        if let Some(log) = &self.log {
+            if let Some(item) = item.to_item(Bytes::copy_from_slice(key)) {
+                log.push(Record::Store(&item));
+            }
+        }
+    }
+
+    /// Queue the item `id`, just stored under `key` and indexed, in the
+    /// append log. Only once indexed, so a log rewrite under way either
+    /// finds the item or gets the record.
+    fn log_new(&self, key: &[u8], id: u64) {
+        if self.log.is_none() {
+            self.changes.fetch_add(1, Ordering::Relaxed);
+        } else if let Some(item) = self.cache.get(&id) {
+            self.log_store(key, &item);
+        }
+    }
+
+    /// The part of the index `key` is in.
+    ///
+    /// Keys are spread over separately locked maps by hash, see
+    /// `CacheBuilder::shards`, so readers only ever wait on writers of keys
+    /// in the same shard. Each operation locks a single shard; only
+    /// `make_room` goes through them all, one at a time.
+    fn shard(&self, key: &[u8]) -> &IndexShard {
+        &self.index[self.shard_number(key)]
+    }
+
+    fn shard_number(&self, key: &[u8]) -> usize {
+        self.hasher.hash_one(key) as usize % self.index.len()
+    }
+
+    /// Lock the stripe of `key_locks` `key` falls in, for a read-modify-write
+    /// operation. Take before any other lock.
+    fn key_lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.key_locks[self.hasher.hash_one(key) as usize % self.key_locks.len()].lock()
+    }
+
+    /// Keep `item`, stored under `key`, for the `SnapshotIter`s under way
+    /// before changing or removing it. Call under the lock of the key's index
+    /// shard and the item's map entry.
+    fn journal(&self, key: &[u8], item: &MemoryItem) {
+        if self.journaling.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let shard = self.shard_number(key);
+        for journal in self.journals.read().iter() {
+            journal.keep(shard, key, item);
+        }
+    }
+
+    /// Note that the item `id` under `key` expires at `new` rather than
+    /// `old`, for `sweep_expired`. Call under the locks `journal` needs.
+    ///
+    /// The item is due once it is past its stale grace too.
+    fn expires(&self, key: &[u8], id: u64, old: Option<u64>, new: Option<u64>) {
+        if let Some(deadlines) = &self.deadlines {
+            if old != new {
+                let grace = self.stale_grace.unwrap_or(0);
+                let (old, new) = (old.map(|old| old + grace), new.map(|new| new + grace));
+                deadlines.update(self.shard_number(key), key, id, old, new);
+            }
+        }
+    }
+
+    /// The current time by the cache's clock, in seconds since the unix
+    /// epoch.
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Convert `exptime`, as sent by a client storing under `key`, to when
+    /// the item expires, see `clock::deadline`. With a
+    /// `CacheBuilder::ttl_jitter` the deadline is moved by a random amount
+    /// within it, picked anew for every store.
+    pub(crate) fn deadline(&self, key: &[u8], exptime: i64) -> Option<u64> {
+        let now = self.now();
+        let deadline = clock::deadline(exptime, now)?;
+        let Some((jitter, both_ways)) = self.ttl_jitter else {
+            return Some(deadline);
+        };
+        let random = self.hasher.hash_one((key, self.cas.load(Ordering::Relaxed)));
+        Some(jitter.spread(deadline, now, both_ways, random))
+    }
+
+    fn next_cas(&self) -> u64 {
+        self.cas.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Count a `get` or `gets` of `keys` keys in `cmd_get`, one per key
+    /// like memcached. Other reads count only as hits or misses.
+    pub(crate) fn count_gets(&self, keys: usize) {
+        self.stats.cmd_get.fetch_add(keys as u64, Ordering::Relaxed);
+    }
+
+    /// Count a storage command sent by a client in `cmd_set`. Imports and
+    /// replicated changes are not counted.
+    pub(crate) fn count_set(&self) {
+        CacheStats::incr(&self.stats.cmd_set);
+    }
+
+    /// A freshly written item, with the next CAS.
+    fn new_item(&self, key: &[u8], flags: u32, expiration: Option<u64>, data: Bytes) -> MemoryItem {
+        self.stats.total_items.fetch_add(1, Ordering::Relaxed);
+        let mut item = MemoryItem::new(key, flags, expiration, self.next_cas(), self.now(), data);
+        self.chunk(key, &mut item);
+        self.policy.on_access(&item, item.cas, item.stored_at);
+        item
+    }
+
+    /// Largest value to store, see `CacheBuilder::large_item_limit`.
+    fn item_size_limit(&self) -> usize {
+        self.large_item_limit.unwrap_or(self.max_item_size)
+    }
+
+    /// Move the value of `item`, stored under `key`, to chunks if it is past
+    /// `max_item_size`. Call before counting the bytes it holds.
+    fn chunk(&self, key: &[u8], item: &mut MemoryItem) {
+        let Some(chunks) = &self.chunks else {
+            return;
+        };
+        if item.data.len() > self.max_item_size {
+            item.chunked = Some(chunks.store(key, &item.data, self.max_item_size));
+            item.data = Value::default();
+        }
+    }
+
+    /// Remove the chunks no item holds anymore, see `Chunks::remove_orphans`.
+    /// Returns how many values they were of.
+    pub(crate) fn remove_orphan_chunks(&self) -> usize {
+        let Some(chunks) = &self.chunks else {
+            return 0;
+        };
+        chunks.remove_orphans(|key, id| {
+            let index = self.shard(key).read();
+            index
+                .get(key)
+                .and_then(|item| self.cache.get(item))
+                .is_some_and(|item| item.chunked.as_ref().is_some_and(|chunked| chunked.id() == id))
+        })
+    }
+
+    /// Whether `item` has expired, or been flushed, at `now`.
+    fn is_dead(&self, item: &MemoryItem, now: u64) -> bool {
+        item.is_dead(now, &self.flush.load())
+    }
+
+    /// Whether `item` expired by `now`, less than the stale grace ago, and
+    /// is kept to be served stale, see `get_stale`.
+    fn is_stale(&self, item: &MemoryItem, now: u64) -> bool {
+        let Some(grace) = self.stale_grace else {
+            return false;
+        };
+        item.expiration.is_some_and(|expiration| expiration <= now && now < expiration + grace)
+            && !item.is_flushed(now, &self.flush.load())
+    }
+
+    /// Whether `item` is dead at `now` and not kept to be served stale
+    /// either, so it can be removed.
+    fn is_gone(&self, item: &MemoryItem, now: u64) -> bool {
+        self.is_dead(item, now) && !self.is_stale(item, now)
+    }
+
+    /// Flush every item stored so far, or with `at` (seconds since the unix
+    /// epoch) every item stored before then, once it has come. Replaces a
+    /// flush still pending.
+    ///
+    /// Flushed items are missed right away but only removed as they are
+    /// come across, like expired ones.
+    pub async fn flush(&self, at: Option<u64>) {
+        self.logged(|| {
+            let cas = self.flush_now(at);
+            self.log_change(Record::Flush { cas, at });
+        })
+        .await
+    }
+
+    /// `flush`, without the append log. Returns the CAS last handed out
+    /// when it was issued.
+    fn flush_now(&self, at: Option<u64>) -> u64 {
+        let now = self.now();
+        let cas = self.cas.load(Ordering::Relaxed);
+        let pending = at.filter(|at| *at > now);
+        let previous = self.flush.at.swap(pending.unwrap_or(0), Ordering::Relaxed);
+        if previous != 0 && previous <= now {
+            self.flush.before.fetch_max(previous, Ordering::Relaxed);
+        }
+        if pending.is_none() {
+            self.flush.cas.fetch_max(cas, Ordering::Relaxed);
+        }
+        cas
+    }
+
+    /// Apply a flush read back from the append log. One still to come is
+    /// pending again. Otherwise the items up to `cas` go, those stored before
+    /// it was issued; for a delayed flush that came while the server was
+    /// down, the items stored while it was pending stay.
+    pub(crate) fn replay_flush(&self, cas: u64, at: Option<u64>) {
+        match at.filter(|at| *at > self.now()) {
+            Some(at) => {
+                self.flush_now(Some(at));
+            }
+            None => {
+                self.flush.cas.fetch_max(cas, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The CAS last handed out.
+    pub(crate) fn current_cas(&self) -> u64 {
+        self.cas.load(Ordering::Relaxed)
+    }
+
+    /// The last id and CAS handed out, see `watermark`.
+    pub(crate) fn watermark(&self) -> Watermark {
+        Watermark { id: self.id.last(), cas: self.current_cas() }
+    }
+
+    /// Hand out only ids and CAS values above those of `mark` from now on.
+    pub(crate) fn raise_watermark(&self, mark: Watermark) {
+        self.id.skip_past(mark.id);
+        self.cas.fetch_max(mark.cas, Ordering::Relaxed);
+    }
+
+    /// When a flush given a time is to come, if one is.
+    pub(crate) fn pending_flush(&self) -> Option<u64> {
+        match self.flush.at.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(at).filter(|at| *at > self.now()),
+        }
+    }
+
+    /// Remove every item, and where eviction and sweeps were, leaving the
+    /// cache as built but for its counters and a flush still pending. CAS
+    /// values go on from where they were, so none is handed out twice.
+    ///
+    /// Unlike `flush`, the items go right away and their memory with them.
+    /// Every index shard is write locked at once, so reads and stores made
+    /// meanwhile come either before the clear or after it, and the append
+    /// log has them in the same order.
+    pub async fn clear(&self) {
+        self.logged(|| self.clear_now()).await;
+    }
+
+    /// `clear`, without waiting on the append log. Also for replaying a
+    /// rewritten log, which holds the whole cache.
+    pub(crate) fn clear_now(&self) {
+        let mut shards: Vec<_> = self.index.iter().map(|shard| shard.write()).collect();
+        let mut removed = Vec::new();
+        for index in &mut shards {
+            for (key, id) in std::mem::take(&mut **index) {
+                if let Some((_, item)) = self.cache.remove(&id) {
+                    self.journal(&key, &item);
+                    self.expires(&key, id, item.expiration, None);
+                    self.discharge(&key, footprint(key.len(), item.size()));
+                    if self.removals.is_some() {
+                        removed.push(key);
+                    }
+                }
+            }
+        }
+        self.log_change(Record::Reset);
+        *self.hand.lock() = (0, None);
+        *self.sweep_hand.lock() = (0, Vec::new());
+        drop(shards);
+        for key in removed {
+            self.removed(&key, RemovalReason::Flushed);
+        }
+    }
+
+    /// Returns the item count and the counters, as `(name, value)` pairs in
+    /// reporting order, see `CacheStats`.
+    pub(crate) fn stats(&self) -> Vec<(String, u64)> {
+        let mut stats = vec![("curr_items".to_string(), self.len() as u64)];
+        stats.extend(self.stats.snapshot());
+        stats.push(("map_capacity".to_string(), self.cache.capacity() as u64));
+        stats.push(("id_counter_exhausted".to_string(), self.id.exhausted()));
+        if let Some(chunks) = &self.chunks {
+            let (items, bytes) = chunks.len();
+            stats.push(("large_items".to_string(), items as u64));
+            stats.push(("large_item_bytes".to_string(), bytes as u64));
+        }
+        stats
+    }
+
+    /// Returns the eviction and expiry counters as `(name, value)` pairs,
+    /// reported by `stats items`.
+    pub(crate) fn item_stats(&self) -> Vec<(String, u64)> {
+        self.stats.items(self.len() as u64)
+    }
+
+    /// Returns the bytes held by stored items, by estimate.
+    pub fn bytes(&self) -> usize {
+        self.stats.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many items have been evicted to make room.
+    pub fn evictions(&self) -> u64 {
+        self.stats.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many items have been written, updates included.
+    pub fn total_items(&self) -> u64 {
+        self.stats.total_items.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many stores were refused as out of memory.
+    pub fn out_of_memory_errors(&self) -> u64 {
+        self.stats.out_of_memory.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many items `flush_prefix` has removed.
+    pub fn prefix_flushed(&self) -> u64 {
+        self.stats.prefix_flushed.load(Ordering::Relaxed)
+    }
+
+    /// Count a store refused for want of room.
+    fn out_of_memory(&self) -> Outcome {
+        self.stats.out_of_memory.fetch_add(1, Ordering::Relaxed);
+        Outcome::OutOfMemory
+    }
+
+    /// Whether the item under `key` may grow by `growth` bytes in place.
+    /// Growing happens under the index read lock, where nothing can be
+    /// evicted, so only `EvictionPolicy::None` refuses to go past the limit
+    /// or the key's quota; otherwise the next `set` or `add` evicts to get
+    /// back under them.
+    fn can_grow(&self, key: &[u8], growth: usize) -> bool {
+        if self.policy != EvictionPolicy::None {
+            return true;
+        }
+        self.limits.memory().is_none_or(|limit| self.bytes() + growth <= limit)
+            && self.quota(key).is_none_or(|quota| quota.used() + growth <= quota.limit)
+    }
+
+    /// Whether stores may have to evict first, see `make_room`.
+    fn limited(&self) -> bool {
+        self.limits.memory().is_some() || self.limits.items().is_some() || self.quotas.is_some()
+    }
+
+    /// Whether `needed` more bytes, and `new` more items, would go past the
+    /// memory limit or the item limit.
+    fn over_limits(&self, needed: usize, new: usize) -> bool {
+        self.limits.memory().is_some_and(|limit| self.bytes() + needed > limit)
+            || self.limits.items().is_some_and(|max| self.len() + new > max)
+    }
+
+    /// Change the memory limit to `memory` bytes and the item limit to
+    /// `items`, unlimited if `None`, like `CacheBuilder::memory_limit_bytes`
+    /// and `CacheBuilder::max_items`. Lowered, they take effect on the next
+    /// store, which evicts to get under them.
+    pub(crate) fn set_limits(&self, memory: Option<usize>, items: Option<usize>) {
+        self.limits.set(memory, items);
+    }
+
+    /// Account for the value of the item under `key` changing from `old`
+    /// bytes to `new`, whether or not either is kept in the item.
+    fn resized(&self, key: &[u8], old: usize, new: usize) {
+        let (old, new) = (held(old), held(new));
+        if new >= old {
+            self.charge(key, new - old);
+        } else {
+            self.discharge(key, old - new);
+        }
+    }
+
+    /// Account for `bytes` more held for the item under `key`, in the total
+    /// and in its quota.
+    fn charge(&self, key: &[u8], bytes: usize) {
+        self.stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(quota) = self.quota(key) {
+            quota.charge(bytes);
+        }
+    }
+
+    /// Report the item under `key` leaving for `reason`, see
+    /// `CacheBuilder::removals`. Call without any index or map lock.
+    fn removed(&self, key: &[u8], reason: RemovalReason) {
+        let Some(removals) = &self.removals else {
+            return;
+        };
+        let removal = Removal { key: Bytes::copy_from_slice(key), reason };
+        if removals.try_send(removal).is_err() {
+            CacheStats::incr(&self.stats.removals_dropped);
+        }
+    }
+
+    /// Why `item`, found expired or flushed at `now`, leaves.
+    fn dead_reason(&self, item: &MemoryItem, now: u64) -> RemovalReason {
+        if item.spilled.as_ref().is_some_and(Spilled::is_lost) {
+            RemovalReason::Evicted
+        } else if item.is_expired(now) {
+            RemovalReason::Expired
+        } else {
+            RemovalReason::Flushed
+        }
+    }
+
+    /// Count `item` removed expired or flushed, as `expired_unfetched` too
+    /// if it was never read.
+    fn count_expired(&self, item: &MemoryItem) {
+        CacheStats::incr(&self.stats.expired);
+        if item.fetches() == 0 {
+            CacheStats::incr(&self.stats.expired_unfetched);
+        }
+    }
+
+    /// Count `item` replaced by a store once expired or flushed, like
+    /// `count_expired`.
+    fn count_reclaimed(&self, item: &MemoryItem) {
+        CacheStats::incr(&self.stats.reclaimed);
+        if item.fetches() == 0 {
+            CacheStats::incr(&self.stats.expired_unfetched);
+        }
+    }
+
+    /// Count `item` evicted at `now`, as `evicted_unfetched` too if it was
+    /// never read, and keep how long it had gone unread as `evicted_time`.
+    fn count_evicted(&self, item: &MemoryItem, now: u64) {
+        CacheStats::incr(&self.stats.evictions);
+        if item.fetches() == 0 {
+            CacheStats::incr(&self.stats.evicted_unfetched);
+        }
+        let idle = now.saturating_sub(item.last_access());
+        self.stats.evicted_time.store(idle, Ordering::Relaxed);
+    }
+
+    /// Account for `bytes` less held for the item under `key`, like `charge`.
+    fn discharge(&self, key: &[u8], bytes: usize) {
+        self.stats.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(quota) = self.quota(key) {
+            quota.discharge(bytes);
+        }
+    }
+
+    /// The quota the item under `key` counts against, if any.
+    fn quota(&self, key: &[u8]) -> Option<&Quota> {
+        self.quotas.as_ref()?.find(key)
+    }
+
+    /// Returns how many changes have been made: items stored, deleted and
+    /// touched, and flushes. Evictions and expirations do not count.
+    pub fn changes(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of items stored.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Returns the item stored under `key`, unless it has expired. An
+    /// expired item is removed. A value on disk is read back and moved to
+    /// memory, see `CacheBuilder::overflow`.
+    pub async fn get(&self, key: &[u8]) -> Option<ItemView> {
+        let found = self.find(key);
+        let counter = match found {
+            Some(_) => &self.stats.get_hits,
+            None => &self.stats.get_misses,
+        };
+        CacheStats::incr(counter);
+        found
+    }
+
+    /// Returns the item stored under `key` like `get`, or one that expired
+    /// less than `CacheBuilder::stale_grace` ago, with how fresh it is.
+    ///
+    /// Of the callers getting an expired item, the first is told it `Won`
+    /// and should store a new value; the others are told it is `Stale`,
+    /// until a new value is stored or the grace ends. The item is marked
+    /// under its map entry lock, so only one caller wins each expiry.
+    pub async fn get_stale(&self, key: &[u8]) -> Option<(ItemView, Freshness)> {
+        let found = self.find_stale(key);
+        let counter = match found {
+            Some((_, Freshness::Fresh)) => &self.stats.get_hits,
+            Some(_) => {
+                CacheStats::incr(&self.stats.get_stale);
+                &self.stats.get_hits
+            }
+            None => &self.stats.get_misses,
+        };
+        CacheStats::incr(counter);
+        found
+    }
+
+    /// Returns the item stored under `key` like `get`, unless it still has
+    /// CAS `cas`: then only that it is `Unchanged`, without copying its value
+    /// or reading it back from disk. Either counts as a hit.
+    pub async fn get_if_modified(&self, key: &[u8], cas: u64) -> Option<Revalidated> {
+        let found = self.find_if_modified(key, cas);
+        let counter = match found {
+            Some(_) => &self.stats.get_hits,
+            None => &self.stats.get_misses,
+        };
+        CacheStats::incr(counter);
+        found
+    }
+
+    fn find_if_modified(&self, key: &[u8], cas: u64) -> Option<Revalidated> {
+        let index = self.shard(key).read();
+        let id = *index.get(key)?;
+        let item = self.cache.get(&id)?;
+        drop(index);
+        let now = self.now();
+        if item.cas != cas || self.is_dead(&item, now) {
+            return self.read(key, id, item, now).map(Revalidated::Changed);
+        }
+        self.policy.on_access(&item, self.cas.load(Ordering::Relaxed), now);
+        item.record_access(now, true);
+        Some(Revalidated::Unchanged)
+    }
+
+    fn find_stale(&self, key: &[u8]) -> Option<(ItemView, Freshness)> {
+        let index = self.shard(key).read();
+        let id = *index.get(key)?;
+        let item = self.cache.get(&id)?;
+        drop(index);
+        let now = self.now();
+        if !self.is_stale(&item, now) {
+            return self.read(key, id, item, now).map(|item| (item, Freshness::Fresh));
+        }
+        let freshness = if item.refresh_won.swap(true, Ordering::Relaxed) {
+            Freshness::Stale
+        } else {
+            Freshness::Won
+        };
+        let found = match item.on_disk() {
+            Some(on_disk) => {
+                drop(item);
+                on_disk.read(key)
+            }
+            None => item.to_view(key),
+        };
+        Some((found?, freshness))
+    }
+
+    /// Returns the items stored under `keys`, in the same order, `None` for
+    /// those that are missing, like `get` for each of them.
+    ///
+    /// The ids of the keys are looked up first, with each index shard the
+    /// keys fall in read locked once for all of its keys; the items are
+    /// read once the locks are released, all as of the same second.
+    pub async fn get_multi(&self, keys: &[impl AsRef<[u8]>]) -> Vec<Option<ItemView>> {
+        let mut by_shard: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(n, key)| (self.shard_number(key.as_ref()), n))
+            .collect();
+        by_shard.sort_unstable();
+        let mut ids = vec![None; keys.len()];
+        for keys_in_shard in by_shard.chunk_by(|(a, _), (b, _)| a == b) {
+            let index = self.index[keys_in_shard[0].0].read();
+            for &(_, n) in keys_in_shard {
+                ids[n] = index.get(keys[n].as_ref()).copied();
+            }
+        }
+
+        let now = self.now();
+        ids.into_iter()
+            .zip(keys)
+            .map(|(id, key)| {
+                let found = id.and_then(|id| self.read(key.as_ref(), id, self.cache.get(&id)?, now));
+                let counter = match found {
+                    Some(_) => &self.stats.get_hits,
+                    None => &self.stats.get_misses,
+                };
+                CacheStats::incr(counter);
+                found
+            })
+            .collect()
+    }
+
+    /// Returns the item stored under `key` like `get`, or else stores the
+    /// value `compute` comes up with, expiring at `expiration`, and returns
+    /// that. When there is no room for it, it is returned all the same.
+    ///
+    /// Of the callers missing the same key at once, only one runs its
+    /// `compute`; the others wait for it and get what it came up with. If
+    /// it fails, nothing is stored and they all fail, the others with a copy
+    /// of its message. If it is dropped before it is done, the next of them
+    /// takes over.
+    pub async fn get_or_insert_with<F, Fut>(
+        &self,
+        key: Bytes,
+        expiration: Option<u64>,
+        compute: F,
+    ) -> anyhow::Result<ItemView>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<Bytes>>,
+    {
+        let done = loop {
+            if let Some(item) = self.get(&key).await {
+                return Ok(item);
+            }
+            let mut computed = {
+                let mut computing = self.computing.lock();
+                match computing.get(&key) {
+                    Some(computed) => computed.clone(),
+                    // The last computation may have been stored since the
+                    // miss, and have left already
+                    None => match self.find(&key) {
+                        Some(item) => return Ok(item),
+                        None => {
+                            let (done, computed) = watch::channel(None);
+                            computing.insert(key.clone(), computed);
+                            break done;
+                        }
+                    },
+                }
+            };
+            // Fails if the computation was dropped, then it is taken over
+            let outcome = computed.wait_for(Option::is_some).await.map(|outcome| outcome.clone());
+            if let Ok(Some(outcome)) = outcome {
+                return outcome.map_err(anyhow::Error::msg);
+            }
+        };
+
+        // Leaves `computing` however this ends, dropped included
+        let _computing = Computing { cache: self, key: &key };
+        let outcome = match compute().await {
+            Ok(data) => {
+                self.set(key.clone(), 0, expiration, data.clone()).await;
+                Ok(self.find(&key).unwrap_or(ItemView {
+                    flags: 0,
+                    cas: 0,
+                    expiration,
+                    data,
+                }))
+            }
+            Err(err) => Err(err),
+        };
+        let _ = done.send(Some(outcome.as_ref().map(ItemView::clone).map_err(|err| format!("{:#}", err))));
+        outcome
+    }
+
+    fn find(&self, key: &[u8]) -> Option<ItemView> {
+        let index = self.shard(key).read();
+        let id = *index.get(key)?;
+        let item = self.cache.get(&id)?;
+        drop(index);
+        self.read(key, id, item, self.now())
+    }
+
+    /// Returns `item`, the item `id` found under `key`, unless it has
+    /// expired by `now`, recording the read, see `get`. Call without any
+    /// lock but its map entry's, which is released before a value on disk
+    /// is read back.
+    fn read(&self, key: &[u8], id: u64, item: ItemRef<'_>, now: u64) -> Option<ItemView> {
+        if self.is_dead(&item, now) {
+            if let Some(overflow) = &self.overflow {
+                if item.spilled.as_ref().is_some_and(Spilled::is_lost) {
+                    overflow.count_read(false);
+                }
+            }
+            CacheStats::incr(&self.stats.get_expired);
+            drop(item);
+            self.remove_expired(key, id);
+            return None;
+        }
+        self.policy.on_access(&item, self.cas.load(Ordering::Relaxed), now);
+        item.record_access(now, true);
+        let Some(on_disk) = item.on_disk() else {
+            return item.to_view(key);
+        };
+        drop(item);
+        let found = on_disk.read(key);
+        if let Some(overflow) = &self.overflow {
+            overflow.count_read(found.is_some());
+        }
+        match &found {
+            Some(found) => self.promote(key, id, found),
+            None => {
+                self.remove_expired(key, id);
+            }
+        }
+        found
+    }
+
+    /// Move the value of the item `id` under `key` back to memory, just read
+    /// from disk as `item`, unless the item changed meanwhile.
+    fn promote(&self, key: &[u8], id: u64, item: &ItemView) {
+        let Some(mut stored) = self.cache.get_mut(&id) else {
+            return;
+        };
+        if stored.cas == item.cas && stored.spilled.is_some() {
+            stored.spilled = None;
+            stored.data = Value::new(item.data.clone());
+            self.resized(key, 0, item.data.len());
+        }
+    }
+
+    /// Move the values still in the overflow segment most freed, if less
+    /// than half of it is left, to the segment written to. The segment is
+    /// removed once none are left, freeing the space of the values removed
+    /// from it. Returns how many were moved.
+    ///
+    /// Each value is read and written again without any lock held, then
+    /// swapped in under the read lock of its key's index shard and its map
+    /// entry lock, like other changes to the item, unless the item changed
+    /// meanwhile.
+    pub(crate) fn compact_overflow(&self) -> io::Result<usize> {
+        let Some(overflow) = &self.overflow else {
+            return Ok(0);
+        };
+        let Some(segment) = overflow.to_compact() else {
+            return Ok(0);
+        };
+        let mut moved = 0;
+        for (offset, key) in segment.keys()? {
+            let index = self.shard(&key).read();
+            let Some(&id) = index.get(&key) else {
+                continue;
+            };
+            let Some(item) = self.cache.get(&id) else {
+                continue;
+            };
+            let spilled = item.spilled.as_ref().filter(|spilled| spilled.is_at(&segment, offset));
+            let Some(location) = spilled.map(Spilled::location) else {
+                continue;
+            };
+            let cas = item.cas;
+            drop(item);
+            drop(index);
+
+            let Some(data) = read_spilled(&location, &key, cas) else {
+                continue;
+            };
+            let Some(spilled) = overflow.write(&key, cas, &data)? else {
+                continue;
+            };
+            let index = self.shard(&key).read();
+            if index.get(&key) != Some(&id) {
+                continue;
+            }
+            let Some(mut item) = self.cache.get_mut(&id) else {
+                continue;
+            };
+            if item.cas == cas && item.spilled.as_ref().is_some_and(|spilled| spilled.is_at(&segment, offset)) {
+                item.spilled = Some(spilled);
+                moved += 1;
+            }
+        }
+        overflow.count_moved(moved);
+        Ok(moved)
+    }
+
+    /// The CAS and value of the item `id`, if there is an overflow tier and
+    /// enough memory to gain by moving the value to disk, see `spill`.
+    fn spillable(&self, id: u64) -> Option<(u64, Bytes)> {
+        self.overflow.as_ref()?;
+        let item = self.cache.get(&id)?;
+        if item.spilled.is_some() || item.data.len() < MIN_SPILL {
+            return None;
+        }
+        Some((item.cas, item.data.to_bytes()))
+    }
+
+    /// Move `data`, the value of the item `id` with `cas` stored under `key`
+    /// found by `spillable`, to disk rather than evict the item. Call without
+    /// any lock held: the value is written first, then dropped from memory
+    /// unless the item changed meanwhile. Returns whether it was moved, or
+    /// `None` if it could not be written.
+    fn spill(&self, key: &[u8], id: u64, cas: u64, data: &[u8]) -> Option<bool> {
+        let overflow = self.overflow.as_ref()?;
+        let spilled = match overflow.write(key, cas, data) {
+            Ok(spilled) => spilled?,
+            Err(err) => {
+                warn!("failed to move a value to disk: {}", err);
+                return None;
+            }
+        };
+        let index = self.shard(key).read();
+        if index.get(key) != Some(&id) {
+            return Some(false);
+        }
+        let Some(mut item) = self.cache.get_mut(&id) else {
+            return Some(false);
+        };
+        if item.cas != cas || item.spilled.is_some() || item.data.len() != data.len() {
+            return Some(false);
+        }
+        debug!(key = ?Bytes::copy_from_slice(key), len = data.len(), "moving to disk");
+        self.resized(key, data.len(), 0);
+        item.data = Value::default();
+        item.spilled = Some(spilled);
+        Some(true)
+    }
+
+    /// Remove the item stored under `key`. Returns whether there was one; an
+    /// expired item is removed too but counts as missing.
+    pub async fn delete(&self, key: &[u8]) -> bool {
+        let deleted = self.logged(|| self.remove(key)).await;
+        let counter = if deleted { &self.stats.delete_hits } else { &self.stats.delete_misses };
+        CacheStats::incr(counter);
+        deleted
+    }
+
+    /// `delete`, without waiting on the append log.
+    pub(crate) fn remove(&self, key: &[u8]) -> bool {
+        let mut index = self.shard(key).write();
+        let id = match index.remove(key) {
+            Some(id) => id,
+            None => return false,
+        };
+        let item = self.cache.remove(&id);
+        if let Some((_, item)) = &item {
+            self.journal(key, item);
+            self.expires(key, id, item.expiration, None);
+        }
+        self.log_change(Record::Delete(key));
+        drop(index);
+        item.is_some_and(|(_, item)| {
+            self.discharge(key, footprint(key.len(), item.size()));
+            let now = self.now();
+            let dead = self.is_dead(&item, now);
+            if dead {
+                self.count_expired(&item);
+                self.removed(key, self.dead_reason(&item, now));
+            }
+            !dead
+        })
+    }
+
+    /// Remove every item whose key starts with `prefix`, going through them
+    /// in key order, `FLUSH_PREFIX_BATCH` at a time. Returns how many had not
+    /// expired.
+    ///
+    /// Only the items stored before the call go: those stored meanwhile
+    /// have a later CAS, and are left whether or not the flush has come to
+    /// their key yet. Unlike `flush`, each item is removed like by `delete`.
+    pub async fn flush_prefix(&self, prefix: &[u8]) -> usize {
+        let cas = self.cas.load(Ordering::Relaxed);
+        self.flush_prefix_before(prefix, cas).await
+    }
+
+    /// `flush_prefix` of the items stored up to `cas`.
+    async fn flush_prefix_before(&self, prefix: &[u8], cas: u64) -> usize {
+        let mut flushed = 0;
+        let mut after = None;
+        loop {
+            let (keys, last) = self.scan(prefix, after.as_deref(), FLUSH_PREFIX_BATCH);
+            for key in keys {
+                if self.logged(|| self.remove_stored_before(&key, cas)).await {
+                    flushed += 1;
+                }
+            }
+            match last {
+                Some(last) => after = Some(last),
+                None => break,
+            }
+        }
+        self.stats.prefix_flushed.fetch_add(flushed as u64, Ordering::Relaxed);
+        flushed
+    }
+
+    /// `remove`, only if the item under `key` has a CAS up to `cas`.
+    fn remove_stored_before(&self, key: &[u8], cas: u64) -> bool {
+        let mut index = self.shard(key).write();
+        let Some(&id) = index.get(key) else {
+            return false;
+        };
+        let Some((_, item)) = self.cache.remove_if(&id, |_, item| item.cas <= cas) else {
+            return false;
+        };
+        self.journal(key, &item);
+        self.expires(key, id, item.expiration, None);
+        index.remove(key);
+        self.log_change(Record::Delete(key));
+        drop(index);
+        self.discharge(key, footprint(key.len(), item.size()));
+        let now = self.now();
+        let dead = self.is_dead(&item, now);
+        if dead {
+            self.count_expired(&item);
+            self.removed(key, self.dead_reason(&item, now));
+        } else {
+            self.removed(key, RemovalReason::Flushed);
+        }
+        !dead
+    }
+
+    /// Remove the item `id` stored under `key`, if it is still there and
+    /// still expired. It may have been replaced or touched since it was
+    /// looked up. Returns whether it was removed.
+    fn remove_expired(&self, key: &[u8], id: u64) -> bool {
+        let mut index = self.shard(key).write();
+        if index.get(key) != Some(&id) {
+            return false;
+        }
+        let now = self.now();
+        let Some((_, item)) = self.cache.remove_if(&id, |_, item| self.is_gone(item, now)) else {
+            return false;
+        };
+        self.journal(key, &item);
+        self.expires(key, id, item.expiration, None);
+        index.remove(key);
+        self.discharge(key, footprint(key.len(), item.size()));
+        drop(index);
+        self.count_expired(&item);
+        self.removed(key, self.dead_reason(&item, now));
+        true
+    }
+
+    /// Remove expired items, found the way `CacheBuilder::expiry_sweep`
+    /// says. Each is looked up again before it goes, so one touched or
+    /// replaced since is left. Blocks; returns how many were removed.
+    pub(crate) fn sweep_expired(&self) -> usize {
+        match self.sweep {
+            ExpirySweep::None => 0,
+            ExpirySweep::Scan => self.sweep_scan(),
+            ExpirySweep::Index => self.sweep_index(),
+            ExpirySweep::Sampled => self.sweep_sampled(Instant::now() + SWEEP_BUDGET),
+        }
+    }
+
+    /// Remove every expired or flushed item, going through all of them,
+    /// `SNAPSHOT_BATCH` keys at a time.
+    fn sweep_scan(&self) -> usize {
+        let mut removed = 0;
+        for shard in 0..self.index.len() {
+            let mut after = None;
+            loop {
+                let (dead, last) = self.find_dead(shard, after.as_deref(), SNAPSHOT_BATCH);
+                removed += dead.iter().filter(|(key, id)| self.remove_expired(key, *id)).count();
+                match last {
+                    Some(last) => after = Some(last),
+                    None => break,
+                }
+            }
+        }
+        removed
+    }
+
+    /// Remove the items whose expiration has come, in the order they expire.
+    /// Only those are gone through, not every item. Flushed items are left
+    /// to be come across.
+    fn sweep_index(&self) -> usize {
+        let Some(deadlines) = &self.deadlines else {
+            return 0;
+        };
+        let now = self.now();
+        let mut removed = 0;
+        for shard in 0..self.index.len() {
+            loop {
+                let due = deadlines.due(shard, now, SWEEP_BATCH);
+                removed += due.iter().filter(|(key, id)| self.remove_expired(key, *id)).count();
+                if due.len() < SWEEP_BATCH {
+                    break;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Remove the expired or flushed items among `EXPIRY_SAMPLE` keys of
+    /// each index shard in turn, sampling the shard again while more than a
+    /// quarter of a sample had expired, until `deadline`. Returns how many
+    /// were removed.
+    ///
+    /// Like eviction, each sample starts where the last one in the shard
+    /// ended, so every key is come to in turn. A shard with few expired
+    /// items costs a sample a sweep; at most about a quarter of the items
+    /// left can have expired, or the time is up.
+    fn sweep_sampled(&self, deadline: Instant) -> usize {
+        let mut hand = self.sweep_hand.lock();
+        let (next, cursors) = &mut *hand;
+        cursors.resize(self.index.len(), None);
+        let mut removed = 0;
+        for _ in 0..self.index.len() {
+            let shard = *next;
+            *next = (*next + 1) % self.index.len();
+            loop {
+                let (dead, last) = self.find_dead(shard, cursors[shard].as_deref(), EXPIRY_SAMPLE);
+                cursors[shard] = last;
+                removed += dead.iter().filter(|(key, id)| self.remove_expired(key, *id)).count();
+                if dead.len() * 4 <= EXPIRY_SAMPLE || Instant::now() >= deadline {
+                    break;
+                }
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        removed
+    }
+
+    /// Give back the memory that items removed in bulk leave behind, a bit
+    /// at a time, see `reclaim::reclaim_periodically`.
+    ///
+    /// Flushed items are only removed as they are come across, so if there
+    /// was a flush since the last call they are all looked for first, like
+    /// `ExpirySweep::Scan` does. Then the map shards holding `SHRINK_FACTOR`
+    /// times the slots their items need are rebuilt smaller, each under its
+    /// write lock, going on from where the last call stopped for up to
+    /// `SHRINK_BUDGET`. The index needs none of this: a `BTreeMap` frees its
+    /// nodes as keys leave.
+    ///
+    /// Blocks; returns how many items were removed, and the slots of the map
+    /// before and after.
+    pub(crate) fn reclaim_memory(&self) -> (usize, usize, usize) {
+        let flushed = self.flush.load();
+        let cutoff = (flushed.cas, flushed.before(self.now()));
+        let flushed_since = std::mem::replace(&mut self.reclaim_hand.lock().1, cutoff) != cutoff;
+        let removed = if flushed_since { self.sweep_scan() } else { 0 };
+
+        let before = self.cache.capacity();
+        let deadline = Instant::now() + SHRINK_BUDGET;
+        let shards = self.cache.shards().len();
+        let start = self.reclaim_hand.lock().0;
+        for number in (start..start + shards).map(|number| number % shards) {
+            if self.shrink_shard(number, SHRINK_FACTOR) && Instant::now() >= deadline {
+                self.reclaim_hand.lock().0 = number + 1;
+                break;
+            }
+        }
+        (removed, before, self.cache.capacity())
+    }
+
+    /// Shrink every map shard to fit its items, giving back the memory of
+    /// the slots it grew to hold more, one shard at a time. For embedders
+    /// that just removed many items; the server does it bit by bit, see
+    /// `reclaim_memory`.
+    pub fn shrink_to_fit(&self) {
+        for number in 0..self.cache.shards().len() {
+            self.shrink_shard(number, 1);
+        }
+    }
+
+    /// Rebuild map shard `number` with room for twice its items, if it holds
+    /// more than `factor` times the slots they need. Returns whether it did.
+    fn shrink_shard(&self, number: usize, factor: usize) -> bool {
+        let shard = &self.cache.shards()[number];
+        let floor = self.initial_capacity / self.cache.shards().len();
+        let needed = |len: usize| len.max(floor);
+        let (len, capacity) = {
+            let table = shard.read();
+            (table.len(), table.capacity())
+        };
+        if capacity <= factor * needed(len) {
+            return false;
+        }
+        let mut table = shard.write();
+        let hasher = self.cache.hasher();
+        let target = needed(table.len() * 2);
+        table.shrink_to(target, |(id, _)| hasher.hash_one(id));
+        let freed = capacity.saturating_sub(table.capacity());
+        CacheStats::incr(&self.stats.map_shrinks);
+        self.stats.map_slots_freed.fetch_add(freed as u64, Ordering::Relaxed);
+        true
+    }
+
+    /// Returns the keys and ids of the expired or flushed items among up to
+    /// `limit` keys of index shard `shard` after `after`, and the last key
+    /// looked at, unless the shard ended before `limit` of them.
+    fn find_dead(&self, shard: usize, after: Option<&[u8]>, limit: usize) -> (Vec<(Bytes, u64)>, Option<Bytes>) {
+        let now = self.now();
+        let index = self.index[shard].read();
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut dead = Vec::new();
+        let mut taken = 0;
+        let mut last = None;
+        for (key, id) in index.range::<[u8], _>((start, Bound::Unbounded)).take(limit) {
+            if self.cache.get(id).is_some_and(|item| self.is_gone(&item, now)) {
+                dead.push((key.clone(), *id));
+            }
+            taken += 1;
+            last = Some(key);
+        }
+        (dead, last.filter(|_| taken == limit).cloned())
+    }
+
+    /// Iterates over every item that has not expired, in key order.
+    ///
+    /// Only the keys are copied up front, one index shard at a time; values
+    /// are fetched as the iterator advances. Items removed in the meantime
+    /// are skipped, items added are not seen. Dumping items this way does
+    /// not count as reading them, neither for eviction nor their access
+    /// times.
+    pub fn items(&self) -> impl Iterator<Item = Item> + '_ {
+        let now = self.now();
+        let mut keys: Vec<(Bytes, u64)> = Vec::with_capacity(self.len());
+        for shard in self.index.iter() {
+            keys.extend(shard.read().iter().map(|(key, id)| (key.clone(), *id)));
+        }
+        keys.sort_unstable();
+        keys.into_iter().filter_map(move |(key, id)| {
+            let item = self.cache.get(&id).filter(|item| !self.is_dead(item, now))?;
+            item.to_item(key)
+        })
+    }
+
+    /// Up to `limit` keys starting with `prefix`, of items that have not
+    /// expired, in key order. With `after`, only keys that come after it.
+    /// Also returns the last key when there may be more to come, to pass
+    /// back as `after` for the next ones.
+    ///
+    /// Each index shard is read locked only while at most `limit` of its
+    /// keys are copied. The items are checked once the locks are released,
+    /// so dead ones mean going through the shards again from the last key
+    /// looked at. Like `items`, this does not count as reading the items.
+    pub fn scan(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> (Vec<Bytes>, Option<Bytes>) {
+        let now = self.now();
+        let mut start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(Bytes::copy_from_slice(after)),
+            _ => Bound::Included(Bytes::copy_from_slice(prefix)),
+        };
+        let mut keys = Vec::with_capacity(limit);
+        while keys.len() < limit {
+            // One more than wanted, to tell whether there are more.
+            let wanted = limit - keys.len() + 1;
+            let mut batch: Vec<(Bytes, u64)> = Vec::new();
+            for shard in self.index.iter() {
+                let shard = shard.read();
+                batch.extend(
+                    shard
+                        .range((start.clone(), Bound::Unbounded))
+                        .take_while(|(key, _)| key.starts_with(prefix))
+                        .take(wanted)
+                        .map(|(key, id)| (key.clone(), *id)),
+                );
+            }
+            batch.sort_unstable();
+            let more = batch.len() >= wanted;
+            batch.truncate(wanted - 1);
+            let Some((last, _)) = batch.last() else {
+                return (keys, None);
+            };
+            start = Bound::Excluded(last.clone());
+            keys.extend(batch.into_iter().filter_map(|(key, id)| {
+                let live = self.cache.get(&id).is_some_and(|item| !self.is_dead(&item, now));
+                live.then_some(key)
+            }));
+            if !more {
+                return (keys, None);
+            }
+        }
+        let last = keys.last().cloned();
+        (keys, last)
+    }
+
+    /// How long the items have left to live, looking at up to `budget`
+    /// keys. Like `scan`, each index shard is read locked only while a batch
+    /// of its keys is gone through, and this does not count as reading the
+    /// items. Blocks for as long as the budget allows.
+    pub(crate) fn ttl_histogram(&self, budget: usize) -> TtlHistogram {
+        let (now, flushed) = (self.now(), self.flush.load());
+        let mut histogram = TtlHistogram::default();
+        for shard in self.index.iter() {
+            let mut after: Option<Bytes> = None;
+            loop {
+                let batch = SNAPSHOT_BATCH.min(budget - histogram.seen as usize);
+                if batch == 0 {
+                    return histogram;
+                }
+                let index = shard.read();
+                let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+                let mut taken = 0;
+                after = None;
+                for (key, id) in index.range::<Bytes, _>((start, Bound::Unbounded)).take(batch) {
+                    if let Some(item) = self.cache.get(id).filter(|item| !item.is_dead(now, &flushed)) {
+                        histogram.add(item.expiration.map(|expiration| expiration.saturating_sub(now)));
+                    }
+                    taken += 1;
+                    after = Some(key.clone());
+                }
+                histogram.seen += taken;
+                if taken < batch as u64 {
+                    break;
+                }
+            }
+        }
+        histogram.complete = true;
+        histogram
+    }
+
+    /// Look for index entries and items that have lost each other: keys
+    /// whose item is missing from the map, which read as misses but are
+    /// never removed, and items no key leads to, which take memory for good.
+    /// Neither should happen; this is for finding out whether it has. With
+    /// `repair`, both are removed. Blocks until every key and item has been
+    /// looked at.
+    ///
+    /// The ids in the map are taken first, then the keys are gone through
+    /// like in `ttl_histogram`, `SNAPSHOT_BATCH` at a time under the read
+    /// lock of their index shard. Since items leave the map under that lock
+    /// too, a key found without its item is dangling. A new item is put in
+    /// the map before its key is indexed, under the upgradable lock of the
+    /// shard, so the first batch of each shard is taken under the write lock
+    /// instead: stores under way by then are done, and every item taken
+    /// before has its key indexed unless it is orphaned or gone. An orphaned
+    /// item's key is gone, so whatever quota it counted against still counts
+    /// it once it is removed.
+    pub(crate) fn verify(&self, repair: bool) -> Integrity {
+        let ids: Vec<u64> = self.cache.iter().map(|item| *item.key()).collect();
+        let mut indexed = HashSet::with_capacity(ids.len());
+        let (keys, dangling) = self.check_index(&mut indexed);
+        let orphaned: Vec<u64> = ids
+            .iter()
+            .copied()
+            .filter(|id| !indexed.contains(id) && self.cache.contains_key(id))
+            .collect();
+
+        let mut integrity = Integrity {
+            keys,
+            items: ids.len() as u64,
+            dangling_keys: dangling.len() as u64,
+            orphaned_items: orphaned.len() as u64,
+            repaired: 0,
+        };
+        if repair {
+            for (key, id) in dangling {
+                let mut index = self.shard(&key).write();
+                if index.get(&key) == Some(&id) && !self.cache.contains_key(&id) {
+                    index.remove(&key);
+                    integrity.repaired += 1;
+                }
+            }
+            for id in orphaned {
+                if let Some((_, item)) = self.cache.remove(&id) {
+                    self.stats.bytes.fetch_sub(footprint(item.key_len as usize, item.size()), Ordering::Relaxed);
+                    integrity.repaired += 1;
+                }
+            }
+        }
+        integrity
+    }
+
+    /// Go through every key, adding its id to `indexed`. Returns how many
+    /// keys there were, and the keys and ids of those whose item is missing
+    /// from the map. See `verify`.
+    fn check_index(&self, indexed: &mut HashSet<u64>) -> (u64, Vec<(Bytes, u64)>) {
+        let mut keys = 0;
+        let mut dangling = Vec::new();
+        for shard in self.index.iter() {
+            let mut after: Option<Bytes> = None;
+            loop {
+                let index = match after {
+                    None => RwLockWriteGuard::downgrade(shard.write()),
+                    Some(_) => shard.read(),
+                };
+                let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+                let mut taken = 0;
+                after = None;
+                for (key, id) in index.range::<Bytes, _>((start, Bound::Unbounded)).take(SNAPSHOT_BATCH) {
+                    indexed.insert(*id);
+                    if !self.cache.contains_key(id) {
+                        dangling.push((key.clone(), *id));
+                    }
+                    taken += 1;
+                    after = Some(key.clone());
+                }
+                keys += taken;
+                if taken < SNAPSHOT_BATCH as u64 {
+                    break;
+                }
+            }
+        }
+        (keys, dangling)
+    }
+
+    /// Iterates over every item there was when called, as it was then,
+    /// whatever changes are made meanwhile. In no particular order.
+    ///
+    /// Keys are taken `SNAPSHOT_BATCH` at a time, under the read lock of
+    /// their index shard, so writers only ever wait for one batch. Items
+    /// changed or removed before the iteration comes to them are kept as they
+    /// were until it does, see `Journal`; that is all it costs writers.
+    pub fn snapshot_iter(&self) -> SnapshotIter<'_> {
+        // With every shard locked, no change is under way: those before
+        // have their CAS, those after see the journal. Other locks are
+        // taken one shard at a time, so taking them all in order is safe.
+        let shards: Vec<_> = self.index.iter().map(|shard| shard.write()).collect();
+        let journal = Arc::new(Journal {
+            cas: self.cas.load(Ordering::Relaxed),
+            now: self.now(),
+            flushed: self.flush.load(),
+            state: Mutex::new(JournalState::default()),
+        });
+        self.journals.write().push(journal.clone());
+        self.journaling.fetch_add(1, Ordering::SeqCst);
+        drop(shards);
+        SnapshotIter {
+            cache: self,
+            journal,
+            batch: Vec::new(),
+        }
+    }
+
+    /// Iterates over the metadata of every item that has not expired, in no
+    /// particular order. Values are neither copied nor read back from disk.
+    ///
+    /// Keys are taken `SNAPSHOT_BATCH` at a time, under the read lock of
+    /// their index shard, like `snapshot_iter`, but no journal is kept:
+    /// items are seen as they are when the iteration comes to them, those
+    /// stored meanwhile may or may not be, and those removed are not. This
+    /// does not count as reading the items.
+    pub fn metadata(&self) -> MetaIter<'_> {
+        MetaIter {
+            cache: self,
+            now: self.now(),
+            shard: 0,
+            done: None,
+            batch: Vec::new(),
+        }
+    }
+
+    /// Store `item` as is, CAS included, replacing any item with its key.
+    /// Later stores get higher CAS values than the item's.
+    pub fn restore(&self, item: Item) {
+        self.restore_as(item, || self.id.gen());
+    }
+
+    /// `restore` every item of `items`, taking the ids of those new in one
+    /// go rather than one at a time.
+    pub(crate) fn restore_batch(&self, items: Vec<Item>) {
+        let mut ids = self.id.gen_n(items.len() as u32);
+        for item in items {
+            self.restore_as(item, || ids.next().expect("an id for every item"));
+        }
+    }
+
+    /// `restore`, with the id `id` returns if the key is new.
+    fn restore_as(&self, item: Item, id: impl FnOnce() -> u64) {
+        self.cas.fetch_max(item.cas, Ordering::Relaxed);
+        let mut index = self.shard(&item.key).write();
+        let id = *index.entry(item.key.clone()).or_insert_with(id);
+        let (key, expiration) = (item.key.clone(), item.expiration);
+        let mut item = MemoryItem::from_item(item, self.now());
+        self.chunk(&key, &mut item);
+        self.policy.on_access(&item, item.cas, item.stored_at);
+        let len = item.size();
+        let old = self.cache.insert(id, item);
+        self.expires(&key, id, old.as_ref().and_then(|old| old.expiration), expiration);
+        match old {
+            Some(old) => self.resized(&key, old.size(), len),
+            None => self.charge(&key, footprint(key.len(), len)),
+        }
+    }
+
+    /// Evict items until `needed` more bytes, and `new` more items, fit under
+    /// the limits, or nothing but `keep` is left to evict. Returns whether
+    /// they fit.
+    ///
+    /// Eviction goes by sample: of `EVICTION_SAMPLE` keys from where the last
+    /// sample ended, going through the index shards in turn, the item the
+    /// policy ranks lowest goes. Only one shard is locked at a time, so
+    /// callers must not hold any. Reads only record themselves in the item,
+    /// so they never wait on eviction. Expired items go first and are not
+    /// counted as evictions. Under `EvictionPolicy::None` only those go, and
+    /// only as long as each sample turns one up.
+    ///
+    /// With an overflow tier, the value goes to disk instead and the rest of
+    /// the item stays, unless it is the item limit that is reached. Items
+    /// whose value is on disk already do not count in the sample, and are
+    /// only evicted when it has nothing else; up to `EVICTION_SAMPLE` times
+    /// as many are looked at.
+    ///
+    /// When `keep` counts against a quota, items of the same quota are
+    /// evicted first to fit `needed` more bytes under it, the same way but
+    /// sampling only them.
+    fn make_room(&self, keep: &[u8], needed: usize, new: usize) -> bool {
+        if let Some(quota) = self.quota(keep) {
+            let over = || quota.used() + needed > quota.limit;
+            if !self.evict_while(over, Some(quota), keep, new) {
+                return false;
+            }
+        }
+        self.evict_while(|| self.over_limits(needed, new), None, keep, new)
+    }
+
+    /// Evict items `within` a quota, or any, but `keep` while `over`, see
+    /// `make_room`. Returns whether it no longer is.
+    fn evict_while(&self, over: impl Fn() -> bool, within: Option<&Quota>, keep: &[u8], new: usize) -> bool {
+        if !over() {
+            return true;
+        }
+        let mut hand = within.map_or(&*self.hand, |quota| &quota.hand).lock();
+        let now = self.now();
+        // Reported once the hand is released
+        let mut removed = Vec::new();
+        // As of the last look, not again after, as reads moving values back
+        // from disk may take the room meanwhile
+        let mut fits = true;
+        while over() {
+            // (key, id, (on disk, rank), expired) of the item to evict
+            let mut victim: Option<(Bytes, u64, (bool, u64), bool)> = None;
+            let mut sampled = 0;
+            // Items with their value on disk looked at, not counted in the
+            // sample up to `EVICTION_SAMPLE` times over
+            let mut spilled = 0;
+            let mut shards = 0;
+            while sampled < EVICTION_SAMPLE && shards <= self.index.len() {
+                let (shard, after) = &mut *hand;
+                let index = self.index[*shard].read();
+                let start = match (after.as_deref(), within) {
+                    (Some(after), _) => Bound::Excluded(after),
+                    (None, Some(quota)) => Bound::Included(&quota.prefix[..]),
+                    (None, None) => Bound::Unbounded,
+                };
+                let mut sample = index
+                    .range::<[u8], _>((start, Bound::Unbounded))
+                    .take_while(|(key, _)| within.is_none_or(|quota| key.starts_with(&quota.prefix)))
+                    .filter(|(key, _)| {
+                        key[..] != *keep
+                            && within.is_none_or(|quota| self.quota(key).is_some_and(|of| std::ptr::eq(of, quota)))
+                    });
+
+                let mut last = None;
+                while sampled < EVICTION_SAMPLE {
+                    let Some((key, id)) = sample.next() else {
+                        last = None;
+                        break;
+                    };
+                    last = Some(key);
+                    let Some(item) = self.cache.get(id) else {
+                        sampled += 1;
+                        continue;
+                    };
+                    let dead = self.is_dead(&item, now);
+                    let on_disk = !dead && item.spilled.is_some();
+                    if on_disk && spilled < EVICTION_SAMPLE * EVICTION_SAMPLE {
+                        spilled += 1;
+                    } else {
+                        sampled += 1;
+                    }
+                    if !dead && self.policy == EvictionPolicy::None {
+                        continue;
+                    }
+                    let rank = if dead { (false, 0) } else { (on_disk, self.policy.rank(&item, now)) };
+                    if victim.as_ref().is_none_or(|(_, _, lowest, _)| rank < *lowest) {
+                        victim = Some((key.clone(), *id, rank, dead));
+                    }
+                }
+                match last {
+                    Some(last) => *after = Some(last.clone()),
+                    None => {
+                        // Nothing left in this shard, on to the next one
+                        *shard = (*shard + 1) % self.index.len();
+                        *after = None;
+                        shards += 1;
+                    }
+                }
+            }
+            let (key, id, on_disk, dead) = match victim {
+                Some((key, id, (on_disk, _), dead)) => (key, id, on_disk, dead),
+                None => {
+                    fits = false;
+                    break;
+                }
+            };
+            if on_disk && self.spilling.load(Ordering::Acquire) > 0 {
+                // Nothing else in memory but what another eviction is moving
+                // to disk, which frees memory once done; wait for it
+                MutexGuard::unlocked(&mut hand, std::thread::yield_now);
+                continue;
+            }
+
+            // Sampled under a read lock, so the key may hold another item by
+            // now; then it is sampled again
+            let mut index = self.shard(&key).write();
+            if index.get(&key) != Some(&id) {
+                continue;
+            }
+            // Or another eviction moved its value to disk since
+            if !on_disk && self.cache.get(&id).is_some_and(|item| item.spilled.is_some()) {
+                continue;
+            }
+            let full = self.limits.items().is_some_and(|max| self.len() + new > max);
+            if let Some((cas, data)) = (!dead && !full).then(|| self.spillable(id)).flatten() {
+                // Written to disk with neither the shard nor the hand locked.
+                // Moved, or changed meanwhile, it is sampled again; only if it
+                // cannot be written does it go, still the same
+                drop(index);
+                self.spilling.fetch_add(1, Ordering::AcqRel);
+                let written = MutexGuard::unlocked(&mut hand, || self.spill(&key, id, cas, &data));
+                self.spilling.fetch_sub(1, Ordering::AcqRel);
+                if written.is_some() || !over() {
+                    continue;
+                }
+                index = self.shard(&key).write();
+                let same = self.cache.get(&id).is_some_and(|item| item.cas == cas && item.spilled.is_none());
+                if index.get(&key) != Some(&id) || !same {
+                    continue;
+                }
+            }
+            index.remove(&key);
+            let Some((_, item)) = self.cache.remove(&id) else {
+                continue;
+            };
+            self.journal(&key, &item);
+            self.expires(&key, id, item.expiration, None);
+            drop(index);
+            self.discharge(&key, footprint(key.len(), item.size()));
+            if self.removals.is_some() {
+                let reason = if dead { self.dead_reason(&item, now) } else { RemovalReason::Evicted };
+                removed.push((key.clone(), reason));
+            }
+            if dead {
+                self.count_expired(&item);
+            } else {
+                self.count_evicted(&item, now);
+                if let Some(quota) = self.quota(&key) {
+                    quota.incr_evictions();
+                }
+                let idle = now.saturating_sub(item.last_access());
+                debug!(key = ?key, idle, fetches = item.fetches(), "evicting");
+            }
+        }
+        drop(hand);
+        for (key, reason) in removed {
+            self.removed(&key, reason);
+        }
+        fits
+    }
+
+    /// Store `data` under `key`, expiring at `expiration` (seconds since the
+    /// unix epoch).
+    ///
+    /// With a memory limit, items are evicted first to make room. Returns
+    /// `OutOfMemory`, leaving any previous item alone, if none could be made.
+    /// Values past the item size limit are refused with `TooLarge`, by every
+    /// store.
+    pub async fn set(&self, key: Bytes, flags: u32, expiration: Option<u64>, data: Bytes) -> Outcome {
+        self.logged(|| self.store(key, flags, expiration, data)).await
+    }
+
+    fn store(&self, key: Bytes, flags: u32, expiration: Option<u64>, data: Bytes) -> Outcome {
+        if data.len() > self.item_size_limit() {
+            return Outcome::TooLarge;
+        }
+        let shard = self.shard(&key);
+        if self.limited() {
+            let (needed, new) = match shard.read().get(&key) {
+                Some(id) => {
+                    let old = self.cache.get(id).map_or(0, |item| item.size());
+                    (data.len().saturating_sub(old), 0)
+                }
+                None => (footprint(key.len(), data.len()), 1),
+            };
+            if !self.make_room(&key, needed, new) {
+                return self.out_of_memory();
+            }
+        }
+        let mut index = shard.upgradable_read();
+        match index.get(&key) {
+            // Updates an existing `Item`. Its CAS is drawn under the map
+            // entry lock, so it only ever grows, even against concurrent
+            // appends or increments.
+            Some(&id) => {
+                let entry = self.cache.entry(id);
+                let now = self.now();
+                let (old, expired_at, reason) = match &entry {
+                    Entry::Occupied(entry) => {
+                        self.journal(&key, entry.get());
+                        let reason = if self.is_dead(entry.get(), now) {
+                            self.count_reclaimed(entry.get());
+                            self.dead_reason(entry.get(), now)
+                        } else {
+                            RemovalReason::Replaced
+                        };
+                        (entry.get().size(), entry.get().expiration, Some(reason))
+                    }
+                    Entry::Vacant(_) => (0, None, None),
+                };
+                let item = entry.insert(self.new_item(&key, flags, expiration, data));
+                self.expires(&key, id, expired_at, expiration);
+                self.log_store(&key, &item);
+                self.resized(&key, old, item.size());
+                drop(item);
+                drop(index);
+                if let Some(reason) = reason {
+                    self.removed(&key, reason);
+                }
+                Outcome::Stored
+            }
+            // Inserts a new `Item`
+            None => {
+                // Readers only find the item once it is indexed
+                let new_id = self.id.gen();
+                let item = self.new_item(&key, flags, expiration, data);
+                self.charge(&key, footprint(key.len(), item.size()));
+                self.cache.insert(new_id, item);
+                self.expires(&key, new_id, None, expiration);
+                index.with_upgraded(|index| index.insert(key.clone(), new_id));
+                self.log_new(&key, new_id);
+                Outcome::Stored
+            }
+        }
+    }
+
+    /// Store `data` under `key` unless it already holds an item that has not
+    /// expired. Returns `NotStored` if it does.
+    ///
+    /// Like `set`, this holds the upgradable lock of the key's index shard
+    /// throughout, so of several clients adding the same key only one wins.
+    pub async fn add(&self, key: Bytes, flags: u32, expiration: Option<u64>, data: Bytes) -> Outcome {
+        self.logged(|| self.store_new(key, flags, expiration, data)).await
+    }
+
+    fn store_new(&self, key: Bytes, flags: u32, expiration: Option<u64>, data: Bytes) -> Outcome {
+        if data.len() > self.item_size_limit() {
+            return Outcome::TooLarge;
+        }
+        let shard = self.shard(&key);
+        if self.limited() {
+            let new = match shard.read().get(&key) {
+                Some(id) if self.cache.get(id).is_some_and(|item| !self.is_dead(&item, self.now())) => {
+                    return Outcome::NotStored;
+                }
+                Some(_) => 0,
+                None => 1,
+            };
+            if !self.make_room(&key, footprint(key.len(), data.len()), new) {
+                return self.out_of_memory();
+            }
+        }
+        let mut index = shard.upgradable_read();
+        match index.get(&key) {
+            // Replaces an expired `Item`
+            Some(&id) => match self.cache.entry(id) {
+                Entry::Occupied(entry) if !self.is_dead(entry.get(), self.now()) => Outcome::NotStored,
+                entry => {
+                    let (old, expired_at, reason) = match &entry {
+                        Entry::Occupied(entry) => {
+                            self.journal(&key, entry.get());
+                            self.count_reclaimed(entry.get());
+                            let reason = self.dead_reason(entry.get(), self.now());
+                            (entry.get().size(), entry.get().expiration, Some(reason))
+                        }
+                        Entry::Vacant(_) => (0, None, None),
+                    };
+                    let item = entry.insert(self.new_item(&key, flags, expiration, data));
+                    self.expires(&key, id, expired_at, expiration);
+                    self.log_store(&key, &item);
+                    self.resized(&key, old, item.size());
+                    drop(item);
+                    drop(index);
+                    if let Some(reason) = reason {
+                        self.removed(&key, reason);
+                    }
+                    Outcome::Stored
+                }
+            },
+            // Inserts a new `Item`
+            None => {
+                // Readers only find the item once it is indexed
+                let new_id = self.id.gen();
+                let item = self.new_item(&key, flags, expiration, data);
+                self.charge(&key, footprint(key.len(), item.size()));
+                self.cache.insert(new_id, item);
+                self.expires(&key, new_id, None, expiration);
+                index.with_upgraded(|index| index.insert(key.clone(), new_id));
+                self.log_new(&key, new_id);
+                Outcome::Stored
+            }
+        }
+    }
+
+    /// Store `data` under `key` only if it already holds an item that has not
+    /// expired. Nothing of that item is kept. Returns `NotStored` if there
+    /// is none.
+    ///
+    /// The check and the store happen under the index read lock and the
+    /// item's map entry lock, so the item cannot be deleted in between. An
+    /// expired item is removed.
+    pub async fn replace(&self, key: &[u8], flags: u32, expiration: Option<u64>, data: Bytes) -> Outcome {
+        self.logged(|| self.store_existing(key, flags, expiration, data)).await
+    }
+
+    fn store_existing(&self, key: &[u8], flags: u32, expiration: Option<u64>, data: Bytes) -> Outcome {
+        if data.len() > self.item_size_limit() {
+            return Outcome::TooLarge;
+        }
+        let index = self.shard(key).read();
+        let id = match index.get(key) {
+            Some(id) => *id,
+            None => return Outcome::NotStored,
+        };
+        let Some(mut item) = self.cache.get_mut(&id) else {
+            return Outcome::NotStored;
+        };
+        if self.is_dead(&item, self.now()) {
+            drop(item);
+            drop(index);
+            self.remove_expired(key, id);
+            return Outcome::NotStored;
+        }
+        if !self.can_grow(key, data.len().saturating_sub(item.size())) {
+            return self.out_of_memory();
+        }
+        self.journal(key, &item);
+        self.expires(key, id, item.expiration, expiration);
+        let old = item.size();
+        *item = self.new_item(key, flags, expiration, data);
+        self.resized(key, old, item.size());
+        self.log_store(key, &item);
+        drop(item);
+        drop(index);
+        self.removed(key, RemovalReason::Replaced);
+        Outcome::Stored
+    }
+
+    /// Add `data` to the end of the item stored under `key`, keeping its
+    /// flags and expiration. Returns `NotStored` if there is no such item.
+    pub async fn append(&self, key: &[u8], data: Bytes) -> Outcome {
+        self.logged(|| self.concat(key, data, false)).await
+    }
+
+    /// Add `data` to the start of the item stored under `key`, like
+    /// `append`.
+    pub async fn prepend(&self, key: &[u8], data: Bytes) -> Outcome {
+        self.logged(|| self.concat(key, data, true)).await
+    }
+
+    /// The id, CAS and value of the item under `key` to change, unless
+    /// there is none. An expired item is removed. The value is read back if
+    /// it is on disk, once the locks of the lookup are released, or in
+    /// chunks.
+    fn current(&self, key: &[u8]) -> Option<(u64, u64, Bytes)> {
+        let index = self.shard(key).read();
+        let id = *index.get(key)?;
+        let item = self.cache.get(&id)?;
+        if self.is_dead(&item, self.now()) {
+            drop(item);
+            drop(index);
+            self.remove_expired(key, id);
+            return None;
+        }
+        drop(index);
+        let Some(on_disk) = item.on_disk() else {
+            return Some((id, item.cas, item.read_value(key)?));
+        };
+        drop(item);
+        let cas = on_disk.cas;
+        Some((id, cas, on_disk.read(key)?.data))
+    }
+
+    /// Write `data` as the value of the item `id` under `key`, read by
+    /// `current` with CAS `cas`, keeping its flags and expiration. Returns
+    /// false if the item changed or expired since, to read it again.
+    fn update(&self, key: &[u8], id: u64, cas: u64, data: Bytes) -> bool {
+        let index = self.shard(key).read();
+        if index.get(key) != Some(&id) {
+            return false;
+        }
+        let Some(mut item) = self.cache.get_mut(&id) else {
+            return false;
+        };
+        if item.cas != cas || self.is_dead(&item, self.now()) {
+            return false;
+        }
+        self.journal(key, &item);
+        let old = item.size();
+        item.data = Value::new(data);
+        item.spilled = None;
+        item.chunked = None;
+        self.chunk(key, &mut item);
+        self.resized(key, old, item.size());
+        item.cas = self.next_cas();
+        self.policy.on_access(&item, item.cas, self.now());
+        item.reset_access(self.now());
+        self.log_store(key, &item);
+        true
+    }
+
+    /// Join `data` and the item under `key` into one new buffer, `data`
+    /// first if `prepend`. The value is read and joined under the key's
+    /// lock, so concurrent joins each see the other's result, and only
+    /// written back under its map entry lock.
+    fn concat(&self, key: &[u8], data: Bytes, prepend: bool) -> Outcome {
+        let _locked = self.key_lock(key);
+        loop {
+            let Some((id, cas, value)) = self.current(key) else {
+                return Outcome::NotStored;
+            };
+            let len = value.len() + data.len();
+            if len > self.item_size_limit() {
+                return Outcome::TooLarge;
+            }
+            if !self.can_grow(key, data.len()) {
+                return self.out_of_memory();
+            }
+
+            let (first, second) = if prepend { (&data, &value) } else { (&value, &data) };
+            let mut joined = BytesMut::with_capacity(len);
+            joined.extend_from_slice(first);
+            joined.extend_from_slice(second);
+            if self.update(key, id, cas, joined.freeze()) {
+                self.stats.total_items.fetch_add(1, Ordering::Relaxed);
+                return Outcome::Stored;
+            }
+        }
+    }
+
+    /// Increment or decrement the decimal number stored under `key` by
+    /// `delta`, keeping the item's flags and expiration.
+    ///
+    /// The number is read and changed under the key's lock, and written
+    /// back under the item's map entry lock, so concurrent changes all count.
+    pub async fn add_delta(&self, key: &[u8], delta: u64, direction: Direction) -> Delta {
+        let changed = self.logged(|| self.change_number(key, delta, direction)).await;
+        let (hits, misses) = match direction {
+            Direction::Incr => (&self.stats.incr_hits, &self.stats.incr_misses),
+            Direction::Decr => (&self.stats.decr_hits, &self.stats.decr_misses),
+        };
+        match changed {
+            Delta::Value(_) => CacheStats::incr(hits),
+            Delta::NotFound => CacheStats::incr(misses),
+            Delta::NonNumeric => {}
+        }
+        changed
+    }
+
+    fn change_number(&self, key: &[u8], delta: u64, direction: Direction) -> Delta {
+        let _locked = self.key_lock(key);
+        loop {
+            let Some((id, cas, value)) = self.current(key) else {
+                return Delta::NotFound;
+            };
+            let value = match parse_number(&value) {
+                Some(value) => value,
+                None => return Delta::NonNumeric,
+            };
+
+            let value = match direction {
+                Direction::Incr => value.wrapping_add(delta),
+                Direction::Decr => value.saturating_sub(delta),
+            };
+            if self.update(key, id, cas, Bytes::from(value.to_string())) {
+                return Delta::Value(value);
+            }
+        }
+    }
+
+    /// Change when the item stored under `key` expires, leaving its value and
+    /// CAS alone. Returns whether there was such an item; an expired one is
+    /// removed instead.
+    ///
+    /// Expired items are only removed after checking again under the map
+    /// entry lock, so an item touched to a later expiration is never removed
+    /// by someone who saw the earlier one.
+    pub async fn touch(&self, key: &[u8], expiration: Option<u64>) -> bool {
+        let touched = self.logged(|| self.set_expiration(key, expiration)).await;
+        let counter = if touched { &self.stats.touch_hits } else { &self.stats.touch_misses };
+        CacheStats::incr(counter);
+        touched
+    }
+
+    /// `touch`, without waiting on the append log.
+    pub(crate) fn set_expiration(&self, key: &[u8], expiration: Option<u64>) -> bool {
+        let index = self.shard(key).read();
+        let id = match index.get(key) {
+            Some(id) => *id,
+            None => return false,
+        };
+        let Some(mut item) = self.cache.get_mut(&id) else {
+            return false;
+        };
+        if self.is_dead(&item, self.now()) {
+            drop(item);
+            drop(index);
+            self.remove_expired(key, id);
+            return false;
+        }
+        self.journal(key, &item);
+        self.expires(key, id, item.expiration, expiration);
+        item.expiration = expiration;
+        self.policy.on_access(&item, self.cas.load(Ordering::Relaxed), self.now());
+        item.record_access(self.now(), false);
+        self.log_change(Record::Touch(key, expiration));
+        true
+    }
+
+    /// Store `data` under `key` only if the item there still has CAS `cas`.
+    ///
+    /// Returns `Exists` if the item has changed since, `NotFound` if there is
+    /// none. The comparison and the store happen under the item's map entry
+    /// lock, so of several clients replacing the same version only one wins.
+    /// CAS values are never reused, so a key deleted and stored again does
+    /// not match a CAS read before either.
+    pub async fn check_and_set(
+        &self,
+        key: &[u8],
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+        cas: u64,
+    ) -> Outcome {
+        let outcome = self.logged(|| self.store_unchanged(key, flags, expiration, data, cas)).await;
+        let counter = match outcome {
+            Outcome::Stored => &self.stats.cas_hits,
+            Outcome::NotFound => &self.stats.cas_misses,
+            Outcome::Exists => &self.stats.cas_badval,
+            _ => return outcome,
+        };
+        CacheStats::incr(counter);
+        outcome
+    }
+
+    fn store_unchanged(
+        &self,
+        key: &[u8],
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+        cas: u64,
+    ) -> Outcome {
+        if data.len() > self.item_size_limit() {
+            return Outcome::TooLarge;
+        }
+        let _locked = self.key_lock(key);
+        let index = self.shard(key).read();
+        let id = match index.get(key) {
+            Some(id) => *id,
+            None => return Outcome::NotFound,
+        };
+        let Some(mut item) = self.cache.get_mut(&id) else {
+            return Outcome::NotFound;
+        };
+        if self.is_dead(&item, self.now()) {
+            return Outcome::NotFound;
+        }
+        if item.cas != cas {
+            return Outcome::Exists;
+        }
+        if !self.can_grow(key, data.len().saturating_sub(item.size())) {
+            return self.out_of_memory();
+        }
+        self.journal(key, &item);
+        self.expires(key, id, item.expiration, expiration);
+        let old = item.size();
+        *item = self.new_item(key, flags, expiration, data);
+        self.resized(key, old, item.size());
+        self.log_store(key, &item);
+        drop(item);
+        drop(index);
+        self.removed(key, RemovalReason::Replaced);
+        Outcome::Stored
+    }
+}
+
+/// Parse `data` as a decimal `u64`, digits only.
+fn parse_number(data: &[u8]) -> Option<u64> {
+    if data.is_empty() || !data.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    impl Cache {
+        /// Keys in the index, across all shards.
+        fn indexed(&self) -> usize {
+            self.index.iter().map(|shard| shard.read().len()).sum()
+        }
+
+        /// Items whose value is on disk.
+        fn spilled(&self) -> usize {
+            self.cache.iter().filter(|item| item.spilled.is_some()).count()
+        }
+
+        /// Move the value of the item under `key` to disk, see `spill`.
+        fn spill_key(&self, key: &[u8]) -> bool {
+            let id = self.shard(key).read()[key];
+            let Some((cas, data)) = self.spillable(id) else {
+                return false;
+            };
+            self.spill(key, id, cas, &data) == Some(true)
+        }
+    }
+
+    /// An overflow tier of `limit` bytes in a fresh directory.
+    fn overflow(name: &str, limit: u64) -> (std::path::PathBuf, Option<Arc<Overflow>>) {
+        let dir = std::env::temp_dir().join(format!("sidica-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let overflow = Overflow::open(&dir, limit).unwrap();
+        (dir, Some(Arc::new(overflow)))
+    }
+
+    /// The 1000 byte value number `n` of `key`.
+    fn value_of(key: &[u8], n: usize) -> Bytes {
+        let mut value = format!("{}-{}-", String::from_utf8_lossy(key), n).into_bytes();
+        value.resize(1000, b'.');
+        Bytes::from(value)
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let cache = Cache::builder()
+            .shards(1)
+            .initial_capacity(10)
+            .max_item_size(10)
+            .max_items(Some(100))
+            .build();
+        assert_eq!(cache.index.len(), 1);
+        let fits = || Bytes::from(vec![b'x'; 10]);
+        let too_large = || Bytes::from(vec![b'x'; 11]);
+        assert_eq!(cache.set(Bytes::from("key"), 0, None, too_large()).await, Outcome::TooLarge);
+        assert_eq!(cache.add(Bytes::from("key"), 0, None, too_large()).await, Outcome::TooLarge);
+        assert_eq!(cache.set(Bytes::from("key"), 0, None, fits()).await, Outcome::Stored);
+        assert_eq!(cache.replace(b"key", 0, None, too_large()).await, Outcome::TooLarge);
+        let cas = cache.get(b"key").await.unwrap().cas;
+        assert_eq!(cache.check_and_set(b"key", 0, None, too_large(), cas).await, Outcome::TooLarge);
+        assert_eq!(cache.append(b"key", Bytes::from("x")).await, Outcome::TooLarge);
+        assert_eq!(cache.get(b"key").await.unwrap().data, fits());
+
+        // Every key in the one shard, evicted from there
+        for n in 0..200 {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, fits()).await;
+        }
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.scan(b"key", None, 1000).0.len(), 100);
+        assert_eq!(cache.snapshot_iter().count(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_large_items_are_kept_in_chunks() {
+        let clock = Clock::default();
+        let cache = Cache::builder()
+            .clock(clock.clone())
+            .max_item_size(10)
+            .large_item_limit(Some(100))
+            .build();
+        let chunked = || cache.chunks.as_ref().unwrap().len();
+        let large: Bytes = (0..35).map(|n| n as u8).collect::<Vec<u8>>().into();
+        let key = Bytes::from("key");
+        assert_eq!(cache.set(key.clone(), 0, None, large.clone()).await, Outcome::Stored);
+        assert_eq!(cache.get(&key).await.unwrap().data, large);
+        assert_eq!(chunked(), (1, 35));
+        assert_eq!(cache.bytes(), footprint(3, 35 + 4 * crate::chunks::CHUNK_OVERHEAD));
+        assert_eq!(cache.set(key.clone(), 0, None, Bytes::from(vec![b'x'; 101])).await, Outcome::TooLarge);
+
+        // Put back together to change, and chunked again
+        assert_eq!(cache.append(&key, Bytes::from("end")).await, Outcome::Stored);
+        let appended = cache.get(&key).await.unwrap().data;
+        assert_eq!((&appended[..35], &appended[35..]), (&large[..], &b"end"[..]));
+        assert_eq!(chunked(), (1, 38));
+        assert_eq!(cache.append(&key, Bytes::from(vec![b'x'; 63])).await, Outcome::TooLarge);
+
+        // Freed by an overwrite, a delete and on expiry
+        cache.set(key.clone(), 0, None, Bytes::from("small")).await;
+        assert_eq!(chunked(), (0, 0));
+        assert_eq!(cache.bytes(), footprint(3, 5));
+        cache.set(key.clone(), 0, None, large.clone()).await;
+        assert!(cache.delete(&key).await);
+        assert_eq!(chunked(), (0, 0));
+        cache.set(key.clone(), 0, Some(cache.now() + 1), large.clone()).await;
+        clock.advance(1);
+        assert!(cache.get(&key).await.is_none());
+        assert_eq!(chunked(), (0, 0));
+        assert_eq!(cache.bytes(), 0);
+        assert_eq!(cache.remove_orphan_chunks(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_expires_on_get() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        cache
+            .set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("bar"))
+            .await;
+        cache.set(Bytes::from("kept"), 0, None, Bytes::from("baz")).await;
+        assert!(cache.get(&key).await.is_some());
+        assert_eq!(cache.items().count(), 2);
+
+        clock.advance(1);
+        assert_eq!(cache.items().count(), 1);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key).await.is_none());
+        // Removed from both maps once seen
+        assert_eq!(cache.len(), 1);
+        assert!(!cache.shard(&key).read().contains_key(&key));
+        assert!(cache.get(b"kept").await.is_some());
+
+        // The key can be stored again
+        assert_eq!(cache.set(key.clone(), 0, None, Bytes::from("new")).await, Outcome::Stored);
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"new");
+    }
+
+    #[tokio::test]
+    async fn test_get_multi_keeps_the_order_of_the_keys() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        for n in 0..100 {
+            cache.set(Bytes::from(format!("key{}", n)), n, None, value_of(b"key", n as usize)).await;
+        }
+        cache.set(Bytes::from("gone"), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        clock.advance(1);
+
+        let mut keys: Vec<String> = (0..100).rev().map(|n| format!("key{}", n)).collect();
+        keys.extend(["nope".to_string(), "gone".to_string(), "key7".to_string()]);
+        let items = cache.get_multi(&keys).await;
+        assert_eq!(items.len(), keys.len());
+        for (n, item) in items[..100].iter().enumerate() {
+            let item = item.as_ref().unwrap();
+            assert_eq!((item.flags, &item.data), (99 - n as u32, &value_of(b"key", 99 - n)));
+        }
+        assert!(items[100].is_none() && items[101].is_none());
+        assert_eq!(items[102].as_ref().unwrap().flags, 7);
+        let stats: HashMap<_, _> = cache.stats().into_iter().collect();
+        assert_eq!((stats["get_hits"], stats["get_misses"]), (101, 2));
+        assert_eq!(cache.len(), 100);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_get_or_insert_with_computes_once() {
+        let cache = Cache::new();
+        let computed = Arc::new(AtomicUsize::new(0));
+        let callers: Vec<_> = (0..100)
+            .map(|_| {
+                let (cache, computed) = (cache.clone(), computed.clone());
+                tokio::spawn(async move {
+                    cache
+                        .get_or_insert_with(Bytes::from("key"), None, || async move {
+                            computed.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                            Ok(Bytes::from("computed"))
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for caller in callers {
+            assert_eq!(&caller.await.unwrap().unwrap().data[..], b"computed");
+        }
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+        assert_eq!(&cache.get(b"key").await.unwrap().data[..], b"computed");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_get_or_insert_with_shares_errors() {
+        let cache = Cache::new();
+        let callers: Vec<_> = (0..10)
+            .map(|_| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    cache
+                        .get_or_insert_with(Bytes::from("key"), None, || async {
+                            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                            anyhow::bail!("backend down")
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for caller in callers {
+            assert_eq!(caller.await.unwrap().unwrap_err().to_string(), "backend down");
+        }
+        assert!(cache.get(b"key").await.is_none());
+        assert!(cache.computing.lock().is_empty());
+
+        let item = cache
+            .get_or_insert_with(Bytes::from("key"), None, || async { Ok(Bytes::from("x")) })
+            .await
+            .unwrap();
+        assert_eq!(&item.data[..], b"x");
+    }
+
+    #[tokio::test]
+    async fn test_get_or_insert_with_takes_over_dropped_computations() {
+        let cache = Cache::new();
+        let started = Arc::new(tokio::sync::Notify::new());
+        let leader = {
+            let (cache, started) = (cache.clone(), started.clone());
+            tokio::spawn(async move {
+                cache
+                    .get_or_insert_with(Bytes::from("key"), None, || async move {
+                        started.notify_one();
+                        std::future::pending().await
+                    })
+                    .await
+            })
+        };
+        started.notified().await;
+        let waiter = {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_insert_with(Bytes::from("key"), None, || async { Ok(Bytes::from("taken over")) })
+                    .await
+            })
+        };
+        tokio::task::yield_now().await;
+        leader.abort();
+        assert_eq!(&waiter.await.unwrap().unwrap().data[..], b"taken over");
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        assert!(!cache.delete(&key).await);
+
+        cache.set(key.clone(), 0, None, Bytes::from("bar")).await;
+        assert!(cache.delete(&key).await);
+        assert!(cache.get(&key).await.is_none());
+        assert!(!cache.delete(&key).await);
+
+        // Expired items are removed but not reported
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("bar")).await;
+        clock.advance(1);
+        assert!(!cache.delete(&key).await);
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_delete_races_set_and_get() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let cache = cache.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    for _ in 0..2000 {
+                        match task % 4 {
+                            0 => {
+                                cache.set(key.clone(), 0, None, Bytes::from("bar")).await;
+                            }
+                            1 => {
+                                cache.delete(&key).await;
+                            }
+                            2 => {
+                                cache.replace(&key, 0, None, Bytes::from("bar")).await;
+                                cache.touch(&key, None).await;
+                            }
+                            _ => {
+                                if let Some(item) = cache.get(&key).await {
+                                    assert_eq!(&item.data[..], b"bar");
+                                }
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // The index and the map agree
+        let indexed = cache.indexed();
+        assert_eq!(indexed, cache.len());
+        assert!(indexed <= 1);
+    }
+
+    #[tokio::test]
+    async fn test_indexed_id_without_item_is_a_miss() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, None, Bytes::from("1")).await;
+        let id = *cache.shard(&key).read().get(&key).unwrap();
+        cache.cache.remove(&id);
+
+        assert!(cache.get(&key).await.is_none());
+        assert_eq!(cache.items().count(), 0);
+        assert_eq!(cache.replace(&key, 0, None, Bytes::from("2")).await, Outcome::NotStored);
+        assert_eq!(cache.append(&key, Bytes::from("2")).await, Outcome::NotStored);
+        assert_eq!(cache.add_delta(&key, 1, Direction::Incr).await, Delta::NotFound);
+        assert!(!cache.touch(&key, None).await);
+        let outcome = cache.check_and_set(&key, 0, None, Bytes::from("2"), 1).await;
+        assert_eq!(outcome, Outcome::NotFound);
+
+        // Storing under the key again mends it
+        assert_eq!(cache.set(key.clone(), 0, None, Bytes::from("3")).await, Outcome::Stored);
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"3");
+    }
+
+    #[tokio::test]
+    async fn test_check_and_set() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        let cas = |data| cache.check_and_set(&key, 0, None, Bytes::from(data), 1);
+        assert_eq!(cas("bar").await, Outcome::NotFound);
+
+        cache.set(key.clone(), 0, None, Bytes::from("bar")).await;
+        let seen = cache.get(&key).await.unwrap().cas;
+        assert_eq!(
+            cache.check_and_set(&key, 3, None, Bytes::from("baz"), seen).await,
+            Outcome::Stored
+        );
+        let item = cache.get(&key).await.unwrap();
+        assert_eq!((item.flags, &item.data[..]), (3, &b"baz"[..]));
+        assert!(item.cas > seen);
+
+        // The CAS read before is stale now
+        assert_eq!(
+            cache.check_and_set(&key, 0, None, Bytes::from("qux"), seen).await,
+            Outcome::Exists
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_and_set_after_recreate() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, None, Bytes::from("bar")).await;
+        let seen = cache.get(&key).await.unwrap().cas;
+        let cas = || cache.check_and_set(&key, 0, None, Bytes::from("qux"), seen);
+
+        // Deleted, the item is gone
+        cache.delete(&key).await;
+        assert_eq!(cas().await, Outcome::NotFound);
+
+        // Stored again, it is another item, even with the same value
+        cache.set(key.clone(), 0, None, Bytes::from("bar")).await;
+        assert!(cache.get(&key).await.unwrap().cas > seen);
+        assert_eq!(cas().await, Outcome::Exists);
+
+        // Likewise when added in place of an expired item
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("bar")).await;
+        let seen = cache.get(&key).await.unwrap().cas;
+        clock.advance(1);
+        let cas = || cache.check_and_set(&key, 0, None, Bytes::from("qux"), seen);
+        assert_eq!(cas().await, Outcome::NotFound);
+        cache.add(key.clone(), 0, None, Bytes::from("bar")).await;
+        assert_eq!(cas().await, Outcome::Exists);
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"bar");
+    }
+
+    #[tokio::test]
+    async fn test_add() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        let expiration = Some(cache.now() + 1);
+        assert_eq!(
+            cache.add(key.clone(), 0, expiration, Bytes::from("bar")).await,
+            Outcome::Stored
+        );
+        assert_eq!(
+            cache.add(key.clone(), 0, None, Bytes::from("baz")).await,
+            Outcome::NotStored
+        );
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"bar");
+
+        // An expired item is replaced in place
+        clock.advance(1);
+        assert_eq!(
+            cache.add(key.clone(), 0, None, Bytes::from("qux")).await,
+            Outcome::Stored
+        );
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"qux");
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.indexed(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_add_race_has_one_winner() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        let tasks: Vec<_> = (0..32)
+            .map(|task| {
+                let cache = cache.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    let data = Bytes::from(task.to_string());
+                    (task, cache.add(key, 0, None, data).await)
+                })
+            })
+            .collect();
+        let mut winners = Vec::new();
+        for task in tasks {
+            if let (task, Outcome::Stored) = task.await.unwrap() {
+                winners.push(task);
+            }
+        }
+
+        assert_eq!(winners.len(), 1);
+        let item = cache.get(&key).await.unwrap();
+        assert_eq!(item.data, Bytes::from(winners[0].to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        assert_eq!(
+            cache.replace(&key, 0, None, Bytes::from("bar")).await,
+            Outcome::NotStored
+        );
+        assert!(cache.is_empty());
+
+        cache.set(key.clone(), 1, None, Bytes::from("bar")).await;
+        let before = cache.get(&key).await.unwrap();
+        let expiration = Some(cache.now() + 1);
+        assert_eq!(
+            cache.replace(&key, 2, expiration, Bytes::from("baz")).await,
+            Outcome::Stored
+        );
+        let item = cache.get(&key).await.unwrap();
+        assert_eq!((item.flags, item.expiration, &item.data[..]), (2, expiration, &b"baz"[..]));
+        assert!(item.cas > before.cas);
+
+        // An expired item counts as missing and is removed
+        clock.advance(1);
+        assert_eq!(
+            cache.replace(&key, 0, None, Bytes::from("qux")).await,
+            Outcome::NotStored
+        );
+        assert!(cache.is_empty());
+        assert!(cache.indexed() == 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_replace_races_delete() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let cache = cache.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    for _ in 0..2000 {
+                        match task % 3 {
+                            0 => {
+                                cache.add(key.clone(), 0, None, Bytes::from("bar")).await;
+                            }
+                            1 => {
+                                cache.delete(&key).await;
+                            }
+                            _ => {
+                                cache.replace(&key, 0, None, Bytes::from("baz")).await;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // A replace never brings back a deleted key
+        let indexed = cache.indexed();
+        assert_eq!(indexed, cache.len());
+        assert!(indexed <= 1);
+    }
+
+    #[tokio::test]
+    async fn test_append_and_prepend() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        assert_eq!(cache.append(&key, Bytes::from("bar")).await, Outcome::NotStored);
+        assert_eq!(cache.prepend(&key, Bytes::from("bar")).await, Outcome::NotStored);
+        assert!(cache.is_empty());
+
+        let expiration = Some(cache.now() + 1);
+        cache.set(key.clone(), 7, expiration, Bytes::from("b")).await;
+        let before = cache.get(&key).await.unwrap();
+        assert_eq!(cache.append(&key, Bytes::from("c")).await, Outcome::Stored);
+        assert_eq!(cache.prepend(&key, Bytes::from("a")).await, Outcome::Stored);
+        let item = cache.get(&key).await.unwrap();
+        assert_eq!(&item.data[..], b"abc");
+        assert_eq!((item.flags, item.expiration), (7, expiration));
+        assert!(item.cas > before.cas);
+
+        let too_much = Bytes::from(vec![b'x'; MAX_ITEM_SIZE - 2]);
+        assert_eq!(cache.append(&key, too_much).await, Outcome::TooLarge);
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"abc");
+
+        clock.advance(1);
+        assert_eq!(cache.append(&key, Bytes::from("d")).await, Outcome::NotStored);
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_short_values_are_kept_in_the_item() {
+        let cache = Cache::new();
+        let key = Bytes::from("counter");
+        let inline = |cache: &Cache| {
+            let id = *cache.shard(&key).read().get(&key[..]).unwrap();
+            matches!(cache.cache.get(&id).unwrap().data, Value::Inline { .. })
+        };
+
+        cache.set(key.clone(), 0, None, Bytes::from("12345678")).await;
+        assert!(inline(&cache));
+        assert_eq!(cache.bytes(), key.len() + ITEM_OVERHEAD);
+
+        // Grows past what fits, and back
+        let tail = Bytes::from(vec![b'9'; INLINE_MAX - 8]);
+        assert_eq!(cache.append(&key, tail.clone()).await, Outcome::Stored);
+        assert!(inline(&cache));
+        assert_eq!(cache.append(&key, Bytes::from("0")).await, Outcome::Stored);
+        assert!(!inline(&cache));
+        let data = cache.get(&key).await.unwrap().data;
+        assert_eq!(data, [&b"12345678"[..], &tail, b"0"].concat());
+        assert_eq!(cache.bytes(), footprint(key.len(), INLINE_MAX + 1));
+        cache.set(key.clone(), 0, None, Bytes::from("1")).await;
+        assert_eq!(cache.add_delta(&key, 41, Direction::Incr).await, Delta::Value(42));
+        assert!(inline(&cache));
+        assert_eq!(cache.bytes(), key.len() + ITEM_OVERHEAD);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_appends_keep_every_chunk() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, None, Bytes::from("START")).await;
+
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let cache = cache.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    let chunk = Bytes::from(vec![b'a' + task as u8; task + 1]);
+                    for round in 0..500 {
+                        let outcome = if round % 2 == 0 {
+                            cache.append(&key, chunk.clone()).await
+                        } else {
+                            cache.prepend(&key, chunk.clone()).await
+                        };
+                        assert_eq!(outcome, Outcome::Stored);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let data = cache.get(&key).await.unwrap().data;
+        let appended: usize = (1..=8).map(|len| len * 500).sum();
+        assert_eq!(data.len(), "START".len() + appended);
+        for task in 0..8 {
+            let byte = b'a' + task as u8;
+            let count = data.iter().filter(|b| **b == byte).count();
+            assert_eq!(count, (task + 1) * 500);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_delta() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        assert_eq!(cache.add_delta(&key, 1, Direction::Incr).await, Delta::NotFound);
+
+        cache.set(key.clone(), 5, None, Bytes::from("9")).await;
+        let before = cache.get(&key).await.unwrap();
+        assert_eq!(cache.add_delta(&key, 1, Direction::Incr).await, Delta::Value(10));
+        let item = cache.get(&key).await.unwrap();
+        assert_eq!((item.flags, &item.data[..]), (5, &b"10"[..]));
+        assert!(item.cas > before.cas);
+
+        // Decrements stop at 0, increments wrap
+        assert_eq!(cache.add_delta(&key, 3, Direction::Decr).await, Delta::Value(7));
+        assert_eq!(cache.add_delta(&key, 8, Direction::Decr).await, Delta::Value(0));
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"0");
+        cache.set(key.clone(), 0, None, Bytes::from(u64::MAX.to_string())).await;
+        assert_eq!(cache.add_delta(&key, 2, Direction::Incr).await, Delta::Value(1));
+
+        for data in ["", "bar", "-1", "+1", " 1", "18446744073709551616"] {
+            cache.set(key.clone(), 0, None, Bytes::from(data)).await;
+            assert_eq!(
+                cache.add_delta(&key, 1, Direction::Incr).await,
+                Delta::NonNumeric,
+                "{:?}",
+                data
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_increments_all_count() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, None, Bytes::from("0")).await;
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    for _ in 0..1000 {
+                        cache.add_delta(&key, 1, Direction::Incr).await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"8000");
+    }
+
+    /// Prepended zeros leave the number as it is, so an increment lost to a
+    /// prepend writing back what it read before shows in the count.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_prepends_and_increments_all_count() {
+        let cache = Cache::new();
+        let keys: Vec<Bytes> = (0..4).map(|key| Bytes::from(format!("key{}", key))).collect();
+        for key in &keys {
+            cache.set(key.clone(), 0, None, Bytes::from("0")).await;
+        }
+
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let cache = cache.clone();
+                let keys = keys.clone();
+                tokio::spawn(async move {
+                    for n in 0..500 {
+                        let key = &keys[n % keys.len()];
+                        if task % 2 == 0 {
+                            assert!(matches!(cache.add_delta(key, 1, Direction::Incr).await, Delta::Value(_)));
+                        } else {
+                            assert_eq!(cache.prepend(key, Bytes::from("0")).await, Outcome::Stored);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        for key in &keys {
+            let data = cache.get(key).await.unwrap().data;
+            // Four tasks incrementing each key 125 times
+            assert_eq!(parse_number(&data), Some(500), "{:?}", key);
+        }
+        assert_eq!(cache.indexed(), cache.len());
+    }
+
+    #[tokio::test]
+    async fn test_touch() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        assert!(!cache.touch(&key, None).await);
+
+        cache.set(key.clone(), 3, Some(cache.now() + 1), Bytes::from("bar")).await;
+        let before = cache.get(&key).await.unwrap();
+        assert!(cache.touch(&key, Some(cache.now() + 10)).await);
+        clock.advance(1);
+        let item = cache.get(&key).await.unwrap();
+        assert_eq!((item.flags, item.cas, &item.data[..]), (3, before.cas, &b"bar"[..]));
+        assert_eq!(item.expiration, Some(before.expiration.unwrap() + 9));
+
+        // Touching to never
+        assert!(cache.touch(&key, None).await);
+        clock.advance(100);
+        assert!(cache.get(&key).await.is_some());
+
+        // Too late once expired
+        assert!(cache.touch(&key, Some(cache.now())).await);
+        assert!(!cache.touch(&key, None).await);
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_access_times_and_fetches() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        let access = |cache: &Cache| {
+            let id = *cache.shard(&key).read().get(&key).unwrap();
+            let item = cache.cache.get(&id).unwrap();
+            (item.last_access() - item.stored_at, item.fetches())
+        };
+        cache.set(key.clone(), 0, None, Bytes::from("1")).await;
+        assert_eq!(access(&cache), (0, 0));
+
+        clock.advance(5);
+        cache.get(&key).await;
+        assert_eq!(access(&cache), (5, 1));
+        // Counts stop at MAX_FETCHES
+        for _ in 0..5 {
+            cache.get(&key).await;
+        }
+        assert_eq!(access(&cache), (5, MAX_FETCHES));
+
+        // Touching is an access but no fetch, dumping neither
+        clock.advance(5);
+        cache.touch(&key, None).await;
+        assert_eq!(access(&cache), (10, MAX_FETCHES));
+        clock.advance(5);
+        assert_eq!(cache.items().count(), 1);
+        assert_eq!(access(&cache), (10, MAX_FETCHES));
+
+        // A new value starts over, in place or not
+        cache.add_delta(&key, 1, Direction::Incr).await;
+        assert_eq!(access(&cache), (0, 0));
+        cache.get(&key).await;
+        cache.set(key.clone(), 0, None, Bytes::from("1")).await;
+        assert_eq!(access(&cache), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_touched_item_survives_stale_removal() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("bar")).await;
+        let id = *cache.shard(&key).read().get(&key).unwrap();
+
+        // Seen expired, then given a later expiration before the removal
+        clock.advance(1);
+        cache.cache.get_mut(&id).unwrap().expiration = Some(cache.now() + 60);
+        cache.remove_expired(&key, id);
+        assert!(cache.get(&key).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_goes_through_what_expired_only() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).expiry_sweep(ExpirySweep::Index).build();
+        let deadlines = cache.deadlines.clone().unwrap();
+        for n in 0..1000 {
+            cache.set(Bytes::from(format!("kept{}", n)), 0, None, Bytes::from("x")).await;
+        }
+        let key = |n: usize| Bytes::from(format!("key{}", n));
+        for n in 0..10 {
+            cache.set(key(n), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        }
+        assert!(cache.touch(&key(0), Some(cache.now() + 60)).await);
+        cache.set(key(1), 0, None, Bytes::from("y")).await;
+        assert!(cache.delete(&key(2)).await);
+        assert_eq!(deadlines.len(), 8);
+        assert_eq!(cache.sweep_expired(), 0);
+
+        clock.advance(1);
+        assert_eq!(cache.sweep_expired(), 7);
+        assert_eq!(cache.len(), 1002);
+        assert!(cache.shard(&key(3)).read().get(&key(3)).is_none());
+        assert_eq!(deadlines.len(), 1);
+        assert!(cache.get(&key(0)).await.is_some());
+        assert!(cache.get(&key(1)).await.is_some());
+
+        clock.advance(60);
+        assert_eq!(cache.sweep_expired(), 1);
+        assert_eq!(deadlines.len(), 0);
+        assert_eq!(cache.stats.expired.load(Ordering::Relaxed), 8);
+    }
+
+    #[tokio::test]
+    async fn test_expired_counted_fetched_or_not() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).expiry_sweep(ExpirySweep::Index).build();
+        let key = |n: usize| Bytes::from(format!("key{}", n));
+        for n in 0..4 {
+            cache.set(key(n), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        }
+        assert!(cache.get(&key(0)).await.is_some());
+        assert!(cache.get(&key(1)).await.is_some());
+        clock.advance(1);
+
+        // Come across by a read, by a store and by the sweep
+        assert!(cache.get(&key(0)).await.is_none());
+        assert!(cache.get(&key(2)).await.is_none());
+        cache.set(key(3), 0, None, Bytes::from("y")).await;
+        assert_eq!(cache.sweep_expired(), 1);
+
+        let stat = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        assert_eq!(stat(&cache.stats.expired), 3);
+        assert_eq!(stat(&cache.stats.reclaimed), 1);
+        assert_eq!(stat(&cache.stats.expired_unfetched), 2);
+        assert_eq!(stat(&cache.stats.evictions), 0);
+    }
+
+    #[tokio::test]
+    async fn test_scan_sweep_removes_every_dead_item() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).expiry_sweep(ExpirySweep::Scan).build();
+        for n in 0..3000 {
+            let expiration = (n % 3 == 0).then(|| cache.now() + 1);
+            cache.set(Bytes::from(format!("key{}", n)), 0, expiration, Bytes::from("x")).await;
+        }
+        assert_eq!(cache.sweep_expired(), 0);
+        clock.advance(1);
+        assert_eq!(cache.sweep_expired(), 1000);
+        assert_eq!(cache.len(), 2000);
+
+        // Flushed items are dead too
+        cache.flush(None).await;
+        assert_eq!(cache.sweep_expired(), 2000);
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sampled_sweep_goes_on_while_samples_are_mostly_expired() {
+        let clock = Clock::default();
+        let cache = Cache::builder()
+            .clock(clock.clone())
+            .shards(1)
+            .expiry_sweep(ExpirySweep::Sampled)
+            .build();
+        let far = Instant::now() + Duration::from_secs(60);
+        for n in 0..200 {
+            let expiration = (n % 2 == 0).then(|| cache.now() + 1);
+            cache.set(Bytes::from(format!("key{:03}", n)), 0, expiration, Bytes::from("x")).await;
+        }
+        clock.advance(1);
+        // Half of every sample has expired, so sampling goes on through them
+        assert_eq!(cache.sweep_sampled(far), 100);
+        assert_eq!(cache.len(), 100);
+
+        // One sample a sweep while few have, each after the last
+        cache.set(Bytes::from("zzz"), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        clock.advance(1);
+        for _ in 0..(100 / EXPIRY_SAMPLE) {
+            assert_eq!(cache.sweep_sampled(far), 0);
+        }
+        assert_eq!(cache.sweep_sampled(far), 1);
+        assert_eq!(cache.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_jitter_spreads_deadlines() {
+        let cache = Cache::builder().ttl_jitter(Some(Jitter::Percent(10))).build();
+        let mut ttls = std::collections::HashSet::new();
+        for n in 0..1000 {
+            let key = Bytes::from(format!("key{}", n));
+            let before = cache.now();
+            let deadline = cache.deadline(&key, 100).unwrap();
+            assert!(before + 90 <= deadline && deadline <= cache.now() + 100);
+            cache.set(key, 0, Some(deadline), Bytes::from("x")).await;
+            ttls.insert(deadline - before);
+        }
+        assert!(ttls.len() > 5, "{:?}", ttls);
+        // Never, and right away, stay as they are
+        assert_eq!(cache.deadline(b"key", 0), None);
+        assert_eq!(cache.deadline(b"key", -1), Some(cache.now()));
+    }
+
+    #[tokio::test]
+    async fn test_get_if_modified() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = Bytes::from("foo");
+        let changed = |found: Option<Revalidated>| match found {
+            Some(Revalidated::Changed(item)) => Some(item.data),
+            _ => None,
+        };
+        assert!(cache.get_if_modified(&key, 0).await.is_none());
+
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("old")).await;
+        let cas = cache.get(&key).await.unwrap().cas;
+        assert!(matches!(cache.get_if_modified(&key, cas).await, Some(Revalidated::Unchanged)));
+        assert_eq!(changed(cache.get_if_modified(&key, cas - 1).await), Some(Bytes::from("old")));
+
+        // Changed once stored again, even with the same value
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("old")).await;
+        assert_eq!(changed(cache.get_if_modified(&key, cas).await), Some(Bytes::from("old")));
+
+        // Missing once expired, whatever the CAS
+        let cas = cache.get(&key).await.unwrap().cas;
+        clock.advance(1);
+        assert!(cache.get_if_modified(&key, cas).await.is_none());
+        assert_eq!(cache.stats.get_hits.load(Ordering::Relaxed), 5);
+        assert_eq!(cache.stats.get_misses.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_items_have_one_winner_per_expiry() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).stale_grace(Some(10)).build();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("old")).await;
+        let freshness = |found: Option<(ItemView, Freshness)>| found.map(|(item, freshness)| (item.data, freshness));
+        assert_eq!(freshness(cache.get_stale(&key).await), Some((Bytes::from("old"), Freshness::Fresh)));
+
+        // Missed by `get`, but kept for `get_stale`
+        clock.advance(1);
+        assert!(cache.get(&key).await.is_none());
+        assert!(!cache.touch(&key, None).await);
+        assert_eq!(freshness(cache.get_stale(&key).await), Some((Bytes::from("old"), Freshness::Won)));
+        for _ in 0..3 {
+            assert_eq!(freshness(cache.get_stale(&key).await), Some((Bytes::from("old"), Freshness::Stale)));
+        }
+
+        // A new value is fresh, and wins anew once it expires
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("new")).await;
+        assert_eq!(freshness(cache.get_stale(&key).await), Some((Bytes::from("new"), Freshness::Fresh)));
+        clock.advance(1);
+        assert_eq!(freshness(cache.get_stale(&key).await), Some((Bytes::from("new"), Freshness::Won)));
+
+        // Gone once the grace ends, or flushed
+        clock.advance(10);
+        assert!(cache.get_stale(&key).await.is_none());
+        assert!(cache.is_empty());
+        cache.set(key.clone(), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        clock.advance(1);
+        cache.flush(None).await;
+        assert!(cache.get_stale(&key).await.is_none());
+        assert_eq!(cache.stats.get_stale.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn test_sweeps_leave_stale_items_until_the_grace_ends() {
+        for sweep in [ExpirySweep::Scan, ExpirySweep::Index] {
+            let clock = Clock::default();
+            let cache = Cache::builder().clock(clock.clone()).expiry_sweep(sweep).stale_grace(Some(10)).build();
+            cache.set(Bytes::from("foo"), 0, Some(cache.now() + 1), Bytes::from("bar")).await;
+            clock.advance(9);
+            assert_eq!(cache.sweep_expired(), 0);
+            assert!(cache.get_stale(b"foo").await.is_some());
+            clock.advance(2);
+            assert_eq!(cache.sweep_expired(), 1, "{:?}", sweep);
+            assert!(cache.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_leaves_items_touched_since() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).expiry_sweep(ExpirySweep::Index).build();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, Some(cache.now() + 60), Bytes::from("bar")).await;
+        let id = *cache.shard(&key).read().get(&key).unwrap();
+
+        // Taken due at the earlier expiration, but touched before removal
+        let deadlines = cache.deadlines.as_ref().unwrap();
+        deadlines.update(cache.shard_number(&key), &key, id, None, Some(cache.now() + 1));
+        clock.advance(1);
+        assert_eq!(cache.sweep_expired(), 0);
+        assert!(cache.get(&key).await.is_some());
+        assert_eq!(deadlines.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_each_operation() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let (a, b, c, d) = (Bytes::from("a"), Bytes::from("b"), Bytes::from("c"), Bytes::from("d"));
+        cache.set(a.clone(), 0, None, Bytes::from("1")).await;
+        cache.set(b.clone(), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        cache.set(d.clone(), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        cache.get(&a).await.unwrap();
+        assert!(cache.get(&c).await.is_none());
+        cache.add_delta(&a, 1, Direction::Incr).await;
+        cache.add_delta(&c, 1, Direction::Decr).await;
+        assert_eq!(cache.add_delta(&b, 1, Direction::Incr).await, Delta::NonNumeric);
+        let cas = cache.get(&a).await.unwrap().cas;
+        cache.check_and_set(&a, 0, None, Bytes::from("2"), cas + 1).await;
+        cache.check_and_set(&a, 0, None, Bytes::from("2"), cas).await;
+        cache.check_and_set(&c, 0, None, Bytes::from("2"), cas).await;
+        cache.touch(&a, None).await;
+        cache.touch(&c, None).await;
+        // Counted for each key
+        let found = cache.get_multi(&[&a, &c, &d]).await;
+        assert_eq!(found.iter().map(Option::is_some).collect::<Vec<_>>(), [true, false, true]);
+        assert_eq!(cache.append(&c, Bytes::from("x")).await, Outcome::NotStored);
+        clock.advance(1);
+        assert!(cache.get(&b).await.is_none());
+        cache.set(d.clone(), 0, None, Bytes::from("x")).await;
+        assert!(cache.delete(&a).await);
+        assert!(!cache.delete(&a).await);
+        // Only what commands count
+        cache.count_gets(3);
+        cache.count_set();
+
+        let stats: HashMap<_, _> = cache.stats().into_iter().collect();
+        let expected = [
+            ("curr_items", 1),
+            ("bytes", footprint(1, 1) as u64),
+            ("total_items", 5),
+            ("evictions", 0),
+            ("evicted_unfetched", 0),
+            ("evicted_time", 0),
+            ("expired", 1),
+            ("expired_unfetched", 1),
+            ("map_shrinks", 0),
+            ("map_slots_freed", 0),
+            ("reclaimed", 1),
+            ("removals_dropped", 0),
+            ("out_of_memory_errors", 0),
+            ("prefix_flushed_items", 0),
+            ("get_hits", 4),
+            ("get_misses", 3),
+            ("get_expired", 1),
+            ("get_stale", 0),
+            ("delete_hits", 1),
+            ("delete_misses", 1),
+            ("incr_hits", 1),
+            ("incr_misses", 0),
+            ("decr_hits", 0),
+            ("decr_misses", 1),
+            ("cas_hits", 1),
+            ("cas_misses", 1),
+            ("cas_badval", 1),
+            ("touch_hits", 1),
+            ("touch_misses", 1),
+            ("cmd_get", 3),
+            ("cmd_set", 1),
+            ("map_capacity", cache.cache.capacity() as u64),
+            ("id_counter_exhausted", 0),
+        ];
+        assert_eq!(stats.len(), expected.len());
+        for (name, value) in expected {
+            assert_eq!(stats[name], value, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ttl_histogram() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let now = cache.now();
+        let ttls = [None, None, Some(1), Some(30), Some(59), Some(61), Some(3599), Some(86_400), Some(1_000_000)];
+        for (n, ttl) in ttls.into_iter().enumerate() {
+            let expiration = ttl.map(|ttl| now + ttl);
+            cache.set(Bytes::from(format!("key{}", n)), 0, expiration, Bytes::from("x")).await;
+        }
+        let histogram = cache.ttl_histogram(4);
+        assert_eq!(histogram.seen, 4);
+        assert!(!histogram.complete);
+        assert_eq!(histogram.expiring.iter().sum::<u64>() + histogram.never, 4);
+
+        clock.advance(1);
+        let histogram = cache.ttl_histogram(usize::MAX);
+        assert_eq!(histogram.expiring, [2, 1, 1, 1, 1]);
+        assert_eq!((histogram.never, histogram.seen, histogram.complete), (2, 9, true));
+    }
+
+    #[tokio::test]
+    async fn test_verify_finds_and_repairs_orphans() {
+        let cache = Cache::new();
+        let keys: Vec<Bytes> = (0..100).map(|key| Bytes::from(format!("key{}", key))).collect();
+        for key in &keys {
+            cache.set(key.clone(), 0, None, Bytes::from("x")).await;
+        }
+        let id = |key: &Bytes| *cache.shard(key).read().get(key).unwrap();
+        assert_eq!(cache.verify(false), Integrity { keys: 100, items: 100, ..Integrity::default() });
+
+        // What the 97 items left once repaired take
+        let baseline = cache.bytes() - 3 * footprint(keys[0].len(), 1);
+
+        // Keys whose item is gone read as misses
+        for key in &keys[..2] {
+            cache.cache.remove(&id(key));
+            cache.discharge(key, footprint(key.len(), 1));
+        }
+        assert!(cache.get(&keys[0]).await.is_none());
+        // Items whose key is gone, and one that never had one
+        let orphaned = &keys[2];
+        let orphaned_id = id(orphaned);
+        cache.shard(orphaned).write().remove(orphaned);
+        let stray = MemoryItem::new(b"stray", 0, None, cache.next_cas(), cache.now(), Bytes::from("y"));
+        cache.cache.insert(cache.id.gen(), stray);
+        cache.charge(b"", footprint(5, 1));
+
+        let found = Integrity { keys: 99, items: 99, dangling_keys: 2, orphaned_items: 2, repaired: 0 };
+        assert_eq!(cache.verify(false), found);
+        assert!(cache.cache.contains_key(&orphaned_id));
+        assert_eq!(cache.verify(true), Integrity { repaired: 4, ..found });
+        assert_eq!(cache.verify(false), Integrity { keys: 97, items: 97, ..Integrity::default() });
+        assert_eq!((cache.indexed(), cache.len()), (97, 97));
+        // The orphans are discharged as they were charged, key and all
+        assert_eq!(cache.bytes(), baseline);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_verify_finds_nothing_while_items_come_and_go() {
+        let cache = Cache::new();
+        let tasks: Vec<_> = (0..3)
+            .map(|task| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    for n in 0..5000 {
+                        let key = Bytes::from(format!("{}-{}", task, n % 100));
+                        match n % 3 {
+                            0 => drop(cache.delete(&key).await),
+                            _ => drop(cache.set(key, 0, None, Bytes::from("x")).await),
+                        }
+                    }
+                })
+            })
+            .collect();
+        while !tasks.iter().all(|task| task.is_finished()) {
+            let found = cache.verify(false);
+            assert_eq!((found.dangling_keys, found.orphaned_items), (0, 0));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(cache.verify(true).repaired, 0);
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let cache = Cache::new();
+        for key in ["a", "b", "c"] {
+            cache.set(Bytes::from(key), 0, None, Bytes::from("old")).await;
+        }
+        cache.flush(None).await;
+
+        let a = Bytes::from("a");
+        assert_eq!(cache.items().count(), 0);
+        // Only removed once come across
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(&a).await.is_none());
+        assert!(!cache.delete(b"b").await);
+        assert_eq!(cache.append(b"c", Bytes::from("x")).await, Outcome::NotStored);
+        assert!(cache.is_empty());
+
+        assert_eq!(cache.add(a.clone(), 0, None, Bytes::from("new")).await, Outcome::Stored);
+        assert_eq!(&cache.get(&a).await.unwrap().data[..], b"new");
+    }
+
+    #[tokio::test]
+    async fn test_flush_prefix() {
+        let cache = Cache::new();
+        let keys = ["use", "user", "user:", "user:1", "user:2", "users:1", "user;1", "a:user:1"];
+        for key in keys {
+            cache.set(Bytes::from(key), 0, None, Bytes::from("old")).await;
+        }
+        cache.set(Bytes::from("user:gone"), 0, Some(cache.now() - 1), Bytes::new()).await;
+
+        assert_eq!(cache.flush_prefix(b"user:").await, 3);
+        let left: Vec<_> = cache.items().map(|item| item.key).collect();
+        assert_eq!(left, ["a:user:1", "use", "user", "user;1", "users:1"]);
+        assert_eq!(cache.prefix_flushed(), 3);
+        assert_eq!(cache.flush_prefix(b"user:").await, 0);
+        assert_eq!(cache.flush_prefix(b"users:").await, 1);
+        assert_eq!(cache.prefix_flushed(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_flush_prefix_leaves_later_stores() {
+        let cache = Cache::new();
+        let key = |n: usize| Bytes::from(format!("user:{:05}", n));
+        for n in 0..5000 {
+            cache.set(key(n), 0, None, Bytes::from("old")).await;
+        }
+        let cas = cache.cas.load(Ordering::Relaxed);
+        // Stored once the flush has started, before and after it gets there
+        for n in (0..6000).step_by(2) {
+            cache.set(key(n), 0, None, Bytes::from("new")).await;
+        }
+
+        assert_eq!(cache.flush_prefix_before(b"user:", cas).await, 2500);
+        let left: Vec<_> = cache.items().map(|item| (item.key, item.data)).collect();
+        let stored: Vec<_> = (0..6000).step_by(2).map(|n| (key(n), Bytes::from("new"))).collect();
+        assert_eq!(left, stored);
+    }
+
+    #[tokio::test]
+    async fn test_delayed_flush() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let (old, new) = (Bytes::from("old"), Bytes::from("new"));
+        cache.set(old.clone(), 0, None, Bytes::from("bar")).await;
+        cache.flush(Some(cache.now() + 10)).await;
+        clock.advance(9);
+        assert!(cache.get(&old).await.is_some());
+
+        clock.advance(1);
+        cache.set(new.clone(), 0, None, Bytes::from("bar")).await;
+        assert!(cache.get(&old).await.is_none());
+        assert!(cache.get(&new).await.is_some());
+
+        // A later flush does not bring back what an earlier one flushed
+        cache.set(old.clone(), 0, None, Bytes::from("bar")).await;
+        let id = *cache.shard(&old).read().get(&old).unwrap();
+        cache.cache.get_mut(&id).unwrap().stored_at -= 1;
+        cache.flush(Some(cache.now() + 10)).await;
+        assert!(cache.get(&old).await.is_none());
+        assert!(cache.get(&new).await.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_sets_during_flush_stay_visible() {
+        let cache = Cache::new();
+        let flushed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let before = Bytes::from("before");
+        cache.set(before.clone(), 0, None, Bytes::from("bar")).await;
+
+        let tasks: Vec<_> = (0..4)
+            .map(|task| {
+                let cache = cache.clone();
+                let flushed = flushed.clone();
+                tokio::spawn(async move {
+                    let key = Bytes::from(task.to_string());
+                    let mut round = 0u64;
+                    while !flushed.load(Ordering::Relaxed) {
+                        cache.set(key.clone(), 0, None, Bytes::from(round.to_string())).await;
+                        round += 1;
+                        tokio::task::yield_now().await;
+                    }
+                    cache.set(key.clone(), 0, None, Bytes::from("last")).await;
+                })
+            })
+            .collect();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        cache.flush(None).await;
+        assert!(cache.get(&before).await.is_none());
+        flushed.store(true, Ordering::Relaxed);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        for task in 0..4 {
+            let item = cache.get(task.to_string().as_bytes()).await.unwrap();
+            assert_eq!(&item.data[..], b"last");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bytes_return_to_baseline() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let keys: Vec<Bytes> = (0..100).map(|key| Bytes::from(format!("key{}", key))).collect();
+        let live = |cache: &Cache| -> usize {
+            cache.items().map(|item| footprint(item.key.len(), item.data.len())).sum()
+        };
+
+        for (n, key) in keys.iter().enumerate() {
+            let data = Bytes::from(vec![b'1'; n]);
+            match n % 3 {
+                0 => cache.set(key.clone(), 0, None, data).await,
+                1 => cache.add(key.clone(), 0, None, data).await,
+                _ => {
+                    cache.set(key.clone(), 0, None, Bytes::new()).await;
+                    cache.replace(key, 0, None, data).await
+                }
+            };
+        }
+        assert_eq!(cache.bytes(), live(&cache));
+
+        for (n, key) in keys.iter().enumerate() {
+            match n % 5 {
+                0 => cache.append(key, Bytes::from("22")).await,
+                1 => cache.prepend(key, Bytes::from("3")).await,
+                2 => {
+                    let cas = cache.get(key).await.unwrap().cas;
+                    cache.check_and_set(key, 0, None, Bytes::from("4"), cas).await
+                }
+                3 => {
+                    cache.add_delta(key, 1_000_000, Direction::Incr).await;
+                    Outcome::Stored
+                }
+                _ => cache.set(key.clone(), 0, None, Bytes::from("5")).await,
+            };
+        }
+        assert_eq!(cache.bytes(), live(&cache));
+
+        // Removed by delete, on expiry and after a flush
+        for key in &keys[..30] {
+            cache.delete(key).await;
+        }
+        for key in &keys[30..60] {
+            cache.touch(key, Some(cache.now())).await;
+        }
+        clock.advance(1);
+        for key in &keys[30..60] {
+            assert!(cache.get(key).await.is_none());
+        }
+        assert_eq!(cache.bytes(), live(&cache));
+        cache.flush(None).await;
+        for key in &keys[60..] {
+            assert!(cache.get(key).await.is_none());
+        }
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_removals_are_reported_with_their_reason() {
+        let clock = Clock::default();
+        let size = footprint(4, 100);
+        let (tx, mut rx) = mpsc::channel(100);
+        let cache = Cache::builder()
+            .clock(clock.clone())
+            .memory_limit_bytes(Some(3 * size))
+            .removals(Some(tx))
+            .build();
+        let value = || Bytes::from(vec![b'x'; 100]);
+        let mut removals = || {
+            let mut removals = Vec::new();
+            while let Ok(Removal { key, reason }) = rx.try_recv() {
+                removals.push((String::from_utf8(key.to_vec()).unwrap(), reason));
+            }
+            removals
+        };
+
+        cache.set(Bytes::from("key1"), 0, None, value()).await;
+        cache.set(Bytes::from("key1"), 0, None, value()).await;
+        assert_eq!(removals(), [("key1".to_string(), RemovalReason::Replaced)]);
+
+        cache.set(Bytes::from("key2"), 0, Some(cache.now() + 1), value()).await;
+        clock.advance(1);
+        assert!(cache.get(b"key2").await.is_none());
+        assert_eq!(removals(), [("key2".to_string(), RemovalReason::Expired)]);
+
+        for key in ["key3", "key4", "key5"] {
+            cache.set(Bytes::from(key), 0, None, value()).await;
+        }
+        assert_eq!(removals(), [("key1".to_string(), RemovalReason::Evicted)]);
+
+        cache.flush(None).await;
+        clock.advance(1);
+        assert!(cache.get(b"key3").await.is_none());
+        assert_eq!(removals(), [("key3".to_string(), RemovalReason::Flushed)]);
+        cache.set(Bytes::from("key4"), 0, None, value()).await;
+        assert_eq!(removals(), [("key4".to_string(), RemovalReason::Flushed)]);
+        // Flushed items not come across yet go with the rest
+        cache.clear().await;
+        let mut cleared = removals();
+        cleared.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(cleared, [("key4".to_string(), RemovalReason::Flushed), ("key5".to_string(), RemovalReason::Flushed)]);
+        // Deletes are not reported
+        cache.set(Bytes::from("key6"), 0, None, value()).await;
+        assert!(cache.delete(b"key6").await);
+        assert_eq!(removals(), []);
+        assert_eq!(cache.stats.removals_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_removals_past_the_channel_are_dropped() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let cache = Cache::builder().removals(Some(tx)).build();
+        for _ in 0..3 {
+            cache.set(Bytes::from("key"), 0, None, Bytes::from("value")).await;
+        }
+        assert_eq!(rx.try_recv().unwrap().reason, RemovalReason::Replaced);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(cache.stats.removals_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_clear() {
+        let cache = Cache::builder()
+            .memory_limit_bytes(Some(1_000_000))
+            .expiry_sweep(ExpirySweep::Index)
+            .build();
+        for n in 0..100 {
+            let expiration = (n % 2 == 0).then(|| cache.now() + 60);
+            cache.set(Bytes::from(format!("key{}", n)), 0, expiration, Bytes::from("value")).await;
+        }
+        let cas = cache.get(b"key99").await.unwrap().cas;
+        cache.clear().await;
+        assert!(cache.is_empty());
+        assert_eq!(cache.indexed(), 0);
+        assert_eq!(cache.bytes(), 0);
+        assert_eq!(cache.deadlines.as_ref().unwrap().len(), 0);
+        assert!(cache.get(b"key0").await.is_none());
+
+        // CAS values go on
+        cache.set(Bytes::from("key0"), 0, None, Bytes::from("value")).await;
+        assert!(cache.get(b"key0").await.unwrap().cas > cas);
+        assert_eq!(cache.bytes(), footprint(4, 5));
+    }
+
+    #[tokio::test]
+    async fn test_reclaim_memory_after_a_flush() {
+        let cache = Cache::builder().initial_capacity(0).build();
+        let capacity = |cache: &Cache| cache.stats().into_iter().find(|(name, _)| name == "map_capacity").unwrap().1;
+        for n in 0..100_000 {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, Bytes::new()).await;
+        }
+        let filled = capacity(&cache);
+        assert!(filled >= 100_000);
+        assert_eq!(cache.reclaim_memory(), (0, filled as usize, filled as usize));
+
+        let flushed = cache.len();
+        cache.flush(None).await;
+        cache.set(Bytes::from("kept"), 0, None, Bytes::new()).await;
+        let (removed, _, _) = cache.reclaim_memory();
+        assert_eq!(removed, flushed);
+        assert_eq!(cache.len(), 1);
+        while cache.reclaim_memory().1 != capacity(&cache) as usize {}
+        assert!(capacity(&cache) < filled / 100, "{} of {}", capacity(&cache), filled);
+        assert_eq!(cache.reclaim_memory().0, 0);
+        assert!(cache.get(b"kept").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_shrink_to_fit() {
+        let cache = Cache::new();
+        for n in 0..10_000 {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, Bytes::new()).await;
+        }
+        for n in 0..9_000 {
+            cache.delete(format!("key{}", n).as_bytes()).await;
+        }
+        let before = cache.cache.capacity();
+        cache.shrink_to_fit();
+        assert!(cache.cache.capacity() < before / 2, "{} of {}", cache.cache.capacity(), before);
+        assert_eq!(cache.items().count(), 1000);
+        let freed = cache.stats.map_slots_freed.load(Ordering::Relaxed) as usize;
+        assert_eq!(freed, before - cache.cache.capacity());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_clear_races_stores() {
+        let cache = Cache::new();
+        let tasks: Vec<_> = (0..4)
+            .map(|task| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    for n in 0..500 {
+                        let key = Bytes::from(format!("t{}-{:03}", task, n % 200));
+                        cache.set(key, 0, None, Bytes::from(vec![0; 100])).await;
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..20 {
+            cache.clear().await;
+            tokio::task::yield_now().await;
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(cache.indexed(), cache.len());
+        let live: usize = cache.items().map(|item| footprint(item.key.len(), item.data.len())).sum();
+        assert_eq!(cache.bytes(), live);
+        cache.clear().await;
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_eviction_keeps_index_and_map_in_step() {
+        let size = footprint("t0-000".len(), 100);
+        let cache = Cache::builder().memory_limit_bytes(Some(50 * size)).build();
+        let tasks: Vec<_> = (0..4)
+            .map(|task| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    for n in 0..500 {
+                        let key = Bytes::from(format!("t{}-{:03}", task, n % 200));
+                        match n % 4 {
+                            0 => {
+                                cache.delete(&key).await;
+                            }
+                            1 => {
+                                cache.get(&key).await;
+                            }
+                            _ => {
+                                cache.set(key, 0, None, Bytes::from(vec![0; 100])).await;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(cache.indexed(), cache.len());
+        let live: usize = cache.items().map(|item| footprint(item.key.len(), item.data.len())).sum();
+        assert_eq!(cache.bytes(), live);
+        // Each task may have stored one item past the limit in a race
+        assert!(cache.bytes() <= (50 + 4) * size);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_cold_items() {
+        let size = footprint("key000".len(), 100);
+        let cache = Cache::builder().memory_limit_bytes(Some(50 * size)).build();
+        let hot: Vec<Bytes> = (0..10).map(|key| Bytes::from(format!("hot{:03}", key))).collect();
+        for key in &hot {
+            cache.set(key.clone(), 0, None, Bytes::from(vec![0; 100])).await;
+        }
+
+        for n in 0..200 {
+            let key = Bytes::from(format!("key{:03}", n));
+            cache.set(key, 0, None, Bytes::from(vec![0; 100])).await;
+            assert!(cache.bytes() <= 50 * size);
+            for key in &hot {
+                assert!(cache.get(key).await.is_some(), "{:?} evicted", key);
+            }
+        }
+
+        assert_eq!(cache.len(), 50);
+        assert_eq!(cache.evictions(), 160);
+        // The oldest cold items went first
+        assert!(cache.get(b"key000").await.is_none());
+        assert!(cache.get(b"key199").await.is_some());
+        assert_eq!(cache.indexed(), cache.len());
+    }
+
+    #[tokio::test]
+    async fn test_evicted_counted_fetched_or_not() {
+        let clock = Clock::default();
+        let size = footprint("key0".len(), 100);
+        let cache = Cache::builder().clock(clock.clone()).memory_limit_bytes(Some(4 * size)).build();
+        let key = |n: usize| Bytes::from(format!("key{}", n));
+        cache.set(key(0), 0, None, Bytes::from(vec![0; 100])).await;
+        assert!(cache.get(&key(0)).await.is_some());
+        clock.advance(5);
+        for n in 1..4 {
+            cache.set(key(n), 0, None, Bytes::from(vec![0; 100])).await;
+        }
+        clock.advance(5);
+
+        cache.set(key(4), 0, None, Bytes::from(vec![0; 100])).await;
+        assert!(cache.get(&key(0)).await.is_none());
+        assert_eq!(cache.stats.evicted_time.load(Ordering::Relaxed), 10);
+        cache.set(key(5), 0, None, Bytes::from(vec![0; 100])).await;
+
+        let stat = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        assert_eq!(stat(&cache.stats.evictions), 2);
+        assert_eq!(stat(&cache.stats.evicted_unfetched), 1);
+        assert_eq!(stat(&cache.stats.evicted_time), 5);
+        assert_eq!(stat(&cache.stats.expired), 0);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_keeps_the_key_being_set() {
+        let size = footprint("foo".len(), 100);
+        let cache = Cache::builder().memory_limit_bytes(Some(size)).build();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, None, Bytes::from(vec![0; 100])).await;
+        // Growing past the limit has nothing else to evict
+        assert_eq!(
+            cache.set(key.clone(), 0, None, Bytes::from(vec![0; 200])).await,
+            Outcome::OutOfMemory
+        );
+        assert_eq!(cache.get(&key).await.unwrap().data.len(), 100);
+        assert_eq!(cache.evictions(), 0);
+
+        cache.set(Bytes::from("bar"), 0, None, Bytes::from(vec![0; 10])).await;
+        assert!(cache.get(&key).await.is_none());
+        assert_eq!(cache.evictions(), 1);
+    }
+
+    /// Reads `hot` a few times, then sets 100 new keys, into a cache with
+    /// room for 50 items. Returns the hot keys left.
+    async fn survivors_of_scan(policy: EvictionPolicy) -> usize {
+        let size = footprint("key000".len(), 100);
+        let cache = Cache::builder().memory_limit_bytes(Some(50 * size)).eviction_policy(policy).build();
+        let hot: Vec<Bytes> = (0..10).map(|key| Bytes::from(format!("hot{:03}", key))).collect();
+        for key in &hot {
+            cache.set(key.clone(), 0, None, Bytes::from(vec![0; 100])).await;
+            for _ in 0..5 {
+                cache.get(key).await;
+            }
+        }
+        for n in 0..100 {
+            cache.set(Bytes::from(format!("key{:03}", n)), 0, None, Bytes::from(vec![0; 100])).await;
+        }
+        assert!(cache.bytes() <= 50 * size);
+
+        let mut left = 0;
+        for key in &hot {
+            left += cache.get(key).await.is_some() as usize;
+        }
+        left
+    }
+
+    #[tokio::test]
+    async fn test_lfu_keeps_frequent_items_through_a_scan() {
+        assert_eq!(survivors_of_scan(EvictionPolicy::Lfu).await, 10);
+        assert_eq!(survivors_of_scan(EvictionPolicy::Lru).await, 0);
+    }
+
+    #[test]
+    fn test_lfu_count_decays() {
+        let policy = EvictionPolicy::Lfu;
+        let item = MemoryItem::new(b"", 0, None, 1, 0, Bytes::new());
+        policy.on_access(&item, 1, 1000);
+        assert_eq!(policy.rank(&item, 1000), LFU_INITIAL);
+        for _ in 0..3 {
+            policy.on_access(&item, 1, 1000);
+        }
+        assert_eq!(policy.rank(&item, 1000), 8);
+        assert_eq!(policy.rank(&item, 1000 + LFU_DECAY), 4);
+        assert_eq!(policy.rank(&item, 1000 + 3 * LFU_DECAY), 1);
+        // A use counts from the decayed count
+        policy.on_access(&item, 1, 1000 + LFU_DECAY);
+        assert_eq!(policy.rank(&item, 1000 + LFU_DECAY), 5);
+    }
+
+    #[tokio::test]
+    async fn test_no_eviction_policy() {
+        let size = footprint("key0".len(), 100);
+        let clock = Clock::default();
+        let cache = Cache::builder()
+            .clock(clock.clone())
+            .memory_limit_bytes(Some(2 * size))
+            .eviction_policy(EvictionPolicy::None)
+            .build();
+        let data = || Bytes::from(vec![0; 100]);
+        assert_eq!(cache.set(Bytes::from("key0"), 0, None, data()).await, Outcome::Stored);
+        assert_eq!(
+            cache.set(Bytes::from("key1"), 0, Some(cache.now() + 1), data()).await,
+            Outcome::Stored
+        );
+
+        // Nothing is evicted, and nothing already stored changes
+        assert_eq!(cache.set(Bytes::from("key2"), 0, None, data()).await, Outcome::OutOfMemory);
+        assert_eq!(cache.add(Bytes::from("key2"), 0, None, data()).await, Outcome::OutOfMemory);
+        assert_eq!(
+            cache.set(Bytes::from("key0"), 0, None, Bytes::from(vec![1; 101])).await,
+            Outcome::OutOfMemory
+        );
+        assert_eq!(cache.append(b"key0", Bytes::from("x")).await, Outcome::OutOfMemory);
+        let cas = cache.get(b"key0").await.unwrap().cas;
+        assert_eq!(
+            cache.check_and_set(b"key0", 0, None, Bytes::from(vec![1; 101]), cas).await,
+            Outcome::OutOfMemory
+        );
+        assert_eq!(cache.get(b"key0").await.unwrap().data, data());
+        // Shrinking still fits
+        assert_eq!(cache.replace(b"key0", 0, None, Bytes::from(vec![1; 50])).await, Outcome::Stored);
+        assert_eq!(cache.out_of_memory_errors(), 5);
+
+        // Expired items make room
+        clock.advance(1);
+        assert_eq!(cache.set(Bytes::from("key2"), 0, None, data()).await, Outcome::Stored);
+        assert!(cache.get(b"key1").await.is_none());
+        assert_eq!(cache.evictions(), 0);
+    }
+
+    fn quotas(limits: &[(&str, usize)]) -> Option<Quotas> {
+        Quotas::with_limits(limits.iter().map(|(prefix, limit)| (Bytes::copy_from_slice(prefix.as_bytes()), *limit)))
+    }
+
+    #[tokio::test]
+    async fn test_quota_evicts_only_its_prefix() {
+        let size = footprint("a:key00".len(), 100);
+        let cache = Cache::builder().quotas(quotas(&[("a:", 10 * size), ("a:b:", 5 * size)])).build();
+        let data = || Bytes::from(vec![0; 100]);
+        for n in 0..20 {
+            cache.set(Bytes::from(format!("c:key{:02}", n)), 0, None, data()).await;
+            cache.set(Bytes::from(format!("a:b:k{:02}", n)), 0, None, data()).await;
+        }
+        for n in 0..30 {
+            assert_eq!(cache.set(Bytes::from(format!("a:key{:02}", n)), 0, None, data()).await, Outcome::Stored);
+        }
+
+        let used = |prefix: &str| cache.quota(prefix.as_bytes()).unwrap().used();
+        assert_eq!((used("a:"), used("a:b:")), (10 * size, 5 * size));
+        assert_eq!(cache.len(), 20 + 5 + 10);
+        for n in 0..20 {
+            assert!(cache.get(format!("c:key{:02}", n).as_bytes()).await.is_some());
+        }
+        // The latest, with the keys of the nested prefix left to their own
+        assert!(cache.get(b"a:key29").await.is_some());
+        assert!(cache.get(b"a:b:k19").await.is_some());
+        assert_eq!(cache.evictions(), 15 + 20);
+
+        for n in 0..30 {
+            cache.delete(format!("a:key{:02}", n).as_bytes()).await;
+        }
+        assert_eq!(used("a:"), 0);
+        assert_eq!(cache.bytes(), 25 * size);
+        let stats = cache.quotas().unwrap().stats();
+        assert_eq!(stats[2], ("a::evictions".to_string(), 20));
+        assert_eq!(stats[5], ("a:b::evictions".to_string(), 15));
+    }
+
+    #[tokio::test]
+    async fn test_quota_refuses_stores_without_evictions() {
+        let size = footprint("a:key0".len(), 100);
+        let cache = Cache::builder()
+            .quotas(quotas(&[("a:", 2 * size)]))
+            .eviction_policy(EvictionPolicy::None)
+            .build();
+        let data = || Bytes::from(vec![0; 100]);
+        assert_eq!(cache.set(Bytes::from("a:key0"), 0, None, data()).await, Outcome::Stored);
+        assert_eq!(cache.set(Bytes::from("a:key1"), 0, None, data()).await, Outcome::Stored);
+        assert_eq!(cache.set(Bytes::from("a:key2"), 0, None, data()).await, Outcome::OutOfMemory);
+        assert_eq!(cache.append(b"a:key0", Bytes::from("x")).await, Outcome::OutOfMemory);
+        // Other keys are not limited
+        assert_eq!(cache.set(Bytes::from("b:key2"), 0, None, data()).await, Outcome::Stored);
+        cache.delete(b"a:key0").await;
+        assert_eq!(cache.set(Bytes::from("a:key2"), 0, None, data()).await, Outcome::Stored);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_memory_recovers_after_delete() {
+        let size = footprint("key0".len(), 100);
+        let cache = Cache::builder()
+            .memory_limit_bytes(Some(2 * size))
+            .eviction_policy(EvictionPolicy::None)
+            .build();
+        for n in 0..2 {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, Bytes::from(vec![0; 100])).await;
+        }
+        let key = Bytes::from("key2");
+        assert_eq!(cache.set(key.clone(), 0, None, Bytes::from("new")).await, Outcome::OutOfMemory);
+        assert!(cache.get(&key).await.is_none());
+
+        assert!(cache.delete(b"key0").await);
+        assert_eq!(cache.set(key.clone(), 0, None, Bytes::from("new")).await, Outcome::Stored);
+        assert_eq!(&cache.get(&key).await.unwrap().data[..], b"new");
+        assert!(cache.bytes() <= 2 * size);
+    }
+
+    #[tokio::test]
+    async fn test_max_items() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).max_items(Some(3)).build();
+        for n in 0..5 {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, Bytes::from("x")).await;
+        }
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.evictions(), 2);
+        // Updates do not count against the limit
+        cache.set(Bytes::from("key4"), 0, None, Bytes::from("y")).await;
+        assert_eq!(cache.evictions(), 2);
+        assert_eq!(cache.total_items(), 6);
+
+        let cache = Cache::builder()
+            .clock(clock.clone())
+            .max_items(Some(2))
+            .eviction_policy(EvictionPolicy::None)
+            .build();
+        cache.set(Bytes::from("a"), 0, None, Bytes::from("x")).await;
+        cache.set(Bytes::from("b"), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        assert_eq!(cache.add(Bytes::from("c"), 0, None, Bytes::from("x")).await, Outcome::OutOfMemory);
+        assert_eq!(cache.set(Bytes::from("a"), 0, None, Bytes::from("y")).await, Outcome::Stored);
+        clock.advance(1);
+        assert_eq!(cache.set(Bytes::from("c"), 0, None, Bytes::from("x")).await, Outcome::Stored);
+        assert!(cache.delete(b"a").await);
+        assert_eq!(cache.set(Bytes::from("d"), 0, None, Bytes::from("x")).await, Outcome::Stored);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.total_items(), 5);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_updates_get_distinct_cas() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let cache = cache.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    let mut seen = Vec::new();
+                    for n in 0..500 {
+                        if n % 3 == 2 {
+                            cache.append(&key, Bytes::from("x")).await;
+                        } else {
+                            let data = Bytes::from(format!("{}-{}", task, n));
+                            cache.set(key.clone(), 0, None, data).await;
+                        }
+                        let item = cache.get(&key).await.unwrap();
+                        seen.push((item.cas, item.data));
+                    }
+                    seen
+                })
+            })
+            .collect();
+        let mut values = HashMap::new();
+        for task in tasks {
+            let mut last = 0;
+            for (cas, data) in task.await.unwrap() {
+                // Every value has its own CAS, and CAS values only grow
+                assert_eq!(values.entry(cas).or_insert_with(|| data.clone()), &data);
+                assert!(cas >= last);
+                last = cas;
+            }
+        }
+        let last = cache.get(&key).await.unwrap().cas;
+        assert!(values.keys().all(|cas| *cas <= last));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_check_and_set_race_has_one_winner() {
+        let cache = Cache::new();
+        let key = Bytes::from("foo");
+        cache.set(key.clone(), 0, None, Bytes::from("start")).await;
+        let seen = cache.get(&key).await.unwrap().cas;
+
+        let tasks: Vec<_> = (0..32)
+            .map(|task| {
+                let cache = cache.clone();
+                let key = key.clone();
+                tokio::spawn(async move {
+                    let data = Bytes::from(task.to_string());
+                    cache.check_and_set(&key, 0, None, data, seen).await
+                })
+            })
+            .collect();
+        let mut outcomes = Vec::new();
+        for task in tasks {
+            outcomes.push(task.await.unwrap());
+        }
+
+        let stored = outcomes.iter().filter(|outcome| **outcome == Outcome::Stored).count();
+        assert_eq!(stored, 1);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, Outcome::Stored | Outcome::Exists)));
+    }
+
+    #[tokio::test]
+    async fn test_scan_pages_through_a_prefix_in_order() {
+        let cache = Cache::new();
+        for n in 0..500 {
+            // Every other one expired already
+            let expiration = (n % 2 == 1).then(|| cache.now() - 1);
+            cache.set(Bytes::from(format!("user:{:03}", n)), 0, expiration, Bytes::new()).await;
+            cache.set(Bytes::from(format!("usez:{:03}", n)), 0, None, Bytes::new()).await;
+        }
+        cache.set(Bytes::from("use"), 0, None, Bytes::new()).await;
+
+        let mut scanned = Vec::new();
+        let mut after = None;
+        loop {
+            let (keys, last) = cache.scan(b"user:", after.as_deref(), 30);
+            assert!(keys.len() <= 30);
+            scanned.extend(keys);
+            match last {
+                Some(last) => after = Some(last),
+                None => break,
+            }
+        }
+        let expected: Vec<_> = (0..500).step_by(2).map(|n| Bytes::from(format!("user:{:03}", n))).collect();
+        assert_eq!(scanned, expected);
+
+        assert_eq!(cache.scan(b"user:", None, 251), (expected.clone(), None));
+        let (keys, last) = cache.scan(b"user:", None, 249);
+        assert_eq!(last.as_ref(), keys.last());
+        assert_eq!(cache.scan(b"user:", last.as_deref(), 10), (vec![expected[249].clone()], None));
+        assert_eq!(cache.scan(b"nobody", None, 10), (Vec::new(), None));
+    }
+
+    /// `(key, cas, expiration, data)` of `items`, sorted.
+    fn contents(items: impl Iterator<Item = Item>) -> Vec<(Bytes, u64, Option<u64>, Bytes)> {
+        let mut contents: Vec<_> = items
+            .map(|item| (item.key, item.cas, item.expiration, item.data))
+            .collect();
+        contents.sort();
+        contents
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_iter_is_point_in_time() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = |n: usize| Bytes::from(format!("key{}", n));
+        for n in 0..5000 {
+            let expiration = (n % 10 == 0).then(|| cache.now() + 1);
+            cache.set(key(n), 0, expiration, Bytes::from(n.to_string())).await;
+        }
+        cache.set(key(5000), 0, Some(cache.now() - 1), Bytes::new()).await;
+        // Both as of the same second, for the same items to have expired
+        let (before, mut iter) = loop {
+            let now = cache.now();
+            let (before, iter) = (contents(cache.items()), cache.snapshot_iter());
+            if cache.now() == now {
+                break (before, iter);
+            }
+        };
+
+        let mut taken: Vec<_> = iter.by_ref().take(100).collect();
+        // Every kind of change, to keys it has and has not come to yet
+        clock.advance(1);
+        for n in (0..5000).step_by(3) {
+            match n % 4 {
+                0 => assert_eq!(cache.set(key(n), 0, None, Bytes::from("x")).await, Outcome::Stored),
+                1 => assert!(cache.delete(&key(n)).await || n % 10 == 0),
+                2 => assert!(cache.touch(&key(n), None).await || n % 10 == 0),
+                _ => {
+                    cache.append(&key(n), Bytes::from("x")).await;
+                    cache.add_delta(&key(n), 1, Direction::Incr).await;
+                }
+            }
+        }
+        for n in 5000..6000 {
+            cache.set(key(n), 0, None, Bytes::new()).await;
+        }
+        cache.delete(&key(1)).await;
+        cache.set(key(1), 0, None, Bytes::from("again")).await;
+        cache.flush(None).await;
+        taken.extend(iter);
+
+        assert_eq!(contents(taken.into_iter()), before);
+        assert_eq!(cache.journaling.load(Ordering::Relaxed), 0);
+        assert!(cache.journals.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_iter_keeps_how_items_were_read() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let (read, unread) = (Bytes::from("read"), Bytes::from("unread"));
+        let stored_at = cache.now();
+        cache.set(read.clone(), 0, None, Bytes::from("x")).await;
+        cache.set(unread.clone(), 0, None, Bytes::from("x")).await;
+        clock.advance(5);
+        cache.get(&read).await.unwrap();
+
+        let mut iter = cache.snapshot_iter();
+        // Changed since, so kept as it was
+        clock.advance(5);
+        cache.set(read.clone(), 0, None, Bytes::from("y")).await;
+        let mut accessed = Vec::new();
+        while let Some((item, access)) = iter.next_accessed() {
+            accessed.push((item.key, access));
+        }
+        accessed.sort_by(|a, b| a.0.cmp(&b.0));
+        let access = |last, fetched| Access { last, fetched };
+        assert_eq!(
+            accessed,
+            [(read, access(stored_at + 5, true)), (unread, access(stored_at, false))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        let key = |n: usize| Bytes::from(format!("key{}", n));
+        // More than a batch in some shards
+        for n in 0..SNAPSHOT_BATCH * INDEX_SHARDS * 2 {
+            cache.set(key(n), 0, None, Bytes::from("x")).await;
+        }
+        let stored_at = cache.now();
+        cache.set(key(0), 0, Some(stored_at + 100), Bytes::from("value")).await;
+        cache.set(key(1), 0, Some(stored_at + 1), Bytes::new()).await;
+        cache.set(key(2), 0, None, Bytes::from("x")).await;
+        cache.set(key(3), 0, None, Bytes::from("x")).await;
+        clock.advance(5);
+        cache.get(&key(2)).await.unwrap();
+        let cas = cache.get(&key(0)).await.unwrap().cas;
+
+        let mut metadata: Vec<_> = cache.metadata().collect();
+        assert_eq!(metadata.len(), SNAPSHOT_BATCH * INDEX_SHARDS * 2 - 1);
+        // Each once
+        metadata.sort_by(|a, b| a.key.cmp(&b.key));
+        metadata.dedup_by(|a, b| a.key == b.key);
+        assert_eq!(metadata.len(), SNAPSHOT_BATCH * INDEX_SHARDS * 2 - 1);
+        let meta = |n| metadata.iter().find(|meta| meta.key == key(n)).cloned();
+        let access = |last, fetched| Access { last, fetched };
+        assert_eq!(
+            meta(0),
+            Some(Meta {
+                key: key(0),
+                expiration: Some(stored_at + 100),
+                cas,
+                access: access(stored_at + 5, true),
+                len: 5,
+            })
+        );
+        assert_eq!(meta(1), None);
+        assert_eq!(meta(2).unwrap().access, access(stored_at + 5, true));
+        assert_eq!(meta(3).unwrap().access, access(stored_at, false));
+        assert!(cache.journals.read().is_empty());
+    }
+
+    /// Writes go on while a million items are iterated over, waiting for at
+    /// most a batch at a time. A tenth of that unoptimized, which is slow
+    /// enough.
+    #[test]
+    fn test_snapshot_iter_leaves_writes_going() {
+        const ITEMS: usize = if cfg!(debug_assertions) { 100_000 } else { 1_000_000 };
+        let cache = Cache::new();
+        let key = |n: usize| Bytes::from(format!("key{}", n));
+        for n in 0..ITEMS {
+            cache.restore(Item {
+                key: key(n),
+                flags: 0,
+                cas: n as u64 + 1,
+                expiration: None,
+                data: Bytes::from_static(b"value"),
+            });
+        }
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let (iterated, writes) = std::thread::scope(|scope| {
+            let iteration = scope.spawn(|| {
+                let start = std::time::Instant::now();
+                let count = cache.snapshot_iter().count();
+                done.store(true, Ordering::Relaxed);
+                (count, start.elapsed())
+            });
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let mut latencies = Vec::new();
+            let mut n = ITEMS;
+            while !done.load(Ordering::Relaxed) {
+                let start = std::time::Instant::now();
+                runtime.block_on(async {
+                    cache.set(key(n), 0, None, Bytes::from_static(b"new")).await;
+                    cache.delete(&key(n - ITEMS)).await;
+                });
+                latencies.push(start.elapsed());
+                n += 1;
+            }
+            (iteration.join().unwrap(), latencies)
+        });
+        let (count, took) = iterated;
+        // One more if it started between a set and its delete
+        assert!(count == ITEMS || count == ITEMS + 1, "{} items", count);
+        assert!(!writes.is_empty());
+        let slowest = writes.iter().max().unwrap();
+        assert!(*slowest < took / 4, "a write took {:?} of {:?}", slowest, took);
+    }
+
+    #[tokio::test]
+    async fn test_overflow_keeps_items_past_the_memory_limit() {
+        let size = footprint("key000".len(), 1000);
+        let (dir, overflow) = overflow("cache-overflow", 1024 * 1024);
+        let cache = Cache::builder().memory_limit_bytes(Some(50 * size)).overflow(overflow.clone()).build();
+        let overflow = overflow.unwrap();
+        let keys: Vec<Bytes> = (0..200).map(|key| Bytes::from(format!("key{:03}", key))).collect();
+        for key in &keys {
+            cache.set(key.clone(), 0, None, value_of(key, 0)).await;
+            assert!(cache.bytes() <= 50 * size);
+        }
+        assert_eq!(cache.len(), 200);
+        assert_eq!(cache.evictions(), 0);
+        let spilled = cache.spilled();
+        assert!(spilled >= 150, "{} on disk", spilled);
+
+        for key in &keys {
+            assert_eq!(cache.get(key).await.unwrap().data, value_of(key, 0));
+        }
+        // Read back into memory, moving others out
+        let stats: HashMap<_, _> = overflow.stats().into_iter().collect();
+        assert_eq!(stats["overflow_hits"], spilled as u64);
+        assert_eq!(stats["overflow_misses"], 0);
+        assert_eq!(stats["overflow_items"], cache.spilled() as u64);
+        assert_eq!(cache.items().count(), 200);
+        let live: usize = cache
+            .cache
+            .iter()
+            .map(|item| item.data.len())
+            .sum::<usize>()
+            + 200 * footprint("key000".len(), 0);
+        assert_eq!(cache.bytes(), live);
+
+        cache.flush(None).await;
+        for key in &keys {
+            assert!(cache.get(key).await.is_none());
+        }
+        assert_eq!(cache.bytes(), 0);
+        assert_eq!(cache.spilled(), 0);
+        // Only the segment still written to is left
+        let stats: HashMap<_, _> = overflow.stats().into_iter().collect();
+        assert_eq!(stats["overflow_items"], 0);
+        assert!(stats["overflow_bytes"] <= 1024 * 1024 / 8);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overflow_never_returns_a_stale_value() {
+        let size = footprint("key000".len(), 1000);
+        let (dir, overflow) = overflow("cache-overflow-stale", 1024 * 1024);
+        let cache = Cache::builder().memory_limit_bytes(Some(10 * size)).overflow(overflow).build();
+        let key = Bytes::from("key000");
+        let mut n = 0;
+        let spill = |cache: &Cache, n: &mut usize| {
+            *n += 1;
+            for other in 1..=20 {
+                let other = Bytes::from(format!("key{:03}", other));
+                cache.store(other.clone(), 0, None, value_of(&other, *n));
+            }
+            let id = *cache.shard(b"key000").read().get(&b"key000"[..]).unwrap();
+            assert!(cache.cache.get(&id).unwrap().spilled.is_some());
+        };
+
+        cache.set(key.clone(), 0, None, value_of(&key, 0)).await;
+        spill(&cache, &mut n);
+        cache.set(key.clone(), 0, None, value_of(&key, 1)).await;
+        assert_eq!(cache.get(&key).await.unwrap().data, value_of(&key, 1));
+
+        // Changed in place
+        spill(&cache, &mut n);
+        assert_eq!(cache.append(&key, Bytes::from("+")).await, Outcome::Stored);
+        let item = cache.get(&key).await.unwrap();
+        assert!(item.data[..1000] == value_of(&key, 1));
+        assert!(item.data[1000..] == b"+"[..]);
+        spill(&cache, &mut n);
+        let expiration = Some(cache.now() + 100);
+        assert!(cache.touch(&key, expiration).await);
+        let touched = cache.get(&key).await.unwrap();
+        assert_eq!((touched.data, touched.expiration), (item.data, expiration));
+
+        spill(&cache, &mut n);
+        assert!(cache.delete(&key).await);
+        assert!(cache.get(&key).await.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_overflow_reads_your_writes_under_pressure() {
+        let size = footprint("t0-00".len(), 1000);
+        let (dir, overflow) = overflow("cache-overflow-race", 64 * 1024 * 1024);
+        let cache = Cache::builder().memory_limit_bytes(Some(40 * size)).overflow(overflow).build();
+        let tasks: Vec<_> = (0..4)
+            .map(|task| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    let key = |n: usize| Bytes::from(format!("t{}-{:02}", task, n % 50));
+                    for n in 0..1000 {
+                        cache.set(key(n), 0, None, value_of(&key(n), n)).await;
+                        // Only this task writes its keys, and the last it
+                        // wrote to this one was `m`
+                        let m = n - (n * 7 % 50).min(n);
+                        for n in [n, m] {
+                            let item = cache.get(&key(n)).await.unwrap();
+                            assert_eq!(item.data, value_of(&key(n), n));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(cache.indexed(), cache.len());
+        assert_eq!(cache.evictions(), 0);
+        for task in 0..4 {
+            for n in 950..1000 {
+                let key = Bytes::from(format!("t{}-{:02}", task, n % 50));
+                assert_eq!(cache.get(&key).await.unwrap().data, value_of(&key, n));
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overflow_loses_the_oldest_values_when_full() {
+        // Room for 8 values in each of the 8 segments
+        let (dir, overflow) = overflow("cache-overflow-full", 8 * 8 * 1100);
+        let cache = Cache::builder().overflow(overflow.clone()).build();
+        let overflow = overflow.unwrap();
+        let keys: Vec<Bytes> = (0..200).map(|key| Bytes::from(format!("key{:03}", key))).collect();
+        for key in &keys {
+            cache.set(key.clone(), 0, None, value_of(key, 0)).await;
+            assert!(cache.spill_key(key));
+        }
+
+        let stats: HashMap<_, _> = overflow.stats().into_iter().collect();
+        assert_eq!(stats["overflow_segments_dropped"], 17);
+        assert_eq!(stats["overflow_bytes"], 64 * (16 + 6 + 1000));
+        // Lost, and removed once found so
+        assert!(cache.get(&keys[0]).await.is_none());
+        assert_eq!(overflow.stats()[4], ("overflow_misses", 1));
+        assert_eq!(cache.len(), 199);
+        assert!(cache.get(&keys[0]).await.is_none());
+        assert_eq!(overflow.stats()[4], ("overflow_misses", 1));
+
+        for key in &keys[1..136] {
+            assert!(cache.get(key).await.is_none());
+        }
+        for key in &keys[136..] {
+            assert_eq!(cache.get(key).await.unwrap().data, value_of(key, 0));
+        }
+        assert_eq!(cache.len(), 64);
+        assert_eq!(cache.spilled(), 0);
+        assert_eq!(cache.indexed(), cache.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overflow_compaction_frees_removed_values() {
+        let (dir, overflow) = overflow("cache-overflow-compact", 8 * 8 * 1100);
+        let cache = Cache::builder().overflow(overflow.clone()).build();
+        let overflow = overflow.unwrap();
+        let keys: Vec<Bytes> = (0..40).map(|key| Bytes::from(format!("key{:03}", key))).collect();
+        for key in &keys {
+            cache.set(key.clone(), 0, None, value_of(key, 0)).await;
+            assert!(cache.spill_key(key));
+        }
+        // Not worth it while most of every segment is in use
+        assert_eq!(cache.compact_overflow().unwrap(), 0);
+
+        for key in &keys[..6] {
+            cache.delete(key).await;
+        }
+        assert!(dir.join("segment.0").exists());
+        assert_eq!(cache.compact_overflow().unwrap(), 2);
+        assert!(!dir.join("segment.0").exists());
+        assert_eq!(cache.compact_overflow().unwrap(), 0);
+        assert_eq!(overflow.stats()[6], ("overflow_compacted", 2));
+        assert_eq!(cache.spilled(), 34);
+        for key in &keys[6..] {
+            assert_eq!(cache.get(key).await.unwrap().data, value_of(key, 0));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+/// Under loom, see `sync`. The `DashMap` and `parking_lot` locks of `Cache`
+/// cannot be swapped for loom's, so these check a model of how `store`,
+/// `find` and `remove` take them; keep it in step with those.
+#[cfg(all(test, feature = "loom"))]
+mod loom_tests {
+    use loom::sync::atomic::AtomicU64;
+    use loom::sync::{Arc, Mutex, RwLock};
+    use loom::thread;
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+
+    /// An index shard and the map, down to ids and CAS values.
+    #[derive(Default)]
+    struct Model {
+        /// Held with the index read lock for an upgradable read, and with
+        /// the write lock to write: `parking_lot` lets one upgradable reader
+        /// in at a time, and no writer meanwhile
+        upgradable: Mutex<()>,
+        index: RwLock<HashMap<&'static str, u64>>,
+        /// One lock for the map, where `DashMap` has one per shard
+        map: Mutex<HashMap<u64, u64>>,
+        ids: AtomicU64,
+        cas: AtomicU64,
+    }
+
+    impl Model {
+        fn next_cas(&self) -> u64 {
+            self.cas.fetch_add(1, Ordering::Relaxed) + 1
+        }
+
+        /// `Cache::store`. Returns the CAS stored.
+        fn set(&self, key: &'static str) -> u64 {
+            let _upgradable = self.upgradable.lock().unwrap();
+            let index = self.index.read().unwrap();
+            match index.get(key).copied() {
+                Some(id) => {
+                    let mut map = self.map.lock().unwrap();
+                    let cas = self.next_cas();
+                    map.insert(id, cas);
+                    cas
+                }
+                None => {
+                    let id = self.ids.fetch_add(1, Ordering::Relaxed);
+                    let cas = self.next_cas();
+                    self.map.lock().unwrap().insert(id, cas);
+                    // The upgrade: no writer gets in between
+                    drop(index);
+                    self.index.write().unwrap().insert(key, id);
+                    cas
+                }
+            }
+        }
+
+        /// `Cache::find`. Returns the CAS found.
+        fn get(&self, key: &'static str) -> Option<u64> {
+            let index = self.index.read().unwrap();
+            let id = *index.get(key)?;
+            let cas = self.map.lock().unwrap().get(&id).copied();
+            assert!(cas.is_some(), "{} indexed without its item", key);
+            cas
+        }
+
+        /// `Cache::remove`. Returns whether there was an item.
+        fn delete(&self, key: &'static str) -> bool {
+            let _upgradable = self.upgradable.lock().unwrap();
+            let mut index = self.index.write().unwrap();
+            let Some(id) = index.remove(key) else {
+                return false;
+            };
+            self.map.lock().unwrap().remove(&id);
+            true
+        }
+
+        /// Every item indexed, and nothing else in the map.
+        fn check(&self) {
+            let index = self.index.read().unwrap();
+            let map = self.map.lock().unwrap();
+            let mut ids: Vec<u64> = index.values().copied().collect();
+            ids.sort_unstable();
+            let mut stored: Vec<u64> = map.keys().copied().collect();
+            stored.sort_unstable();
+            assert_eq!(ids, stored);
+        }
+    }
+
+    #[test]
+    fn test_set_get_delete_one_key() {
+        loom::model(|| {
+            let model = Arc::new(Model::default());
+            let setting = {
+                let model = model.clone();
+                thread::spawn(move || model.set("k"))
+            };
+            let deleting = {
+                let model = model.clone();
+                thread::spawn(move || model.delete("k"))
+            };
+            let got = model.get("k");
+
+            let cas = setting.join().unwrap();
+            let deleted = deleting.join().unwrap();
+            assert!(got.is_none() || got == Some(cas));
+            assert_eq!(model.get("k").is_none(), deleted);
+            model.check();
+        });
+    }
+
+    #[test]
+    fn test_updates_get_distinct_cas() {
+        loom::model(|| {
+            let model = Arc::new(Model::default());
+            model.set("k");
+            let updates: Vec<_> = (0..2)
+                .map(|_| {
+                    let model = model.clone();
+                    thread::spawn(move || model.set("k"))
+                })
+                .collect();
+            let got = model.get("k").unwrap();
+
+            let cas: Vec<u64> = updates.into_iter().map(|update| update.join().unwrap()).collect();
+            assert_ne!(cas[0], cas[1]);
+            // The last stored has the highest
+            assert_eq!(model.get("k"), cas.iter().max().copied());
+            assert!(got == 1 || cas.contains(&got));
+            model.check();
+        });
     }
 }
@@ -0,0 +1,97 @@
+//! CRC-32 (IEEE 802.3, as used by zlib and gzip), the checksum of what the
+//! server writes to `--data-dir`.
+
+use std::io::{self, Read, Write};
+
+/// Remainders of every byte value, for one table lookup per byte.
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+/// The CRC-32 of some data followed by `data`, `crc` being that of the data
+/// before. Start from 0.
+pub(crate) fn update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Passes reads or writes through, keeping the CRC-32 of the bytes that went
+/// by.
+#[derive(Debug)]
+pub(crate) struct Checksummed<T> {
+    inner: T,
+    crc: u32,
+}
+
+impl<T> Checksummed<T> {
+    pub(crate) fn new(inner: T) -> Checksummed<T> {
+        Checksummed { inner, crc: 0 }
+    }
+
+    pub(crate) fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(update(0, b""), 0);
+        assert_eq!(update(0, b"123456789"), 0xcbf4_3926);
+        // In pieces, as data passes through `Checksummed`
+        assert_eq!(update(update(0, b"1234"), b"56789"), 0xcbf4_3926);
+
+        let mut out = Checksummed::new(Vec::new());
+        out.write_all(b"12345").unwrap();
+        out.write_all(b"6789").unwrap();
+        assert_eq!(out.crc(), 0xcbf4_3926);
+        let written = out.into_inner();
+        let mut input = Checksummed::new(&written[..]);
+        io::copy(&mut input, &mut io::sink()).unwrap();
+        assert_eq!(input.crc(), 0xcbf4_3926);
+    }
+}
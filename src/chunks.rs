@@ -0,0 +1,197 @@
+//! Large items, see `--large-item-limit`: values past the item size limit
+//! are kept in chunks of that size, each stored on its own under a key
+//! derived from the value's, and put back together when read. The item
+//! itself only holds a `Chunked`, and its chunks go when it does.
+
+use crate::cache::Cache;
+
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::{self, Duration};
+use tracing::warn;
+
+/// Time between checks for chunks no item holds, see
+/// `Chunks::remove_orphans`.
+const ORPHAN_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Bytes counted per chunk besides its data: its key and the chunk itself in
+/// the map.
+pub(crate) const CHUNK_OVERHEAD: usize = mem::size_of::<(u64, u32)>() + mem::size_of::<Bytes>();
+
+/// The chunks of every large value, by the value's id and their place in
+/// it, and the key of the item each value is for with how many chunks it
+/// has.
+#[derive(Debug, Default)]
+pub(crate) struct Chunks {
+    chunks: DashMap<(u64, u32), Bytes>,
+    owners: DashMap<u64, (Bytes, u32)>,
+    /// Id of the next value stored
+    next: AtomicU64,
+    /// Id of the next value as of the last `remove_orphans`
+    checked: AtomicU64,
+}
+
+impl Chunks {
+    /// Store `data`, the value of the item under `key`, in chunks of
+    /// `chunk_size` bytes, each copied into a buffer of its own.
+    pub(crate) fn store(self: &Arc<Chunks>, key: &[u8], data: &[u8], chunk_size: usize) -> Chunked {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut count = 0;
+        for chunk in data.chunks(chunk_size) {
+            self.chunks.insert((id, count), Bytes::copy_from_slice(chunk));
+            count += 1;
+        }
+        self.owners.insert(id, (Bytes::copy_from_slice(key), count));
+        Chunked {
+            chunks: self.clone(),
+            id,
+            count,
+            len: data.len(),
+        }
+    }
+
+    /// Returns how many values there are, and the bytes of their chunks.
+    pub(crate) fn len(&self) -> (usize, usize) {
+        let bytes = self.chunks.iter().map(|chunk| chunk.len()).sum();
+        (self.owners.len(), bytes)
+    }
+
+    fn remove(&self, id: u64, count: u32) {
+        for n in 0..count {
+            self.chunks.remove(&(id, n));
+        }
+        self.owners.remove(&id);
+    }
+
+    /// Remove the chunks of the values whose item, by `holds`, no longer
+    /// holds them, among those stored before the last call. Returns how
+    /// many values were removed.
+    ///
+    /// Values stored since the last call are left, as their items may not
+    /// be in the cache yet. Chunks go with their `Chunked`, so any found
+    /// here were leaked. `holds` is called with nothing here locked, so it
+    /// may lock the item.
+    pub(crate) fn remove_orphans(&self, holds: impl Fn(&[u8], u64) -> bool) -> usize {
+        let before = self.checked.swap(self.next.load(Ordering::Relaxed), Ordering::Relaxed);
+        let owners: Vec<(u64, Bytes, u32)> = self
+            .owners
+            .iter()
+            .filter(|owner| *owner.key() < before)
+            .map(|owner| (*owner.key(), owner.value().0.clone(), owner.value().1))
+            .collect();
+        let mut removed = 0;
+        for (id, key, count) in owners {
+            if !holds(&key, id) {
+                self.remove(id, count);
+                removed += 1;
+            }
+        }
+        removed
+    }
+}
+
+/// A value kept in `Chunks`, removed from there once this is dropped.
+pub(crate) struct Chunked {
+    chunks: Arc<Chunks>,
+    id: u64,
+    count: u32,
+    len: usize,
+}
+
+impl Chunked {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Length of the value.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Bytes counted for the value, see `cache::footprint`.
+    pub(crate) fn size(&self) -> usize {
+        self.len + self.count as usize * CHUNK_OVERHEAD
+    }
+
+    /// The value, put back together. `None` if a chunk is missing, which
+    /// only a bug would cause.
+    pub(crate) fn read(&self) -> Option<Bytes> {
+        let mut data = BytesMut::with_capacity(self.len);
+        for n in 0..self.count {
+            data.extend_from_slice(&self.chunks.chunks.get(&(self.id, n))?);
+        }
+        Some(data.freeze())
+    }
+}
+
+impl Drop for Chunked {
+    fn drop(&mut self) {
+        self.chunks.remove(self.id, self.count);
+    }
+}
+
+impl fmt::Debug for Chunked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Chunked")
+            .field("id", &self.id)
+            .field("count", &self.count)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Remove the chunks no item of `cache` holds every
+/// `ORPHAN_CHECK_INTERVAL`, for as long as the server runs, if it keeps
+/// large items. There should be none; finding some is logged.
+pub(crate) async fn remove_orphans_periodically(cache: Cache) {
+    if cache.large_item_limit().is_none() {
+        return;
+    }
+    let mut interval = time::interval(ORPHAN_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let cache = cache.clone();
+        match tokio::task::spawn_blocking(move || cache.remove_orphan_chunks()).await {
+            Ok(0) | Err(_) => {}
+            Ok(removed) => warn!(removed, "removed large values no item held"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_go_with_their_value() {
+        let chunks = Arc::new(Chunks::default());
+        let data: Vec<u8> = (0..2500).map(|n| n as u8).collect();
+        let chunked = chunks.store(b"key", &data, 1000);
+        assert_eq!(chunks.len(), (1, 2500));
+        assert_eq!(chunked.size(), 2500 + 3 * CHUNK_OVERHEAD);
+        assert_eq!(&chunked.read().unwrap()[..], &data[..]);
+        drop(chunked);
+        assert_eq!(chunks.len(), (0, 0));
+    }
+
+    #[test]
+    fn test_remove_orphans() {
+        let chunks = Arc::new(Chunks::default());
+        let kept = chunks.store(b"kept", &[1; 10], 4);
+        let leaked = chunks.store(b"leaked", &[2; 10], 4);
+        let id = leaked.id();
+        mem::forget(leaked);
+        let holds = |key: &[u8], of: u64| key == b"kept" && of == kept.id();
+        // Too recent to tell the first time
+        assert_eq!(chunks.remove_orphans(holds), 0);
+        let late = chunks.store(b"late", &[3; 10], 4);
+        assert_eq!(chunks.remove_orphans(holds), 1);
+        assert!(chunks.owners.get(&id).is_none());
+        assert_eq!(chunks.len(), (2, 20));
+        drop((kept, late));
+    }
+}
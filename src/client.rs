@@ -0,0 +1,353 @@
+//! An async client for sidica, or any memcached server speaking the text
+//! protocol, for tests and Rust applications alike.
+//!
+//! `Client` has a method for each common command, which sends it and reads
+//! its response back. For the others, `Client::request` sends raw bytes and
+//! returns the response, parsed by `read_response`.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! let mut client = sidica::client::Client::connect("127.0.0.1:11211").await?;
+//! client.set(b"greeting", 0, 0, b"hello").await?;
+//! let value = client.get(b"greeting").await?.unwrap();
+//! assert_eq!(&value.data[..], b"hello");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::commands::{Get, Set};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// An item, as `get` and `gets` return it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Value {
+    pub key: Bytes,
+    pub flags: u32,
+    /// Only returned by `gets`
+    pub cas: Option<u64>,
+    pub data: Bytes,
+}
+
+/// A response to a request, see `read_response`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The `VALUE` blocks up to `END`, of `get` and `gets`
+    Values(Vec<Value>),
+    /// The `STAT` lines up to `END`, of `stats`
+    Stats(Vec<(String, String)>),
+    /// The value `incr` or `decr` left
+    Number(u64),
+    Stored,
+    NotStored,
+    Exists,
+    NotFound,
+    Deleted,
+    Touched,
+    /// `OK`
+    Okay,
+    /// `VA` with the flags and the value, or `HD` with the flags, of `mg`
+    Meta {
+        flags: String,
+        data: Option<Bytes>,
+    },
+    /// `EN`, `mg` found nothing
+    MetaMiss,
+    Error,
+    ClientError(String),
+    ServerError(String),
+    /// Any other line, without its CRLF, e.g. `KEY <key>` of `scan`
+    Line(String),
+}
+
+/// A connection to a server. Each method waits for the response to its
+/// request before returning.
+#[derive(Debug)]
+pub struct Client {
+    stream: BufReader<TcpStream>,
+}
+
+impl Client {
+    /// Connect to the server at `addr`.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        // Requests go out as soon as they are written
+        stream.set_nodelay(true)?;
+        Ok(Client { stream: BufReader::new(stream) })
+    }
+
+    /// The item under `key`, if there is one.
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Value>> {
+        Ok(self.get_multi(&[key]).await?.pop())
+    }
+
+    /// The items found under `keys`, in the order asked.
+    pub async fn get_multi(&mut self, keys: &[&[u8]]) -> Result<Vec<Value>> {
+        let keys = keys.iter().map(|key| Bytes::copy_from_slice(key)).collect();
+        let mut request = BytesMut::new();
+        Get::new(keys).into_frame().encode(&mut request);
+        match self.request(&request).await? {
+            Response::Values(values) => Ok(values),
+            other => Err(unexpected("get", other)),
+        }
+    }
+
+    /// Store `data` under `key`. `exptime` is as the protocol has it: 0 for
+    /// never, seconds from now up to 30 days, a unix time past that.
+    pub async fn set(&mut self, key: &[u8], flags: u32, exptime: i64, data: &[u8]) -> Result<()> {
+        let set = Set::new(Bytes::copy_from_slice(key), flags, exptime, Bytes::copy_from_slice(data));
+        let mut request = BytesMut::new();
+        set.into_frame().encode(&mut request);
+        match self.request(&request).await? {
+            Response::Stored => Ok(()),
+            other => Err(unexpected("set", other)),
+        }
+    }
+
+    /// Remove the item under `key`. Returns whether there was one.
+    pub async fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        match self.request(&line("delete", key, "")).await? {
+            Response::Deleted => Ok(true),
+            Response::NotFound => Ok(false),
+            other => Err(unexpected("delete", other)),
+        }
+    }
+
+    /// Add `delta` to the number under `key`. Returns the new one, if there
+    /// is an item.
+    pub async fn incr(&mut self, key: &[u8], delta: u64) -> Result<Option<u64>> {
+        self.delta("incr", key, delta).await
+    }
+
+    /// Subtract `delta` from the number under `key`, down to 0. Returns the
+    /// new one, if there is an item.
+    pub async fn decr(&mut self, key: &[u8], delta: u64) -> Result<Option<u64>> {
+        self.delta("decr", key, delta).await
+    }
+
+    async fn delta(&mut self, name: &str, key: &[u8], delta: u64) -> Result<Option<u64>> {
+        match self.request(&line(name, key, &delta.to_string())).await? {
+            Response::Number(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            other => Err(unexpected(name, other)),
+        }
+    }
+
+    /// The server's statistics, or those of `group`, e.g. `settings`.
+    pub async fn stats(&mut self, group: Option<&str>) -> Result<Vec<(String, String)>> {
+        let request = match group {
+            Some(group) => format!("stats {}\r\n", group),
+            None => "stats\r\n".to_string(),
+        };
+        match self.request(request.as_bytes()).await? {
+            Response::Stats(stats) => Ok(stats),
+            other => Err(unexpected("stats", other)),
+        }
+    }
+
+    /// Send `request`, raw bytes with their CRLFs, and read the response.
+    /// Requests with `noreply` are not answered; use `send` for those.
+    pub async fn request(&mut self, request: &[u8]) -> Result<Response> {
+        self.send(request).await?;
+        self.read_response().await
+    }
+
+    /// Send `request`, raw bytes with their CRLFs, without reading anything.
+    pub async fn send(&mut self, request: &[u8]) -> Result<()> {
+        self.stream.get_mut().write_all(request).await?;
+        Ok(())
+    }
+
+    /// Read the next response, see `read_response`.
+    pub async fn read_response(&mut self) -> Result<Response> {
+        read_response(&mut self.stream).await
+    }
+}
+
+/// `<name> <key> <rest>`, or without `rest` if empty, ended by CRLF.
+fn line(name: &str, key: &[u8], rest: &str) -> Vec<u8> {
+    let mut line = [name.as_bytes(), b" ", key].concat();
+    if !rest.is_empty() {
+        line.push(b' ');
+        line.extend_from_slice(rest.as_bytes());
+    }
+    line.extend_from_slice(b"\r\n");
+    line
+}
+
+fn unexpected(name: &str, response: Response) -> anyhow::Error {
+    match response {
+        Response::Error => anyhow!("{}: ERROR", name),
+        Response::ClientError(message) => anyhow!("{}: CLIENT_ERROR {}", name, message),
+        Response::ServerError(message) => anyhow!("{}: SERVER_ERROR {}", name, message),
+        other => anyhow!("unexpected response to {}: {:?}", name, other),
+    }
+}
+
+/// Read a response from `reader`: a line, with the blocks or lines that go
+/// with it up to `END` for `VALUE` and `STAT`.
+pub async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Response> {
+    let line = read_line(reader).await?;
+    let response = match &line[..] {
+        b"END" => Response::Values(Vec::new()),
+        b"STORED" => Response::Stored,
+        b"NOT_STORED" => Response::NotStored,
+        b"EXISTS" => Response::Exists,
+        b"NOT_FOUND" => Response::NotFound,
+        b"DELETED" => Response::Deleted,
+        b"TOUCHED" => Response::Touched,
+        b"OK" => Response::Okay,
+        b"EN" => Response::MetaMiss,
+        b"ERROR" => Response::Error,
+        _ if line.starts_with(b"VALUE ") => {
+            let mut values = vec![read_value(reader, &line).await?];
+            loop {
+                match &read_line(reader).await?[..] {
+                    b"END" => break,
+                    line => values.push(read_value(reader, line).await?),
+                }
+            }
+            Response::Values(values)
+        }
+        _ if line.starts_with(b"STAT ") => {
+            let mut stats = vec![stat(&line)?];
+            loop {
+                match &read_line(reader).await?[..] {
+                    b"END" => break,
+                    line => stats.push(stat(line)?),
+                }
+            }
+            Response::Stats(stats)
+        }
+        _ if line.starts_with(b"VA ") => {
+            let line = text(&line)?;
+            let (len, flags) = line[3..].split_once(' ').unwrap_or((&line[3..], ""));
+            let len = len.parse().with_context(|| format!("bad VA line: {:?}", line))?;
+            Response::Meta { flags: flags.to_string(), data: Some(read_block(reader, len).await?) }
+        }
+        _ if line.starts_with(b"HD") => Response::Meta { flags: text(&line[2..])?.trim_start().to_string(), data: None },
+        _ if line.starts_with(b"CLIENT_ERROR ") => Response::ClientError(text(&line[13..])?),
+        _ if line.starts_with(b"SERVER_ERROR ") => Response::ServerError(text(&line[13..])?),
+        _ if !line.is_empty() && line.iter().all(u8::is_ascii_digit) => {
+            Response::Number(text(&line)?.parse().context("number out of range")?)
+        }
+        _ => Response::Line(text(&line)?),
+    };
+    Ok(response)
+}
+
+/// The next line, without its CRLF.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        bail!("connection closed");
+    }
+    if !line.ends_with(b"\r\n") {
+        bail!("response line not ended by CRLF: {:?}", line.escape_ascii().to_string());
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+/// The value `line`, `VALUE <key> <flags> <bytes> [<cas>]`, starts.
+async fn read_value<R: AsyncBufRead + Unpin>(reader: &mut R, line: &[u8]) -> Result<Value> {
+    let bad = || anyhow!("bad VALUE line: {:?}", line.escape_ascii().to_string());
+    let fields: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+    let (key, flags, len, cas) = match fields[..] {
+        [b"VALUE", key, flags, len] => (key, flags, len, None),
+        [b"VALUE", key, flags, len, cas] => (key, flags, len, Some(cas)),
+        _ => return Err(bad()),
+    };
+    let number = |field: &[u8]| atoi::atoi::<u64>(field).ok_or_else(bad);
+    Ok(Value {
+        key: Bytes::copy_from_slice(key),
+        flags: u32::try_from(number(flags)?).map_err(|_| bad())?,
+        cas: cas.map(number).transpose()?,
+        data: read_block(reader, number(len)? as usize).await?,
+    })
+}
+
+/// A data block of `len` bytes and its CRLF.
+async fn read_block<R: AsyncBufRead + Unpin>(reader: &mut R, len: usize) -> Result<Bytes> {
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data).await?;
+    if !data.ends_with(b"\r\n") {
+        bail!("data block not ended by CRLF");
+    }
+    data.truncate(len);
+    Ok(Bytes::from(data))
+}
+
+/// `STAT <name> <value>`, where the value may hold spaces.
+fn stat(line: &[u8]) -> Result<(String, String)> {
+    let line = text(line)?;
+    let stat = line.strip_prefix("STAT ").and_then(|stat| stat.split_once(' '));
+    let (name, value) = stat.with_context(|| format!("bad STAT line: {:?}", line))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn text(line: &[u8]) -> Result<String> {
+    String::from_utf8(line.to_vec()).context("response line not UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut bytes: &[u8]) -> Response {
+        let response = read_response(&mut bytes).await.unwrap();
+        assert!(bytes.is_empty(), "left over: {:?}", bytes.escape_ascii().to_string());
+        response
+    }
+
+    fn value(key: &str, flags: u32, cas: Option<u64>, data: &str) -> Value {
+        Value { key: Bytes::from(key.to_string()), flags, cas, data: Bytes::from(data.to_string()) }
+    }
+
+    #[tokio::test]
+    async fn test_parses_each_response() {
+        assert_eq!(parse(b"END\r\n").await, Response::Values(vec![]));
+        assert_eq!(
+            parse(b"VALUE a 5 3\r\nx\r\n\r\nVALUE b 0 0 42\r\n\r\nEND\r\n").await,
+            Response::Values(vec![value("a", 5, None, "x\r\n"), value("b", 0, Some(42), "")])
+        );
+        assert_eq!(
+            parse(b"STAT pid 12\r\nSTAT version 1.0 beta\r\nEND\r\n").await,
+            Response::Stats(vec![
+                ("pid".to_string(), "12".to_string()),
+                ("version".to_string(), "1.0 beta".to_string())
+            ])
+        );
+        assert_eq!(parse(b"18446744073709551615\r\n").await, Response::Number(u64::MAX));
+        assert_eq!(parse(b"STORED\r\n").await, Response::Stored);
+        assert_eq!(parse(b"NOT_FOUND\r\n").await, Response::NotFound);
+        assert_eq!(
+            parse(b"VA 2 f3 c9\r\nab\r\n").await,
+            Response::Meta { flags: "f3 c9".to_string(), data: Some(Bytes::from("ab")) }
+        );
+        assert_eq!(parse(b"HD\r\n").await, Response::Meta { flags: String::new(), data: None });
+        assert_eq!(parse(b"EN\r\n").await, Response::MetaMiss);
+        assert_eq!(parse(b"ERROR\r\n").await, Response::Error);
+        assert_eq!(parse(b"SERVER_ERROR out of memory\r\n").await, Response::ServerError("out of memory".to_string()));
+        assert_eq!(parse(b"KEY a\r\n").await, Response::Line("KEY a".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_broken_responses() {
+        for bytes in [
+            &b""[..],
+            b"STORED\n",
+            b"VALUE a 0 3\r\nab\r\nEND\r\n",
+            b"VALUE a 4294967296 0\r\n\r\nEND\r\n",
+            b"VALUE a 0\r\n\r\nEND\r\n",
+            b"VALUE a 0 1\r\nx\r\n",
+            b"99999999999999999999\r\n",
+        ] {
+            let mut reader = bytes;
+            assert!(read_response(&mut reader).await.is_err(), "{:?}", bytes.escape_ascii().to_string());
+        }
+    }
+}
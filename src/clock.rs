@@ -0,0 +1,182 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Largest expiration time taken as seconds from now, 30 days. Larger ones
+/// are unix timestamps.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// Wall clock the cache expires items by, in whole seconds since the unix
+/// epoch. Tests move it forward instead of sleeping.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Clock {
+    /// Seconds added to the system time, see `advance`.
+    offset: Arc<AtomicU64>,
+}
+
+impl Clock {
+    pub(crate) fn now(&self) -> u64 {
+        let system = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        system + self.offset.load(Ordering::Relaxed)
+    }
+
+    /// Move this clock, and every clone of it, `secs` into the future.
+    #[cfg(test)]
+    pub(crate) fn advance(&self, secs: u64) {
+        self.offset.fetch_add(secs, Ordering::Relaxed);
+    }
+}
+
+/// Convert an expiration time as sent by clients to the unix timestamp the
+/// item expires at, `None` for never.
+///
+/// Like memcached, 0 is never, up to 30 days is seconds from `now`, and
+/// anything larger a unix timestamp. Negative times, and timestamps already
+/// past, expire the item right away.
+pub(crate) fn deadline(exptime: i64, now: u64) -> Option<u64> {
+    match exptime {
+        0 => None,
+        ..=-1 => Some(now),
+        1..=MAX_RELATIVE_EXPTIME => Some(now + exptime as u64),
+        _ => Some(exptime as u64),
+    }
+}
+
+/// How far `--ttl-jitter` may move a deadline, see `Jitter::spread`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jitter {
+    /// Up to this many seconds
+    Seconds(u64),
+    /// Up to this percentage of the time left to live
+    Percent(u8),
+}
+
+impl Jitter {
+    /// Move `deadline` by up to the jitter, earlier only unless `both_ways`,
+    /// picking the move from `random`. Deadlines less than 2 seconds from
+    /// `now` are left alone, and none is moved to `now` or before, so
+    /// jitter never expires an item right away.
+    pub(crate) fn spread(self, deadline: u64, now: u64, both_ways: bool, random: u64) -> u64 {
+        let ttl = deadline.saturating_sub(now);
+        if ttl < 2 {
+            return deadline;
+        }
+        let window = match self {
+            Jitter::Seconds(secs) => secs,
+            Jitter::Percent(percent) => ttl * u64::from(percent) / 100,
+        };
+        let earlier = window.min(ttl - 1);
+        let later = if both_ways { window } else { 0 };
+        let span = (earlier + later).saturating_add(1);
+        (deadline - earlier).saturating_add(random % span)
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("expected SECONDS, or PERCENT% from 1 to 100, in `{0}`")]
+pub struct ParseJitterError(String);
+
+impl FromStr for Jitter {
+    type Err = ParseJitterError;
+
+    fn from_str(s: &str) -> Result<Jitter, ParseJitterError> {
+        let jitter = match s.strip_suffix('%') {
+            Some(percent) => percent
+                .parse()
+                .ok()
+                .filter(|percent| (1..=100).contains(percent))
+                .map(Jitter::Percent),
+            None => s.parse().ok().filter(|secs| *secs > 0).map(Jitter::Seconds),
+        };
+        jitter.ok_or_else(|| ParseJitterError(s.to_string()))
+    }
+}
+
+impl fmt::Display for Jitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Jitter::Seconds(secs) => write!(f, "{}", secs),
+            Jitter::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn test_deadline() {
+        assert_eq!(deadline(0, NOW), None);
+        assert_eq!(deadline(-1, NOW), Some(NOW));
+        assert_eq!(deadline(i64::MIN, NOW), Some(NOW));
+        assert_eq!(deadline(1, NOW), Some(NOW + 1));
+        // The cutoff itself is still relative
+        assert_eq!(deadline(2_592_000, NOW), Some(NOW + 2_592_000));
+        assert_eq!(deadline(2_592_001, NOW), Some(2_592_001));
+        assert_eq!(deadline(NOW as i64 + 60, NOW), Some(NOW + 60));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_deadline_relative(exptime in 1..=MAX_RELATIVE_EXPTIME, now: u64) {
+            let deadline = deadline(exptime, now).unwrap();
+            proptest::prop_assert!(deadline >= now);
+            proptest::prop_assert_eq!(deadline - now, exptime as u64);
+        }
+
+        #[test]
+        fn test_deadline_absolute(exptime in MAX_RELATIVE_EXPTIME + 1.., now: u64) {
+            proptest::prop_assert_eq!(deadline(exptime, now), Some(exptime as u64));
+        }
+
+        #[test]
+        fn test_deadline_negative(exptime in ..0i64, now: u64) {
+            proptest::prop_assert_eq!(deadline(exptime, now), Some(now));
+        }
+    }
+
+    #[test]
+    fn test_past_deadline_expires() {
+        let clock = Clock::default();
+        let now = clock.now();
+        // An absolute time in the past, and a negative one
+        for exptime in [now as i64 - 60, -1] {
+            let deadline = deadline(exptime, now).unwrap();
+            assert!(deadline <= clock.now());
+        }
+    }
+
+    #[test]
+    fn test_jitter_parse() {
+        assert_eq!("30".parse(), Ok(Jitter::Seconds(30)));
+        assert_eq!("10%".parse(), Ok(Jitter::Percent(10)));
+        for bad in ["0", "0%", "101%", "-5", "x", "%"] {
+            assert!(bad.parse::<Jitter>().is_err(), "{}", bad);
+        }
+        assert_eq!(Jitter::Percent(10).to_string(), "10%");
+    }
+
+    #[test]
+    fn test_jitter_spread() {
+        let spread = |jitter: Jitter, ttl: u64, both_ways: bool| -> (u64, u64) {
+            let deadlines: Vec<_> = (0..1000).map(|random| jitter.spread(NOW + ttl, NOW, both_ways, random)).collect();
+            (*deadlines.iter().min().unwrap() - NOW, *deadlines.iter().max().unwrap() - NOW)
+        };
+        // Earlier only, by up to the window
+        assert_eq!(spread(Jitter::Seconds(30), 300, false), (270, 300));
+        assert_eq!(spread(Jitter::Percent(10), 300, false), (270, 300));
+        assert_eq!(spread(Jitter::Percent(10), 300, true), (270, 330));
+        // Never to now or before
+        assert_eq!(spread(Jitter::Seconds(30), 10, false), (1, 10));
+        assert_eq!(spread(Jitter::Percent(100), 10, true), (1, 20));
+        assert_eq!(spread(Jitter::Seconds(30), 1, true), (1, 1));
+    }
+}
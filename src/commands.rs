@@ -1,24 +1,146 @@
+mod add;
+mod append;
+mod cas;
+#[cfg(debug_assertions)]
+mod debug_panic;
+#[cfg(debug_assertions)]
+mod debug_sleep;
+mod delete;
+mod drain;
+mod export;
+mod flush_all;
+mod flush_prefix;
 mod get;
+mod get_if_modified;
+mod import;
+mod incr;
+mod lru_crawler;
+mod meta_get;
+mod replace;
+mod rewrite_log;
+mod scan;
 mod set;
+mod stats;
+mod touch;
+mod verbosity;
 
-use crate::{cache::Cache, frame::RequestFrame, parse::Parse, Connection};
+use crate::{
+    cache::{Cache, Direction}, frame::{RequestFrame, ResponseFrame}, health::Readiness, parse::{Parse, ParseError}, replication::Replicator, settings::Settings,
+    stats::ServerStats, Connection,
+};
 use anyhow::Result;
+use arc_swap::ArcSwap;
+use std::time::Instant;
+pub use add::Add;
+pub use append::Append;
+pub use cas::Cas;
+#[cfg(debug_assertions)]
+pub use debug_panic::DebugPanic;
+#[cfg(debug_assertions)]
+pub use debug_sleep::DebugSleep;
+pub use delete::Delete;
+pub use drain::Drain;
+pub use export::Export;
+pub use flush_all::FlushAll;
+pub use flush_prefix::FlushPrefix;
 pub use get::Get;
+pub use get_if_modified::GetIfModified;
+pub use import::Import;
+pub use incr::Incr;
+pub use lru_crawler::LruCrawler;
+pub use meta_get::MetaGet;
+pub use replace::Replace;
+pub use rewrite_log::RewriteLog;
+pub use scan::Scan;
 pub use set::Set;
+pub use stats::Stats;
+pub use touch::Touch;
+pub use verbosity::Verbosity;
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
 pub(crate) enum CommandError {
     #[error("command error; unknown command")]
     Unknown,
+    #[error("command error; bad data chunk")]
+    BadDataChunk,
+}
+
+/// What to answer with `CLIENT_ERROR`, rather than closing the connection,
+/// when `Command::from_frame` fails with `err`. Like memcached, that is for
+/// a data block that does not end where its command line says, or a key
+/// too long.
+pub(crate) fn client_error(err: &anyhow::Error) -> Option<&'static str> {
+    if err.downcast_ref() == Some(&CommandError::BadDataChunk) {
+        Some("bad data chunk")
+    } else if err.downcast_ref() == Some(&ParseError::KeyTooLong) {
+        Some("bad command line format")
+    } else {
+        None
+    }
 }
 
 #[derive(Debug)]
 pub enum Command {
     Get(Get),
+    GetIfModified(GetIfModified),
+    MetaGet(MetaGet),
     Set(Set),
+    Add(Add),
+    Replace(Replace),
+    Append(Append),
+    Cas(Cas),
+    Delete(Delete),
+    Incr(Incr),
+    Touch(Touch),
+    FlushAll(FlushAll),
+    FlushPrefix(FlushPrefix),
+    Stats(Stats),
+    Drain(Drain),
+    Verbosity(Verbosity),
+    RewriteLog(RewriteLog),
+    Scan(Scan),
+    LruCrawler(LruCrawler),
+    Export(Export),
+    Import(Import),
+    #[cfg(debug_assertions)]
+    DebugPanic(DebugPanic),
+    #[cfg(debug_assertions)]
+    DebugSleep(DebugSleep),
 }
 
+/// Every name `Command::get_name` returns.
+pub(crate) const NAMES: &[&str] = &[
+    "get",
+    "gets",
+    "get_if_modified",
+    "mg",
+    "set",
+    "add",
+    "replace",
+    "append",
+    "prepend",
+    "cas",
+    "delete",
+    "incr",
+    "decr",
+    "touch",
+    "flush_all",
+    "flush_prefix",
+    "stats",
+    "drain",
+    "verbosity",
+    "rewrite_log",
+    "scan",
+    "lru_crawler",
+    "export",
+    "import",
+    #[cfg(debug_assertions)]
+    "debug_panic",
+    #[cfg(debug_assertions)]
+    "debug_sleep",
+];
+
 impl Command {
     /// Parse a command from a received frame.
     ///
@@ -34,7 +156,28 @@ impl Command {
                 let mut parse = Parse::new(frame);
                 let command_name = parse.next_string()?;
                 let c = match &command_name[..] {
-                    "get" => Command::Get(Get::parse_frame(&mut parse)?),
+                    "get" => Command::Get(Get::parse_frame(&mut parse, false)?),
+                    "gets" => Command::Get(Get::parse_frame(&mut parse, true)?),
+                    "get_if_modified" => Command::GetIfModified(GetIfModified::parse_frame(&mut parse)?),
+                    "mg" => Command::MetaGet(MetaGet::parse_frame(&mut parse)?),
+                    "delete" => Command::Delete(Delete::parse_frame(&mut parse)?),
+                    "incr" => Command::Incr(Incr::parse_frame(&mut parse, Direction::Incr)?),
+                    "decr" => Command::Incr(Incr::parse_frame(&mut parse, Direction::Decr)?),
+                    "touch" => Command::Touch(Touch::parse_frame(&mut parse)?),
+                    "flush_all" => Command::FlushAll(FlushAll::parse_frame(&mut parse)?),
+                    "flush_prefix" => Command::FlushPrefix(FlushPrefix::parse_frame(&mut parse)?),
+                    "stats" => Command::Stats(Stats::parse_frame(&mut parse)?),
+                    "drain" => Command::Drain(Drain::parse_frame(&mut parse)?),
+                    "verbosity" => Command::Verbosity(Verbosity::parse_frame(&mut parse)?),
+                    "rewrite_log" => Command::RewriteLog(RewriteLog::parse_frame(&mut parse)?),
+                    "scan" => Command::Scan(Scan::parse_frame(&mut parse)?),
+                    "lru_crawler" => Command::LruCrawler(LruCrawler::parse_frame(&mut parse)?),
+                    "export" => Command::Export(Export::parse_frame(&mut parse)?),
+                    "import" => Command::Import(Import::parse_frame(&mut parse)?),
+                    #[cfg(debug_assertions)]
+                    "debug_panic" => Command::DebugPanic(DebugPanic::parse_frame(&mut parse)?),
+                    #[cfg(debug_assertions)]
+                    "debug_sleep" => Command::DebugSleep(DebugSleep::parse_frame(&mut parse)?),
                     _ => {
                         // Return `Unknown` to skip the `finish()` call. As
                         // the command is not recognized, there will likely
@@ -51,6 +194,11 @@ impl Command {
 
                 let c = match &command_name[..] {
                     "set" => Command::Set(Set::parse_frame(&mut parse, frame.data)?),
+                    "add" => Command::Add(Add::parse_frame(&mut parse, frame.data)?),
+                    "replace" => Command::Replace(Replace::parse_frame(&mut parse, frame.data)?),
+                    "append" => Command::Append(Append::parse_frame(&mut parse, frame.data, false)?),
+                    "prepend" => Command::Append(Append::parse_frame(&mut parse, frame.data, true)?),
+                    "cas" => Command::Cas(Cas::parse_frame(&mut parse, frame.data)?),
                     _ => {
                         // Return `Unknown` to skip the `finish()` call. As
                         // the command is not recognized, there will likely
@@ -61,6 +209,7 @@ impl Command {
                 parse.finish()?;
                 c
             }
+            RequestFrame::BadDataChunk => return Err(CommandError::BadDataChunk.into()),
         };
 
         // Check if there is any remaining unconsumed fields in the `Parse`
@@ -75,24 +224,150 @@ impl Command {
     /// Apply the command to the specified `Cache` instance.
     ///
     /// The response is written to `dst`. This is called by the server in order
-    /// to execute a received command.
+    /// to execute a received command. Mutations are forwarded to `replicator`
+    /// once applied, when there is one.
+    ///
+    /// Unless `writes_allowed`, mutations are refused with `SERVER_ERROR`
+    /// and the cache is left alone.
+    ///
+    /// The time taken, up to the response flushed, is counted in the
+    /// command's latency histogram, see `ServerStats::latency`.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn apply(
         self,
-        cache: Cache,
+        cache: &Cache,
+        stats: &ServerStats,
+        settings: &ArcSwap<Settings>,
+        readiness: &Readiness,
+        replicator: Option<&Replicator>,
+        writes_allowed: bool,
         dst: &mut Connection,
-        // shutdown: &mut Shutdown,
     ) -> Result<()> {
+        if self.is_mutation() && !writes_allowed {
+            let response = ResponseFrame::ServerError("writes not permitted on replica".to_string());
+            dst.write_and_flush(response).await?;
+            return Ok(());
+        }
+
+        match &self {
+            Command::Get(cmd) => cache.count_gets(cmd.keys().len()),
+            Command::Set(_) | Command::Add(_) | Command::Replace(_) | Command::Append(_) | Command::Cas(_) => {
+                cache.count_set()
+            }
+            _ => {}
+        }
+        let name = self.get_name();
+        let started = Instant::now();
+        let applied = match self {
+            Command::Get(cmd) => cmd.apply(cache, stats.detail(), dst).await,
+            Command::GetIfModified(cmd) => cmd.apply(cache, dst).await,
+            Command::MetaGet(cmd) => cmd.apply(cache, dst).await,
+            Command::Set(cmd) => cmd.apply(cache, replicator, stats.detail(), dst).await,
+            Command::Add(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::Replace(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::Append(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::Cas(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::Delete(cmd) => cmd.apply(cache, replicator, stats.detail(), dst).await,
+            Command::Incr(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::Touch(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::FlushAll(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::FlushPrefix(cmd) => cmd.apply(cache, replicator, dst).await,
+            Command::Stats(cmd) => cmd.apply(stats, cache, &settings.load(), dst).await,
+            Command::Drain(cmd) => cmd.apply(readiness, stats, &settings.load(), dst).await,
+            Command::Verbosity(cmd) => cmd.apply(settings, dst).await,
+            Command::RewriteLog(cmd) => cmd.apply(cache, dst).await,
+            Command::Scan(cmd) => cmd.apply(cache, dst).await,
+            Command::LruCrawler(cmd) => cmd.apply(cache, dst).await,
+            Command::Export(cmd) => cmd.apply(cache, &settings.load(), dst).await,
+            Command::Import(cmd) => cmd.apply(cache, &settings.load(), replicator, dst).await,
+            #[cfg(debug_assertions)]
+            Command::DebugPanic(cmd) => cmd.apply().await,
+            #[cfg(debug_assertions)]
+            Command::DebugSleep(cmd) => cmd.apply(dst).await,
+        };
+        stats.latency().record(name, started.elapsed());
+        applied
+    }
+
+    /// Returns how many keys the command names.
+    pub(crate) fn key_count(&self) -> usize {
         match self {
-            Command::Get(cmd) => cmd.apply(cache, dst).await,
-            Command::Set(cmd) => cmd.apply(cache, dst).await,
+            Command::Get(cmd) => cmd.keys().len(),
+            Command::GetIfModified(_)
+            | Command::MetaGet(_)
+            | Command::Set(_)
+            | Command::Add(_)
+            | Command::Replace(_)
+            | Command::Append(_)
+            | Command::Cas(_)
+            | Command::Delete(_)
+            | Command::Incr(_)
+            | Command::Touch(_) => 1,
+            _ => 0,
         }
     }
 
+    /// Returns the size of the data block sent with the command.
+    pub(crate) fn data_len(&self) -> usize {
+        match self {
+            Command::Set(cmd) => cmd.data.len(),
+            Command::Add(cmd) => cmd.data.len(),
+            Command::Replace(cmd) => cmd.data.len(),
+            Command::Append(cmd) => cmd.data.len(),
+            Command::Cas(cmd) => cmd.data.len(),
+            _ => 0,
+        }
+    }
+
+    /// Whether the command changes the cache.
+    fn is_mutation(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(_)
+                | Command::Add(_)
+                | Command::Replace(_)
+                | Command::Append(_)
+                | Command::Cas(_)
+                | Command::Delete(_)
+                | Command::Incr(_)
+                | Command::Touch(_)
+                | Command::FlushAll(_)
+                | Command::FlushPrefix(_)
+                | Command::Import(_)
+        )
+    }
+
     /// Returns the command name
-    pub(crate) fn get_name(&self) -> &str {
+    pub(crate) fn get_name(&self) -> &'static str {
         match self {
+            Command::Get(cmd) if cmd.with_cas() => "gets",
             Command::Get(_) => "get",
+            Command::GetIfModified(_) => "get_if_modified",
+            Command::MetaGet(_) => "mg",
             Command::Set(_) => "set",
+            Command::Add(_) => "add",
+            Command::Replace(_) => "replace",
+            Command::Append(cmd) if cmd.prepend => "prepend",
+            Command::Append(_) => "append",
+            Command::Cas(_) => "cas",
+            Command::Delete(_) => "delete",
+            Command::Incr(cmd) if cmd.direction == Direction::Decr => "decr",
+            Command::Incr(_) => "incr",
+            Command::Touch(_) => "touch",
+            Command::FlushAll(_) => "flush_all",
+            Command::FlushPrefix(_) => "flush_prefix",
+            Command::Stats(_) => "stats",
+            Command::Drain(_) => "drain",
+            Command::Verbosity(_) => "verbosity",
+            Command::RewriteLog(_) => "rewrite_log",
+            Command::Scan(_) => "scan",
+            Command::LruCrawler(_) => "lru_crawler",
+            Command::Export(_) => "export",
+            Command::Import(_) => "import",
+            #[cfg(debug_assertions)]
+            Command::DebugPanic(_) => "debug_panic",
+            #[cfg(debug_assertions)]
+            Command::DebugSleep(_) => "debug_sleep",
         }
     }
 }
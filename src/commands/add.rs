@@ -0,0 +1,79 @@
+use crate::{
+    cache::Outcome,
+    frame::ResponseFrame,
+    parse::Parse,
+    replication::Replicator,
+    storage::Storage,
+    Connection,
+};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug;
+
+/// Store `data` under `key` unless the key already holds an item.
+///
+/// Answers `STORED`, or `NOT_STORED` if the key was taken.
+#[derive(Debug)]
+pub struct Add {
+    pub key: Bytes,
+    pub flags: u32,
+    /// Expiration time as sent by the client, see `Cache::deadline`
+    pub expiration: i64,
+    pub data: Bytes,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl Add {
+    /// Parse an `Add` instance from a received frame.
+    ///
+    /// The `add` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// add <key> <flags> <exptime> <bytes> [noreply]
+    /// <data>
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse, data: Bytes) -> Result<Add> {
+        let key = parse.next_key()?;
+        let flags = parse.next_u32()?;
+        let expiration = parse.next_i64()?;
+        let _ = parse.next_u32()?; // data_length
+        let noreply = parse.next_noreply()?;
+
+        Ok(Add { key, flags, expiration, data, noreply })
+    }
+
+    /// Apply the `Add` command. Once stored, the value is queued for the
+    /// replica when there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let expiration = cache.deadline(&self.key, self.expiration);
+        let data = replicator.map(|_| self.data.clone());
+        let outcome = cache
+            .add(self.key.clone(), self.flags, expiration, self.data)
+            .await;
+        debug!(key = ?self.key, ?outcome, "adding");
+
+        if let (Outcome::Stored, Some(replicator), Some(data)) = (outcome, replicator, data) {
+            replicator.set(&self.key, self.flags, expiration, &data);
+        }
+
+        if self.noreply {
+            return Ok(());
+        }
+        let response = match outcome {
+            Outcome::Stored => ResponseFrame::Stored,
+            Outcome::TooLarge => ResponseFrame::ServerError("object too large for cache".to_string()),
+            Outcome::OutOfMemory => ResponseFrame::ServerError("out of memory storing object".to_string()),
+            _ => ResponseFrame::NotStored,
+        };
+        dst.write_and_flush(response).await?;
+        Ok(())
+    }
+}
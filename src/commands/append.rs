@@ -0,0 +1,81 @@
+use crate::{
+    cache::Outcome,
+    frame::ResponseFrame,
+    parse::Parse,
+    replication::Replicator,
+    storage::Storage,
+    Connection,
+};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug;
+
+/// Add `data` to the end of the item stored under `key`, or to its start
+/// for `prepend`. The item keeps its flags and expiration.
+///
+/// Answers `STORED`, or `NOT_STORED` if there is no such item.
+#[derive(Debug)]
+pub struct Append {
+    pub key: Bytes,
+    pub data: Bytes,
+    /// Received as `prepend`
+    pub prepend: bool,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl Append {
+    /// Parse an `Append` instance from a received frame.
+    ///
+    /// The `append` or `prepend` string has already been consumed. Flags
+    /// and expiration time are ignored.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// append|prepend <key> <flags> <exptime> <bytes> [noreply]
+    /// <data>
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse, data: Bytes, prepend: bool) -> Result<Append> {
+        let key = parse.next_key()?;
+        let _ = parse.next_u32()?; // flags
+        let _ = parse.next_i64()?; // exptime
+        let _ = parse.next_u32()?; // data_length
+        let noreply = parse.next_noreply()?;
+
+        Ok(Append { key, data, prepend, noreply })
+    }
+
+    /// Apply the `Append` command. Once stored, it is queued for the replica
+    /// when there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let data = replicator.map(|_| self.data.clone());
+        let outcome = if self.prepend {
+            cache.prepend(&self.key, self.data).await
+        } else {
+            cache.append(&self.key, self.data).await
+        };
+        debug!(key = ?self.key, prepend = self.prepend, ?outcome, "concatenating");
+
+        if let (Outcome::Stored, Some(replicator), Some(data)) = (outcome, replicator, data) {
+            replicator.concat(&self.key, &data, self.prepend);
+        }
+
+        if self.noreply {
+            return Ok(());
+        }
+        let response = match outcome {
+            Outcome::Stored => ResponseFrame::Stored,
+            Outcome::TooLarge => ResponseFrame::ServerError("object too large for cache".to_string()),
+            Outcome::OutOfMemory => ResponseFrame::ServerError("out of memory storing object".to_string()),
+            _ => ResponseFrame::NotStored,
+        };
+        dst.write_and_flush(response).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{
+    cache::Outcome,
+    frame::ResponseFrame,
+    parse::Parse,
+    replication::Replicator,
+    storage::Storage,
+    Connection,
+};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug;
+
+/// Store `data` under `key` only if nobody else has changed the item since
+/// the client read it with `gets`.
+///
+/// Answers `STORED`, `EXISTS` if the item has changed, or `NOT_FOUND` if it
+/// is gone.
+#[derive(Debug)]
+pub struct Cas {
+    pub key: Bytes,
+    pub flags: u32,
+    /// Expiration time as sent by the client, see `Cache::deadline`
+    pub expiration: i64,
+    /// The CAS value `gets` returned
+    pub cas: u64,
+    pub data: Bytes,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl Cas {
+    /// Parse a `Cas` instance from a received frame.
+    ///
+    /// The `cas` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// cas <key> <flags> <exptime> <bytes> <cas unique> [noreply]
+    /// <data>
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse, data: Bytes) -> Result<Cas> {
+        let key = parse.next_key()?;
+        let flags = parse.next_u32()?;
+        let expiration = parse.next_i64()?;
+        let _ = parse.next_u32()?; // data_length
+        let cas = parse.next_u64()?;
+        let noreply = parse.next_noreply()?;
+
+        Ok(Cas { key, flags, expiration, cas, data, noreply })
+    }
+
+    /// Apply the `Cas` command. Once stored, the value is queued for the
+    /// replica when there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let expiration = cache.deadline(&self.key, self.expiration);
+        let data = replicator.map(|_| self.data.clone());
+        let outcome = cache
+            .check_and_set(&self.key, self.flags, expiration, self.data, self.cas)
+            .await;
+        debug!(key = ?self.key, ?outcome, "compare and swap");
+
+        if let (Outcome::Stored, Some(replicator), Some(data)) = (outcome, replicator, data) {
+            replicator.set(&self.key, self.flags, expiration, &data);
+        }
+
+        if self.noreply {
+            return Ok(());
+        }
+        let response = match outcome {
+            Outcome::Stored => ResponseFrame::Stored,
+            Outcome::NotStored => ResponseFrame::NotStored,
+            Outcome::TooLarge => ResponseFrame::ServerError("object too large for cache".to_string()),
+            Outcome::Exists => ResponseFrame::Exists,
+            Outcome::NotFound => ResponseFrame::NotFound,
+            Outcome::OutOfMemory => ResponseFrame::ServerError("out of memory storing object".to_string()),
+        };
+        dst.write_and_flush(response).await?;
+        Ok(())
+    }
+}
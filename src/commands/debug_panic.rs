@@ -0,0 +1,27 @@
+use crate::parse::Parse;
+use anyhow::Result;
+
+/// Panic on purpose, to exercise the server's panic isolation. Only compiled
+/// into debug builds.
+#[derive(Debug)]
+pub struct DebugPanic {}
+
+impl DebugPanic {
+    /// Parse a `DebugPanic` instance from a received frame.
+    ///
+    /// The `debug_panic` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// debug_panic
+    /// ```
+    pub(crate) fn parse_frame(_parse: &mut Parse) -> Result<DebugPanic> {
+        Ok(DebugPanic {})
+    }
+
+    /// Apply the `DebugPanic` command. Never returns.
+    pub(crate) async fn apply(self) -> Result<()> {
+        panic!("debug_panic command");
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{frame::ResponseFrame, parse::Parse, Connection};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::time;
+
+/// Sleep before answering, to exercise the latency histograms. Only
+/// compiled into debug builds.
+#[derive(Debug)]
+pub struct DebugSleep {
+    millis: u64,
+}
+
+impl DebugSleep {
+    /// Parse a `DebugSleep` instance from a received frame.
+    ///
+    /// The `debug_sleep` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// debug_sleep <milliseconds>
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<DebugSleep> {
+        let millis = parse.next_u64()?;
+        Ok(DebugSleep { millis })
+    }
+
+    /// Apply the `DebugSleep` command, answering `OK` once the time is up.
+    pub(crate) async fn apply(self, dst: &mut Connection) -> Result<()> {
+        time::sleep(Duration::from_millis(self.millis)).await;
+        dst.write_and_flush(ResponseFrame::Okay).await?;
+        Ok(())
+    }
+}
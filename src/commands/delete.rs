@@ -0,0 +1,61 @@
+use crate::detail::{Access, Detail};
+use crate::{frame::ResponseFrame, parse::Parse, replication::Replicator, storage::Storage, Connection};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug;
+
+/// Remove `key` from the cache. Answers `DELETED`, or `NOT_FOUND` if there
+/// was nothing to remove.
+#[derive(Debug)]
+pub struct Delete {
+    pub key: Bytes,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl Delete {
+    /// Parse a `Delete` instance from a received frame.
+    ///
+    /// The `delete` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// delete <key> [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Delete> {
+        let key = parse.next_key()?;
+        let noreply = parse.next_noreply()?;
+        Ok(Delete { key, noreply })
+    }
+
+    /// Apply the `Delete` command. A removal is queued for the replica when
+    /// there is one. The key is counted in `detail`.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        replicator: Option<&Replicator>,
+        detail: &Detail,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        detail.record(&self.key, Access::Delete);
+        let deleted = cache.delete(&self.key).await;
+        debug!(key = ?self.key, deleted, "deleting");
+        if deleted {
+            if let Some(replicator) = replicator {
+                replicator.delete(&self.key);
+            }
+        }
+
+        if self.noreply {
+            return Ok(());
+        }
+        let response = if deleted {
+            ResponseFrame::Deleted
+        } else {
+            ResponseFrame::NotFound
+        };
+        dst.write_and_flush(response).await?;
+        Ok(())
+    }
+}
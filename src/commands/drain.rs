@@ -0,0 +1,55 @@
+use crate::{
+    frame::ResponseFrame, health::{self, Readiness}, parse::Parse, settings::Settings,
+    stats::ServerStats, Connection,
+};
+use anyhow::{bail, Result};
+
+/// Start or stop draining, see `health::drain`. Only accepted with
+/// `--enable-shutdown`.
+#[derive(Debug)]
+pub struct Drain {
+    on: bool,
+    /// Do not answer, the client does not wait for it
+    noreply: bool,
+}
+
+impl Drain {
+    /// Parse a `Drain` instance from a received frame.
+    ///
+    /// The `drain` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// drain on|off [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Drain> {
+        let on = match &parse.next_string()?[..] {
+            "on" => true,
+            "off" => false,
+            other => bail!("protocol error; expected on or off, got {:?}", other),
+        };
+        let noreply = parse.next_noreply()?;
+        Ok(Drain { on, noreply })
+    }
+
+    /// Apply the `Drain` command, answering `OK`.
+    pub(crate) async fn apply(
+        self,
+        readiness: &Readiness,
+        stats: &ServerStats,
+        settings: &Settings,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let response = if settings.enable_shutdown {
+            health::drain(readiness, stats, self.on);
+            ResponseFrame::Okay
+        } else {
+            ResponseFrame::ClientError("drain not enabled, see --enable-shutdown".to_string())
+        };
+        if !self.noreply {
+            dst.write_and_flush(response).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{cache::Cache, export, frame::ResponseFrame, parse::Parse, settings::Settings, Connection};
+use anyhow::Result;
+
+/// Write every item to a file in `--dump-dir`, see `export::export`.
+/// Answers with how many items and bytes were written, as `STAT` lines
+/// followed by `END`.
+///
+/// Not a memcached command.
+#[derive(Debug)]
+pub struct Export {
+    /// File name in `--dump-dir`
+    name: String,
+}
+
+impl Export {
+    /// Parse an `Export` instance from a received frame.
+    ///
+    /// The `export` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// export <file>
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Export> {
+        let name = parse.next_string()?;
+        Ok(Export { name })
+    }
+
+    /// Apply the `Export` command. The file is written aside, leaving the
+    /// connection's worker free meanwhile.
+    pub(crate) async fn apply(self, cache: &Cache, settings: &Settings, dst: &mut Connection) -> Result<()> {
+        let path = match dump_path(settings, &self.name) {
+            Ok(path) => path,
+            Err(response) => return dst.write_and_flush(response).await,
+        };
+        let cache = cache.clone();
+        match tokio::task::spawn_blocking(move || export::export(&path, &cache)).await? {
+            Ok(exported) => {
+                dst.write(ResponseFrame::Stat("items".to_string(), exported.items.to_string()))
+                    .await?;
+                dst.write(ResponseFrame::Stat("bytes".to_string(), exported.bytes.to_string()))
+                    .await?;
+                dst.end_and_flush().await
+            }
+            Err(err) => dst.write_and_flush(ResponseFrame::ServerError(format!("{:#}", err))).await,
+        }
+    }
+}
+
+/// The file `name` in `--dump-dir`, or the error to answer with.
+pub(crate) fn dump_path(settings: &Settings, name: &str) -> Result<std::path::PathBuf, ResponseFrame> {
+    let Some(dir) = &settings.dump_dir else {
+        return Err(ResponseFrame::ClientError("no dump directory, see --dump-dir".to_string()));
+    };
+    export::path(dir, name).ok_or_else(|| ResponseFrame::ClientError("bad file name".to_string()))
+}
@@ -0,0 +1,60 @@
+use crate::{cache::Cache, clock, frame::ResponseFrame, parse::Parse, replication::Replicator, Connection};
+use anyhow::Result;
+use tracing::info;
+
+/// Flush every item, right away or after a delay. See `Cache::flush`.
+#[derive(Debug)]
+pub struct FlushAll {
+    /// Delay as sent by the client, read like an expiration time, see
+    /// `clock::deadline`. 0 for right away.
+    pub delay: i64,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl FlushAll {
+    /// Parse a `FlushAll` instance from a received frame.
+    ///
+    /// The `flush_all` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// flush_all [delay] [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<FlushAll> {
+        let mut flush = FlushAll { delay: 0, noreply: false };
+        if parse.complete() {
+            return Ok(flush);
+        }
+        let next = parse.next_bytes()?;
+        if &next[..] == b"noreply" {
+            flush.noreply = true;
+        } else {
+            flush.delay = Parse::new(next).next_i64()?;
+            flush.noreply = parse.next_noreply()?;
+        }
+        Ok(flush)
+    }
+
+    /// Apply the `FlushAll` command, answering `OK`. The flush is queued for
+    /// the replica when there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &Cache,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let at = clock::deadline(self.delay, cache.now());
+        cache.flush(at).await;
+        info!(?at, "flushing every item");
+        if let Some(replicator) = replicator {
+            replicator.flush(at);
+        }
+
+        if !self.noreply {
+            dst.write_and_flush(ResponseFrame::Okay).await?;
+        }
+        Ok(())
+    }
+}
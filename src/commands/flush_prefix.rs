@@ -0,0 +1,52 @@
+use crate::{cache::Cache, frame::ResponseFrame, parse::Parse, replication::Replicator, Connection};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::info;
+
+/// Remove every item whose key starts with a prefix, answering `OK`. How
+/// many items went is counted in `stats`. See `Cache::flush_prefix`.
+///
+/// Not a memcached command.
+#[derive(Debug)]
+pub struct FlushPrefix {
+    pub prefix: Bytes,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl FlushPrefix {
+    /// Parse a `FlushPrefix` instance from a received frame.
+    ///
+    /// The `flush_prefix` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// flush_prefix <prefix> [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<FlushPrefix> {
+        let prefix = parse.next_key()?;
+        let noreply = parse.next_noreply()?;
+        Ok(FlushPrefix { prefix, noreply })
+    }
+
+    /// Apply the `FlushPrefix` command. The flush is queued for the replica
+    /// when there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &Cache,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let flushed = cache.flush_prefix(&self.prefix).await;
+        info!(prefix = ?self.prefix, flushed, "flushing a prefix");
+        if let Some(replicator) = replicator {
+            replicator.flush_prefix(&self.prefix);
+        }
+
+        if !self.noreply {
+            dst.write_and_flush(ResponseFrame::Okay).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,13 @@
-use crate::{cache::Cache, frame::ResponseFrame, parse::Parse, Connection};
+use crate::{
+    detail::{Access, Detail},
+    frame::{RequestFrame, ResponseFrame},
+    parse::Parse,
+    storage::Storage,
+    Connection,
+};
 use anyhow::Result;
-use log::debug;
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::debug;
 
 /// Get the value of key.
 ///
@@ -9,19 +16,26 @@ use log::debug;
 /// handles string values.
 #[derive(Debug)]
 pub struct Get {
-    keys: Vec<String>,
+    keys: Vec<Bytes>,
+    /// Return the CAS of every item too, for `gets`
+    with_cas: bool,
 }
 
 impl Get {
     /// Create a new `Get` command which fetches `key`.
-    pub fn new(keys: Vec<String>) -> Get {
-        Get { keys }
+    pub fn new(keys: Vec<Bytes>) -> Get {
+        Get { keys, with_cas: false }
     }
 
-    // /// Get the key
-    // pub fn key(&self) -> &str {
-    //     &self.key
-    // }
+    /// Whether this is `gets`, which returns the CAS of every item too.
+    pub(crate) fn with_cas(&self) -> bool {
+        self.with_cas
+    }
+
+    /// Returns the keys to fetch
+    pub(crate) fn keys(&self) -> &[Bytes] {
+        &self.keys
+    }
 
     /// Parse a `Get` instance from a received frame.
     ///
@@ -29,7 +43,8 @@ impl Get {
     /// `Frame`. At this point, the entire frame has already been received from
     /// the socket.
     ///
-    /// The `GET` string has already been consumed.
+    /// The `get` or `gets` string has already been consumed, `with_cas` for
+    /// the latter.
     ///
     /// # Returns
     ///
@@ -41,52 +56,78 @@ impl Get {
     /// Expects an array frame containing two entries.
     ///
     /// ```text
-    /// GET key
+    /// get|gets <key>*
     /// ```
-    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Get> {
-        let mut keys = vec![parse.next_string()?];
+    pub(crate) fn parse_frame(parse: &mut Parse, with_cas: bool) -> Result<Get> {
+        let mut keys = vec![parse.next_key()?];
 
         while !parse.complete() {
-            keys.push(parse.next_string()?)
+            keys.push(parse.next_key()?)
         }
 
-        Ok(Get { keys })
+        Ok(Get { keys, with_cas })
+    }
+
+    /// Converts the command into the frame a client sends for it.
+    pub(crate) fn into_frame(self) -> RequestFrame {
+        let name: &[u8] = if self.with_cas { b"gets" } else { b"get" };
+        let mut line = BytesMut::from(name);
+        for key in &self.keys {
+            line.put_u8(b' ');
+            line.put_slice(key);
+        }
+        RequestFrame::Other(line.freeze())
     }
 
-    /// Apply the `Get` command to the specified `Cache` instance.
+    /// Apply the `Get` command to the specified `Storage` instance. Several
+    /// keys are looked up together, see `Cache::get_multi`.
     ///
     /// The response is written to `dst`. This is called by the server in order
-    /// to execute a received command.
-    pub(crate) async fn apply(self, cache: Cache, dst: &mut Connection) -> Result<()> {
+    /// to execute a received command. Each key is counted in `detail`.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        detail: &Detail,
+        dst: &mut Connection,
+    ) -> Result<()> {
         // If there is only one key skip loop
         if self.keys.len() == 1 {
             let key = &self.keys[0];
-            
-            if let Some(item) = cache.get(&key).await {
+
+            let item = cache.get(key).await;
+            detail.record(key, Access::Get { hit: item.is_some() });
+            if let Some(item) = item {
+                debug!(key = ?key, bytes = item.data.len(), "hit");
                 let frame = ResponseFrame::Value {
                     key: key.clone(),
                     flags: item.flags,
                     data_length: item.data.len(),
-                    cas: None,
+                    cas: self.with_cas.then_some(item.cas),
                     data: item.data,
                 };
-                debug!("{:?}", frame);
                 dst.write_and_end(frame).await?;
+            } else {
+                debug!(key = ?key, "miss");
+                dst.end_and_flush().await?;
             }
             return Ok(());
         }
 
-        for key in self.keys {
-            if let Some(item) = cache.get(&key).await {
+        let items = cache.get_multi(&self.keys).await;
+        for (key, item) in self.keys.into_iter().zip(items) {
+            detail.record(&key, Access::Get { hit: item.is_some() });
+            if let Some(item) = item {
+                debug!(key = ?key, bytes = item.data.len(), "hit");
                 let frame = ResponseFrame::Value {
                     key,
                     flags: item.flags,
                     data_length: item.data.len(),
-                    cas: None,
+                    cas: self.with_cas.then_some(item.cas),
                     data: item.data,
                 };
-                debug!("{:?}", frame);
-                dst.write(frame);
+                dst.write(frame).await?;
+            } else {
+                debug!(key = ?key, "miss");
             }
         }
 
@@ -94,3 +135,103 @@ impl Get {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::Cache;
+    use crate::commands::Command;
+    use crate::detail::Detail;
+    use crate::frame::RequestFrame;
+    use crate::Connection;
+    use bytes::Bytes;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the allocations made on each thread.
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    /// Allocations made by a 100-key multiget. Keys share the buffer of the
+    /// request line, so parsing only grows the list of keys; what is left
+    /// per key is the lookup and formatting the `VALUE` line.
+    #[tokio::test]
+    async fn test_multiget_allocations() {
+        let cache = Cache::new();
+        let keys: Vec<String> = (0..100).map(|key| format!("key{:03}", key)).collect();
+        for key in &keys {
+            cache.set(Bytes::from(key.clone()), 0, None, Bytes::from("value")).await;
+        }
+        let line = Bytes::from(format!("get {}", keys.join(" ")));
+        let (_client, server) = tokio::io::duplex(64 * 1024);
+        let mut dst = Connection::new(server);
+        let detail = Detail::default();
+
+        let start = allocations();
+        let Command::Get(get) = Command::from_frame(RequestFrame::Other(line)).unwrap() else {
+            unreachable!()
+        };
+        let parsed = allocations();
+        get.apply(&cache, &detail, &mut dst).await.unwrap();
+        let done = allocations();
+        assert!(
+            parsed - start < 16,
+            "100-key multiget: {} allocations parsing, {} applying",
+            parsed - start,
+            done - parsed
+        );
+    }
+
+    /// Allocations made by a hit on a value too long to be kept in the item,
+    /// parsing aside. The number fields of the `VALUE` line are formatted on
+    /// the stack and the item is returned without its key, so once the
+    /// response buffer has grown there are none. Shorter values are copied
+    /// out, one allocation each.
+    #[tokio::test]
+    async fn test_get_hit_allocations() {
+        use tokio::io::AsyncReadExt;
+
+        let cache = Cache::new();
+        let value = "a value past the length kept inline";
+        cache.set(Bytes::from("key"), 0, None, Bytes::from(value)).await;
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut dst = Connection::new(server);
+        let detail = Detail::default();
+        let mut response = vec![0; 64];
+
+        for round in 0..3 {
+            let Command::Get(get) = Command::from_frame(RequestFrame::Other(Bytes::from("get key"))).unwrap() else {
+                unreachable!()
+            };
+            let start = allocations();
+            get.apply(&cache, &detail, &mut dst).await.unwrap();
+            let done = allocations();
+            let read = client.read(&mut response).await.unwrap();
+            let expected = format!("VALUE key 0 {}\r\n{}\r\nEND\r\n", value.len(), value);
+            assert_eq!(&response[..read], expected.as_bytes());
+            if round > 0 {
+                assert_eq!(done - start, 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{
+    cache::{Cache, Revalidated},
+    frame::ResponseFrame,
+    parse::Parse,
+    Connection,
+};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug;
+
+/// Get the item stored under `key` unless the client's copy, read with CAS
+/// `cas`, is still current, see `Cache::get_if_modified`.
+///
+/// Answers like `gets` if the item has changed or is gone, or `EXISTS`
+/// without the value if it has not.
+#[derive(Debug)]
+pub struct GetIfModified {
+    /// The CAS `gets` returned with the client's copy
+    cas: u64,
+    key: Bytes,
+}
+
+impl GetIfModified {
+    /// Parse a `GetIfModified` instance from a received frame.
+    ///
+    /// The `get_if_modified` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// get_if_modified <cas unique> <key>
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<GetIfModified> {
+        let cas = parse.next_u64()?;
+        let key = parse.next_key()?;
+        Ok(GetIfModified { cas, key })
+    }
+
+    /// Apply the `GetIfModified` command. The response is written to `dst`.
+    pub(crate) async fn apply(self, cache: &Cache, dst: &mut Connection) -> Result<()> {
+        match cache.get_if_modified(&self.key, self.cas).await {
+            Some(Revalidated::Unchanged) => {
+                debug!(key = ?self.key, cas = self.cas, "unchanged");
+                dst.write_and_flush(ResponseFrame::Exists).await?;
+            }
+            Some(Revalidated::Changed(item)) => {
+                debug!(key = ?self.key, bytes = item.data.len(), "changed");
+                let frame = ResponseFrame::Value {
+                    key: self.key,
+                    flags: item.flags,
+                    data_length: item.data.len(),
+                    cas: Some(item.cas),
+                    data: item.data,
+                };
+                dst.write_and_end(frame).await?;
+            }
+            None => {
+                debug!(key = ?self.key, "miss");
+                dst.end_and_flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
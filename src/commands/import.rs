@@ -0,0 +1,76 @@
+use crate::{
+    cache::Cache,
+    commands::export::dump_path,
+    export,
+    frame::ResponseFrame,
+    parse::{Parse, ParseError},
+    replication::Replicator,
+    settings::Settings,
+    Connection,
+};
+use anyhow::Result;
+
+/// Store the items of a file written by `export`, see `export::import`.
+/// Answers with how many items were stored, skipped and left out as
+/// expired, as `STAT` lines followed by `END`.
+///
+/// Not a memcached command.
+#[derive(Debug)]
+pub struct Import {
+    /// File name in `--dump-dir`
+    name: String,
+    /// Replace the items already there under the same keys, rather than
+    /// skip those in the file
+    overwrite: bool,
+}
+
+impl Import {
+    /// Parse an `Import` instance from a received frame.
+    ///
+    /// The `import` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// import <file> [overwrite]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Import> {
+        let name = parse.next_string()?;
+        let mut overwrite = false;
+        if !parse.complete() {
+            if parse.next_string()? != "overwrite" {
+                return Err(ParseError::LineToLong.into());
+            }
+            overwrite = true;
+        }
+        Ok(Import { name, overwrite })
+    }
+
+    /// Apply the `Import` command. Imported items are queued for the
+    /// replica when there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &Cache,
+        settings: &Settings,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let path = match dump_path(settings, &self.name) {
+            Ok(path) => path,
+            Err(response) => return dst.write_and_flush(response).await,
+        };
+        match export::import(&path, cache, self.overwrite, replicator).await {
+            Ok(imported) => {
+                for (name, value) in [
+                    ("items", imported.items),
+                    ("skipped", imported.skipped),
+                    ("expired", imported.expired),
+                ] {
+                    dst.write(ResponseFrame::Stat(name.to_string(), value.to_string())).await?;
+                }
+                dst.end_and_flush().await
+            }
+            Err(err) => dst.write_and_flush(ResponseFrame::ServerError(format!("{:#}", err))).await,
+        }
+    }
+}
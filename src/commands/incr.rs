@@ -0,0 +1,71 @@
+use crate::{
+    cache::{Delta, Direction},
+    frame::ResponseFrame,
+    parse::Parse,
+    replication::Replicator,
+    storage::Storage,
+    Connection,
+};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug;
+
+/// Increment, or for `decr` decrement, the decimal number stored under
+/// `key`. See `Cache::add_delta`.
+///
+/// Answers the new number, or `NOT_FOUND` if there is no such item.
+#[derive(Debug)]
+pub struct Incr {
+    pub key: Bytes,
+    pub delta: u64,
+    pub direction: Direction,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl Incr {
+    /// Parse an `Incr` instance from a received frame.
+    ///
+    /// The `incr` or `decr` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// incr|decr <key> <delta> [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse, direction: Direction) -> Result<Incr> {
+        let key = parse.next_key()?;
+        let delta = parse.next_u64()?;
+        let noreply = parse.next_noreply()?;
+        Ok(Incr { key, delta, direction, noreply })
+    }
+
+    /// Apply the `Incr` command. A change is queued for the replica when
+    /// there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let delta = cache.add_delta(&self.key, self.delta, self.direction).await;
+        debug!(key = ?self.key, direction = ?self.direction, ?delta, "changing number");
+
+        if let (Delta::Value(_), Some(replicator)) = (delta, replicator) {
+            replicator.delta(&self.key, self.delta, self.direction);
+        }
+
+        if self.noreply {
+            return Ok(());
+        }
+        let response = match delta {
+            Delta::Value(value) => ResponseFrame::Crement(value),
+            Delta::NotFound => ResponseFrame::NotFound,
+            Delta::NonNumeric => ResponseFrame::ClientError(
+                "cannot increment or decrement non-numeric value".to_string(),
+            ),
+        };
+        dst.write_and_flush(response).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    cache::{footprint, Cache},
+    frame::ResponseFrame,
+    parse::Parse,
+    Connection,
+};
+use anyhow::Result;
+use std::fmt::Write;
+
+/// Lines written between flushes, letting other connections have a turn.
+const DUMP_BATCH: usize = 1024;
+
+/// Of memcached's `lru_crawler` commands, only `metadump all`: a line of
+/// metadata for every item, followed by `END`.
+///
+/// ```text
+/// key=<key> exp=<exptime> la=<time> cas=<cas> fetch=<yes|no> cls=1 size=<bytes>
+/// ```
+///
+/// Keys are URL-encoded; times are seconds since the unix epoch, `exp=-1`
+/// for never. There are no slab classes, so every item is in class 1.
+#[derive(Debug)]
+pub struct LruCrawler {
+    subcommand: String,
+    /// What to dump, for `metadump`
+    classes: Option<String>,
+}
+
+impl LruCrawler {
+    /// Parse a `LruCrawler` instance from a received frame.
+    ///
+    /// The `lru_crawler` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// lru_crawler metadump all
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<LruCrawler> {
+        let subcommand = parse.next_string()?;
+        let classes = if parse.complete() {
+            None
+        } else {
+            Some(parse.next_string()?)
+        };
+        Ok(LruCrawler { subcommand, classes })
+    }
+
+    /// Apply the `LruCrawler` command, dumping the items as the dump comes
+    /// to them, see `Cache::metadata`. No value is copied, and no lock is
+    /// held while the lines are written, so a client reading slowly or going
+    /// away holds up nothing.
+    pub(crate) async fn apply(self, cache: &Cache, dst: &mut Connection) -> Result<()> {
+        if self.subcommand != "metadump" || self.classes.as_deref() != Some("all") {
+            let response = ResponseFrame::ClientError("only metadump all is supported".to_string());
+            dst.write_and_flush(response).await?;
+            return Ok(());
+        }
+
+        let mut written = 0;
+        for meta in cache.metadata() {
+            let mut line = String::with_capacity(meta.key.len() * 3 + 96);
+            line.push_str("key=");
+            url_encode(&mut line, &meta.key);
+            let _ = write!(
+                line,
+                " exp={} la={} cas={} fetch={} cls=1 size={}",
+                meta.expiration.map_or(-1, |expiration| expiration as i64),
+                meta.access.last,
+                meta.cas,
+                if meta.access.fetched { "yes" } else { "no" },
+                footprint(meta.key.len(), meta.len),
+            );
+            dst.write(ResponseFrame::Line(line)).await?;
+            written += 1;
+            if written % DUMP_BATCH == 0 {
+                // Even within a batch of pipelined requests
+                dst.flush_now().await?;
+                tokio::task::yield_now().await;
+            }
+        }
+        dst.end_and_flush().await?;
+        Ok(())
+    }
+}
+
+/// Append `key` to `out` with every byte but letters, digits and `-._~`
+/// percent-encoded, as memcached does.
+fn url_encode(out: &mut String, key: &[u8]) {
+    for &byte in key {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            let _ = write!(out, "%{:02X}", byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_encode() {
+        let mut out = String::new();
+        url_encode(&mut out, b"user:1/a b~%\xff");
+        assert_eq!(out, "user%3A1%2Fa%20b~%25%FF");
+    }
+}
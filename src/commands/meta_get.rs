@@ -0,0 +1,87 @@
+use crate::{
+    cache::{Cache, Freshness},
+    frame::ResponseFrame,
+    parse::Parse,
+    Connection,
+};
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::io::Write;
+use tracing::debug;
+
+/// Get the item stored under `key`, the meta protocol way, served stale for
+/// a while once it expired, see `Cache::get_stale`. Of the flags of the
+/// meta protocol's `mg`, those asking for the item are known:
+///
+/// - `v`: the value
+/// - `f`: the client flags
+/// - `c`: the CAS
+/// - `t`: the seconds until it expires, `-1` if it does not
+/// - `k`: the key
+///
+/// Answers `VA <size> <flags>*` and the value, or `HD <flags>*` without
+/// `v`, or `EN` if there is no such item. An expired item also has `X`, and
+/// `W` if the client should store a new value or else `Z`.
+#[derive(Debug)]
+pub struct MetaGet {
+    key: Bytes,
+    flags: Vec<char>,
+}
+
+impl MetaGet {
+    /// Parse a `MetaGet` instance from a received frame.
+    ///
+    /// The `mg` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// mg <key> <flag>*
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<MetaGet> {
+        let key = parse.next_key()?;
+        let mut flags = Vec::new();
+        while !parse.complete() {
+            match &parse.next_string()?[..] {
+                flag @ ("v" | "f" | "c" | "t" | "k") => flags.extend(flag.chars()),
+                other => bail!("protocol error; unknown mg flag {:?}", other),
+            }
+        }
+        Ok(MetaGet { key, flags })
+    }
+
+    /// Apply the `MetaGet` command. The response is written to `dst`.
+    pub(crate) async fn apply(self, cache: &Cache, dst: &mut Connection) -> Result<()> {
+        let Some((item, freshness)) = cache.get_stale(&self.key).await else {
+            debug!(key = ?self.key, "miss");
+            dst.write_and_flush(ResponseFrame::Line("EN".to_string())).await?;
+            return Ok(());
+        };
+        debug!(key = ?self.key, bytes = item.data.len(), ?freshness, "hit");
+
+        let mut flags = Vec::new();
+        for flag in &self.flags {
+            match flag {
+                'f' => write!(flags, " f{}", item.flags)?,
+                'c' => write!(flags, " c{}", item.cas)?,
+                't' => match item.expiration {
+                    Some(expiration) => write!(flags, " t{}", expiration.saturating_sub(cache.now()))?,
+                    None => flags.extend_from_slice(b" t-1"),
+                },
+                'k' => {
+                    flags.extend_from_slice(b" k");
+                    flags.extend_from_slice(&self.key);
+                }
+                _ => {}
+            }
+        }
+        match freshness {
+            Freshness::Fresh => {}
+            Freshness::Won => flags.extend_from_slice(b" W X"),
+            Freshness::Stale => flags.extend_from_slice(b" X Z"),
+        }
+        let data = self.flags.contains(&'v').then_some(item.data);
+        dst.write_and_flush(ResponseFrame::Meta { flags, data }).await?;
+        Ok(())
+    }
+}
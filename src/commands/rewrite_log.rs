@@ -0,0 +1,39 @@
+use crate::{cache::Cache, frame::ResponseFrame, parse::Parse, Connection};
+use anyhow::Result;
+
+/// Rewrite the append log now rather than once it has grown, see
+/// `AppendLog::rewrite`. Answers once the rewrite has started.
+#[derive(Debug)]
+pub struct RewriteLog {
+    /// Do not answer, the client does not wait for it
+    noreply: bool,
+}
+
+impl RewriteLog {
+    /// Parse a `RewriteLog` instance from a received frame.
+    ///
+    /// The `rewrite_log` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// rewrite_log [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<RewriteLog> {
+        let noreply = parse.next_noreply()?;
+        Ok(RewriteLog { noreply })
+    }
+
+    /// Apply the `RewriteLog` command, answering `OK`.
+    pub(crate) async fn apply(self, cache: &Cache, dst: &mut Connection) -> Result<()> {
+        let response = match cache.append_log() {
+            Some(log) if log.rewrite(cache) => ResponseFrame::Okay,
+            Some(_) => ResponseFrame::ServerError("log rewrite already under way".to_string()),
+            None => ResponseFrame::ClientError("no append log, see --append-log".to_string()),
+        };
+        if !self.noreply {
+            dst.write_and_flush(response).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{cache::Cache, frame::ResponseFrame, parse::Parse, Connection};
+use anyhow::Result;
+use bytes::Bytes;
+use std::fmt::Write;
+
+/// Most keys a single `scan` returns, so one does not hold up the index for
+/// long.
+const MAX_LIMIT: usize = 1000;
+
+/// List the keys starting with a prefix, in order, as `KEY <key>` lines
+/// followed by `END`. When there may be more, a `NEXT <token>` line comes
+/// before `END`; sending the token back goes on from there.
+///
+/// Not a memcached command, see `Cache::scan`.
+#[derive(Debug)]
+pub struct Scan {
+    prefix: Bytes,
+    limit: usize,
+    /// Where a previous `scan` stopped, as sent by the client
+    token: Option<Bytes>,
+}
+
+impl Scan {
+    /// Parse a `Scan` instance from a received frame.
+    ///
+    /// The `scan` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// scan <prefix> <limit> [token]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Scan> {
+        let prefix = parse.next_key()?;
+        let limit = parse.next_u32()? as usize;
+        let token = if parse.complete() {
+            None
+        } else {
+            Some(parse.next_bytes()?)
+        };
+        Ok(Scan { prefix, limit, token })
+    }
+
+    /// Apply the `Scan` command. Limits past `MAX_LIMIT` and tokens that do
+    /// not come from a `scan` are answered with `CLIENT_ERROR`.
+    pub(crate) async fn apply(self, cache: &Cache, dst: &mut Connection) -> Result<()> {
+        if self.limit == 0 || self.limit > MAX_LIMIT {
+            let response = ResponseFrame::ClientError(format!("limit must be 1 to {MAX_LIMIT}"));
+            dst.write_and_flush(response).await?;
+            return Ok(());
+        }
+        let after = match self.token.as_deref().map(decode_token) {
+            None => None,
+            Some(Some(after)) => Some(after),
+            Some(None) => {
+                let response = ResponseFrame::ClientError("bad scan token".to_string());
+                dst.write_and_flush(response).await?;
+                return Ok(());
+            }
+        };
+
+        let (keys, last) = cache.scan(&self.prefix, after.as_deref(), self.limit);
+        for key in keys {
+            dst.write(ResponseFrame::Key(key)).await?;
+        }
+        if let Some(last) = last {
+            dst.write(ResponseFrame::Next(encode_token(&last))).await?;
+        }
+        dst.end_and_flush().await?;
+        Ok(())
+    }
+}
+
+/// The token for a scan going on after `key`: the key in hex, which keeps
+/// it a single word whatever the key holds.
+fn encode_token(key: &[u8]) -> String {
+    key.iter().fold(String::with_capacity(key.len() * 2), |mut token, byte| {
+        let _ = write!(token, "{byte:02x}");
+        token
+    })
+}
+
+/// The key `token` goes on after, if it is one `encode_token` makes.
+fn decode_token(token: &[u8]) -> Option<Vec<u8>> {
+    if !token.len().is_multiple_of(2) || !token.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    token
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_round_trips() {
+        let token = encode_token(b"user:\x00\xff");
+        assert_eq!(token, "757365723a00ff");
+        assert_eq!(decode_token(token.as_bytes()).unwrap(), b"user:\x00\xff");
+        assert_eq!(decode_token(b"abc"), None);
+        assert_eq!(decode_token(b"zz"), None);
+        assert_eq!(decode_token(b"+1"), None);
+    }
+}
@@ -1,13 +1,15 @@
 use crate::{
-    cache::{Cache, Item},
-    frame::ResponseFrame,
+    cache::Outcome,
+    detail::{Access, Detail},
+    frame::{RequestFrame, ResponseFrame, StorageFrame},
     parse::Parse,
+    replication::Replicator,
+    storage::Storage,
     Connection,
 };
 use anyhow::Result;
-use bytes::Bytes;
-use log::debug;
-use std::time::Duration;
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::debug;
 
 /// Set `key` to hold the string `value`.
 ///
@@ -23,54 +25,103 @@ use std::time::Duration;
 /// * PX `milliseconds` -- Set the specified expire time, in milliseconds.
 #[derive(Debug)]
 pub struct Set {
-    pub key: String,
+    pub key: Bytes,
     pub flags: u32,
     pub cas: u64,
-    pub expiration: Option<u32>,
+    /// Expiration time as sent by the client, see `Cache::deadline`
+    pub expiration: i64,
     pub data: Bytes,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
 }
 
 impl Set {
     /// Create a new `Set` command which sets `key` to `value`.
     ///
-    /// If `expire` is `Some`, the value should expire after the specified
-    /// duration.
-    pub fn new(key: String, flags: u32, expiration: Option<u32>, data: Bytes) -> Set {
+    /// The value expires as `expiration` says, see `clock::deadline`; 0 for
+    /// never.
+    pub fn new(key: Bytes, flags: u32, expiration: i64, data: Bytes) -> Set {
         Set {
             key,
             flags,
             expiration,
             cas: 0,
             data,
+            noreply: false,
         }
     }
 
     pub(crate) fn parse_frame(parse: &mut Parse, data: Bytes) -> Result<Set> {
         // Read the key to set. This is a required field
-        let key = parse.next_string()?;
+        let key = parse.next_key()?;
 
         // Read the value to set. This is a required field.
         let flags = parse.next_u32()?;
 
-        // ToDo: convert expiration
-        let expiration = parse.next_u32()?;
+        let expiration = parse.next_i64()?;
 
         let _ = parse.next_u32()?; // data_length
 
-        Ok(Set { key, flags, cas: 0, expiration: Some(expiration), data })
+        let noreply = parse.next_noreply()?;
+
+        Ok(Set { key, flags, cas: 0, expiration, data, noreply })
+    }
+
+    /// Converts the command into the frame a client sends for it.
+    pub(crate) fn into_frame(self) -> RequestFrame {
+        let mut command_line = BytesMut::from(&b"set "[..]);
+        command_line.put_slice(&self.key);
+        command_line.put_slice(format!(" {} {} {}", self.flags, self.expiration, self.data.len()).as_bytes());
+        if self.noreply {
+            command_line.put_slice(b" noreply");
+        }
+        RequestFrame::Storage(StorageFrame {
+            command_line: command_line.freeze(),
+            data: self.data,
+        })
     }
 
     /// Apply the `Set` command to the specified `Db` instance.
     ///
     /// The response is written to `dst`. This is called by the server in order
-    /// to execute a received command.
-    pub(crate) async fn apply(self, cache: Cache, dst: &mut Connection) -> Result<()> {
+    /// to execute a received command. Once stored, the value is queued for
+    /// the replica when there is one. The key is counted in `detail`.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        replicator: Option<&Replicator>,
+        detail: &Detail,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        debug!(key = ?self.key, bytes = self.data.len(), "storing");
+        detail.record(&self.key, Access::Set);
+
+        let expiration = cache.deadline(&self.key, self.expiration);
+
         // Set the value in the shared database state.
-        cache.set(self.key, self.flags, self.expiration, self.data);
+        let outcome = match replicator {
+            Some(replicator) => {
+                let outcome = cache
+                    .set(self.key.clone(), self.flags, expiration, self.data.clone())
+                    .await;
+                if outcome == Outcome::Stored {
+                    replicator.set(&self.key, self.flags, expiration, &self.data);
+                }
+                outcome
+            }
+            None => cache.set(self.key, self.flags, expiration, self.data).await,
+        };
+
+        if self.noreply {
+            return Ok(());
+        }
 
-        // Create a success response and write it to `dst`.
-        let response = ResponseFrame::Stored;
-        debug!("{:?}", response);
+        // Create a response and write it to `dst`.
+        let response = match outcome {
+            Outcome::TooLarge => ResponseFrame::ServerError("object too large for cache".to_string()),
+            Outcome::OutOfMemory => ResponseFrame::ServerError("out of memory storing object".to_string()),
+            _ => ResponseFrame::Stored,
+        };
         dst.write_and_flush(response).await?;
 
         Ok(())
@@ -0,0 +1,208 @@
+use crate::server::MAX_CONNECTIONS;
+use crate::stats::{self, ServerStats};
+use crate::{cache::Cache, frame::ResponseFrame, parse::Parse, settings::Settings, Connection};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tracing::debug;
+
+/// Most keys `stats ttl` looks at, so that it answers in bounded time
+/// however large the cache.
+const TTL_BUDGET: usize = 1_000_000;
+
+/// Report server statistics as `STAT <name> <value>` lines followed by `END`.
+#[derive(Debug)]
+pub struct Stats {
+    /// Which statistics to report. `None` for the general counters.
+    group: Option<String>,
+    /// What to do with the counts per prefix, for `stats detail`
+    detail: Option<String>,
+}
+
+impl Stats {
+    /// Parse a `Stats` instance from a received frame.
+    ///
+    /// The `stats` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// stats [group]
+    /// stats detail on|off|dump
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Stats> {
+        let group = if parse.complete() {
+            None
+        } else {
+            Some(parse.next_string()?)
+        };
+        let detail = if group.as_deref() == Some("detail") && !parse.complete() {
+            Some(parse.next_string()?)
+        } else {
+            None
+        };
+        Ok(Stats { group, detail })
+    }
+
+    /// Apply the `Stats` command, writing the requested statistics to `dst`.
+    ///
+    /// The counters come after what memcached reports first and monitoring
+    /// expects of it: `uptime`, `time`, `version`, `pointer_size`, the CPU
+    /// time used as `rusage_user` and `rusage_system`, `max_connections` and
+    /// `threads`.
+    ///
+    /// `stats settings` reports the server settings instead of the counters,
+    /// `stats items` the eviction and expiry counters the way memcached
+    /// reports them per slab class, `stats conns` the open TCP connections,
+    /// see `Registry::snapshot`, `stats quotas` the memory used per
+    /// `--quota` prefix, and `stats ttl`
+    /// how long items have left to live, see `Cache::ttl_histogram`, and
+    /// `stats integrity` whether keys and items have lost each other, see
+    /// `Cache::verify`; nothing is repaired. Those two are worked out aside,
+    /// and in full before any line is written. `stats latency` reports how
+    /// long each command took, see `Latencies`, and `stats reset` forgets
+    /// those times, answering `RESET`; the counters are left alone.
+    /// `stats detail on` and `off` start and stop counting the keys read,
+    /// found, stored and deleted per prefix, see `Detail`, and `stats detail
+    /// dump` reports those counts.
+    /// Unknown groups are answered with `ERROR`.
+    pub(crate) async fn apply(
+        self,
+        stats: &ServerStats,
+        cache: &Cache,
+        settings: &Settings,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let lines = match self.group.as_deref() {
+            None => {
+                let mut lines = process(stats, cache, settings);
+                lines.extend(stats.snapshot().into_iter().map(|(name, value)| (name, value.to_string())));
+                lines.extend(cache.stats().into_iter().map(|(name, value)| (name, value.to_string())));
+                lines.push((
+                    "max_items".to_string(),
+                    settings.max_items.unwrap_or(0).to_string(),
+                ));
+                if let Some(overflow) = cache.overflow() {
+                    lines.extend(
+                        overflow
+                            .stats()
+                            .into_iter()
+                            .map(|(name, value)| (name.to_string(), value.to_string())),
+                    );
+                }
+                if let Some(log) = cache.append_log() {
+                    lines.push(("log_bytes".to_string(), log.size().to_string()));
+                    lines.push((
+                        "log_rewrite_in_progress".to_string(),
+                        u8::from(log.rewriting()).to_string(),
+                    ));
+                }
+                lines
+            }
+            Some("settings") => settings.snapshot(),
+            Some("conns") => stats.registry().snapshot(),
+            Some("items") => cache
+                .item_stats()
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect(),
+            Some("quotas") => cache.quotas().map_or_else(Vec::new, |quotas| {
+                quotas
+                    .stats()
+                    .into_iter()
+                    .map(|(name, value)| (name, value.to_string()))
+                    .collect()
+            }),
+            Some("ttl") => {
+                let cache = cache.clone();
+                tokio::task::spawn_blocking(move || cache.ttl_histogram(TTL_BUDGET))
+                    .await?
+                    .snapshot()
+                    .into_iter()
+                    .map(|(name, value)| (name, value.to_string()))
+                    .collect()
+            }
+            Some("latency") => stats
+                .latency()
+                .snapshot()
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect(),
+            Some("detail") => {
+                let detail = stats.detail();
+                match self.detail.as_deref() {
+                    Some("on") => {
+                        let delimiter = settings.detail_delimiter as u8;
+                        detail.on(delimiter, settings.detail_max_prefixes as usize);
+                    }
+                    Some("off") => detail.off(),
+                    Some("dump") => {
+                        for line in detail.dump() {
+                            dst.write(ResponseFrame::Line(line)).await?;
+                        }
+                        dst.end_and_flush().await?;
+                        return Ok(());
+                    }
+                    _ => {
+                        let usage = "usage: stats detail on|off|dump".to_string();
+                        dst.write_and_flush(ResponseFrame::ClientError(usage)).await?;
+                        return Ok(());
+                    }
+                }
+                dst.write_and_flush(ResponseFrame::Okay).await?;
+                return Ok(());
+            }
+            Some("reset") => {
+                stats.latency().reset();
+                dst.write_and_flush(ResponseFrame::Line("RESET".to_string())).await?;
+                return Ok(());
+            }
+            Some("integrity") => {
+                let cache = cache.clone();
+                tokio::task::spawn_blocking(move || cache.verify(false))
+                    .await?
+                    .snapshot()
+                    .into_iter()
+                    .map(|(name, value)| (name, value.to_string()))
+                    .collect()
+            }
+            Some(_) => {
+                dst.write_and_flush(ResponseFrame::Error).await?;
+                return Ok(());
+            }
+        };
+
+        debug!(group = ?self.group, stats = lines.len(), "reporting stats");
+        for (name, value) in lines {
+            dst.write(ResponseFrame::Stat(name, value)).await?;
+        }
+
+        dst.end_and_flush().await?;
+        Ok(())
+    }
+}
+
+/// What memcached reports of the server process, ahead of the counters.
+fn process(stats: &ServerStats, cache: &Cache, settings: &Settings) -> Vec<(String, String)> {
+    let (user, system) = stats::rusage().unwrap_or_default();
+    let seconds = |time: Duration| format!("{}.{:06}", time.as_secs(), time.subsec_micros());
+    // Pinned, each acceptor serves its connections on a thread of its own
+    let threads = if settings.core_pinned {
+        stats.acceptors()
+    } else {
+        Handle::current().metrics().num_workers()
+    };
+    [
+        ("uptime", stats.uptime().to_string()),
+        ("time", cache.now().to_string()),
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("pointer_size", usize::BITS.to_string()),
+        ("rusage_user", seconds(user)),
+        ("rusage_system", seconds(system)),
+        ("max_connections", MAX_CONNECTIONS.to_string()),
+        ("threads", threads.to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect()
+}
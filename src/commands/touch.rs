@@ -0,0 +1,62 @@
+use crate::{frame::ResponseFrame, parse::Parse, replication::Replicator, storage::Storage, Connection};
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug;
+
+/// Change when the item stored under `key` expires. Answers `TOUCHED`, or
+/// `NOT_FOUND` if there is no such item.
+#[derive(Debug)]
+pub struct Touch {
+    pub key: Bytes,
+    /// Expiration time as sent by the client, see `Cache::deadline`
+    pub expiration: i64,
+    /// Do not answer, the client does not wait for it
+    pub noreply: bool,
+}
+
+impl Touch {
+    /// Parse a `Touch` instance from a received frame.
+    ///
+    /// The `touch` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// touch <key> <exptime> [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Touch> {
+        let key = parse.next_key()?;
+        let expiration = parse.next_i64()?;
+        let noreply = parse.next_noreply()?;
+        Ok(Touch { key, expiration, noreply })
+    }
+
+    /// Apply the `Touch` command. The change is queued for the replica when
+    /// there is one.
+    pub(crate) async fn apply(
+        self,
+        cache: &impl Storage,
+        replicator: Option<&Replicator>,
+        dst: &mut Connection,
+    ) -> Result<()> {
+        let expiration = cache.deadline(&self.key, self.expiration);
+        let touched = cache.touch(&self.key, expiration).await;
+        debug!(key = ?self.key, touched, "touching");
+        if touched {
+            if let Some(replicator) = replicator {
+                replicator.touch(&self.key, expiration);
+            }
+        }
+
+        if self.noreply {
+            return Ok(());
+        }
+        let response = if touched {
+            ResponseFrame::Touched
+        } else {
+            ResponseFrame::NotFound
+        };
+        dst.write_and_flush(response).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{frame::ResponseFrame, logging, parse::Parse, settings::Settings, Connection};
+use anyhow::Result;
+use arc_swap::ArcSwap;
+
+/// Change how much the server logs, see `--verbosity`. Takes effect
+/// immediately, for every connection, and lasts until the next reload.
+#[derive(Debug)]
+pub struct Verbosity {
+    level: u8,
+    /// Do not answer, the client does not wait for it
+    noreply: bool,
+}
+
+impl Verbosity {
+    /// Parse a `Verbosity` instance from a received frame.
+    ///
+    /// The `verbosity` string has already been consumed. Levels above the
+    /// highest are treated as the highest.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// verbosity <level> [noreply]
+    /// ```
+    pub(crate) fn parse_frame(parse: &mut Parse) -> Result<Verbosity> {
+        let level = parse.next_u32()?.min(2) as u8;
+        let noreply = parse.next_noreply()?;
+        Ok(Verbosity { level, noreply })
+    }
+
+    /// Apply the `Verbosity` command, answering `OK`.
+    pub(crate) async fn apply(self, settings: &ArcSwap<Settings>, dst: &mut Connection) -> Result<()> {
+        logging::set_verbosity(settings, self.level);
+        if !self.noreply {
+            dst.write_and_flush(ResponseFrame::Okay).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,78 @@
 use crate::frame::{RequestFrame, ResponseFrame};
-use anyhow::{Error, Result};
+use anyhow::Result;
 use bytes::{Buf, BytesMut};
-use std::io::Cursor;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
+use std::fmt::Debug;
+use std::io::{self, Cursor, IoSlice};
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
 
 const READ_BUFFER_SIZE: usize = 4096;
 
-//To read frames, the `Connection` uses an internal buffer, which is filled
+/// A byte stream a `Connection` can be built on, e.g. a `TcpStream` or a TLS
+/// stream wrapping one.
+pub trait Socket: AsyncRead + AsyncWrite + Debug + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Debug + Send + Unpin> Socket for T {}
+
+/// Bytes read from and written to the socket of a `Connection`, see
+/// `Connection::take_traffic`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Traffic {
+    pub(crate) read: u64,
+    pub(crate) written: u64,
+}
+
+/// A socket counting the bytes written to it, so that they are counted
+/// once the write buffer is flushed, or bypassed by a large value.
+#[derive(Debug)]
+struct Metered {
+    socket: Box<dyn Socket>,
+    written: u64,
+}
+
+impl AsyncRead for Metered {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Metered {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.socket).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.written += n as u64;
+        }
+        polled
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.socket).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.written += n as u64;
+        }
+        polled
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.socket.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_shutdown(cx)
+    }
+}
+
+/// To read frames, the `Connection` uses an internal buffer, which is filled
 /// up until there are enough bytes to create a full frame. Once this happens,
 /// the `Connection` creates the frame and returns it to the caller.
 ///
@@ -15,35 +80,53 @@ const READ_BUFFER_SIZE: usize = 4096;
 /// The contents of the write buffer are then written to the socket.
 #[derive(Debug)]
 pub struct Connection {
-    stream: BufWriter<TcpStream>,
+    stream: BufWriter<Metered>,
     buffer: BytesMut,
+    /// Bytes read since the last `take_traffic`
+    read: u64,
+    /// Whether flushes wait for `uncork`, see `cork`
+    corked: bool,
 }
 
 impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+    pub fn new(socket: impl Socket + 'static) -> Connection {
         Connection {
-            stream: BufWriter::new(socket),
+            stream: BufWriter::new(Metered {
+                socket: Box::new(socket),
+                written: 0,
+            }),
             buffer: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            read: 0,
+            corked: false,
         }
     }
 
-    /// Read a single `Frame` value from the underlying stream.
-    ///
-    /// The function waits until it has retrieved enough data to parse a frame.
-    /// Any data remaining in the read buffer after the frame has been parsed is
-    /// kept there for the next call to `read_frame`.
-    ///
-    /// # Returns
+    /// Returns the bytes read from and written to the socket since the last
+    /// call, data blocks and values included. Bytes still in the write
+    /// buffer count once flushed.
+    pub(crate) fn take_traffic(&mut self) -> Traffic {
+        Traffic {
+            read: mem::take(&mut self.read),
+            written: mem::take(&mut self.stream.get_mut().written),
+        }
+    }
+
+    /// Read every frame that has been received, waiting for one if none
+    /// has. Frames pipelined by the peer and buffered by a single read are
+    /// all taken at once, without reading from the socket in between. Any
+    /// data left in the read buffer, the start of a frame, is kept there for
+    /// the next call.
     ///
-    /// On success, the received frame is returned. If the `TcpStream`
-    /// is closed in a way that doesn't break a frame in half, it returns
-    /// `None`. Otherwise, an error is returned.
-    pub async fn read_frame(&mut self) -> Result<Option<RequestFrame>> {
+    /// Returns `false` once the peer closed the connection in a way that
+    /// doesn't break a frame in half. Otherwise, an error is returned.
+    pub async fn read_frames(&mut self, frames: &mut Vec<RequestFrame>) -> Result<bool> {
         loop {
-            // Attempt to parse a frame from the buffered data. If enough data
-            // has been buffered, the frame is returned.
-            if let Some(frame) = self.parse_frame()? {
-                return Ok(Some(frame));
+            // Take every frame buffered, if there is a whole one
+            while let Some(frame) = self.parse_frame()? {
+                frames.push(frame);
+            }
+            if !frames.is_empty() {
+                return Ok(true);
             }
 
             // There is not enough buffered data to read a frame. Attempt to
@@ -52,15 +135,16 @@ impl Connection {
             // On success, the number of bytes is returned. `0` indicates "end
             // of stream".
             let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+            self.read += bytes_read as u64;
             if bytes_read == 0 {
                 // The remote closed the connection. For this to be a clean
                 // shutdown, there should be no data in the read buffer. If
                 // there is, this means that the peer closed the socket while
                 // sending a frame.
                 if self.buffer.is_empty() {
-                    return Ok(None);
+                    return Ok(false);
                 } else {
-                    return Err(Error::msg("connection reset by peer"));
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer").into());
                 }
             }
         }
@@ -71,7 +155,7 @@ impl Connection {
     /// enough data has been buffered yet, `Ok(None)` is returned. If the
     /// buffered data does not represent a valid frame, `Err` is returned.
     fn parse_frame(&mut self) -> Result<Option<RequestFrame>> {
-        // use frame::Error::Incomplete;
+        use crate::frame::Error::Incomplete;
 
         let mut buf = Cursor::new(&self.buffer[..]);
 
@@ -119,18 +203,15 @@ impl Connection {
             // We do not want to return `Err` from here as this "error" is an
             // expected runtime condition.
             Err(Incomplete) => Ok(None),
-            // An error was encountered while parsing the frame. The connection
-            // is now in an invalid state. Returning `Err` from here will result
-            // in the connection being closed.
-            Err(e) => Err(e.into()),
         }
     }
 
     async fn write_value(&mut self, frame: ResponseFrame) -> Result<()> {
         use ResponseFrame::*;
 
+        // Numbers are formatted on the stack, so a hit allocates nothing
+        let mut number = itoa::Buffer::new();
         match frame {
-            // Figure out better way to convert int to ascii
             Value {
                 key,
                 flags,
@@ -138,19 +219,46 @@ impl Connection {
                 cas,
                 data,
             } => {
-                self.stream.write_all(b"VALUE").await?;
-                self.stream.write_all(key.as_bytes()).await?;
-                self.stream.write_all(flags.to_string().as_bytes()).await?;
-                self.stream
-                    .write_all(data_length.to_string().as_bytes())
-                    .await?;
+                self.stream.write_all(b"VALUE ").await?;
+                self.stream.write_all(&key).await?;
+                self.stream.write_all(b" ").await?;
+                self.stream.write_all(number.format(flags).as_bytes()).await?;
+                self.stream.write_all(b" ").await?;
+                self.stream.write_all(number.format(data_length).as_bytes()).await?;
                 if let Some(cas) = cas {
-                    self.stream.write_all(cas.to_string().as_bytes()).await?;
+                    self.stream.write_all(b" ").await?;
+                    self.stream.write_all(number.format(cas).as_bytes()).await?;
                 }
                 self.stream.write_all(b"\r\n").await?;
                 self.stream.write_all(data.as_ref()).await?;
             }
-            Crement(val) => self.stream.write_all(val.to_string().as_bytes()).await?,
+            Stat(name, value) => {
+                self.stream.write_all(b"STAT ").await?;
+                self.stream.write_all(name.as_bytes()).await?;
+                self.stream.write_all(b" ").await?;
+                self.stream.write_all(value.as_bytes()).await?;
+            }
+            Key(key) => {
+                self.stream.write_all(b"KEY ").await?;
+                self.stream.write_all(&key).await?;
+            }
+            Next(token) => {
+                self.stream.write_all(b"NEXT ").await?;
+                self.stream.write_all(token.as_bytes()).await?;
+            }
+            Meta { flags, data: Some(data) } => {
+                self.stream.write_all(b"VA ").await?;
+                self.stream.write_all(number.format(data.len()).as_bytes()).await?;
+                self.stream.write_all(&flags).await?;
+                self.stream.write_all(b"\r\n").await?;
+                self.stream.write_all(&data).await?;
+            }
+            Meta { flags, data: None } => {
+                self.stream.write_all(b"HD").await?;
+                self.stream.write_all(&flags).await?;
+            }
+            Line(line) => self.stream.write_all(line.as_bytes()).await?,
+            Crement(val) => self.stream.write_all(number.format(val).as_bytes()).await?,
             ClientError(val) => {
                 self.stream.write_all(b"CLIENT_ERROR ").await?;
                 self.stream.write_all(val.as_bytes()).await?;
@@ -164,6 +272,7 @@ impl Connection {
             NotStored => self.stream.write_all(b"NOT_STORED").await?,
             Touched => self.stream.write_all(b"TOUCHED").await?,
             Exists => self.stream.write_all(b"EXISTS").await?,
+            Okay => self.stream.write_all(b"OK").await?,
             NotFound => self.stream.write_all(b"NOT_FOUND").await?,
 
             Error => self.stream.write_all(b"ERROR").await?,
@@ -176,15 +285,13 @@ impl Connection {
 
     pub async fn write_and_flush(&mut self, frame: ResponseFrame) -> Result<()> {
         self.write_value(frame).await?;
-        self.stream.flush().await?;
-        Ok(())
+        self.flush().await
     }
 
     pub async fn write_and_end(&mut self, frame: ResponseFrame) -> Result<()> {
         self.write_value(frame).await?;
         self.stream.write_all(b"END\r\n").await?;
-        self.stream.flush().await?;
-        Ok(())
+        self.flush().await
     }
 
     pub async fn write(&mut self, frame: ResponseFrame) -> Result<()> {
@@ -192,11 +299,37 @@ impl Connection {
         Ok(())
     }
 
+    pub async fn flush(&mut self) -> Result<()> {
+        if !self.corked {
+            self.stream.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Flush even while corked. For a long response written a piece at a
+    /// time, which the client should get as it goes rather than at the end.
+    pub(crate) async fn flush_now(&mut self) -> Result<()> {
+        self.stream.flush().await?;
+        Ok(())
+    }
+
     pub async fn end_and_flush(&mut self) -> Result<()> {
         // Check that all multi response have "END"
         self.stream.write_all(b"END\r\n").await?;
-        self.stream.flush().await?;
-        Ok(())
+        self.flush().await
+    }
+
+    /// Hold back flushes until `uncork`, so that the responses to a batch of
+    /// pipelined requests go out together rather than one write each. The
+    /// write buffer is still written out whenever it fills up.
+    pub(crate) fn cork(&mut self) {
+        self.corked = true;
+    }
+
+    /// Flush what `cork` held back, and flush as usual from then on.
+    pub(crate) async fn uncork(&mut self) -> Result<()> {
+        self.corked = false;
+        self.flush().await
     }
 
     // pub async fn write_frames(&mut self, frames: Vec<ResponseFrame>) -> Result<()> {
@@ -208,4 +341,200 @@ impl Connection {
     //     self.stream.flush().await?;
     //     Ok(())
     // }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+
+    fn value(key: &[u8], flags: u32, cas: Option<u64>, data: &'static [u8]) -> ResponseFrame {
+        ResponseFrame::Value {
+            key: Bytes::copy_from_slice(key),
+            flags,
+            data_length: data.len(),
+            cas,
+            data: Bytes::from_static(data),
+        }
+    }
+
+    /// The bytes `frames` are written as, followed by `END` if `end`, the
+    /// way a multi-line response is.
+    async fn written(frames: Vec<ResponseFrame>, end: bool) -> Vec<u8> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut connection = Connection::new(server);
+        for frame in frames {
+            connection.write(frame).await.unwrap();
+        }
+        if end {
+            connection.end_and_flush().await.unwrap();
+        } else {
+            connection.flush().await.unwrap();
+        }
+        drop(connection);
+        let mut bytes = Vec::new();
+        client.read_to_end(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    /// A socket reading from `input` and counting the writes made to it.
+    #[derive(Debug)]
+    struct Counting {
+        input: Cursor<Vec<u8>>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for Counting {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Counting {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// 64 pipelined requests are taken in one read, and answered in one
+    /// write while corked rather than one each.
+    #[tokio::test]
+    async fn test_pipelined_batch() {
+        let writes = Arc::default();
+        let socket = Counting {
+            input: Cursor::new(b"get foo\r\n".repeat(64)),
+            writes: Arc::clone(&writes),
+        };
+        let mut connection = Connection::new(socket);
+        let mut frames = Vec::new();
+
+        assert!(connection.read_frames(&mut frames).await.unwrap());
+        assert_eq!(frames.len(), 64);
+        assert_eq!(connection.take_traffic().read, 64 * 9);
+        connection.cork();
+        for _ in frames.drain(..) {
+            connection.end_and_flush().await.unwrap();
+        }
+        assert_eq!(writes.load(Ordering::Relaxed), 0);
+        connection.uncork().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        assert_eq!(connection.take_traffic().written, 64 * 5);
+
+        // Uncorked, each flush writes
+        connection.end_and_flush().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+        assert!(!connection.read_frames(&mut frames).await.unwrap());
+        assert!(frames.is_empty());
+    }
+
+    /// Data blocks are as long as their command line says, CRLFs and all.
+    /// One running on past that is taken with the rest of its line.
+    #[tokio::test]
+    async fn test_data_blocks() {
+        let socket = Counting {
+            input: Cursor::new(b"set a 0 0 4\r\na\r\nb\r\nset b 0 0 1\r\nxy\r\nget a\r\n".to_vec()),
+            writes: Arc::default(),
+        };
+        let mut connection = Connection::new(socket);
+        let mut frames = Vec::new();
+
+        assert!(connection.read_frames(&mut frames).await.unwrap());
+        assert!(
+            matches!(
+                &frames[..],
+                [RequestFrame::Storage(set), RequestFrame::BadDataChunk, RequestFrame::Other(get)]
+                    if set.data == "a\r\nb" && get == "get a"
+            ),
+            "{:?}",
+            frames
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_now_while_corked() {
+        let writes = Arc::default();
+        let socket = Counting {
+            input: Cursor::new(Vec::new()),
+            writes: Arc::clone(&writes),
+        };
+        let mut connection = Connection::new(socket);
+
+        connection.cork();
+        connection.write(ResponseFrame::Line("a line".to_string())).await.unwrap();
+        connection.flush().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 0);
+        connection.flush_now().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        connection.end_and_flush().await.unwrap();
+        connection.uncork().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+    }
+
+    /// Every response is written byte for byte as the file of its name in
+    /// `tests/golden` holds. A change to the wire format has to change the
+    /// file too.
+    #[tokio::test]
+    async fn test_golden() {
+        use ResponseFrame::*;
+
+        let long_key = [b'k'; 250];
+        let cases = [
+            ("value", vec![value(b"foo", 5, None, b"bar")], false),
+            ("value_cas", vec![value(b"foo", u32::MAX, Some(u64::MAX), b"bar")], false),
+            ("value_empty", vec![value(b"foo", 0, Some(1), b"")], false),
+            ("value_long_key", vec![value(&long_key, 0, None, b"x")], false),
+            (
+                "multiget",
+                vec![value(b"a", 0, None, b"1"), value(b"b", 1, None, b""), value(b"c", 2, None, b"333")],
+                true,
+            ),
+            ("multiget_miss", vec![], true),
+            ("crement", vec![Crement(0), Crement(u64::MAX)], false),
+            ("stored", vec![Stored], false),
+            ("not_stored", vec![NotStored], false),
+            ("exists", vec![Exists], false),
+            ("not_found", vec![NotFound], false),
+            ("deleted", vec![Deleted], false),
+            ("touched", vec![Touched], false),
+            ("okay", vec![Okay], false),
+            ("error", vec![Error], false),
+            ("client_error", vec![ClientError("bad command line format".to_string())], false),
+            ("server_error", vec![ServerError("out of memory storing object".to_string())], false),
+            (
+                "stats",
+                vec![Stat("pid".to_string(), "1".to_string()), Stat("version".to_string(), "0.1.0".to_string())],
+                true,
+            ),
+            ("scan", vec![Key(Bytes::from_static(b"a")), Key(Bytes::from_static(b"b")), Next("2".to_string())], true),
+            ("meta_value", vec![Meta { flags: b" c1 f5".to_vec(), data: Some(Bytes::from_static(b"bar")) }], false),
+            ("meta_value_empty", vec![Meta { flags: Vec::new(), data: Some(Bytes::new()) }], false),
+            ("meta_hit", vec![Meta { flags: b" t-1".to_vec(), data: None }], false),
+            ("line", vec![Line("key=foo exp=-1 la=1 cas=1 fetch=no cls=1 size=66".to_string())], true),
+        ];
+
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for (name, frames, end) in cases {
+            let bytes = written(frames, end).await;
+            let expected = fs::read(golden.join(name)).unwrap();
+            assert_eq!(
+                bytes.escape_ascii().to_string(),
+                expected.escape_ascii().to_string(),
+                "tests/golden/{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Fail if the pid file at `path` names a running process.
+///
+/// A missing file, or one left behind by a process that is gone, is fine;
+/// `PidFile::create` overwrites it.
+pub(crate) fn check_pid_file(path: &Path) -> Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("reading pid file {}", path.display()))
+        }
+    };
+
+    if let Ok(pid) = contents.trim().parse::<u32>() {
+        if is_running(pid) {
+            bail!(
+                "already running with pid {} (pid file {})",
+                pid,
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A pid file holding this process's pid, removed when dropped.
+#[derive(Debug)]
+pub(crate) struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the current pid to `path`. Call after `daemonize`, which changes
+    /// the pid.
+    ///
+    /// The pid is written to a temporary file that is then renamed into
+    /// place, so readers never see a partially written file. After a warm
+    /// restart under `--user` the directory may not be writable, the file
+    /// handed over by the old process is overwritten in place then.
+    pub(crate) fn create(path: &Path) -> Result<PidFile> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let contents = format!("{}\n", std::process::id());
+        match fs::write(&tmp, &contents) {
+            Ok(()) => fs::rename(&tmp, path),
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                fs::write(path, &contents)
+            }
+            Err(err) => Err(err),
+        }
+        .with_context(|| format!("writing pid file {}", path.display()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    /// Leaves the file alone if it names another process by now, the one
+    /// that took over in a warm restart.
+    fn drop(&mut self) {
+        let ours = fs::read_to_string(&self.path)
+            .is_ok_and(|contents| contents.trim() == std::process::id().to_string());
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Detach from the terminal and continue in the background.
+///
+/// Forks twice with a `setsid` in between, so the process is no longer the
+/// child of the launching shell and can never reacquire a controlling
+/// terminal, then points stdin, stdout and stderr at /dev/null. The original
+/// process exits with status 0. The working directory is left alone so
+/// relative paths in the settings keep working for reloads.
+///
+/// Must be called before any threads are started, in particular before the
+/// tokio runtime is built.
+#[cfg(unix)]
+pub(crate) fn daemonize() -> Result<()> {
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    fn fork() -> Result<()> {
+        // SAFETY: the process is still single threaded, so the child gets a
+        // consistent copy of it.
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()).context("fork"),
+            0 => Ok(()),
+            // SAFETY: `_exit` skips destructors and atexit handlers, which
+            // belong to the child now.
+            _ => unsafe { libc::_exit(0) },
+        }
+    }
+
+    fork()?;
+    // SAFETY: no preconditions; the child of a fork is never a group leader.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error()).context("setsid");
+    }
+    fork()?;
+
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("opening /dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: both are open descriptors; `dup2` replaces `fd` atomically.
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error()).context("redirecting stdio");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn daemonize() -> Result<()> {
+    bail!("--daemon is only supported on unix")
+}
+
+/// Fail if `drop_privileges` could not switch to `user`: the user does not
+/// exist or the process is not running as root.
+#[cfg(unix)]
+pub(crate) fn check_user(user: &str) -> Result<()> {
+    target_user(user).map(|_| ())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn check_user(_user: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Switch to `user`, its primary group and its supplementary groups.
+///
+/// `paths` (the pid file, the data and overflow directories and their files)
+/// are first handed over to the user so it can still manage them. Only root can do this; anyone else gets an error rather than
+/// carrying on with the wrong privileges. Call once everything that needs
+/// root is done, in particular binding privileged ports.
+#[cfg(unix)]
+pub(crate) fn drop_privileges(user: &str, paths: &[&Path]) -> Result<()> {
+    use std::io;
+    use std::os::unix::fs::chown;
+
+    let (uid, gid) = target_user(user)?;
+    // SAFETY: no preconditions
+    if uid != 0 && unsafe { libc::geteuid() } == uid {
+        // Already switched, the old process did it before a warm restart
+        return Ok(());
+    }
+
+    for path in paths {
+        chown(path, Some(uid), Some(gid))
+            .with_context(|| format!("changing the owner of {}", path.display()))?;
+    }
+
+    let name = std::ffi::CString::new(user)?;
+    // SAFETY: `name` is a valid C string; the ids come from the user database.
+    // The group changes must come first, they are not allowed after `setuid`.
+    unsafe {
+        if libc::initgroups(name.as_ptr(), gid as _) == -1 {
+            return Err(io::Error::last_os_error()).context("initgroups");
+        }
+        if libc::setgid(gid) == -1 {
+            return Err(io::Error::last_os_error()).context("setgid");
+        }
+        if libc::setuid(uid) == -1 {
+            return Err(io::Error::last_os_error()).context("setuid");
+        }
+        // Getting root back must be impossible now
+        if uid != 0 && libc::setuid(0) != -1 {
+            bail!("still able to regain root after switching to {}", user);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn drop_privileges(user: &str, _paths: &[&Path]) -> Result<()> {
+    tracing::warn!(
+        "--user {} ignored, dropping privileges is only supported on unix",
+        user
+    );
+    Ok(())
+}
+
+/// Returns the uid and gid to switch to, failing unless running as root or
+/// as `user` already.
+#[cfg(unix)]
+fn target_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let (uid, gid) = lookup_user(user)?;
+    // SAFETY: no preconditions
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 && euid != uid {
+        bail!("--user {} requires starting as root", user);
+    }
+    Ok((uid, gid))
+}
+
+/// Returns the uid and primary gid of `user`.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = std::ffi::CString::new(user)?;
+    // SAFETY: `passwd` is plain old data; `getpwnam_r` fills it in.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+
+    // SAFETY: every pointer is valid for the duration of the call and `buf`
+    // outlives the use of the strings in `passwd`.
+    let rc = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if found.is_null() {
+        if rc != 0 {
+            return Err(std::io::Error::from_raw_os_error(rc))
+                .with_context(|| format!("looking up user {}", user));
+        }
+        bail!("unknown user {}", user);
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    // SAFETY: signal 0 only checks that the process exists and could be
+    // signalled. EPERM means it exists but belongs to someone else.
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid_file_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sidica-{}-{}.pid", name, std::process::id()))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pid_file_lifecycle() {
+        let path = pid_file_path("lifecycle");
+        check_pid_file(&path).unwrap();
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        // This process is alive, so a second instance must refuse to start
+        assert!(check_pid_file(&path).is_err());
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lookup_user() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(
+            lookup_user("sidica-no-such-user").unwrap_err().to_string(),
+            "unknown user sidica-no-such-user"
+        );
+    }
+
+    #[test]
+    fn test_stale_pid_file() {
+        let path = pid_file_path("stale");
+        // Far above any real pid_max
+        fs::write(&path, "2147483647\n").unwrap();
+        check_pid_file(&path).unwrap();
+
+        fs::write(&path, "garbage").unwrap();
+        check_pid_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Counts of reads, hits, stores and deletes per key prefix, kept while
+//! `stats detail on` and reported by `stats detail dump`, like memcached's.
+//!
+//! A key's prefix is what comes before the first `--detail-delimiter`, the
+//! empty prefix if there is none. At most `--detail-max-prefixes` are told
+//! apart; once there are that many, new ones are counted together as the
+//! overflow, so clients making up prefixes cannot use up memory.
+
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// What a key was used for, see `Detail::record`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// Read, and found or not
+    Get { hit: bool },
+    Set,
+    Delete,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    gets: AtomicU64,
+    hits: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, access: Access) {
+        let counter = match access {
+            Access::Get { hit } => {
+                if hit {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                }
+                &self.gets
+            }
+            Access::Set => &self.sets,
+            Access::Delete => &self.deletes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether anything was counted.
+    fn any(&self) -> bool {
+        [&self.gets, &self.sets, &self.deletes]
+            .iter()
+            .any(|counter| counter.load(Ordering::Relaxed) > 0)
+    }
+
+    /// `get <n> hit <n> set <n> del <n>`, as memcached reports them.
+    fn line(&self) -> String {
+        format!(
+            "get {} hit {} set {} del {}",
+            self.gets.load(Ordering::Relaxed),
+            self.hits.load(Ordering::Relaxed),
+            self.sets.load(Ordering::Relaxed),
+            self.deletes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// The per prefix counts of a server, see `ServerStats::detail`.
+#[derive(Debug, Default)]
+pub(crate) struct Detail {
+    on: AtomicBool,
+    delimiter: AtomicU8,
+    max_prefixes: AtomicUsize,
+    prefixes: DashMap<Bytes, Counters>,
+    /// Entries in `prefixes`, or about to be
+    len: AtomicUsize,
+    overflow: Counters,
+}
+
+impl Detail {
+    /// Start counting, with keys split at `delimiter` and up to
+    /// `max_prefixes` told apart. Counts kept so far are added to.
+    pub(crate) fn on(&self, delimiter: u8, max_prefixes: usize) {
+        self.delimiter.store(delimiter, Ordering::Relaxed);
+        self.max_prefixes.store(max_prefixes, Ordering::Relaxed);
+        self.on.store(true, Ordering::Release);
+    }
+
+    /// Stop counting, keeping the counts so far.
+    pub(crate) fn off(&self) {
+        self.on.store(false, Ordering::Relaxed);
+    }
+
+    /// Count `access` to `key` under its prefix, if on.
+    pub(crate) fn record(&self, key: &[u8], access: Access) {
+        if !self.on.load(Ordering::Acquire) {
+            return;
+        }
+        let delimiter = self.delimiter.load(Ordering::Relaxed);
+        let prefix = match key.iter().position(|&byte| byte == delimiter) {
+            Some(end) => &key[..end],
+            None => &[],
+        };
+        if let Some(counters) = self.prefixes.get(prefix) {
+            counters.record(access);
+            return;
+        }
+        // Take a place before making the entry, so that racing ones cannot
+        // go past the limit
+        let max = self.max_prefixes.load(Ordering::Relaxed);
+        if self.len.fetch_add(1, Ordering::Relaxed) >= max {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            self.overflow.record(access);
+            return;
+        }
+        let entry = self.prefixes.entry(Bytes::copy_from_slice(prefix));
+        if matches!(entry, Entry::Occupied(_)) {
+            // Made meanwhile
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        entry.or_default().record(access);
+    }
+
+    /// Returns `PREFIX <prefix> get <n> hit <n> set <n> del <n>` for every
+    /// prefix, in byte order of the prefixes so that dumps can be diffed,
+    /// then `OVERFLOW get <n> ...` if any went uncounted by prefix.
+    pub(crate) fn dump(&self) -> Vec<String> {
+        let mut lines: Vec<_> = self
+            .prefixes
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().line()))
+            .collect();
+        lines.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let mut lines: Vec<_> = lines
+            .into_iter()
+            .map(|(prefix, counts)| format!("PREFIX {} {}", String::from_utf8_lossy(&prefix), counts))
+            .collect();
+        if self.overflow.any() {
+            lines.push(format!("OVERFLOW {}", self.overflow.line()));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_by_prefix() {
+        let detail = Detail::default();
+        // Off, nothing is counted
+        detail.record(b"user:1", Access::Set);
+        assert!(detail.dump().is_empty());
+
+        detail.on(b':', 10);
+        detail.record(b"user:1", Access::Set);
+        detail.record(b"user:1", Access::Get { hit: true });
+        detail.record(b"user:2", Access::Get { hit: false });
+        detail.record(b"session:a:b", Access::Delete);
+        // Without the delimiter, under the empty prefix
+        detail.record(b"plain", Access::Get { hit: true });
+        detail.off();
+        detail.record(b"user:3", Access::Set);
+        assert_eq!(
+            detail.dump(),
+            [
+                "PREFIX  get 1 hit 1 set 0 del 0",
+                "PREFIX session get 0 hit 0 set 0 del 1",
+                "PREFIX user get 2 hit 1 set 1 del 0",
+            ]
+        );
+
+        detail.on(b'/', 10);
+        detail.record(b"user/1", Access::Set);
+        assert_eq!(detail.dump()[2], "PREFIX user get 2 hit 1 set 2 del 0");
+    }
+
+    #[test]
+    fn test_overflow() {
+        let detail = Detail::default();
+        detail.on(b':', 2);
+        for prefix in ["a", "b", "c", "d", "a"] {
+            detail.record(format!("{}:key", prefix).as_bytes(), Access::Get { hit: false });
+        }
+        detail.record(b"b:key", Access::Set);
+        detail.record(b"e:key", Access::Set);
+        assert_eq!(
+            detail.dump(),
+            [
+                "PREFIX a get 2 hit 0 set 0 del 0",
+                "PREFIX b get 1 hit 0 set 1 del 0",
+                "OVERFLOW get 2 hit 0 set 1 del 0",
+            ]
+        );
+    }
+}
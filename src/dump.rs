@@ -0,0 +1,103 @@
+use crate::cache::Cache;
+use crate::settings::Settings;
+use crate::stats::ServerStats;
+
+use std::fmt::Write;
+#[cfg(unix)]
+use {arc_swap::ArcSwap, std::sync::Arc};
+
+/// Render a diagnostic dump of the server as one multi-line block.
+///
+/// Every line carries `dump=<id>` so a dump can be picked out of the log
+/// even if it ends up interleaved with other output. Only atomics and the
+/// cache map's item count are read; the index lock is never taken.
+pub(crate) fn dump(
+    id: u64,
+    stats: &ServerStats,
+    cache: &Cache,
+    settings: &Settings,
+) -> String {
+    let mut out = format!("stats dump {} begin\n", id);
+
+    for (name, value) in stats.snapshot() {
+        let _ = writeln!(out, "dump={} stat {}={}", id, name, value);
+    }
+    for connection in stats.registry().connections() {
+        let proxy = connection.peer.proxy.map_or_else(|| "-".to_string(), |proxy| proxy.to_string());
+        let _ = writeln!(
+            out,
+            "dump={} connection id={} peer={} proxy={} cn={} state={} age={:.1}s last_command={}",
+            id,
+            connection.id,
+            connection.peer.addr,
+            proxy,
+            connection.peer.cn.as_deref().unwrap_or("-"),
+            connection.state,
+            connection.age.as_secs_f64(),
+            connection.last_command.unwrap_or("-"),
+        );
+    }
+    let _ = writeln!(out, "dump={} cache items={}", id, cache.len());
+    for (name, value) in settings.snapshot() {
+        let _ = writeln!(out, "dump={} setting {}={}", id, name, value);
+    }
+
+    let _ = write!(out, "stats dump {} end", id);
+    out
+}
+
+/// Log a dump every time the process receives SIGUSR1.
+#[cfg(unix)]
+pub(crate) async fn on_sigusr1(
+    stats: Arc<ServerStats>,
+    cache: Cache,
+    settings: Arc<ArcSwap<Settings>>,
+) {
+    use tracing::{error, info};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!("failed to install SIGUSR1 handler: {}", err);
+            return;
+        }
+    };
+    let mut id = 0;
+    while signals.recv().await.is_some() {
+        id += 1;
+        info!("{}", dump(id, &stats, &cache, &settings.load()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_dump() {
+        let stats = ServerStats::new(1);
+        stats.incr_tcp_requests();
+        let peer: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let connection = stats.registry().register(peer.into());
+        connection.processing("stats");
+        let settings = Settings::parse_from(["sidica", "-p", "11211"]);
+
+        let dump = dump(7, &stats, &Cache::new(), &settings);
+        let lines: Vec<_> = dump.lines().collect();
+
+        assert_eq!(lines[0], "stats dump 7 begin");
+        assert_eq!(lines[lines.len() - 1], "stats dump 7 end");
+        assert!(lines[1..lines.len() - 1]
+            .iter()
+            .all(|line| line.starts_with("dump=7 ")));
+        assert!(lines.contains(&"dump=7 stat tcp_requests=1"));
+        assert!(lines.iter().any(|line| line.starts_with(
+            "dump=7 connection id=0 peer=10.0.0.1:1000 proxy=- cn=- state=processing age="
+        ) && line.ends_with(" last_command=stats")));
+        assert!(lines.contains(&"dump=7 cache items=0"));
+        assert!(lines.contains(&"dump=7 setting tcpport=11211"));
+    }
+}
@@ -0,0 +1,116 @@
+//! Active expiry: removing expired items before a read or an eviction comes
+//! across them, see `--expiry-sweep`.
+
+use crate::cache::Cache;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use tokio::time::{self, Duration};
+use tracing::debug;
+
+/// Time between sweeps.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time between sweeps of `ExpirySweep::Sampled`, each taking up to
+/// 25 milliseconds: at most a quarter of a core.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How expired items are looked for, see `Cache::sweep_expired`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ExpirySweep {
+    /// They are not; they are removed when read or evicted
+    #[default]
+    None,
+    /// Every item is looked at every second. Costs as much as there are
+    /// items.
+    Scan,
+    /// The items that expire are kept in order of expiration too, and those
+    /// due are removed every second. Costs an entry per such item.
+    Index,
+    /// A few items of each index shard are looked at ten times a second,
+    /// and more while many of those turn out expired, for up to a quarter
+    /// of the time. Leaves up to about a quarter of the items expired.
+    Sampled,
+}
+
+impl ExpirySweep {
+    fn interval(self) -> Duration {
+        match self {
+            ExpirySweep::Sampled => SAMPLE_INTERVAL,
+            _ => SWEEP_INTERVAL,
+        }
+    }
+}
+
+/// Keys of the items of an index shard that expire, by expiration and id.
+type DeadlineShard = Mutex<BTreeMap<(u64, u64), Bytes>>;
+
+/// The items that expire, by when, in each index shard. Entries are changed
+/// along with the items, under their locks, and nothing else is locked while
+/// one of these is.
+#[derive(Debug)]
+pub(crate) struct Deadlines {
+    shards: Box<[DeadlineShard]>,
+}
+
+impl Deadlines {
+    pub(crate) fn new(shards: usize) -> Deadlines {
+        Deadlines {
+            shards: (0..shards).map(|_| Mutex::new(BTreeMap::new())).collect(),
+        }
+    }
+
+    /// Note that the item `id` under `key`, in index shard `shard`, expires
+    /// at `new` rather than `old`.
+    pub(crate) fn update(&self, shard: usize, key: &[u8], id: u64, old: Option<u64>, new: Option<u64>) {
+        let mut deadlines = self.shards[shard].lock();
+        let entry = old.and_then(|old| deadlines.remove(&(old, id)));
+        if let Some(new) = new {
+            deadlines.insert((new, id), entry.unwrap_or_else(|| Bytes::copy_from_slice(key)));
+        }
+    }
+
+    /// Take out up to `limit` of the entries of `shard` due by `now`, the
+    /// earliest first, as the keys and ids of their items.
+    pub(crate) fn due(&self, shard: usize, now: u64, limit: usize) -> Vec<(Bytes, u64)> {
+        let mut deadlines = self.shards[shard].lock();
+        let mut due = Vec::new();
+        while due.len() < limit {
+            match deadlines.first_entry() {
+                Some(entry) if entry.key().0 <= now => {
+                    let ((_, id), key) = entry.remove_entry();
+                    due.push((key, id));
+                }
+                _ => break,
+            }
+        }
+        due
+    }
+
+    /// Returns how many entries there are.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().len()).sum()
+    }
+}
+
+/// Remove the expired items of `cache` every `SWEEP_INTERVAL`, or
+/// `SAMPLE_INTERVAL`, for as long as the server runs, unless it has no way
+/// to find them.
+pub(crate) async fn sweep_periodically(cache: Cache) {
+    let sweep = cache.expiry_sweep();
+    if sweep == ExpirySweep::None {
+        return;
+    }
+    let mut interval = time::interval(sweep.interval());
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let cache = cache.clone();
+        match tokio::task::spawn_blocking(move || cache.sweep_expired()).await {
+            Ok(0) | Err(_) => {}
+            Ok(removed) => debug!(removed, "removed expired items"),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Export files: the items of a running server written by `export` and
+//! merged into another by `import`, to move a dataset between hosts or seed
+//! a test server. Unlike snapshots, they are only written and read when
+//! asked, in `--dump-dir`.
+//!
+//! The layout is that of a snapshot, see `snapshot`, only starting with
+//! `SIDICAEX`. Both share `snapshot::FORMAT_VERSION` and change together;
+//! files of other versions are refused.
+
+use crate::cache::{Cache, Outcome};
+use crate::replication::Replicator;
+use crate::snapshot;
+
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use tokio::sync::mpsc;
+use tracing::info;
+
+const MAGIC: &[u8; 8] = b"SIDICAEX";
+
+/// Items read ahead of those being stored while importing.
+const IMPORT_QUEUE: usize = 1024;
+
+/// Items imported between progress reports in the log.
+const PROGRESS_EVERY: usize = 100_000;
+
+/// The file `name` in `dir`. Only plain file names are taken, so clients
+/// cannot reach outside of it.
+pub(crate) fn path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Some(dir.join(name)),
+        _ => None,
+    }
+}
+
+/// An export file written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Exported {
+    pub(crate) items: usize,
+    /// Size of the file
+    pub(crate) bytes: u64,
+}
+
+/// Write every item in `cache` to `path`, as of when called, see
+/// `Cache::snapshot_iter`. Replaces the file there once complete. Blocks.
+pub(crate) fn export(path: &Path, cache: &Cache) -> Result<Exported> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let exported = snapshot::write(&temporary, MAGIC, cache, &AtomicBool::new(false))
+        .and_then(|items| {
+            let items = items.expect("never aborted");
+            let bytes = fs::metadata(&temporary)?.len();
+            fs::rename(&temporary, path)?;
+            Ok(Exported { items, bytes })
+        })
+        .with_context(|| format!("exporting to {}", path.display()));
+    match &exported {
+        Ok(exported) => info!(path = %path.display(), items = exported.items, "exported"),
+        Err(_) => {
+            let _ = fs::remove_file(&temporary);
+        }
+    }
+    exported
+}
+
+/// What importing a file came to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Imported {
+    /// Items stored
+    pub(crate) items: usize,
+    /// Items left out as their keys were taken, unless overwriting
+    pub(crate) skipped: usize,
+    /// Items left out as they have expired since the export
+    pub(crate) expired: usize,
+}
+
+/// Store the items of the export file at `path` in `cache`, replacing those
+/// with the same keys if `overwrite`, keeping them otherwise. Each item
+/// gets a new CAS, and is queued for the replica when there is one.
+///
+/// Items are stored like by `set` or `add`, so past the memory limit others
+/// are evicted to make room. When nothing can be, importing stops there
+/// with an error; so does it at a damaged file. The items stored up to then
+/// stay.
+pub(crate) async fn import(
+    path: &Path,
+    cache: &Cache,
+    overwrite: bool,
+    replicator: Option<&Replicator>,
+) -> Result<Imported> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let (items_tx, mut items_rx) = mpsc::channel(IMPORT_QUEUE);
+    // Reading blocks, so it is done aside. It stops once `items_rx` goes.
+    let reader = tokio::task::spawn_blocking(move || {
+        snapshot::read(file, MAGIC, "an export file", |item| {
+            items_tx.blocking_send(item).context("import given up")
+        })
+    });
+
+    let mut imported = Imported::default();
+    let stored = async {
+        while let Some(item) = items_rx.recv().await {
+            if item.expiration.is_some_and(|expiration| expiration <= cache.now()) {
+                imported.expired += 1;
+                continue;
+            }
+            let (key, data) = (item.key.clone(), item.data.clone());
+            let outcome = if overwrite {
+                cache.set(item.key, item.flags, item.expiration, item.data).await
+            } else {
+                cache.add(item.key, item.flags, item.expiration, item.data).await
+            };
+            match outcome {
+                Outcome::Stored => {
+                    if let Some(replicator) = replicator {
+                        replicator.set(&key, item.flags, item.expiration, &data);
+                    }
+                    imported.items += 1;
+                    if imported.items % PROGRESS_EVERY == 0 {
+                        info!(path = %path.display(), items = imported.items, "importing");
+                    }
+                }
+                Outcome::NotStored => imported.skipped += 1,
+                Outcome::OutOfMemory => {
+                    bail!("out of memory after {} items, see --memory-limit", imported.items)
+                }
+                outcome => bail!("item {:?} not stored: {:?}", key, outcome),
+            }
+        }
+        Ok(())
+    };
+    let stored = stored.await;
+    drop(items_rx);
+    let read = reader.await?;
+    stored
+        .and(read)
+        .with_context(|| format!("importing {}", path.display()))?;
+    info!(
+        path = %path.display(),
+        items = imported.items,
+        skipped = imported.skipped,
+        expired = imported.expired,
+        "imported"
+    );
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::EvictionPolicy;
+    use crate::clock::Clock;
+    use bytes::Bytes;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sidica-export-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_path_takes_only_file_names() {
+        let dir = Path::new("/dumps");
+        assert_eq!(path(dir, "users.dump"), Some(dir.join("users.dump")));
+        for name in ["", ".", "..", "../users.dump", "a/users.dump", "/etc/passwd"] {
+            assert_eq!(path(dir, name), None, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_merges_into_a_running_cache() {
+        let dir = dir("merge");
+        let path = dir.join("dump");
+        let source = Cache::new();
+        source.set(Bytes::from("a"), 1, None, Bytes::from("exported")).await;
+        source.set(Bytes::from("b"), 2, Some(source.now() + 60), Bytes::from("exported")).await;
+        source.set(Bytes::from("gone"), 0, Some(source.now() + 1), Bytes::from("x")).await;
+        assert_eq!(export(&path, &source).unwrap().items, 3);
+        assert!(!dir.join("dump.tmp").exists());
+
+        let clock = Clock::default();
+        let cache = Cache::builder().clock(clock.clone()).build();
+        cache.set(Bytes::from("a"), 0, None, Bytes::from("kept")).await;
+        cache.set(Bytes::from("c"), 0, None, Bytes::from("kept")).await;
+        // Far enough for `gone` to have expired
+        clock.advance(1);
+        let imported = import(&path, &cache, false, None).await.unwrap();
+        assert_eq!(imported, Imported { items: 1, skipped: 1, expired: 1 });
+        assert_eq!(&cache.get(b"a").await.unwrap().data[..], b"kept");
+        let b = cache.get(b"b").await.unwrap();
+        assert_eq!((b.flags, b.expiration, &b.data[..]), (2, Some(source.now() + 60), &b"exported"[..]));
+        assert_eq!(&cache.get(b"c").await.unwrap().data[..], b"kept");
+
+        let imported = import(&path, &cache, true, None).await.unwrap();
+        assert_eq!(imported, Imported { items: 2, skipped: 0, expired: 1 });
+        assert_eq!(&cache.get(b"a").await.unwrap().data[..], b"exported");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_stops_out_of_memory() {
+        let dir = dir("full");
+        let path = dir.join("dump");
+        let source = Cache::new();
+        for n in 0..100 {
+            source.set(Bytes::from(format!("key{}", n)), 0, None, Bytes::from(vec![0; 1000])).await;
+        }
+        export(&path, &source).unwrap();
+
+        let cache = Cache::builder()
+            .memory_limit_bytes(Some(50_000))
+            .eviction_policy(EvictionPolicy::None)
+            .build();
+        let error = format!("{:#}", import(&path, &cache, false, None).await.unwrap_err());
+        assert!(error.contains("out of memory after"), "{}", error);
+        assert!(cache.len() > 10 && cache.len() < 50, "{} items", cache.len());
+
+        // With evictions, the last ones are kept
+        let cache = Cache::builder().memory_limit_bytes(Some(50_000)).build();
+        assert_eq!(import(&path, &cache, false, None).await.unwrap().items, 100);
+        assert!(cache.bytes() <= 50_000);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
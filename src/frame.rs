@@ -1,13 +1,13 @@
-use anyhow::Error;
-use bytes::{Buf, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 use std::io::Cursor;
+use thiserror::Error;
 
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
     // Maybe skip 3 or 4 bytes
     // Scan the bytes directly
     let start = src.position() as usize;
     // Scan to the second to last byte
-    let end = src.get_ref().len() - 1;
+    let end = src.get_ref().len().saturating_sub(1);
 
     for i in start..end {
         if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
@@ -18,8 +18,44 @@ fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
             return Ok(&src.get_ref()[start..i]);
         }
     }
-    // Err(Error::Incomplete)
-    Err(Error::msg("Incomplete"))
+    Err(Error::Incomplete)
+}
+
+/// The data block of a storage command with command line `line`: as many
+/// bytes as the line declares, then CRLF. `None` if something else follows
+/// them, in which case the rest of that line is skipped along. Without a
+/// length to go by, the block runs up to the next CRLF.
+fn get_data<'a>(src: &mut Cursor<&'a [u8]>, line: &[u8]) -> Result<Option<&'a [u8]>, Error> {
+    let Some(len) = declared_len(line) else {
+        return get_line(src).map(Some);
+    };
+    let buf = *src.get_ref();
+    let start = src.position() as usize;
+    let end = start.saturating_add(len);
+    if buf.len().saturating_sub(2) < end {
+        return Err(Error::Incomplete);
+    }
+    if &buf[end..end + 2] == b"\r\n" {
+        src.set_position((end + 2) as u64);
+        return Ok(Some(&buf[start..end]));
+    }
+    match buf[end..].iter().position(|&b| b == b'\n') {
+        Some(at) => {
+            src.set_position((end + at + 1) as u64);
+            Ok(None)
+        }
+        None => Err(Error::Incomplete),
+    }
+}
+
+/// The length of the data block a storage command line declares, after the
+/// key, flags and exptime.
+fn declared_len(line: &[u8]) -> Option<usize> {
+    let token = line.split(|&b| b == b' ').filter(|token| !token.is_empty()).nth(4)?;
+    if !token.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(token).ok()?.parse().ok()
 }
 
 /// Storage commands use two lines. The first is the command and the second is data.
@@ -34,41 +70,56 @@ pub struct StorageFrame {
 pub enum RequestFrame {
     Storage(StorageFrame),
     Other(Bytes),
+    /// A storage command whose data block does not end where its command
+    /// line says, answered with `CLIENT_ERROR bad data chunk`
+    BadDataChunk,
 }
 
-// #[derive(Debug)]
-// pub enum Error {
-//     /// Not enough data is available to parse a message
-//     Incomplete,
-
-// }
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Not enough data is available to parse a message
+    #[error("stream ended early")]
+    Incomplete,
+}
 
 impl RequestFrame {
     /// Checks if an entire message can be decoded from `src`
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
-        match get_first_byte(src)? {
-            b's' | b'a' | b'r' | b'p' | b'c' => {
-                get_line(src)?;
-                get_line(src)?;
-            }
-            _ => {
-                get_line(src)?;
-            }
+        let line = get_line(src)?;
+        if is_storage_command(line) {
+            get_data(src, line)?;
         }
         Ok(())
     }
 
     /// The message has already been validated with `check`.
     pub fn parse(src: &mut Cursor<&[u8]>) -> Result<RequestFrame, Error> {
-        match get_first_byte(src)? {
-            b's' | b'a' | b'r' | b'p' | b'c' => {
-                let command_line = Bytes::copy_from_slice(get_line(src)?);
-                let data = Bytes::copy_from_slice(get_line(src)?);
+        let line = get_line(src)?;
+        if is_storage_command(line) {
+            let Some(data) = get_data(src, line)? else {
+                return Ok(RequestFrame::BadDataChunk);
+            };
+            let command_line = Bytes::copy_from_slice(line);
+            let data = Bytes::copy_from_slice(data);
+
+            return Ok(RequestFrame::Storage(StorageFrame { command_line, data }));
+        }
+        Ok(RequestFrame::Other(Bytes::copy_from_slice(line)))
+    }
 
-                Ok(RequestFrame::Storage(StorageFrame { command_line, data }))
+    /// Append the frame to `dst` the way a client sends it.
+    pub fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            RequestFrame::Storage(frame) => {
+                dst.put_slice(&frame.command_line);
+                dst.put_slice(b"\r\n");
+                dst.put_slice(&frame.data);
             }
-            _ => Ok(RequestFrame::Other(Bytes::copy_from_slice(get_line(src)?))),
+            RequestFrame::Other(line) => dst.put_slice(line),
+            // Nothing a client would send
+            RequestFrame::BadDataChunk => return,
         }
+        dst.put_slice(b"\r\n");
     }
 
     // Converts the frame to an "unexpected frame" error
@@ -77,30 +128,46 @@ impl RequestFrame {
     // }
 }
 
-fn get_first_byte(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
-    if !src.has_remaining() {
-        return Err(Error::msg("Incomplete"));
-    }
-
-    Ok(src.get_u8())
+/// Storage commands are followed by a data line, see `StorageFrame`.
+fn is_storage_command(command_line: &[u8]) -> bool {
+    let name = command_line.split(|&b| b == b' ').next().unwrap_or_default();
+    matches!(
+        name,
+        b"set" | b"add" | b"replace" | b"append" | b"prepend" | b"cas"
+    )
 }
 
 #[derive(Clone, Debug)]
 pub enum ResponseFrame {
     Value {
-        key: String,
+        key: Bytes,
         flags: u32,
         data_length: usize,
         cas: Option<u64>,
         data: Bytes
     },
-    Crement(usize), // Result of increment or decrement
+    Crement(u64), // Result of increment or decrement
     Deleted,
     Stored,
     Touched,
     NotFound,
     NotStored,
     Exists,
+    /// `OK`
+    Okay,
+    Stat(String, String),
+    /// `KEY <key>`, a key found by `scan`
+    Key(Bytes),
+    /// `NEXT <token>`, where a `scan` goes on from
+    Next(String),
+    /// `VA <size>` and the flags, each after a space, then the value, or
+    /// `HD` and the flags without one, answering `mg`
+    Meta {
+        flags: Vec<u8>,
+        data: Option<Bytes>,
+    },
+    /// A line as is, e.g. of `lru_crawler metadump`
+    Line(String),
     ClientError(String),
     ServerError(String),
     Error,
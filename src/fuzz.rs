@@ -0,0 +1,60 @@
+//! What the fuzz targets in `fuzz/` check of the bytes they are given, see
+//! `fuzz/README.md`. Not part of the server.
+
+use crate::commands::Command;
+use crate::frame::{Error, RequestFrame};
+use std::io::Cursor;
+
+/// Take a frame off the front of `data` the way a connection does: with
+/// `RequestFrame::check` then `RequestFrame::parse`. Panics unless parse
+/// takes the bytes check did, and only those.
+pub fn frame(data: &[u8]) {
+    let _ = checked_frame(data);
+}
+
+/// `frame`, then `Command::from_frame` on the frame, which must not panic
+/// whatever it holds.
+pub fn command(data: &[u8]) {
+    if let Some(frame) = checked_frame(data) {
+        let _ = Command::from_frame(frame);
+    }
+}
+
+fn checked_frame(data: &[u8]) -> Option<RequestFrame> {
+    let mut cursor = Cursor::new(data);
+    match RequestFrame::check(&mut cursor) {
+        Ok(()) => {}
+        Err(Error::Incomplete) => return None,
+    }
+    let len = cursor.position() as usize;
+    assert!(len <= data.len(), "check went {} bytes past the end", len - data.len());
+
+    cursor.set_position(0);
+    let frame = RequestFrame::parse(&mut cursor).expect("parse failed on a frame check took");
+    assert_eq!(cursor.position() as usize, len, "check and parse took frames of different lengths");
+    // Nothing past the frame is looked at
+    let mut cursor = Cursor::new(&data[..len]);
+    RequestFrame::parse(&mut cursor).expect("parse read past the frame check took");
+    Some(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    /// The seeds checked in for each target pass its checks.
+    #[test]
+    fn test_corpus() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+        for (target, check) in [("frame", frame as fn(&[u8])), ("command", command)] {
+            let mut seeds = 0;
+            for entry in fs::read_dir(corpus.join(target)).unwrap() {
+                check(&fs::read(entry.unwrap().path()).unwrap());
+                seeds += 1;
+            }
+            assert!(seeds > 0, "no seeds for {}", target);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Warm restarts: hand the cache and the listening sockets to a new process.
+//!
+//! On SIGUSR2 the running server writes every item to a handoff file and
+//! starts the binary at the path it was itself started from, with the same
+//! arguments plus `--load-handoff <file>`. The listening sockets are passed
+//! on as inherited descriptors, so connections arriving during the switch
+//! wait in the listen backlog instead of being refused. The old process then
+//! stops accepting, drains its connections and exits, while the new one
+//! loads the file and starts accepting.
+//!
+//! Changes made after the file is written are not carried over.
+
+use crate::cache::Cache;
+use crate::snapshot::{read_item, write_item, END};
+
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+
+/// First line of a handoff file, followed by the sockets passed on.
+const MAGIC: &str = "sidica-handoff 2";
+
+/// The sockets passed to the new process, in descriptor order: the TCP
+/// listeners, then the UDP socket and the health listener when enabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Counts {
+    tcp: usize,
+    udp: bool,
+    health: bool,
+}
+
+/// Write `cache` to `path` in the handoff format, with a header announcing
+/// `tcp` TCP listeners and the optional UDP socket and health listener.
+fn write(path: &Path, cache: &Cache, counts: Counts) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(
+        out,
+        "{} tcp={} udp={} health={}",
+        MAGIC, counts.tcp, counts.udp as u8, counts.health as u8
+    )?;
+
+    // Items are laid out as in snapshots
+    for item in cache.items() {
+        write_item(&mut out, &item)?;
+    }
+    out.write_all(&END.to_be_bytes())?;
+
+    out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// A handoff file being taken over by a new process, see `--load-handoff`.
+#[derive(Debug)]
+pub(crate) struct Handoff {
+    path: PathBuf,
+    reader: BufReader<File>,
+    counts: Counts,
+}
+
+impl Handoff {
+    /// Open the handoff file at `path` and read its header.
+    pub(crate) fn open(path: &Path) -> Result<Handoff> {
+        let context = || format!("reading handoff file {}", path.display());
+        let mut reader = BufReader::new(File::open(path).with_context(context)?);
+        let mut header = String::new();
+        reader.read_line(&mut header).with_context(context)?;
+        let counts = parse_header(header.trim_end()).with_context(context)?;
+
+        Ok(Handoff {
+            path: path.to_path_buf(),
+            reader,
+            counts,
+        })
+    }
+
+    /// Take ownership of the sockets passed on by the old process.
+    #[cfg(unix)]
+    pub(crate) fn sockets(
+        &self,
+    ) -> Result<(Vec<TcpListener>, Option<UdpSocket>, Option<TcpListener>)> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        let mut fds = (FIRST_FD..).map(|fd| {
+            // SAFETY: the old process passes these descriptors for this one
+            // to own, and each is only taken once.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let socket = socket2::Socket::from(fd);
+            socket.set_cloexec(true)?;
+            socket.set_nonblocking(true)?;
+            Ok::<_, io::Error>(socket)
+        });
+        let mut next = || fds.next().unwrap();
+
+        let tcp = (0..self.counts.tcp)
+            .map(|_| next().map(TcpListener::from))
+            .collect::<Result<_, _>>()?;
+        let udp = self.counts.udp.then(&mut next).transpose()?.map(UdpSocket::from);
+        let health = self.counts.health.then(&mut next).transpose()?.map(TcpListener::from);
+        Ok((tcp, udp, health))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn sockets(
+        &self,
+    ) -> Result<(Vec<TcpListener>, Option<UdpSocket>, Option<TcpListener>)> {
+        bail!("--load-handoff is only supported on unix")
+    }
+
+    /// Load the items into `cache` and remove the file. Returns how many
+    /// items were loaded.
+    pub(crate) fn load(mut self, cache: &Cache) -> Result<usize> {
+        let mut loaded = 0;
+        while let Some(item) = read_item(&mut self.reader)
+            .with_context(|| format!("loading handoff file {}", self.path.display()))?
+        {
+            cache.restore(item);
+            loaded += 1;
+        }
+        fs::remove_file(&self.path)
+            .with_context(|| format!("removing handoff file {}", self.path.display()))?;
+        Ok(loaded)
+    }
+}
+
+fn parse_header(header: &str) -> Result<Counts> {
+    let fields = match header.strip_prefix(MAGIC) {
+        Some(fields) => fields,
+        None => bail!("not a handoff file"),
+    };
+    let mut counts = Counts {
+        tcp: 0,
+        udp: false,
+        health: false,
+    };
+    for field in fields.split_whitespace() {
+        match field.split_once('=') {
+            Some(("tcp", count)) => counts.tcp = count.parse()?,
+            Some(("udp", count)) => counts.udp = count.parse::<u8>()? != 0,
+            Some(("health", count)) => counts.health = count.parse::<u8>()? != 0,
+            _ => bail!("invalid header field {:?}", field),
+        }
+    }
+    Ok(counts)
+}
+
+/// Descriptor the first socket is passed on as, like systemd's
+/// SD_LISTEN_FDS_START.
+#[cfg(unix)]
+const FIRST_FD: i32 = 3;
+
+/// The descriptors of the sockets to pass on, see `Counts` for the order.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub(crate) struct Sockets {
+    pub(crate) tcp: Vec<std::os::fd::RawFd>,
+    pub(crate) udp: Option<std::os::fd::RawFd>,
+    pub(crate) health: Option<std::os::fd::RawFd>,
+}
+
+/// Hand off to a new process every time SIGUSR2 is received, returning once
+/// one has been started. A failed attempt is logged and leaves this process
+/// running as before.
+///
+/// The descriptors in `sockets` must stay open for as long as this runs.
+#[cfg(unix)]
+pub(crate) async fn on_sigusr2(cache: Cache, sockets: Sockets) {
+    use tracing::{error, info};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!("failed to install SIGUSR2 handler: {}", err);
+            return std::future::pending().await;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let path = std::env::temp_dir().join(format!("sidica-handoff-{}", std::process::id()));
+        let cache = cache.clone();
+        let sockets = sockets.clone();
+        let handed_off = tokio::task::spawn_blocking(move || {
+            let result = hand_off(&path, &cache, &sockets);
+            if result.is_err() {
+                let _ = fs::remove_file(&path);
+                // Closed by `hand_off`
+                if let Some(log) = cache.append_log() {
+                    if let Err(err) = log.start() {
+                        error!("failed to restart the append log: {}", err);
+                    }
+                }
+            }
+            result
+        })
+        .await;
+
+        match handed_off {
+            Ok(Ok(pid)) => {
+                info!("handed off to process {}", pid);
+                return;
+            }
+            Ok(Err(err)) => error!("warm restart failed: {:#}", err),
+            Err(err) => error!("warm restart failed: {}", err),
+        }
+    }
+    std::future::pending().await
+}
+
+/// Write the handoff file and start the new process, returning its pid.
+#[cfg(unix)]
+fn hand_off(path: &Path, cache: &Cache, sockets: &Sockets) -> Result<u32> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    let fds: Vec<_> = sockets
+        .tcp
+        .iter()
+        .chain(&sockets.udp)
+        .chain(&sockets.health)
+        .copied()
+        .collect();
+    let counts = Counts {
+        tcp: sockets.tcp.len(),
+        udp: sockets.udp.is_some(),
+        health: sockets.health.is_some(),
+    };
+    // The new process appends to the log after the changes handed off
+    if let Some(log) = cache.append_log() {
+        log.close();
+    }
+    write(path, cache, counts).with_context(|| format!("writing {}", path.display()))?;
+
+    // Copies above the range they are moved into in the child, so moving one
+    // into place never overwrites another. Closed on exec.
+    let last = FIRST_FD + fds.len() as i32;
+    let copies = fds
+        .iter()
+        .map(|&fd| {
+            // SAFETY: `fd` is open for as long as `on_sigusr2` runs.
+            match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, last) } {
+                -1 => Err(io::Error::last_os_error()),
+                // SAFETY: `fcntl` returned a new descriptor for us to own.
+                copy => Ok(unsafe { OwnedFd::from_raw_fd(copy) }),
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let raw: Vec<_> = copies.iter().map(|copy| copy.as_raw_fd()).collect();
+
+    let mut args = std::env::args_os();
+    let program = args.next().context("no program name in the arguments")?;
+    let mut command = Command::new(program);
+    command.args(args).arg("--load-handoff").arg(path);
+    // SAFETY: only async-signal-safe calls between fork and exec; `dup2`
+    // leaves the new descriptors open across exec.
+    unsafe {
+        command.pre_exec(move || {
+            for (target, &fd) in (FIRST_FD..).zip(&raw) {
+                if libc::dup2(fd, target) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().context("starting the new process")?;
+    Ok(child.id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let cache = Cache::new();
+        let expiration = Some(cache.now() + 60);
+        cache
+            .set(Bytes::from("foo"), 5, expiration, Bytes::from("bar"))
+            .await;
+        // Bumps the CAS
+        cache.set(Bytes::from("foo"), 5, expiration, Bytes::from("baz")).await;
+        cache.set(Bytes::from("empty"), 0, None, Bytes::new()).await;
+
+        let path = std::env::temp_dir().join(format!("sidica-handoff-test-{}", std::process::id()));
+        let counts = Counts {
+            tcp: 2,
+            udp: false,
+            health: true,
+        };
+        write(&path, &cache, counts).unwrap();
+
+        let handoff = Handoff::open(&path).unwrap();
+        assert_eq!(handoff.counts, counts);
+        let loaded = Cache::new();
+        assert_eq!(handoff.load(&loaded).unwrap(), 2);
+        assert!(!path.exists());
+
+        let foo = loaded.get(b"foo").await.unwrap();
+        assert_eq!(
+            (foo.flags, foo.cas, foo.expiration, &foo.data[..]),
+            (5, 2, expiration, &b"baz"[..])
+        );
+        let empty = loaded.get(b"empty").await.unwrap();
+        assert_eq!((empty.expiration, empty.data.len()), (None, 0));
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let path = std::env::temp_dir().join(format!("sidica-handoff-cut-{}", std::process::id()));
+        fs::write(&path, format!("{} tcp=1 udp=0 health=0\n\0\0\0\x03fo", MAGIC)).unwrap();
+        assert!(Handoff::open(&path).unwrap().load(&Cache::new()).is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert!(parse_header("sidica-handoff 2 tcp=x").is_err());
+        assert!(parse_header("something else").is_err());
+    }
+}
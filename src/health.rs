@@ -0,0 +1,288 @@
+use crate::cache::Cache;
+use crate::stats::ServerStats;
+
+use tracing::{debug, error, info};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+/// How often `Loading` checks for a probe.
+const LOADING_POLL: Duration = Duration::from_millis(10);
+
+/// Whether the server should receive traffic.
+///
+/// Starts out not ready. The server marks itself ready once startup is done
+/// and not ready again as soon as a graceful shutdown begins, so load
+/// balancers stop sending new connections while existing ones drain. It is
+/// not ready while draining either, see `drain`.
+#[derive(Debug, Default)]
+pub(crate) struct Readiness {
+    ready: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Readiness {
+    pub(crate) fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed) && !self.is_draining()
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+/// Start or stop draining.
+///
+/// While draining, new connections are answered with `SERVER_ERROR
+/// draining` and closed, and health checks report not ready, so load
+/// balancers move the traffic elsewhere. Connections already open are
+/// served as usual.
+pub(crate) fn drain(readiness: &Readiness, stats: &ServerStats, draining: bool) {
+    if readiness.draining.swap(draining, Ordering::Relaxed) != draining {
+        stats.set_draining(draining);
+        if draining {
+            info!("draining, refusing new connections");
+        } else {
+            info!("no longer draining, accepting new connections");
+        }
+    }
+}
+
+/// Toggle draining every time the process receives SIGWINCH.
+///
+/// Terminals send SIGWINCH when resized, so it is ignored while the server
+/// runs in the foreground of one.
+#[cfg(unix)]
+pub(crate) async fn on_sigwinch(readiness: Arc<Readiness>, stats: Arc<ServerStats>) {
+    use std::io::IsTerminal;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::window_change()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!("failed to install SIGWINCH handler: {}", err);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        if std::io::stdin().is_terminal() {
+            debug!("ignoring SIGWINCH from the terminal");
+            continue;
+        }
+        drain(&readiness, &stats, !readiness.is_draining());
+    }
+}
+
+/// Health-check listener. Answers every connection with a single status line
+/// and closes it:
+///
+/// ```text
+/// OK <version> items=<n>\r\n
+/// NOT_READY <version> items=<n>\r\n
+/// ```
+///
+/// Probes never go through the protocol handler, so they do not take up a
+/// connection slot.
+#[derive(Debug)]
+pub(crate) struct Listener {
+    pub(crate) listener: TcpListener,
+    pub(crate) cache: Cache,
+    pub(crate) readiness: Arc<Readiness>,
+}
+
+impl Listener {
+    /// Answer probes until the task is aborted. The server keeps this running
+    /// through a graceful shutdown so probes see it draining.
+    pub(crate) async fn run(&self) {
+        info!("accepting health checks");
+
+        loop {
+            let (mut socket, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("health check accept failed: {}", err);
+                    continue;
+                }
+            };
+
+            let status = status(&self.readiness, &self.cache);
+            tokio::spawn(async move {
+                if let Err(err) = socket.write_all(status.as_bytes()).await {
+                    debug!("health check from {} failed: {}", addr, err);
+                }
+            });
+        }
+    }
+
+}
+
+/// Answers health checks from a thread of its own while the server loads
+/// its snapshot and append log, before there is a runtime to run `Listener`
+/// on. Probes are answered the same way, not ready until the load is done.
+///
+/// Stops when dropped, and is gone by then: daemonizing needs the process
+/// single threaded again.
+#[derive(Debug)]
+pub(crate) struct Loading {
+    done: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Loading {
+    /// Answer probes to `listener` about `cache`, which is being loaded.
+    pub(crate) fn start(
+        listener: &std::net::TcpListener,
+        cache: Cache,
+        readiness: Arc<Readiness>,
+    ) -> io::Result<Loading> {
+        let listener = listener.try_clone()?;
+        listener.set_nonblocking(true)?;
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new().name("health-loading".to_string()).spawn({
+            let done = done.clone();
+            move || {
+                info!("accepting health checks while loading");
+                while !done.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((mut socket, addr)) => {
+                            let status = status(&readiness, &cache);
+                            if let Err(err) = socket.write_all(status.as_bytes()) {
+                                debug!("health check from {} failed: {}", addr, err);
+                            }
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(LOADING_POLL),
+                        Err(err) => {
+                            error!("health check accept failed: {}", err);
+                            thread::sleep(LOADING_POLL);
+                        }
+                    }
+                }
+            }
+        })?;
+        Ok(Loading { done, thread: Some(thread) })
+    }
+}
+
+impl Drop for Loading {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The status line answered to a probe.
+fn status(readiness: &Readiness, cache: &Cache) -> String {
+    let state = if readiness.is_ready() {
+        "OK"
+    } else {
+        "NOT_READY"
+    };
+    format!(
+        "{} {} items={}\r\n",
+        state,
+        env!("CARGO_PKG_VERSION"),
+        cache.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    async fn probe(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut status = String::new();
+        stream.read_to_string(&mut status).await.unwrap();
+        status
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cache = Cache::new();
+        let readiness = Arc::new(Readiness::default());
+
+        let health = Listener {
+            listener,
+            cache: cache.clone(),
+            readiness: readiness.clone(),
+        };
+        tokio::spawn(async move { health.run().await });
+
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            probe(addr).await,
+            format!("NOT_READY {} items=0\r\n", version)
+        );
+
+        readiness.set_ready(true);
+        cache
+            .set(Bytes::from("foo"), 0, None, Bytes::from("bar"))
+            .await;
+        assert_eq!(probe(addr).await, format!("OK {} items=1\r\n", version));
+
+        readiness.set_ready(false);
+        assert!(probe(addr).await.starts_with("NOT_READY "));
+    }
+
+    #[test]
+    fn test_loading() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let readiness = Arc::new(Readiness::default());
+        let probe = || {
+            let mut status = String::new();
+            std::net::TcpStream::connect(addr).unwrap().read_to_string(&mut status).unwrap();
+            status
+        };
+
+        let loading = Loading::start(&listener, Cache::new(), readiness.clone()).unwrap();
+        assert_eq!(probe(), format!("NOT_READY {} items=0\r\n", env!("CARGO_PKG_VERSION")));
+        drop(loading);
+        // Queued for the listener that takes over
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        drop(accepted);
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_drain() {
+        let readiness = Readiness::default();
+        let stats = ServerStats::new(1);
+        let draining = |stats: &ServerStats| {
+            stats
+                .snapshot()
+                .into_iter()
+                .find(|(name, _)| name == "draining")
+                .unwrap()
+                .1
+        };
+        readiness.set_ready(true);
+
+        drain(&readiness, &stats, true);
+        assert!(readiness.is_draining());
+        assert!(!readiness.is_ready());
+        assert_eq!(draining(&stats), 1);
+
+        drain(&readiness, &stats, false);
+        assert!(readiness.is_ready());
+        assert_eq!(draining(&stats), 0);
+    }
+}
@@ -1,65 +1,286 @@
-// Maybe use duration since first timestamp, but how to persit on disk
-
+use crate::sync::AtomicU64;
 use std::{
-    sync::atomic::{AtomicU32, Ordering},
-    time::{SystemTime, UNIX_EPOCH},
+    fmt,
+    ops::Range,
+    str::FromStr,
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use thiserror::Error;
+
+/// What the time in ids counts, see `Layout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Seconds,
+    Milliseconds,
+}
+
+impl Unit {
+    /// The widest count that leaves the time in this unit bits enough to go
+    /// on until past 2106: 32 for seconds, 42 for milliseconds.
+    const fn max_count_bits(self) -> u32 {
+        match self {
+            Unit::Seconds => 32,
+            Unit::Milliseconds => 22,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Unit::Seconds => "seconds",
+            Unit::Milliseconds => "milliseconds",
+        }
+    }
+}
+
+/// How an id is split: the time since the unix epoch, in `unit`, in the
+/// high bits, and a count of the ids handed out in that time in the low
+/// `count_bits`. Finer time leaves fewer bits to count in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    unit: Unit,
+    count_bits: u32,
+}
+
+impl Layout {
+    /// Seconds and a 32 bit count, the layout of ids from before there was
+    /// a choice.
+    pub const SECONDS: Layout = Layout::new(Unit::Seconds, 32);
 
+    /// A layout counting in `count_bits` per `unit`.
+    ///
+    /// # Panics
+    ///
+    /// Unless `count_bits` is from 1 to what leaves the time enough bits,
+    /// see `Unit::max_count_bits`. In a constant, that is when compiling.
+    pub const fn new(unit: Unit, count_bits: u32) -> Layout {
+        match Layout::checked(unit, count_bits) {
+            Some(layout) => layout,
+            None => panic!("the count of an id takes from 1 bit to what leaves the time enough"),
+        }
+    }
+
+    /// `Layout::new`, `None` where it would panic.
+    pub const fn checked(unit: Unit, count_bits: u32) -> Option<Layout> {
+        if count_bits == 0 || count_bits > unit.max_count_bits() {
+            return None;
+        }
+        Some(Layout { unit, count_bits })
+    }
+
+    /// The id of `count` in `time`, in the layout's unit.
+    fn combine(self, time: u64, count: u64) -> u64 {
+        time << self.count_bits | count
+    }
+
+    /// The highest count, all of its bits set.
+    #[cfg(test)]
+    fn max_count(self) -> u64 {
+        (1 << self.count_bits) - 1
+    }
+
+    /// `since_epoch` in the layout's unit.
+    fn time(self, since_epoch: Duration) -> u64 {
+        match self.unit {
+            Unit::Seconds => since_epoch.as_secs(),
+            Unit::Milliseconds => since_epoch.as_millis() as u64,
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout::SECONDS
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("expected seconds:BITS with BITS from 1 to 32, or milliseconds:BITS from 1 to 22, in `{0}`")]
+pub struct ParseLayoutError(String);
+
+impl FromStr for Layout {
+    type Err = ParseLayoutError;
+
+    fn from_str(s: &str) -> Result<Layout, ParseLayoutError> {
+        let (unit, bits) = s.split_once(':').ok_or_else(|| ParseLayoutError(s.to_string()))?;
+        let unit = match unit {
+            "seconds" => Some(Unit::Seconds),
+            "milliseconds" => Some(Unit::Milliseconds),
+            _ => None,
+        };
+        unit.zip(bits.parse().ok())
+            .and_then(|(unit, bits)| Layout::checked(unit, bits))
+            .ok_or_else(|| ParseLayoutError(s.to_string()))
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.unit.name(), self.count_bits)
+    }
+}
+
+/// Hands out ids made of the current time and a count of the ids handed out
+/// in it, as `Layout` has it: by default the unix timestamp in the high 32
+/// bits and the count of that second in the low 32.
 #[derive(Debug)]
 pub struct Generator {
-    ts: AtomicU32, // Unix Timestamp
-    count: AtomicU32,
+    layout: Layout,
+    /// The next id to hand out, time and count together so that both change
+    /// at once
+    next: AtomicU64,
+    /// Times the count of a second ran out, see `gen`
+    exhausted: AtomicU64,
+    /// The time since the unix epoch
+    clock: fn() -> Duration,
 }
 
 impl Generator {
+    #[cfg(test)]
     pub fn new() -> Generator {
+        Self::with_layout(Layout::default())
+    }
+
+    /// A generator of ids split as `layout` has it.
+    pub fn with_layout(layout: Layout) -> Generator {
+        Self::with_clock(layout, Self::since_epoch)
+    }
+
+    /// A generator telling the time by `clock` rather than the system time.
+    fn with_clock(layout: Layout, clock: fn() -> Duration) -> Generator {
         Generator {
-            ts: AtomicU32::new(Self::current_ts()),
-            count: AtomicU32::new(0),
+            layout,
+            next: AtomicU64::new(layout.combine(layout.time(clock()), 0)),
+            exhausted: AtomicU64::new(0),
+            clock,
         }
     }
 
-    fn current_ts() -> u32 {
+    /// A generator whose next id is `count` in second `timestamp`.
+    #[cfg(test)]
+    pub(crate) fn at(timestamp: u32, count: u32) -> Generator {
+        let gen = Self::new();
+        gen.next.store(Layout::SECONDS.combine(timestamp.into(), count.into()), Ordering::Relaxed);
+        gen
+    }
+
+    /// Returns how many times the count of a second ran out, so ids went on
+    /// into the next timestamp before its time.
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the last id handed out, or one below the first to be.
+    pub fn last(&self) -> u64 {
+        self.next.load(Ordering::Relaxed) - 1
+    }
+
+    /// Hand out only ids above `id` from now on, say the last one handed out
+    /// before a restart, see `watermark`.
+    pub fn skip_past(&self, id: u64) {
+        self.next.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+    }
+
+    fn since_epoch() -> Duration {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("getting time since unix epoch")
-            .as_secs() as u32
     }
 
-    fn combine(timestamp: u32, count: u32) -> u64 {
-        let mut id = [0u8; 8];
-        id[..4].copy_from_slice(&timestamp.to_be_bytes());
-        id[4..].copy_from_slice(&count.to_be_bytes());
-        // println!("{:?}", id); // change to debug
-        u64::from_be_bytes(id)
+    /// Returns an id never handed out before, higher than the last one. The
+    /// count starts over at 0 each second, or millisecond as the layout has
+    /// it; within a second it goes on from the last id. Once the count of a
+    /// second runs out it carries into the time: ids are borrowed from the
+    /// next second, which then goes on from them, rather than wrapping
+    /// around to ids already handed out.
+    ///
+    /// The latest second seen is kept in the last id, so if the system clock
+    /// is stepped back, say by NTP, that second is counted on in until the
+    /// clock is past it again, instead of the seconds before being handed
+    /// out over again.
+    pub fn gen(&self) -> u64 {
+        self.gen_n(1).start
     }
 
-    pub fn gen(&self) -> u64 {
-        let now = Self::current_ts();
-        let last_ts = self.ts.swap(now, Ordering::SeqCst);
-
-        let count: u32;
-        if now == last_ts {
-            count = self.count.fetch_add(1, Ordering::SeqCst);
-        } else {
-            count = 0;
-            // Resets count to 0
-            self.count.store(count, Ordering::SeqCst);
+    /// Returns `n` ids in a row never handed out before, higher than the
+    /// last one, taken in one go, for the caller to hand out. Like `gen`, a
+    /// block the count of a second has no room left for carries into the
+    /// next one.
+    pub fn gen_n(&self, n: u32) -> Range<u64> {
+        let start = self.layout.combine(self.layout.time((self.clock)()), 0);
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let id = next.max(start);
+            let end = id + u64::from(n);
+            match self.next.compare_exchange_weak(next, end, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    // The ids with a full count, each followed by the next
+                    // second's
+                    let bits = self.layout.count_bits;
+                    let exhausted = (end >> bits) - (id >> bits);
+                    if exhausted > 0 {
+                        self.exhausted.fetch_add(exhausted, Ordering::Relaxed);
+                    }
+                    return id..end;
+                }
+                Err(current) => next = current,
+            }
         }
-
-        Self::combine(now, count)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{thread, time::Duration};
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, AtomicU64},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
 
     use super::*;
 
+    fn combine(timestamp: u32, count: u32) -> u64 {
+        Layout::SECONDS.combine(timestamp.into(), count.into())
+    }
+
     #[test]
     fn test_combine() {
-        assert_eq!(Generator::combine(1, 5), 4294967301);
+        // As ids were before there were layouts
+        assert_eq!(combine(1, 5), 4294967301);
+        assert_eq!(combine(1, 5).to_be_bytes(), [0, 0, 0, 1, 0, 0, 0, 5]);
+        let layout = Layout::new(Unit::Milliseconds, 22);
+        assert_eq!(layout.combine(3, 5), 3 << 22 | 5);
+        assert_eq!(layout.max_count(), (1 << 22) - 1);
+    }
+
+    #[test]
+    fn test_parse_layout() {
+        assert_eq!("seconds:32".parse(), Ok(Layout::SECONDS));
+        assert_eq!("milliseconds:20".parse(), Ok(Layout::new(Unit::Milliseconds, 20)));
+        assert_eq!(Layout::new(Unit::Milliseconds, 20).to_string(), "milliseconds:20");
+        for bad in ["seconds:33", "milliseconds:23", "seconds:0", "minutes:8", "seconds", "seconds:x"] {
+            assert!(bad.parse::<Layout>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_milliseconds() {
+        static NOW: AtomicU64 = AtomicU64::new(1_700_000_000_000);
+        let layout = Layout::new(Unit::Milliseconds, 2);
+        let gen = Generator::with_clock(layout, || Duration::from_millis(NOW.load(Ordering::Relaxed)));
+
+        let now = NOW.load(Ordering::Relaxed);
+        let ids: Vec<u64> = (0..5).map(|_| gen.gen()).collect();
+        // The fifth runs out of the 2 bit count
+        let expected = (0..4).map(|count| layout.combine(now, count)).chain([layout.combine(now + 1, 0)]);
+        assert_eq!(ids, expected.collect::<Vec<_>>());
+        assert_eq!(gen.exhausted(), 1);
+        NOW.store(now + 5, Ordering::Relaxed);
+        assert_eq!(gen.gen(), layout.combine(now + 5, 0));
     }
 
     #[test]
@@ -84,4 +305,199 @@ mod tests {
         let id_minus_one_sec = gen.gen() - 4294967296;
         assert_eq!(id, id_minus_one_sec);
     }
+
+    #[test]
+    fn test_count_running_out() {
+        // Ahead of the clock, so that the count runs out before it moves on
+        let timestamp = Generator::since_epoch().as_secs() as u32 + 60;
+        let gen = Generator::at(timestamp, u32::MAX - 2);
+        let ids: Vec<u64> = (0..5).map(|_| gen.gen()).collect();
+        let expected = [
+            combine(timestamp, u32::MAX - 2),
+            combine(timestamp, u32::MAX - 1),
+            combine(timestamp, u32::MAX),
+            combine(timestamp + 1, 0),
+            combine(timestamp + 1, 1),
+        ];
+        assert_eq!(ids, expected);
+        assert_eq!(gen.exhausted(), 1);
+    }
+
+    #[test]
+    fn test_clock_stepped_back() {
+        static NOW: AtomicU32 = AtomicU32::new(1_000_000);
+        let clock = || Duration::from_secs(NOW.load(Ordering::Relaxed).into());
+        let gen = Generator::with_clock(Layout::SECONDS, clock);
+
+        let mut ids: Vec<u64> = (0..3).map(|_| gen.gen()).collect();
+        NOW.store(999_990, Ordering::Relaxed);
+        ids.extend((0..3).map(|_| gen.gen()));
+        NOW.store(1_000_001, Ordering::Relaxed);
+        ids.extend((0..3).map(|_| gen.gen()));
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", ids);
+        assert_eq!(ids[3], combine(1_000_000, 3));
+        assert_eq!(ids[6], combine(1_000_001, 0));
+    }
+
+    #[test]
+    fn test_gen_n() {
+        static NOW: AtomicU64 = AtomicU64::new(1_700_000_000_000);
+        let layout = Layout::new(Unit::Milliseconds, 3);
+        let gen = Generator::with_clock(layout, || Duration::from_millis(NOW.load(Ordering::Relaxed)));
+        let now = NOW.load(Ordering::Relaxed);
+
+        assert_eq!(gen.gen_n(3), layout.combine(now, 0)..layout.combine(now, 3));
+        assert_eq!(gen.gen(), layout.combine(now, 3));
+        assert_eq!(gen.gen_n(0), layout.combine(now, 4)..layout.combine(now, 4));
+        // Spans into the next millisecond, and the one after
+        assert_eq!(gen.gen_n(14), layout.combine(now, 4)..layout.combine(now + 2, 2));
+        assert_eq!(gen.exhausted(), 2);
+        assert_eq!(gen.gen(), layout.combine(now + 2, 2));
+        NOW.store(now + 3, Ordering::Relaxed);
+        assert_eq!(gen.gen_n(2), layout.combine(now + 3, 0)..layout.combine(now + 3, 2));
+    }
+
+    #[test]
+    fn test_gen_n_unique_with_gen() {
+        let gen = Arc::new(Generator::with_layout(Layout::new(Unit::Milliseconds, 4)));
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let gen = gen.clone();
+                thread::spawn(move || {
+                    let mut ids = Vec::new();
+                    for n in 0..5000 {
+                        match thread % 2 {
+                            0 => ids.push(gen.gen()),
+                            _ => ids.extend(gen.gen_n(n % 40)),
+                        }
+                    }
+                    ids
+                })
+            })
+            .collect();
+
+        let mut ids: Vec<u64> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        let generated = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), generated);
+    }
+
+    /// Every interleaving of `gen` with the time moving on, back, or the
+    /// count running out that the threads come across: ids stay unique,
+    /// and rise in the order each thread gets them.
+    #[test]
+    fn test_unique_across_boundaries() {
+        static NOW: AtomicU64 = AtomicU64::new(1_700_000_000_000);
+        static DONE: AtomicBool = AtomicBool::new(false);
+        let layout = Layout::new(Unit::Milliseconds, 2);
+        let gen = Generator::with_clock(layout, || Duration::from_millis(NOW.load(Ordering::Relaxed)));
+        let gen = Arc::new(gen);
+        let clock = thread::spawn(|| {
+            let mut steps = 0u64;
+            while !DONE.load(Ordering::Relaxed) {
+                steps += 1;
+                // Now and then back, by NTP say
+                if steps.is_multiple_of(7) {
+                    NOW.fetch_sub(2, Ordering::Relaxed);
+                } else {
+                    NOW.fetch_add(1, Ordering::Relaxed);
+                }
+                thread::yield_now();
+            }
+        });
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let gen = gen.clone();
+                thread::spawn(move || {
+                    let ids: Vec<u64> = (0..20_000).map(|_| gen.gen()).collect();
+                    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+                    ids
+                })
+            })
+            .collect();
+
+        let mut ids: Vec<u64> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        DONE.store(true, Ordering::Relaxed);
+        clock.join().unwrap();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 80_000);
+        assert!(gen.exhausted() > 0);
+    }
+
+    #[test]
+    fn test_unique_across_seconds() {
+        let gen = Arc::new(Generator::new());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let gen = gen.clone();
+                thread::spawn(move || {
+                    let until = Instant::now() + Duration::from_millis(2500);
+                    let mut ids = Vec::new();
+                    while Instant::now() < until {
+                        ids.extend((0..100).map(|_| gen.gen()));
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    ids
+                })
+            })
+            .collect();
+
+        let mut ids: Vec<u64> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        let generated = ids.len();
+        let seconds: HashSet<u64> = ids.iter().map(|id| id >> 32).collect();
+        assert!(seconds.len() >= 2);
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), generated);
+    }
+}
+
+/// Under loom, see `sync`.
+#[cfg(all(test, feature = "loom"))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    /// Ids stay unique, and rise in the order each thread gets them, in
+    /// every interleaving of the time moving on to the next millisecond
+    /// with the count of this one running out.
+    #[test]
+    fn test_unique_across_a_boundary() {
+        // Not loom's: read as the clock, not modelled
+        static NOW: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        loom::model(|| {
+            NOW.store(1_700_000_000_000, Ordering::Relaxed);
+            // Two ids to a millisecond
+            let layout = Layout::new(Unit::Milliseconds, 1);
+            let clock = || Duration::from_millis(NOW.load(Ordering::Relaxed));
+            let gen = Arc::new(Generator::with_clock(layout, clock));
+
+            let counting = {
+                let gen = gen.clone();
+                thread::spawn(move || vec![gen.gen(), gen.gen()])
+            };
+            let ticking = {
+                let gen = gen.clone();
+                thread::spawn(move || {
+                    NOW.fetch_add(1, Ordering::Relaxed);
+                    vec![gen.gen()]
+                })
+            };
+            let block = gen.gen_n(2).collect::<Vec<_>>();
+
+            let mut ids = block.clone();
+            for thread in [counting, ticking] {
+                let got = thread.join().unwrap();
+                assert!(got.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", got);
+                ids.extend(got);
+            }
+            ids.sort_unstable();
+            ids.dedup();
+            assert_eq!(ids.len(), 5, "{:?}", ids);
+        });
+    }
 }
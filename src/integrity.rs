@@ -0,0 +1,44 @@
+//! Looking for keys and items that have lost each other every
+//! `--integrity-audit` seconds, see `Cache::verify`.
+
+use crate::cache::Cache;
+use crate::settings::Settings;
+
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, warn};
+
+/// How often `audit_periodically` checks whether an audit is due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Verify `cache`, repairing what is found, every `--integrity-audit`
+/// seconds for as long as the server runs. The setting is read again at
+/// each check, so a reload can turn audits on or off.
+pub(crate) async fn audit_periodically(cache: Cache, settings: Arc<ArcSwap<Settings>>) {
+    let mut interval = time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let mut last = Instant::now();
+    loop {
+        interval.tick().await;
+        let Some(every) = settings.load().integrity_audit.map(Duration::from_secs) else {
+            continue;
+        };
+        if last.elapsed() < every {
+            continue;
+        }
+
+        let cache = cache.clone();
+        match tokio::task::spawn_blocking(move || cache.verify(true)).await {
+            Ok(found) if found.dangling_keys > 0 || found.orphaned_items > 0 => warn!(
+                dangling_keys = found.dangling_keys,
+                orphaned_items = found.orphaned_items,
+                repaired = found.repaired,
+                "keys and items had lost each other"
+            ),
+            Ok(found) => debug!(keys = found.keys, items = found.items, "verified the cache"),
+            Err(_) => {}
+        }
+        last = Instant::now();
+    }
+}
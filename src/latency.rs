@@ -0,0 +1,161 @@
+//! Latency histograms per command, reported by `stats latency`.
+//!
+//! The time a command takes, from parsed to answered, lands in one of
+//! `BUCKETS` log scaled buckets: bucket `i` holds times under `2^i`
+//! microseconds and at least half that, the last one everything longer.
+//! Recording is a lookup and a relaxed increment; percentiles are read off
+//! the buckets, so they are upper bounds, at most twice the real value.
+
+use crate::commands::NAMES;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const BUCKETS: usize = 32;
+
+/// Percentiles `Latencies::snapshot` reports.
+const PERCENTILES: [u64; 3] = [50, 95, 99];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> [u64; BUCKETS] {
+        std::array::from_fn(|bucket| self.buckets[bucket].load(Ordering::Relaxed))
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Microseconds the times in `bucket` are under, see the module docs.
+fn upper_bound(bucket: usize) -> u64 {
+    1 << bucket
+}
+
+/// Upper bound of the `percentile` of the times counted in `counts`.
+fn percentile(counts: &[u64; BUCKETS], percentile: u64) -> u64 {
+    let total: u64 = counts.iter().sum();
+    let rank = (total * percentile).div_ceil(100).max(1);
+    let mut seen = 0;
+    for (bucket, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return upper_bound(bucket);
+        }
+    }
+    upper_bound(BUCKETS - 1)
+}
+
+/// A histogram for each command in `commands::NAMES`, see
+/// `ServerStats::latency`.
+#[derive(Debug)]
+pub(crate) struct Latencies {
+    histograms: HashMap<&'static str, Histogram>,
+}
+
+impl Default for Latencies {
+    fn default() -> Latencies {
+        Latencies {
+            histograms: NAMES.iter().map(|&name| (name, Histogram::default())).collect(),
+        }
+    }
+}
+
+impl Latencies {
+    /// Count a `command`, named as by `Command::get_name`, that took
+    /// `elapsed`.
+    pub(crate) fn record(&self, command: &str, elapsed: Duration) {
+        let histogram = self.histograms.get(command);
+        debug_assert!(histogram.is_some(), "{} is missing from commands::NAMES", command);
+        if let Some(histogram) = histogram {
+            histogram.record(elapsed);
+        }
+    }
+
+    /// Forget every time counted so far, see `stats reset`.
+    pub(crate) fn reset(&self) {
+        self.histograms.values().for_each(Histogram::reset);
+    }
+
+    /// Returns `<command>:count`, then `<command>:p50_us`, `p95_us` and
+    /// `p99_us` of every command counted, in name order.
+    pub(crate) fn snapshot(&self) -> Vec<(String, u64)> {
+        let mut histograms: Vec<_> = self.histograms.iter().collect();
+        histograms.sort_unstable_by_key(|(&name, _)| name);
+        let mut stats = Vec::new();
+        for (name, histogram) in histograms {
+            let counts = histogram.counts();
+            let count = counts.iter().sum();
+            if count == 0 {
+                continue;
+            }
+            stats.push((format!("{}:count", name), count));
+            for p in PERCENTILES {
+                stats.push((format!("{}:p{}_us", name, p), percentile(&counts, p)));
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let latencies = Latencies::default();
+        let micros = Duration::from_micros;
+        for elapsed in [micros(0), micros(1), micros(3), micros(1000), Duration::from_secs(86400)] {
+            latencies.record("get", elapsed);
+        }
+        let counts = latencies.histograms["get"].counts();
+        // Under 1, 2, 4 and 1024 microseconds, and the longest
+        for bucket in [0, 1, 2, 10, BUCKETS - 1] {
+            assert_eq!(counts[bucket], 1, "{}", bucket);
+        }
+        assert_eq!(counts.iter().sum::<u64>(), 5);
+    }
+
+    #[test]
+    fn test_percentiles_and_reset() {
+        let latencies = Latencies::default();
+        assert!(latencies.snapshot().is_empty());
+        for _ in 0..90 {
+            latencies.record("set", Duration::from_micros(10));
+        }
+        for _ in 0..9 {
+            latencies.record("set", Duration::from_micros(100));
+        }
+        latencies.record("set", Duration::from_millis(10));
+        latencies.record("get", Duration::from_micros(10));
+
+        let expected = [
+            ("get:count", 1),
+            ("get:p50_us", 16),
+            ("get:p95_us", 16),
+            ("get:p99_us", 16),
+            ("set:count", 100),
+            ("set:p50_us", 16),
+            ("set:p95_us", 128),
+            ("set:p99_us", 128),
+        ];
+        let expected: Vec<_> = expected.iter().map(|&(name, value)| (name.to_string(), value)).collect();
+        assert_eq!(latencies.snapshot(), expected);
+
+        latencies.reset();
+        assert!(latencies.snapshot().is_empty());
+    }
+}
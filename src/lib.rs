@@ -0,0 +1,270 @@
+// The cache locks are synchronous; none may be held across an `.await`, see
+// `cache::Cache`.
+#![deny(clippy::await_holding_lock, clippy::await_holding_invalid_type)]
+
+mod acl;
+mod append_log;
+mod bench;
+mod cache;
+mod checksum;
+mod chunks;
+pub mod client;
+mod clock;
+mod commands;
+mod connection;
+mod daemon;
+mod detail;
+mod dump;
+mod expiry;
+mod export;
+mod frame;
+#[doc(hidden)]
+pub mod fuzz;
+mod handoff;
+mod health;
+mod id_generator;
+mod integrity;
+mod latency;
+mod limit;
+mod listen;
+mod logging;
+#[cfg(feature = "otel")]
+mod otel;
+mod overflow;
+mod parse;
+mod proxy;
+mod quota;
+mod reclaim;
+mod registry;
+mod reload;
+mod replication;
+mod server;
+mod settings;
+mod shutdown;
+mod snapshot;
+mod stats;
+mod storage;
+mod sync;
+mod tls;
+mod udp;
+mod watermark;
+
+/// What the criterion benches in `benches/` exercise directly, without a
+/// server. Not part of the API.
+#[doc(hidden)]
+pub mod internals {
+    pub use crate::cache::Cache;
+    pub use crate::commands::Command;
+    pub use crate::connection::Connection;
+    pub use crate::frame::{RequestFrame, ResponseFrame};
+}
+
+// How to group actions by request, for example multi-get
+
+use crate::append_log::AppendLog;
+use crate::connection::Connection;
+use crate::health::Readiness;
+use crate::overflow::Overflow;
+use crate::settings::Settings;
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use std::net::{TcpListener, UdpSocket};
+use std::sync::Arc;
+use tokio::signal;
+use tracing::{info, warn};
+
+/// Run the server as `settings` loaded from the command line say, until it
+/// is shut down.
+pub fn run() -> Result<()> {
+    let settings = Settings::load()?;
+    logging::init(&settings)?;
+    if let Some(settings::Subcommands::Bench(args)) = settings.command {
+        return bench::main(args);
+    }
+
+    // Everything that can fail at startup happens before daemonizing, while
+    // errors still reach the terminal.
+    let handoff = match &settings.load_handoff {
+        Some(path) => Some(handoff::Handoff::open(path)?),
+        None => None,
+    };
+    // On a warm restart the pid file still names the old process.
+    if let (Some(path), None) = (&settings.pid_file, &handoff) {
+        daemon::check_pid_file(path)?;
+    }
+    if let Some(user) = &settings.user {
+        daemon::check_user(user)?;
+    }
+    tls::config(&settings)?;
+
+    let (listeners, udp, health) = match &handoff {
+        Some(handoff) => handoff.sockets()?,
+        None => bind(&settings)?,
+    };
+
+    let overflow = match &settings.overflow_dir {
+        Some(dir) => Some(Arc::new(Overflow::open(dir, settings.overflow_limit_bytes())?)),
+        None => None,
+    };
+    let empty = || {
+        settings.cache_builder().overflow(overflow.clone()).build()
+    };
+    let mut cache = empty();
+    // Probes meanwhile see the server not ready, rather than not there. This
+    // takes a while with a large snapshot.
+    let readiness = Arc::new(Readiness::default());
+    let loading = match &health {
+        Some(listener) => Some(health::Loading::start(listener, cache.clone(), readiness.clone())?),
+        None => None,
+    };
+    let log = match (&settings.data_dir, settings.append_log) {
+        (Some(dir), true) => Some(AppendLog::open(dir)?),
+        _ => None,
+    };
+    // A warm restart carries the newer cache. The log already has every
+    // change up to it, the new changes are appended after.
+    if let Some(handoff) = handoff {
+        let loaded = handoff.load(&cache)?;
+        info!(items = loaded, "loaded the handoff file");
+    } else if let Some(dir) = &settings.data_dir {
+        let loaded = snapshot::load(dir, &cache).and_then(|loaded| {
+            let replayed = log.as_ref().map(|log| log.replay(&cache)).transpose()?;
+            Ok((loaded, replayed))
+        });
+        match loaded {
+            Ok((loaded, replayed)) => {
+                match loaded {
+                    Some(loaded) => info!(items = loaded, "loaded the snapshot"),
+                    None => info!("no snapshot to load"),
+                }
+                if let Some(replayed) = replayed {
+                    info!(records = replayed, "replayed the append log");
+                }
+            }
+            Err(err) if settings.discard_bad_snapshot => {
+                warn!("discarding the snapshot: {:#}", err);
+                cache = empty();
+                if let Some(log) = &log {
+                    log.clear()?;
+                }
+            }
+            Err(err) => {
+                anyhow::bail!("{:#}; use --discard-bad-snapshot to start empty instead", err);
+            }
+        }
+    }
+    // Items restored above keep their CAS; so that no other one is handed
+    // out twice, nor any id, go on from where the last server left off.
+    if let Some(dir) = &settings.data_dir {
+        match watermark::load(dir) {
+            Ok(Some(mark)) => {
+                cache.raise_watermark(mark);
+                info!(id = mark.id, cas = mark.cas, "loaded the watermark");
+            }
+            Ok(None) => info!("no watermark to load"),
+            Err(err) if settings.discard_bad_snapshot => warn!("discarding the watermark: {:#}", err),
+            Err(err) => {
+                anyhow::bail!("{:#}; use --discard-bad-snapshot to start anyway", err);
+            }
+        }
+        // Not clean anymore once anything is handed out, should the server
+        // be killed before saving it again
+        watermark::save(dir, cache.watermark(), false)?;
+    }
+    if let Some(log) = log {
+        cache = cache.with_append_log(log);
+    }
+    drop(loading);
+
+    info!("listening");
+
+    // Forking is only safe while the process is single threaded, so this
+    // comes before the runtime is built.
+    if settings.daemon {
+        daemon::daemonize()?;
+    }
+    // Removed when `run` returns
+    let _pid_file = match &settings.pid_file {
+        Some(path) => Some(daemon::PidFile::create(path)?),
+        None => None,
+    };
+    if let Some(user) = &settings.user {
+        // Everything written to from now on: the snapshot, watermark and log
+        // are rewritten next to the old ones, overflow segments come and go
+        let mut paths = settings.pid_file.clone().into_iter().collect::<Vec<_>>();
+        for dir in settings.data_dir.iter().chain(&settings.overflow_dir) {
+            paths.push(dir.clone());
+            for entry in std::fs::read_dir(dir)? {
+                paths.push(entry?.path());
+            }
+        }
+        let paths: Vec<_> = paths.iter().map(|path| path.as_path()).collect();
+        daemon::drop_privileges(user, &paths)?;
+    }
+    // After daemonizing, which leaves threads behind
+    if let Some(log) = cache.append_log() {
+        log.start()?;
+    }
+    // Flushes the exported spans when `run` returns
+    #[cfg(feature = "otel")]
+    let _exporter = match &settings.otel_endpoint {
+        Some(endpoint) => Some(otel::start(endpoint, settings.otel_sample_rate)?),
+        None => None,
+    };
+
+    // With `--core-pinned` connections are served on threads of their own,
+    // this runtime only runs the server's housekeeping.
+    let runtime = if settings.core_pinned {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    }
+    .enable_all()
+    .build()?;
+
+    runtime.block_on(async {
+        let udp = match udp {
+            Some(socket) => {
+                socket.set_nonblocking(true)?;
+                Some(tokio::net::UdpSocket::from_std(socket)?)
+            }
+            None => None,
+        };
+        let health = match health {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                Some(tokio::net::TcpListener::from_std(listener)?)
+            }
+            None => None,
+        };
+
+        server::run(listeners, udp, health, readiness, cache, settings, signal::ctrl_c()).await
+    })
+}
+
+/// Bind the sockets configured in `settings`: the TCP listeners, unless
+/// socket activated, and the optional UDP socket and health listener.
+fn bind(settings: &Settings) -> Result<(Vec<TcpListener>, Option<UdpSocket>, Option<TcpListener>)> {
+    // When socket activated the listeners are inherited, not bound.
+    let listeners = match listen::activated()? {
+        Some(listeners) => listeners,
+        None => {
+            let addr = format!("{}:{}", settings.listen, settings.port);
+            let count = if settings.core_pinned {
+                std::thread::available_parallelism()?.get()
+            } else {
+                settings.reuseport.into()
+            };
+            listen::tcp(&addr, count, settings.backlog)?
+        }
+    };
+    let udp = match settings.udp_port {
+        Some(port) => Some(UdpSocket::bind((settings.listen.as_str(), port))?),
+        None => None,
+    };
+    let health = match settings.health_port {
+        Some(port) => Some(TcpListener::bind((settings.listen.as_str(), port))?),
+        None => None,
+    };
+    Ok((listeners, udp, health))
+}
@@ -0,0 +1,213 @@
+use crate::settings::Settings;
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Window over which `--connection-rate-per-ip` is counted.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How often idle clients are dropped from the map.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Why a connection was refused by the `IpLimiter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Rejected {
+    /// The client already has `--max-connections-per-ip` connections open
+    TooManyConnections,
+    /// The client opened more than `--connection-rate-per-ip` connections in
+    /// the last second
+    RateExceeded,
+}
+
+/// Per client IP connection limits.
+///
+/// The limits themselves are read from the settings on every `acquire`, so a
+/// reload takes effect for the next connection. Clients are tracked in a concurrent map keyed by IP. Entries are created on
+/// connect and removed by `prune` once the client has no open connections and
+/// its rate window has passed, so the map only holds recently active clients.
+#[derive(Debug, Default)]
+pub(crate) struct IpLimiter {
+    clients: DashMap<IpAddr, Client>,
+}
+
+#[derive(Debug)]
+struct Client {
+    /// Currently open connections
+    connections: u32,
+    /// Start of the current rate window
+    window: Instant,
+    /// Connections opened since `window`
+    opened: u32,
+}
+
+/// Held by a connection admitted by `IpLimiter::acquire`. Dropping it releases
+/// the connection slot.
+#[derive(Debug)]
+pub(crate) struct IpPermit {
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+}
+
+impl IpLimiter {
+    /// Admit a new connection from `ip` under the limits in `settings`, or
+    /// say why it must be refused. Returns `None`, without tracking the
+    /// client, when no per-IP limit is configured.
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        settings: &Settings,
+    ) -> Result<Option<IpPermit>, Rejected> {
+        let max_connections = settings.max_connections_per_ip;
+        let max_rate = settings.connection_rate_per_ip;
+        if max_connections.is_none() && max_rate.is_none() {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        let mut client = self.clients.entry(ip).or_insert_with(|| Client {
+            connections: 0,
+            window: now,
+            opened: 0,
+        });
+
+        if now.duration_since(client.window) >= RATE_WINDOW {
+            client.window = now;
+            client.opened = 0;
+        }
+        // Refused attempts count towards the rate too, so a client cannot
+        // keep retrying at full speed.
+        client.opened += 1;
+
+        if max_rate.is_some_and(|max| client.opened > max) {
+            return Err(Rejected::RateExceeded);
+        }
+        if max_connections.is_some_and(|max| client.connections >= max) {
+            return Err(Rejected::TooManyConnections);
+        }
+
+        client.connections += 1;
+        Ok(Some(IpPermit {
+            limiter: self.clone(),
+            ip,
+        }))
+    }
+
+    /// Forget clients with no open connections whose rate window has passed.
+    pub(crate) fn prune(&self) {
+        let now = Instant::now();
+        self.clients.retain(|_, client| {
+            client.connections > 0 || now.duration_since(client.window) < RATE_WINDOW
+        });
+    }
+
+    fn release(&self, ip: IpAddr) {
+        if let Some(mut client) = self.clients.get_mut(&ip) {
+            client.connections -= 1;
+        }
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+/// Prune `limiter` every `PRUNE_INTERVAL` until it is dropped.
+pub(crate) async fn prune_periodically(limiter: Weak<IpLimiter>) {
+    let mut interval = time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match limiter.upgrade() {
+            Some(limiter) => limiter.prune(),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn settings(args: &[&str]) -> Settings {
+        Settings::parse_from(["sidica"].iter().chain(args))
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = Arc::new(IpLimiter::default());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(limiter.acquire(ip, &settings(&[])).unwrap().is_none());
+        assert!(limiter.clients.is_empty());
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let limiter = Arc::new(IpLimiter::default());
+        let settings = settings(&["--max-connections-per-ip", "2"]);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.acquire(ip, &settings).unwrap();
+        let _second = limiter.acquire(ip, &settings).unwrap();
+        assert_eq!(
+            limiter.acquire(ip, &settings).unwrap_err(),
+            Rejected::TooManyConnections
+        );
+        // Other clients are unaffected
+        let _other = limiter.acquire(other, &settings).unwrap();
+
+        // Closing a connection frees its slot
+        drop(first);
+        let _third = limiter.acquire(ip, &settings).unwrap();
+
+        // Changed limits apply to the next connection
+        let raised = self::settings(&["--max-connections-per-ip", "3"]);
+        let _fourth = limiter.acquire(ip, &raised).unwrap();
+    }
+
+    #[test]
+    fn test_rate() {
+        let limiter = Arc::new(IpLimiter::default());
+        let settings = settings(&["--connection-rate-per-ip", "3"]);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        for _ in 0..3 {
+            drop(limiter.acquire(ip, &settings).unwrap());
+        }
+        assert_eq!(
+            limiter.acquire(ip, &settings).unwrap_err(),
+            Rejected::RateExceeded
+        );
+
+        // A new window starts after a second
+        limiter.clients.get_mut(&ip).unwrap().window -= RATE_WINDOW;
+        let _permit = limiter.acquire(ip, &settings).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let limiter = Arc::new(IpLimiter::default());
+        let settings = settings(&["--max-connections-per-ip", "1"]);
+        let idle: IpAddr = "10.0.0.1".parse().unwrap();
+        let active: IpAddr = "10.0.0.2".parse().unwrap();
+
+        drop(limiter.acquire(idle, &settings).unwrap());
+        let _permit = limiter.acquire(active, &settings).unwrap();
+
+        // Still inside the rate window
+        limiter.prune();
+        assert_eq!(limiter.clients.len(), 2);
+
+        for mut client in limiter.clients.iter_mut() {
+            client.window -= RATE_WINDOW;
+        }
+        limiter.prune();
+        assert!(!limiter.clients.contains_key(&idle));
+        assert!(limiter.clients.contains_key(&active));
+    }
+}
@@ -0,0 +1,155 @@
+//! Listening sockets are created as std sockets in non-blocking mode, ready
+//! for `tokio::net::TcpListener::from_std`. This lets them be bound before the
+//! runtime starts, which `--daemon` needs as it must fork before any threads
+//! exist.
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Socket, Type};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+
+/// First descriptor passed by systemd, see sd_listen_fds(3).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Adopt the listeners passed in by systemd socket activation.
+///
+/// Returns `None` when the process was not socket activated, that is when
+/// `LISTEN_FDS` is unset or `LISTEN_PID` names another process. Like
+/// sd_listen_fds(3), the variables are removed so they are not inherited by
+/// child processes. Every passed descriptor must be a TCP stream socket.
+#[cfg(unix)]
+pub(crate) fn activated() -> Result<Option<Vec<TcpListener>>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(None),
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+
+    let count: i32 = fds
+        .parse()
+        .with_context(|| format!("invalid LISTEN_FDS: {}", fds))?;
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(adopt)
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn activated() -> Result<Option<Vec<TcpListener>>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn adopt(fd: i32) -> Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: systemd passes these descriptors to this process for it to
+    // own, and `activated` only hands each one out once.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    socket.set_cloexec(true)?;
+
+    let is_stream = socket
+        .r#type()
+        .with_context(|| format!("inherited fd {} is not a socket", fd))?
+        == Type::STREAM;
+    let is_inet = socket.local_addr()?.as_socket().is_some();
+    if !is_stream || !is_inet {
+        anyhow::bail!(
+            "inherited fd {} is not a TCP stream socket; only TCP listeners can be socket activated",
+            fd
+        );
+    }
+
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Bind `count` TCP listeners on `addr`, each with a queue of `backlog`
+/// pending connections.
+///
+/// With a single listener this is a plain bind. With more, every socket sets
+/// SO_REUSEPORT so they can share the address and the kernel spreads incoming
+/// connections across them. If `addr` asks for an ephemeral port, the first
+/// listener picks it and the rest join it.
+pub(crate) fn tcp(addr: &str, count: usize, backlog: i32) -> Result<Vec<TcpListener>> {
+    let mut addr = addr
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("{} did not resolve to an address", addr))?;
+
+    if count <= 1 {
+        return Ok(vec![bind(addr, false, backlog)?]);
+    }
+
+    let mut listeners = Vec::with_capacity(count);
+    for _ in 0..count {
+        let listener = bind(addr, true, backlog)?;
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn bind(addr: SocketAddr, reuseport: bool, backlog: i32) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // Like `tokio::net::TcpListener::bind`, so a restart does not have to
+    // wait for old connections in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if reuseport {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("binding {}", addr))?;
+    socket.listen(backlog)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    Ok(socket.set_reuse_port(true)?)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &Socket) -> Result<()> {
+    anyhow::bail!("--reuseport is not supported on this platform: SO_REUSEPORT is unavailable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_listener() {
+        let listeners = tcp("127.0.0.1:0", 1, 1024).unwrap();
+        assert_eq!(listeners.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reuseport_listeners_share_address() {
+        let listeners = tcp("127.0.0.1:0", 4, 1024).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(listeners.len(), 4);
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Log output. Everything is logged through `tracing`; records from crates
+//! using `log` are forwarded to it.
+
+use crate::settings::{LogFormat, Settings};
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use std::sync::{Arc, OnceLock};
+use tracing::info;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Swaps the filter of the global subscriber, set by `init`.
+static FILTER: OnceLock<FilterHandle> = OnceLock::new();
+
+/// The filter for `--verbosity`. Other crates stay at `info` throughout.
+fn filter(verbosity: u8) -> EnvFilter {
+    EnvFilter::new(match verbosity {
+        0 => "info",
+        1 => "info,sidica=debug",
+        _ => "info,sidica=trace",
+    })
+}
+
+/// Install the global subscriber, writing to stderr in `--log-format`. What
+/// is logged follows `--verbosity`, unless `RUST_LOG` holds `EnvFilter`
+/// directives.
+pub(crate) fn init(settings: &Settings) -> Result<()> {
+    let initial = EnvFilter::try_from_default_env().unwrap_or_else(|_| filter(settings.verbosity));
+    let (filter, handle) = reload::Layer::new(initial);
+    let layer = fmt::layer().with_writer(std::io::stderr);
+    let log = match settings.log_format {
+        LogFormat::Text => layer.with_filter(filter).boxed(),
+        LogFormat::Json => layer.json().with_filter(filter).boxed(),
+    };
+
+    // Spans are exported regardless of the verbosity
+    #[allow(unused_mut)]
+    let mut layers = vec![log];
+    #[cfg(feature = "otel")]
+    if settings.otel_endpoint.is_some() {
+        layers.push(crate::otel::layer());
+    }
+    tracing_subscriber::registry().with(layers).try_init()?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// Apply `verbosity` to the log. Does nothing before `init`.
+pub(crate) fn apply_verbosity(verbosity: u8) {
+    if let Some(handle) = FILTER.get() {
+        reload_filter(handle, verbosity);
+    }
+}
+
+/// Change the verbosity in the shared `settings` and apply it to the log.
+pub(crate) fn set_verbosity(settings: &ArcSwap<Settings>, verbosity: u8) {
+    settings.rcu(|current| {
+        let mut new = Settings::clone(current);
+        new.verbosity = verbosity;
+        Arc::new(new)
+    });
+    apply_verbosity(verbosity);
+}
+
+fn reload_filter(handle: &FilterHandle, verbosity: u8) {
+    match handle.reload(filter(verbosity)) {
+        Ok(()) => info!(verbosity, "log verbosity changed"),
+        // Only fails once the subscriber is gone
+        Err(err) => eprintln!("failed to change log verbosity: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::commands::Command;
+    use crate::frame::RequestFrame;
+    use crate::health::Readiness;
+    use crate::stats::ServerStats;
+    use crate::Connection;
+    use bytes::Bytes;
+    use clap::Parser;
+    use parking_lot::Mutex;
+    use std::io::Write;
+
+    /// Collects everything logged, in memory.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock())).unwrap()
+        }
+    }
+
+    async fn get(cache: &Cache, settings: &ArcSwap<Settings>) {
+        let (_client, server) = tokio::io::duplex(4096);
+        let cmd = Command::from_frame(RequestFrame::Other(Bytes::from_static(b"get foo"))).unwrap();
+        cmd.apply(
+            cache,
+            &ServerStats::new(1),
+            settings,
+            &Readiness::default(),
+            None,
+            true,
+            &mut Connection::new(server),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verbosity_at_runtime() {
+        let captured = Captured::default();
+        let (layer, handle) = reload::Layer::new(filter(0));
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(layer)
+            .with(fmt::layer().with_writer(move || writer.clone()).with_ansi(false));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let cache = Cache::new();
+        let settings = ArcSwap::from_pointee(Settings::parse_from(["sidica"]));
+        get(&cache, &settings).await;
+        assert!(!captured.take().contains("miss"));
+
+        reload_filter(&handle, 1);
+        get(&cache, &settings).await;
+        assert!(captured.take().contains("miss"));
+
+        reload_filter(&handle, 0);
+        get(&cache, &settings).await;
+        assert!(!captured.take().contains("miss"));
+    }
+}
@@ -0,0 +1,61 @@
+//! Export of the command spans over OTLP, with the `otel` feature. See
+//! `--otel-endpoint`.
+
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use std::sync::OnceLock;
+use tracing::warn;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::{reload, Layer, Registry};
+
+type Exporting = Option<OpenTelemetryLayer<Registry, Tracer>>;
+
+/// Swaps in the exporting layer once `start` is called.
+static LAYER: OnceLock<reload::Handle<Exporting, Registry>> = OnceLock::new();
+
+/// The layer exporting the command spans, for `logging::init`. Exports
+/// nothing until `start`. Other spans and events never reach it.
+pub(crate) fn layer() -> Box<dyn Layer<Registry> + Send + Sync> {
+    let (layer, handle) = reload::Layer::new(None);
+    let _ = LAYER.set(handle);
+    layer
+        .with_filter(filter_fn(|metadata| {
+            metadata.is_span() && metadata.name() == "command"
+        }))
+        .boxed()
+}
+
+/// Exports spans until dropped, then flushes those still queued.
+pub(crate) struct Exporter(SdkTracerProvider);
+
+/// Start exporting to `endpoint`, sampling `rate` of the commands.
+///
+/// The exporter runs on threads of its own, so this has to come after
+/// daemonizing.
+pub(crate) fn start(endpoint: &str, rate: f64) -> Result<Exporter> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::TraceIdRatioBased(rate))
+        .with_resource(Resource::builder().with_service_name("sidica").build())
+        .build();
+    if let Some(handle) = LAYER.get() {
+        handle.reload(Some(OpenTelemetryLayer::new(provider.tracer("sidica"))))?;
+    }
+    Ok(Exporter(provider))
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.shutdown() {
+            warn!("failed to flush the exported spans: {}", err);
+        }
+    }
+}
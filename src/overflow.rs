@@ -0,0 +1,477 @@
+//! Disk overflow tier, with `--overflow-dir`: rather than evicting an item
+//! to make room, the cache writes its value to a file and keeps only the
+//! rest of the item in memory, with where the value went. A `get` reads it
+//! back and moves it to memory again.
+//!
+//! Values go one after the other into segment files, `--overflow-limit`
+//! split over `SEGMENTS` of them, each written to until full. Space is never
+//! reused within a segment: a segment file is removed once no item points
+//! into it anymore. When all the space is taken, the oldest segment is
+//! dropped and the items still in it are lost, like evicted ones. So that
+//! few are, the values left in a segment mostly freed are moved to the one
+//! written to, see `compact_periodically`.
+//!
+//! Each value is written after the item's CAS, and its key and value
+//! lengths, u32, then the key; a read checks them against the item, so it
+//! only ever returns the value it was written for. Numbers are big endian.
+//!
+//! Nothing is kept across restarts; the segments left by a previous run are
+//! removed on startup.
+
+use crate::cache::Cache;
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use parking_lot::{Mutex, RwLock};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::time;
+use tracing::{debug, warn};
+
+/// Segments `--overflow-limit` is split over. Dropping the oldest frees
+/// this fraction of the space.
+const SEGMENTS: u64 = 8;
+
+/// Bytes written before each value: CAS u64, key and value lengths u32.
+const HEADER_LEN: usize = 16;
+
+/// Prefix of the segment file names, followed by their number.
+const PREFIX: &str = "segment.";
+
+/// How often `compact_periodically` looks for a segment to compact.
+const COMPACT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts shared by the tier and its segments.
+#[derive(Debug, Default)]
+struct Counts {
+    /// Bytes in the segment files
+    bytes: AtomicU64,
+    /// Values on disk that an item points to
+    items: AtomicU64,
+}
+
+/// The disk tier, see the module documentation.
+#[derive(Debug)]
+pub(crate) struct Overflow {
+    dir: PathBuf,
+    limit: u64,
+    segment_size: u64,
+    counts: Arc<Counts>,
+    segments: Mutex<Segments>,
+    /// Values written
+    written: AtomicU64,
+    /// Reads served from disk, and those that found the value lost
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Segments dropped to stay under the limit
+    dropped: AtomicU64,
+    /// Values moved by compaction
+    moved: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Segments {
+    /// Number of the next segment
+    next: u64,
+    /// Segment values are written to, and where the next one goes
+    current: Option<(Arc<Segment>, u64)>,
+    /// Every segment not removed yet, oldest first
+    all: VecDeque<Weak<Segment>>,
+}
+
+/// A segment file. Removed once dropped, when no item points into it, or
+/// when the tier drops it for room.
+#[derive(Debug)]
+pub(crate) struct Segment {
+    path: PathBuf,
+    /// `None` once dropped for room
+    file: RwLock<Option<File>>,
+    size: AtomicU64,
+    /// Bytes of the values items point to, headers and keys included
+    live: AtomicU64,
+    removed: AtomicBool,
+    counts: Arc<Counts>,
+}
+
+impl Segment {
+    /// The key of every value written, and where it is, in order.
+    pub(crate) fn keys(&self) -> io::Result<Vec<(u64, Bytes)>> {
+        let file = self.file.read();
+        let Some(file) = &*file else {
+            return Ok(Vec::new());
+        };
+        let size = self.size.load(Ordering::Relaxed);
+        let mut keys = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let mut header = [0; HEADER_LEN];
+            read_at(file, &mut header, offset)?;
+            let key_len = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+            let len = u32::from_be_bytes(header[12..].try_into().unwrap()) as u64;
+            let mut key = vec![0; key_len];
+            read_at(file, &mut key, offset + HEADER_LEN as u64)?;
+            keys.push((offset, Bytes::from(key)));
+            offset += (HEADER_LEN + key_len) as u64 + len;
+        }
+        Ok(keys)
+    }
+
+    /// Remove the file, once. Items pointing into it find their value lost.
+    fn remove(&self) {
+        if self.removed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.file.write().take();
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("failed to remove {}: {}", self.path.display(), err);
+        }
+        self.counts.bytes.fetch_sub(self.size.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Where a value is on disk, to read it back without holding on to the item.
+/// Keeps the segment, but is not counted as living in it.
+#[derive(Debug, Clone)]
+pub(crate) struct Location {
+    segment: Arc<Segment>,
+    offset: u64,
+    len: u32,
+}
+
+impl Location {
+    /// Length of the value.
+    pub(crate) fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Read back the value of the item `key` with `cas`. `None` if it was
+    /// lost.
+    pub(crate) fn read(&self, key: &[u8], cas: u64) -> io::Result<Option<Bytes>> {
+        let file = self.segment.file.read();
+        let Some(file) = &*file else {
+            return Ok(None);
+        };
+        let mut record = vec![0; HEADER_LEN + key.len() + self.len()];
+        read_at(file, &mut record, self.offset)?;
+        let (header, rest) = record.split_at(HEADER_LEN);
+        let expected = header_of(cas, key.len(), self.len());
+        if header != expected || &rest[..key.len()] != key {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} holds another value at {}", self.segment.path.display(), self.offset),
+            ));
+        }
+        Ok(Some(Bytes::from(record).slice(HEADER_LEN + key.len()..)))
+    }
+}
+
+/// Where an item's value is on disk. Keeps the segment until the item goes.
+#[derive(Debug)]
+pub(crate) struct Spilled {
+    location: Location,
+    key_len: u32,
+}
+
+impl Spilled {
+    /// Whether the value is the one at `offset` in `segment`.
+    pub(crate) fn is_at(&self, segment: &Arc<Segment>, offset: u64) -> bool {
+        Arc::ptr_eq(&self.location.segment, segment) && self.location.offset == offset
+    }
+
+    /// Length of the value.
+    pub(crate) fn len(&self) -> usize {
+        self.location.len()
+    }
+
+    /// Whether the segment was dropped for room, and the value with it.
+    pub(crate) fn is_lost(&self) -> bool {
+        self.location.segment.removed.load(Ordering::Relaxed)
+    }
+
+    /// Where the value is, to read it once the item is let go of.
+    pub(crate) fn location(&self) -> Location {
+        self.location.clone()
+    }
+}
+
+impl Drop for Spilled {
+    fn drop(&mut self) {
+        let len = HEADER_LEN as u64 + u64::from(self.key_len) + u64::from(self.location.len);
+        self.location.segment.live.fetch_sub(len, Ordering::Relaxed);
+        self.location.segment.counts.items.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut std::mem::take(&mut buf)[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn header_of(cas: u64, key_len: usize, len: usize) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..8].copy_from_slice(&cas.to_be_bytes());
+    header[8..12].copy_from_slice(&(key_len as u32).to_be_bytes());
+    header[12..].copy_from_slice(&(len as u32).to_be_bytes());
+    header
+}
+
+impl Overflow {
+    /// Use `dir` for at most `limit` bytes of values, removing the segments
+    /// a previous run left there.
+    pub(crate) fn open(dir: &Path, limit: u64) -> Result<Overflow> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        let entries = fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if name.starts_with(PREFIX) {
+                fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+            }
+        }
+        Ok(Overflow {
+            dir: dir.to_path_buf(),
+            limit,
+            segment_size: limit / SEGMENTS,
+            counts: Arc::default(),
+            segments: Mutex::default(),
+            written: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            moved: AtomicU64::new(0),
+        })
+    }
+
+    /// Write `data`, the value of the item `key` with `cas`. `None` if it
+    /// does not fit in a segment.
+    pub(crate) fn write(&self, key: &[u8], cas: u64, data: &[u8]) -> io::Result<Option<Spilled>> {
+        let len = (HEADER_LEN + key.len() + data.len()) as u64;
+        if len > self.segment_size {
+            return Ok(None);
+        }
+        let mut segments = self.segments.lock();
+        let (segment, offset) = match &segments.current {
+            Some((segment, end)) if end + len <= self.segment_size => (segment.clone(), *end),
+            _ => (self.start_segment(&mut segments)?, 0),
+        };
+
+        let mut record = BytesMut::with_capacity(len as usize);
+        record.put_slice(&header_of(cas, key.len(), data.len()));
+        record.put_slice(key);
+        record.put_slice(data);
+        if let Some(file) = &*segment.file.read() {
+            write_at(file, &record, offset)?;
+        }
+        segments.current = Some((segment.clone(), offset + len));
+        segment.size.fetch_add(len, Ordering::Relaxed);
+        segment.live.fetch_add(len, Ordering::Relaxed);
+        self.counts.bytes.fetch_add(len, Ordering::Relaxed);
+        self.counts.items.fetch_add(1, Ordering::Relaxed);
+        self.written.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(Spilled {
+            location: Location {
+                segment,
+                offset,
+                len: data.len() as u32,
+            },
+            key_len: key.len() as u32,
+        }))
+    }
+
+    /// Start a new segment, dropping the oldest until there is room for it.
+    fn start_segment(&self, segments: &mut Segments) -> io::Result<Arc<Segment>> {
+        segments.current = None;
+        segments.all.retain(|segment| segment.strong_count() > 0);
+        while segments.all.len() as u64 >= SEGMENTS {
+            if let Some(segment) = segments.all.pop_front().and_then(|segment| segment.upgrade()) {
+                segment.remove();
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let path = self.dir.join(format!("{}{}", PREFIX, segments.next));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        segments.next += 1;
+        let segment = Arc::new(Segment {
+            path,
+            file: RwLock::new(Some(file)),
+            size: AtomicU64::new(0),
+            live: AtomicU64::new(0),
+            removed: AtomicBool::new(false),
+            counts: self.counts.clone(),
+        });
+        segments.all.push_back(Arc::downgrade(&segment));
+        Ok(segment)
+    }
+
+    /// The full segment with the least of its values still pointed to, if
+    /// that is less than half of it and the tier is more than half full.
+    pub(crate) fn to_compact(&self) -> Option<Arc<Segment>> {
+        if self.counts.bytes.load(Ordering::Relaxed) * 2 <= self.limit {
+            return None;
+        }
+        let segments = self.segments.lock();
+        let current = segments.current.as_ref().map(|(segment, _)| segment);
+        segments
+            .all
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|segment| current.is_none_or(|current| !Arc::ptr_eq(segment, current)))
+            .filter(|segment| {
+                segment.live.load(Ordering::Relaxed) * 2 < segment.size.load(Ordering::Relaxed)
+            })
+            .min_by_key(|segment| segment.live.load(Ordering::Relaxed))
+    }
+
+    /// Count values moved out of a segment being compacted.
+    pub(crate) fn count_moved(&self, moved: usize) {
+        self.moved.fetch_add(moved as u64, Ordering::Relaxed);
+    }
+
+    /// Count a read of a value from disk, found or lost.
+    pub(crate) fn count_read(&self, found: bool) {
+        let count = if found { &self.hits } else { &self.misses };
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the statistics reported by `stats`, as `(name, value)` pairs.
+    pub(crate) fn stats(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("overflow_bytes", self.counts.bytes.load(Ordering::Relaxed)),
+            ("overflow_items", self.counts.items.load(Ordering::Relaxed)),
+            ("overflow_written", self.written.load(Ordering::Relaxed)),
+            ("overflow_hits", self.hits.load(Ordering::Relaxed)),
+            ("overflow_misses", self.misses.load(Ordering::Relaxed)),
+            ("overflow_segments_dropped", self.dropped.load(Ordering::Relaxed)),
+            ("overflow_compacted", self.moved.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// Compact a segment of the overflow tier of `cache` whenever one is mostly
+/// freed, see `Cache::compact_overflow`. Checks every `COMPACT_INTERVAL`,
+/// for as long as the server runs.
+pub(crate) async fn compact_periodically(cache: Cache) {
+    if cache.overflow().is_none() {
+        return;
+    }
+    let mut interval = time::interval(COMPACT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let cache = cache.clone();
+        match tokio::task::spawn_blocking(move || cache.compact_overflow()).await {
+            Ok(Ok(0)) | Err(_) => {}
+            Ok(Ok(moved)) => debug!(moved, "compacted a segment of the overflow tier"),
+            Ok(Err(err)) => warn!("failed to compact the overflow tier: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sidica-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_segments_are_removed() {
+        let dir = dir("overflow-segments");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("segment.7"), b"left over").unwrap();
+        // Segments of 100 bytes, room for 2 values of 30
+        let overflow = Overflow::open(&dir, 100 * SEGMENTS).unwrap();
+        assert!(!dir.join("segment.7").exists());
+
+        let value = [1; 30];
+        let spilled: Vec<_> = (0..4u64)
+            .map(|n| overflow.write(b"key", n, &value).unwrap().unwrap())
+            .collect();
+        let bytes = 4 * (HEADER_LEN + 3 + 30) as u64;
+        assert_eq!(overflow.counts.bytes.load(Ordering::Relaxed), bytes);
+        assert_eq!(&spilled[3].location().read(b"key", 3).unwrap().unwrap()[..], &value);
+        // Only for the item it was written for
+        assert!(spilled[3].location().read(b"key", 2).is_err());
+        assert!(spilled[3].location().read(b"other", 3).is_err());
+
+        // A segment goes with the last item in it
+        let mut spilled = spilled.into_iter();
+        drop(spilled.next());
+        assert!(dir.join("segment.0").exists());
+        drop(spilled.next());
+        assert!(!dir.join("segment.0").exists());
+        assert_eq!(overflow.counts.bytes.load(Ordering::Relaxed), bytes / 2);
+        assert_eq!(overflow.counts.items.load(Ordering::Relaxed), 2);
+
+        // Too big for any segment
+        assert!(overflow.write(b"key", 5, &[0; 100]).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drops_the_oldest_segment_for_room() {
+        let dir = dir("overflow-full");
+        let overflow = Overflow::open(&dir, 100 * SEGMENTS).unwrap();
+        let value = [1; 60];
+        let spilled: Vec<_> = (0..=SEGMENTS)
+            .map(|n| overflow.write(b"key", n, &value).unwrap().unwrap())
+            .collect();
+
+        assert_eq!(overflow.dropped.load(Ordering::Relaxed), 1);
+        assert!(spilled[0].is_lost());
+        assert_eq!(spilled[0].location().read(b"key", 0).unwrap(), None);
+        assert!(!dir.join("segment.0").exists());
+        assert!(!spilled[1].is_lost());
+        assert_eq!(&spilled[1].location().read(b"key", 1).unwrap().unwrap()[..], &value);
+        let bytes = SEGMENTS * (HEADER_LEN + 3 + 60) as u64;
+        assert_eq!(overflow.counts.bytes.load(Ordering::Relaxed), bytes);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,11 @@
-use crate::frame::RequestFrame;
-use atoi::atoi;
+use atoi::FromRadix10SignedChecked;
 use bytes::Bytes;
 use std::io::Cursor;
 use thiserror::Error;
 
+/// Longest key taken, as memcached has it.
+pub(crate) const MAX_KEY_LEN: usize = 250;
+
 /// Utility for parsing a command
 ///
 /// Commands are represented as a space delimited line. Each entry in the frame is a
@@ -29,6 +31,11 @@ pub(crate) enum ParseError {
     U32,
     #[error("protocol error; invalid u64")]
     U64,
+    #[error("protocol error; invalid i64")]
+    I64,
+    /// Answered with `CLIENT_ERROR`, see `commands::client_error`
+    #[error("protocol error; key longer than {} bytes", MAX_KEY_LEN)]
+    KeyTooLong,
 }
 
 impl Parse {
@@ -39,26 +46,32 @@ impl Parse {
 
     /// Return the next entry by spilting on SPACE
     fn next(&mut self) -> Result<&[u8], ParseError> {
-        let current_position = self.0.position() as usize;
-
-        // Skips the first byte which should never be a SPACE
-        let start = self.0.position() as usize + 1;
-        // Scan to the second to last byte
-        let end = self.0.get_ref().len() - 1;
-
-        for i in start..end {
-            if self.0.get_ref()[i] == b' ' {
-                // Moves the position to after the SPACE
-                self.0.set_position(i as u64 + 1);
-                return Ok(&self.0.get_ref()[current_position..i]);
-            }
+        let (start, end) = self.next_range()?;
+        Ok(&self.0.get_ref()[start..end])
+    }
+
+    /// Return where the next entry starts and ends in the line.
+    fn next_range(&mut self) -> Result<(usize, usize), ParseError> {
+        let line = self.0.get_ref();
+        let mut start = self.0.position() as usize;
+
+        // Tolerate runs of SPACE between entries
+        while start < line.len() && line[start] == b' ' {
+            start += 1;
         }
-        // Gets data from last SPACE to the end of line
-        if current_position < self.0.get_ref().len() {
-            return Ok(&self.0.get_ref()[current_position..self.0.get_ref().len()]);
+        if start >= line.len() {
+            return Err(ParseError::EndOfLine);
         }
 
-        Err(ParseError::EndOfLine)
+        // The entry runs up to the next SPACE or the end of line
+        let end = line[start..]
+            .iter()
+            .position(|&b| b == b' ')
+            .map_or(line.len(), |i| start + i);
+
+        // Moves the position to after the SPACE
+        self.0.set_position(end as u64 + 1);
+        Ok((start, end))
     }
 
     /// Return the next entry as a string.
@@ -72,6 +85,17 @@ impl Parse {
         }
     }
 
+    /// Return the next entry as a key. Keys are taken as raw bytes and share
+    /// the buffer of the line rather than being copied. Keys over
+    /// `MAX_KEY_LEN` bytes are an error.
+    pub(crate) fn next_key(&mut self) -> Result<Bytes, ParseError> {
+        let (start, end) = self.next_range()?;
+        if end - start > MAX_KEY_LEN {
+            return Err(ParseError::KeyTooLong);
+        }
+        Ok(self.0.get_ref().slice(start..end))
+    }
+
     /// Return the next entry as raw bytes.
     ///
     /// If the next entry cannot be represented as raw bytes, an error is
@@ -84,31 +108,141 @@ impl Parse {
     ///
     /// If the next entry cannot be represented as u32, then an error is returned.
     pub(crate) fn next_u32(&mut self) -> Result<u32, ParseError> {
-        atoi::<u32>(self.next()?).ok_or_else(|| ParseError::U32)
+        number::<u32>(self.next()?).ok_or(ParseError::U32)
     }
 
     /// Return the next entry as an u64.
     ///
     /// If the next entry cannot be represented as u64, then an error is returned.
     pub(crate) fn next_u64(&mut self) -> Result<u64, ParseError> {
-        atoi::<u64>(self.next()?).ok_or_else(|| ParseError::U64)
+        number::<u64>(self.next()?).ok_or(ParseError::U64)
+    }
+
+    /// Return the next entry as an i64.
+    ///
+    /// If the next entry cannot be represented as i64, then an error is returned.
+    pub(crate) fn next_i64(&mut self) -> Result<i64, ParseError> {
+        number::<i64>(self.next()?).ok_or(ParseError::I64)
+    }
+
+    /// Consume the optional `noreply` that ends storage and update commands.
+    ///
+    /// Returns whether it was there.
+    pub(crate) fn next_noreply(&mut self) -> Result<bool, ParseError> {
+        if self.complete() {
+            return Ok(false);
+        }
+        match self.next()? {
+            b"noreply" => Ok(true),
+            _ => Err(ParseError::LineToLong),
+        }
     }
 
     /// Checks if there is more in the line
     pub(crate) fn complete(&mut self) -> bool {
-        // use cusor is_empty when added
-        // try self.0.has_remaining()
-        self.0.position() as usize > self.0.get_ref().len()
+        let line = self.0.get_ref();
+        let position = (self.0.position() as usize).min(line.len());
+        line[position..].iter().all(|&b| b == b' ')
     }
 
     /// Ensure there is no more data in the line
     pub(crate) fn finish(&mut self) -> Result<(), ParseError> {
-        // use cusor is_empty when added
-        // try self.0.has_remaining()
-        if self.0.position() as usize > self.0.get_ref().len() {
+        if self.complete() {
             Ok(())
         } else {
             Err(ParseError::LineToLong)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Parse the whole of `token` as a number, rather than whatever number it
+/// starts with. A sign alone is not a number.
+fn number<T: FromRadix10SignedChecked>(token: &[u8]) -> Option<T> {
+    match T::from_radix_10_signed_checked(token) {
+        (number, used) if used == token.len() && token.last().is_some_and(u8::is_ascii_digit) => number,
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Tokens as they appear on a command line: no SPACE, nor the CRLF
+    /// that ends the line
+    fn token() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(any::<u8>().prop_filter("separator", |b| !b" \r\n".contains(b)), 1..20)
+    }
+
+    fn line(tokens: &[Vec<u8>]) -> Parse {
+        Parse::new(Bytes::from(tokens.join(&b' ')))
+    }
+
+    /// Counterexamples proptest found, from when a number was whatever
+    /// digits a token started with.
+    #[test]
+    fn test_numbers_take_the_whole_token() {
+        for token in [&b"0\0"[..], b"+", b"12abc", b"1-", b"-"] {
+            assert_eq!(Parse::new(Bytes::from_static(token)).next_u32(), Err(ParseError::U32), "{:?}", token);
+            assert_eq!(Parse::new(Bytes::from_static(token)).next_u64(), Err(ParseError::U64), "{:?}", token);
+            assert_eq!(Parse::new(Bytes::from_static(token)).next_i64(), Err(ParseError::I64), "{:?}", token);
+        }
+    }
+
+    #[test]
+    fn test_key_length() {
+        let key = |len| Parse::new(Bytes::from(vec![b'k'; len])).next_key();
+        assert_eq!(key(MAX_KEY_LEN).unwrap().len(), MAX_KEY_LEN);
+        assert_eq!(key(MAX_KEY_LEN + 1), Err(ParseError::KeyTooLong));
+    }
+
+    proptest! {
+        #[test]
+        fn test_returns_the_tokens(tokens in proptest::collection::vec(token(), 0..10)) {
+            let mut parse = line(&tokens);
+            for token in &tokens {
+                prop_assert_eq!(&parse.next_bytes().unwrap()[..], &token[..]);
+            }
+            prop_assert_eq!(parse.next_bytes(), Err(ParseError::EndOfLine));
+            prop_assert!(parse.finish().is_ok());
+        }
+
+        #[test]
+        fn test_numbers_round_trip(a: u32, b: u64, c: i64) {
+            let mut parse = Parse::new(Bytes::from(format!("{} {} {}", a, b, c)));
+            prop_assert_eq!(parse.next_u32(), Ok(a));
+            prop_assert_eq!(parse.next_u64(), Ok(b));
+            prop_assert_eq!(parse.next_i64(), Ok(c));
+            prop_assert!(parse.finish().is_ok());
+        }
+
+        #[test]
+        fn test_numbers_are_all_digits(token in token()) {
+            let digits = |skip: usize| token.len() > skip && token[skip..].iter().all(u8::is_ascii_digit);
+            let line = || Parse::new(Bytes::from(token.clone()));
+            if line().next_u64().is_ok() || line().next_i64().is_ok() {
+                prop_assert!(digits(0) || (b"+-".contains(&token[0]) && digits(1)), "{:?}", token);
+            }
+        }
+
+        #[test]
+        fn test_never_panics(line in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let mut parse = Parse::new(Bytes::from(line));
+            for step in 0.. {
+                let parsed = match step % 6 {
+                    0 => parse.next_string().map(drop),
+                    1 => parse.next_key().map(drop),
+                    2 => parse.next_u32().map(drop),
+                    3 => parse.next_u64().map(drop),
+                    4 => parse.next_i64().map(drop),
+                    _ => parse.next_noreply().map(drop),
+                };
+                if parsed == Err(ParseError::EndOfLine) {
+                    break;
+                }
+            }
+            let _ = parse.finish();
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::{bail, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature opening every version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// A version 1 header is a single line of at most 107 bytes, "\r\n" included.
+const V1_MAX_LEN: usize = 107;
+
+/// Read a PROXY protocol header, version 1 or 2, off the front of `stream`.
+///
+/// Exactly the header is consumed; whatever follows it is left in `stream`
+/// for the protocol (or TLS) layer. Returns the original client address, or
+/// `None` when the header is valid but does not carry one (v1 `UNKNOWN`, v2
+/// `LOCAL` or non-IP families). Returns `Err` if the stream does not start
+/// with a valid header.
+pub(crate) async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>> {
+    // Both versions can be told apart by their first 6 bytes, and no valid
+    // header is shorter than that.
+    let mut start = [0; 6];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY " {
+        // Read byte by byte so nothing past the end of the line is consumed.
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                bail!("PROXY v1 header too long");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if start == V2_SIGNATURE[..6] {
+        let mut header = [0; 16];
+        header[..6].copy_from_slice(&start);
+        stream.read_exact(&mut header[6..]).await?;
+        if header[..12] != V2_SIGNATURE {
+            bail!("invalid PROXY v2 signature");
+        }
+
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut addresses = vec![0; len];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(&header, &addresses)
+    } else {
+        bail!("connection did not start with a PROXY protocol header")
+    }
+}
+
+/// Parse a version 1 header line, "\r\n" included.
+///
+/// ```text
+/// PROXY TCP4 192.168.0.1 192.168.0.11 56324 11211\r\n
+/// PROXY UNKNOWN\r\n
+/// ```
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let mut fields = line.split(' ').skip(1);
+
+    let family = fields.next();
+    if family == Some("UNKNOWN") {
+        return Ok(None);
+    }
+    let (src, _dst, port, _dst_port) = match (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) {
+        (Some(src), Some(dst), Some(port), Some(dst_port), None) => (src, dst, port, dst_port),
+        _ => bail!("malformed PROXY v1 header"),
+    };
+
+    let ip: IpAddr = match family {
+        Some("TCP4") => src.parse::<Ipv4Addr>()?.into(),
+        Some("TCP6") => src.parse::<Ipv6Addr>()?.into(),
+        _ => bail!("unsupported PROXY v1 protocol"),
+    };
+    Ok(Some(SocketAddr::new(ip, port.parse()?)))
+}
+
+/// Parse a version 2 header given its fixed 16 byte part and the address
+/// block that follows it.
+fn parse_v2(header: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 {
+        bail!("unsupported PROXY protocol version {}", version);
+    }
+    match command {
+        // LOCAL: a health check from the proxy itself, no client address
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => bail!("unsupported PROXY v2 command {}", command),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match header[13] >> 4 {
+        // AF_INET: source and destination addresses, then ports
+        0x1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        // AF_INET6
+        0x2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        0x1 | 0x2 => bail!("truncated PROXY v2 address block"),
+        // AF_UNSPEC and AF_UNIX carry no IP address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_v1_tcp4() {
+        let mut stream = &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 11211\r\nget foo\r\n"[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        // The protocol data after the header is left untouched
+        assert_eq!(stream, b"get foo\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_v1_tcp6_and_unknown() {
+        let mut stream = &b"PROXY TCP6 ::1 ::2 4000 11211\r\n"[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("[::1]:4000".parse().unwrap()));
+
+        let mut stream = &b"PROXY UNKNOWN ignored\r\n"[..];
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_v1_invalid() {
+        let mut stream = &b"PROXY TCP4 192.168.0.1 56324 11211\r\n"[..];
+        assert!(read_header(&mut stream).await.is_err());
+
+        let mut stream = &b"PROXY TCP4 not-an-ip 192.168.0.11 56324 11211\r\n"[..];
+        assert!(read_header(&mut stream).await.is_err());
+
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        let mut stream = long.as_bytes();
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_inet() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        data.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        data.extend_from_slice(&1234u16.to_be_bytes());
+        data.extend_from_slice(&11211u16.to_be_bytes());
+        data.extend_from_slice(b"stats\r\n");
+
+        let mut stream = &data[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("10.0.0.1:1234".parse().unwrap()));
+        assert_eq!(stream, b"stats\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_v2_inet6_and_local() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        data.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        data.extend_from_slice(&[0x10, 0x00, 0x2b, 0xcb]);
+        let addr = read_header(&mut &data[..]).await.unwrap();
+        assert_eq!(addr, Some("[::1]:4096".parse().unwrap()));
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &data[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_header() {
+        let mut stream = &b"get foo\r\n"[..];
+        assert!(read_header(&mut stream).await.is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Memory quotas per key prefix, see `--quota`. Each item counts against
+//! the quota of the longest configured prefix its key starts with, if any.
+//! A prefix over its quota only has its own items evicted, see
+//! `Cache::make_room`.
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use thiserror::Error;
+
+/// A `--quota`, e.g. `team-a:=64`: at most so many megabytes for the items
+/// whose keys start with the prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaSpec {
+    prefix: String,
+    megabytes: u64,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Error {
+    #[error("expected PREFIX=MEGABYTES in `{0}`")]
+    Format(String),
+    #[error("invalid prefix in `{0}`, it must be a valid key")]
+    Prefix(String),
+    #[error("invalid megabytes in `{0}`")]
+    Megabytes(String),
+}
+
+impl FromStr for QuotaSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<QuotaSpec, Error> {
+        let (prefix, megabytes) = s.rsplit_once('=').ok_or_else(|| Error::Format(s.to_string()))?;
+        if prefix.is_empty() || prefix.len() > 250 || prefix.bytes().any(|byte| byte <= b' ' || byte == 0x7f) {
+            return Err(Error::Prefix(s.to_string()));
+        }
+        let megabytes = megabytes
+            .parse()
+            .ok()
+            .filter(|megabytes| *megabytes > 0)
+            .ok_or_else(|| Error::Megabytes(s.to_string()))?;
+        Ok(QuotaSpec {
+            prefix: prefix.to_string(),
+            megabytes,
+        })
+    }
+}
+
+impl fmt::Display for QuotaSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.prefix, self.megabytes)
+    }
+}
+
+/// The bytes held for the items of a prefix, and how many it may hold.
+#[derive(Debug)]
+pub(crate) struct Quota {
+    pub(crate) prefix: Bytes,
+    pub(crate) limit: usize,
+    used: AtomicUsize,
+    /// Items of the prefix evicted, to stay under `limit` or the memory
+    /// limit
+    evictions: AtomicU64,
+    /// Index shard eviction samples in, and the last key sampled in it, like
+    /// the cache's own
+    pub(crate) hand: Mutex<(usize, Option<Bytes>)>,
+}
+
+impl Quota {
+    /// Returns the bytes held, see `cache::footprint`.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn charge(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn discharge(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_evictions(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Every quota configured, with what finds the one a key counts against.
+#[derive(Debug)]
+pub(crate) struct Quotas {
+    /// In prefix order
+    quotas: Vec<Quota>,
+    by_prefix: HashMap<Bytes, usize>,
+    /// Lengths of the prefixes, longest first, without repeats
+    lengths: Vec<usize>,
+}
+
+impl Quotas {
+    /// The quotas of `specs`, or `None` if there are none. A prefix given
+    /// more than once gets the last quota given.
+    pub(crate) fn new(specs: &[QuotaSpec]) -> Option<Quotas> {
+        Quotas::with_limits(
+            specs
+                .iter()
+                .map(|spec| (Bytes::from(spec.prefix.clone()), spec.megabytes as usize * 1024 * 1024)),
+        )
+    }
+
+    /// Like `new`, with the limits in bytes.
+    pub(crate) fn with_limits(limits: impl IntoIterator<Item = (Bytes, usize)>) -> Option<Quotas> {
+        let limits: HashMap<_, _> = limits.into_iter().collect();
+        if limits.is_empty() {
+            return None;
+        }
+        let mut limits: Vec<_> = limits.into_iter().collect();
+        limits.sort();
+        let mut lengths: Vec<_> = limits.iter().map(|(prefix, _)| prefix.len()).collect();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        lengths.dedup();
+        let by_prefix = limits
+            .iter()
+            .enumerate()
+            .map(|(n, (prefix, _))| (prefix.clone(), n))
+            .collect();
+        let quotas = limits
+            .into_iter()
+            .map(|(prefix, limit)| Quota {
+                prefix,
+                limit,
+                used: AtomicUsize::new(0),
+                evictions: AtomicU64::new(0),
+                hand: Mutex::new((0, None)),
+            })
+            .collect();
+        Some(Quotas {
+            quotas,
+            by_prefix,
+            lengths,
+        })
+    }
+
+    /// The quota `key` counts against: that of the longest prefix it starts
+    /// with. A lookup per prefix length configured.
+    pub(crate) fn find(&self, key: &[u8]) -> Option<&Quota> {
+        self.lengths
+            .iter()
+            .filter(|len| **len <= key.len())
+            .find_map(|len| self.by_prefix.get(&key[..*len]))
+            .map(|n| &self.quotas[*n])
+    }
+
+    /// Returns the limit, the bytes held and the evictions of every quota,
+    /// as `(name, value)` pairs named after the prefixes, reported by
+    /// `stats quotas`.
+    pub(crate) fn stats(&self) -> Vec<(String, u64)> {
+        let mut stats = Vec::with_capacity(self.quotas.len() * 3);
+        for quota in &self.quotas {
+            let prefix = String::from_utf8_lossy(&quota.prefix);
+            stats.push((format!("{}:limit_bytes", prefix), quota.limit as u64));
+            stats.push((format!("{}:bytes", prefix), quota.used() as u64));
+            stats.push((format!("{}:evictions", prefix), quota.evictions.load(Ordering::Relaxed)));
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let spec: QuotaSpec = "team=a:=64".parse().unwrap();
+        assert_eq!((spec.prefix.as_str(), spec.megabytes), ("team=a:", 64));
+        assert_eq!(spec.to_string(), "team=a:=64");
+        assert_eq!("team".parse::<QuotaSpec>(), Err(Error::Format("team".to_string())));
+        assert_eq!("=64".parse::<QuotaSpec>(), Err(Error::Prefix("=64".to_string())));
+        assert_eq!("a b=64".parse::<QuotaSpec>(), Err(Error::Prefix("a b=64".to_string())));
+        assert_eq!("team:=0".parse::<QuotaSpec>(), Err(Error::Megabytes("team:=0".to_string())));
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let quotas = Quotas::with_limits([
+            (Bytes::from("a:"), 10),
+            (Bytes::from("a:b:"), 20),
+            (Bytes::from("c"), 30),
+            (Bytes::from("a:"), 40),
+        ])
+        .unwrap();
+        let limit = |key: &str| quotas.find(key.as_bytes()).map(|quota| quota.limit);
+        assert_eq!(limit("a:1"), Some(40));
+        assert_eq!(limit("a:b:1"), Some(20));
+        assert_eq!(limit("a:b"), Some(40));
+        assert_eq!(limit("c"), Some(30));
+        assert_eq!(limit("a"), None);
+        assert_eq!(limit("b:a:1"), None);
+        assert!(Quotas::with_limits([]).is_none());
+    }
+}
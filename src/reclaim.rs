@@ -0,0 +1,26 @@
+//! Giving memory back once many items are gone at once, after a flush or a
+//! wave of evictions, see `Cache::reclaim_memory`.
+
+use crate::cache::Cache;
+
+use tokio::time::{self, Duration};
+use tracing::debug;
+
+/// Time between calls to `Cache::reclaim_memory`.
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reclaim the memory of `cache` every `RECLAIM_INTERVAL`, for as long as
+/// the server runs.
+pub(crate) async fn reclaim_periodically(cache: Cache) {
+    let mut interval = time::interval(RECLAIM_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let cache = cache.clone();
+        match tokio::task::spawn_blocking(move || cache.reclaim_memory()).await {
+            Ok((0, before, after)) if before == after => {}
+            Ok((removed, before, after)) => debug!(removed, before, after, "reclaimed memory"),
+            Err(_) => {}
+        }
+    }
+}
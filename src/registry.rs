@@ -0,0 +1,244 @@
+use dashmap::DashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Open TCP connections and what each of them is doing, for diagnostics.
+///
+/// Every `Handler` holds a `Registration` that keeps its entry up to date and
+/// removes it when the connection closes. Reported by `stats conns` and the
+/// SIGUSR1 dump.
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    next_id: AtomicU64,
+    connections: DashMap<u64, Entry>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    peer: Peer,
+    opened: Instant,
+    state: State,
+    last_command: Option<&'static str>,
+}
+
+/// Who is at the other end of a connection, as worked out by the handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Peer {
+    /// The client's address. Taken from the PROXY protocol header when
+    /// `--proxy-protocol` is enabled, otherwise the socket's peer address.
+    pub(crate) addr: SocketAddr,
+    /// The socket's peer address when it is not the client's, i.e. the proxy
+    pub(crate) proxy: Option<SocketAddr>,
+    /// The subject common name of the certificate the client authenticated
+    /// with, see `--tls-client-ca`
+    pub(crate) cn: Option<String>,
+}
+
+/// What a connection is currently doing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum State {
+    /// Waiting for the next command
+    Reading,
+    /// Running a command
+    Processing,
+}
+
+/// A connection as seen at the time of `Registry::connections`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) id: u64,
+    pub(crate) peer: Peer,
+    pub(crate) state: State,
+    pub(crate) age: Duration,
+    pub(crate) last_command: Option<&'static str>,
+}
+
+/// A connection's entry in the `Registry`. Dropping it removes the entry.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    registry: Arc<Registry>,
+    id: u64,
+}
+
+impl Registry {
+    /// Add a connection from `peer`, in the `Reading` state.
+    pub(crate) fn register(self: &Arc<Self>, peer: Peer) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.insert(
+            id,
+            Entry {
+                peer,
+                opened: Instant::now(),
+                state: State::Reading,
+                last_command: None,
+            },
+        );
+        Registration {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    /// Returns the open connections ordered by id, oldest first.
+    pub(crate) fn connections(&self) -> Vec<ConnectionInfo> {
+        let now = Instant::now();
+        let mut connections: Vec<_> = self
+            .connections
+            .iter()
+            .map(|entry| ConnectionInfo {
+                id: *entry.key(),
+                peer: entry.peer.clone(),
+                state: entry.state,
+                age: now.duration_since(entry.opened),
+                last_command: entry.last_command,
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Returns the open connections as `stats conns` reports them, a few
+    /// `<id>:<name>` lines per connection. `proxy` is left out for
+    /// connections that did not come through one, and `cn` for those without
+    /// a client certificate.
+    pub(crate) fn snapshot(&self) -> Vec<(String, String)> {
+        let mut lines = Vec::new();
+        for connection in self.connections() {
+            let id = connection.id;
+            lines.push((format!("{}:addr", id), connection.peer.addr.to_string()));
+            if let Some(proxy) = connection.peer.proxy {
+                lines.push((format!("{}:proxy", id), proxy.to_string()));
+            }
+            if let Some(cn) = connection.peer.cn {
+                lines.push((format!("{}:cn", id), cn));
+            }
+            lines.push((format!("{}:state", id), connection.state.to_string()));
+            lines.push((format!("{}:age", id), connection.age.as_secs().to_string()));
+            let last_command = connection.last_command.unwrap_or("-");
+            lines.push((format!("{}:last_command", id), last_command.to_string()));
+        }
+        lines
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    /// A client connected directly.
+    fn from(addr: SocketAddr) -> Peer {
+        Peer {
+            addr,
+            proxy: None,
+            cn: None,
+        }
+    }
+}
+
+impl Registration {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Mark the connection as running `command`.
+    pub(crate) fn processing(&self, command: &'static str) {
+        if let Some(mut entry) = self.registry.connections.get_mut(&self.id) {
+            entry.state = State::Processing;
+            entry.last_command = Some(command);
+        }
+    }
+
+    /// Mark the connection as waiting for its next command.
+    pub(crate) fn reading(&self) {
+        if let Some(mut entry) = self.registry.connections.get_mut(&self.id) {
+            entry.state = State::Reading;
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.remove(&self.id);
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Reading => f.write_str("reading"),
+            State::Processing => f.write_str("processing"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration() {
+        let registry = Arc::new(Registry::default());
+        let first = registry.register(addr("10.0.0.1:1000").into());
+        let second = registry.register(Peer {
+            addr: addr("10.0.0.2:2000"),
+            proxy: Some(addr("10.0.0.3:3000")),
+            cn: Some("client.example".to_string()),
+        });
+
+        second.processing("get");
+        let connections = registry.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].id, first.id());
+        assert_eq!(connections[0].state, State::Reading);
+        assert_eq!(connections[0].last_command, None);
+        assert_eq!(connections[0].peer.proxy, None);
+        assert_eq!(connections[1].peer.addr, addr("10.0.0.2:2000"));
+        assert_eq!(connections[1].peer.proxy, Some(addr("10.0.0.3:3000")));
+        assert_eq!(connections[1].peer.cn.as_deref(), Some("client.example"));
+        assert_eq!(connections[1].state, State::Processing);
+        assert_eq!(connections[1].last_command, Some("get"));
+
+        // The last command is kept while waiting for the next one
+        second.reading();
+        assert_eq!(registry.connections()[1].state, State::Reading);
+        assert_eq!(registry.connections()[1].last_command, Some("get"));
+
+        drop(first);
+        let connections = registry.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].id, second.id());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let registry = Arc::new(Registry::default());
+        let _direct = registry.register(addr("10.0.0.1:1000").into());
+        let proxied = registry.register(Peer {
+            addr: addr("10.0.0.2:2000"),
+            proxy: Some(addr("10.0.0.3:3000")),
+            cn: Some("client.example".to_string()),
+        });
+        proxied.processing("get");
+
+        let lines = registry.snapshot();
+        let names: Vec<_> = lines.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "0:addr", "0:state", "0:age", "0:last_command",
+                "1:addr", "1:proxy", "1:cn", "1:state", "1:age", "1:last_command",
+            ]
+        );
+        assert_eq!(lines[0].1, "10.0.0.1:1000");
+        assert_eq!(lines[3].1, "-");
+        assert_eq!(lines[4].1, "10.0.0.2:2000");
+        assert_eq!(lines[5].1, "10.0.0.3:3000");
+        assert_eq!(lines[6].1, "client.example");
+        assert_eq!(lines[7].1, "processing");
+        assert_eq!(lines[9].1, "get");
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+}
@@ -0,0 +1,151 @@
+use crate::cache::Cache;
+use crate::logging;
+use crate::settings::Settings;
+use crate::tls;
+
+use anyhow::Result;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use tracing::{error, info, warn};
+use std::ffi::OsString;
+use std::sync::Arc;
+use tokio_rustls::rustls::ServerConfig;
+
+/// Re-read the config file and apply the settings that can change at runtime.
+///
+/// `args` are the command line arguments, which still take precedence over
+/// the file. Settings that need a restart keep their current values and a
+/// warning names them. The TLS configuration is rebuilt from the new settings,
+/// which also picks up renewed certificates at unchanged paths, and the
+/// memory and item limits of `cache` are set to theirs.
+///
+/// Nothing is changed if the file, or the certificates it points to, cannot
+/// be loaded.
+pub(crate) fn reload(
+    settings: &ArcSwap<Settings>,
+    tls: &ArcSwapOption<ServerConfig>,
+    cache: &Cache,
+    args: impl IntoIterator<Item = OsString>,
+) -> Result<()> {
+    let current = settings.load();
+    let path = match &current.config {
+        Some(path) => path,
+        None => {
+            info!("no config file to reload");
+            return Ok(());
+        }
+    };
+
+    let mut new = Settings::with_config(path, args)?;
+    let kept = new.keep_restart_only(&current);
+    if !kept.is_empty() {
+        warn!("ignoring changes that need a restart: {}", kept.join(", "));
+    }
+    let tls_config = tls::config(&new)?;
+
+    let before = current.snapshot();
+    for (name, value) in new.snapshot() {
+        if let Some((_, old)) = before.iter().find(|(old_name, _)| *old_name == name) {
+            if *old != value {
+                info!("{} changed from {} to {}", name, old, value);
+            }
+        }
+    }
+
+    let verbosity = (new.verbosity != current.verbosity).then_some(new.verbosity);
+    cache.set_limits(new.memory_limit_bytes(), new.max_items.map(|max| max as usize));
+    settings.store(Arc::new(new));
+    if let Some(verbosity) = verbosity {
+        logging::apply_verbosity(verbosity);
+    }
+    tls.store(tls_config);
+    info!("reloaded {}", path.display());
+    Ok(())
+}
+
+/// Reload the settings every time the process receives SIGHUP.
+#[cfg(unix)]
+pub(crate) async fn on_sighup(
+    settings: Arc<ArcSwap<Settings>>,
+    tls: Arc<ArcSwapOption<ServerConfig>>,
+    cache: Cache,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            error!("failed to install SIGHUP handler: {}", err);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(err) = reload(&settings, &tls, &cache, std::env::args_os()) {
+            error!("failed to reload settings: {:#}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::fs;
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("sidica-reload-{}.conf", std::process::id()));
+        fs::write(&path, "port = 11211\nallow = 10.0.0.0/8\n").unwrap();
+        let args = || ["sidica", "-c", path.to_str().unwrap()].map(OsString::from);
+
+        let settings = ArcSwap::from_pointee(Settings::with_config(&path, args()).unwrap());
+        let tls = ArcSwapOption::empty();
+        let cache = Cache::new();
+
+        fs::write(
+            &path,
+            "port = 9000\nallow = 192.168.0.0/16\nmax-connection-requests = 5\n",
+        )
+        .unwrap();
+        reload(&settings, &tls, &cache, args()).unwrap();
+
+        let reloaded = settings.load();
+        assert_eq!(reloaded.allow, ["192.168.0.0/16".parse().unwrap()]);
+        assert_eq!(reloaded.max_connection_requests, Some(5));
+        // The listen port needs a restart
+        assert_eq!(reloaded.port, 11211);
+
+        // An invalid file leaves the settings untouched
+        fs::write(&path, "max-connection-requests = none\n").unwrap();
+        assert!(reload(&settings, &tls, &cache, args()).is_err());
+        assert_eq!(settings.load().max_connection_requests, Some(5));
+
+        fs::remove_file(&path).unwrap();
+    }
+    #[tokio::test]
+    async fn test_reload_memory_limit() {
+        let path = std::env::temp_dir().join(format!("sidica-reload-limit-{}.conf", std::process::id()));
+        fs::write(&path, "memory-limit = 2\n").unwrap();
+        let args = || ["sidica", "-c", path.to_str().unwrap()].map(OsString::from);
+
+        let settings = ArcSwap::from_pointee(Settings::with_config(&path, args()).unwrap());
+        let tls = ArcSwapOption::empty();
+        let cache = settings.load().cache_builder().build();
+        let value = Bytes::from(vec![b'x'; 100 * 1024]);
+        for n in 0..15 {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, value.clone()).await;
+        }
+        assert_eq!(cache.evictions(), 0);
+
+        fs::write(&path, "memory-limit = 1\n").unwrap();
+        reload(&settings, &tls, &cache, args()).unwrap();
+        let maxbytes = ("maxbytes".to_string(), (1024 * 1024).to_string());
+        assert!(settings.load().snapshot().contains(&maxbytes));
+
+        // The next store evicts to get under the new limit
+        cache.set(Bytes::from("key15"), 0, None, value).await;
+        assert!(cache.evictions() >= 6, "{} evictions", cache.evictions());
+        assert!(cache.bytes() <= 1024 * 1024, "{} bytes", cache.bytes());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
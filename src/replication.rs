@@ -0,0 +1,251 @@
+use crate::cache::Direction;
+use crate::stats::ServerStats;
+
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::{info, warn};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+
+/// How long to wait before reconnecting to the replica.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Forwards successful mutations to a replica, see `--replica-of-mine`.
+///
+/// Mutations are queued as protocol commands with `noreply` and written to
+/// the replica by `run`. Queueing never waits: when the queue is full the
+/// oldest mutation is dropped to make room. The queue length is reported as
+/// `replication_lag` and the mutations lost, to a full queue or a failed
+/// connection, as `replication_dropped`.
+#[derive(Debug)]
+pub(crate) struct Replicator {
+    queue: Mutex<VecDeque<Bytes>>,
+    capacity: usize,
+    notify: Notify,
+    stats: Arc<ServerStats>,
+}
+
+impl Replicator {
+    pub(crate) fn new(capacity: usize, stats: Arc<ServerStats>) -> Replicator {
+        Replicator {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            notify: Notify::new(),
+            stats,
+        }
+    }
+
+    /// Queue a `set` of `key`. Call once it has been applied locally.
+    ///
+    /// `expiration` is sent as is, as a unix timestamp, so the item expires
+    /// on the replica at the same time however long it is queued.
+    pub(crate) fn set(&self, key: &[u8], flags: u32, expiration: Option<u64>, data: &[u8]) {
+        self.store("set", key, flags, expiration.unwrap_or(0), data);
+    }
+
+    /// Queue an `append` of `data` to `key`, or a `prepend` if `prepend`.
+    /// Call once it has been applied locally.
+    pub(crate) fn concat(&self, key: &[u8], data: &[u8], prepend: bool) {
+        let name = if prepend { "prepend" } else { "append" };
+        self.store(name, key, 0, 0, data);
+    }
+
+    /// Queue a `touch` of `key`, with `expiration` sent as a unix timestamp
+    /// like for `set`. Call once it has been applied locally.
+    pub(crate) fn touch(&self, key: &[u8], expiration: Option<u64>) {
+        let exptime = expiration.unwrap_or(0);
+        self.push(command("touch", key, &format!("{} noreply\r\n", exptime)));
+    }
+
+    /// Queue a `flush_all`, with `at` sent as a unix timestamp like
+    /// expiration times. Call once it has been applied locally.
+    pub(crate) fn flush(&self, at: Option<u64>) {
+        self.push(Bytes::from(format!("flush_all {} noreply\r\n", at.unwrap_or(0))));
+    }
+
+    /// Queue a `flush_prefix` of `prefix`. Call once it has been applied
+    /// locally.
+    pub(crate) fn flush_prefix(&self, prefix: &[u8]) {
+        self.push(command("flush_prefix", prefix, "noreply\r\n"));
+    }
+
+    /// Queue an `incr` of `key` by `delta`, or a `decr`. Call once it has
+    /// been applied locally.
+    pub(crate) fn delta(&self, key: &[u8], delta: u64, direction: Direction) {
+        let name = match direction {
+            Direction::Incr => "incr",
+            Direction::Decr => "decr",
+        };
+        self.push(command(name, key, &format!("{} noreply\r\n", delta)));
+    }
+
+    fn store(&self, name: &str, key: &[u8], flags: u32, exptime: u64, data: &[u8]) {
+        let mut command = BytesMut::with_capacity(name.len() + key.len() + data.len() + 48);
+        put_line_start(&mut command, name, key);
+        command.put_slice(format!("{} {} {} noreply\r\n", flags, exptime, data.len()).as_bytes());
+        command.put_slice(data);
+        command.put_slice(b"\r\n");
+        self.push(command.freeze());
+    }
+
+    /// Queue a `delete` of `key`. Call once it has been applied locally.
+    pub(crate) fn delete(&self, key: &[u8]) {
+        self.push(command("delete", key, "noreply\r\n"));
+    }
+
+    fn push(&self, command: Bytes) {
+        let mut queue = self.queue.lock();
+        if queue.len() >= self.capacity {
+            queue.pop_front();
+            self.stats.incr_replication_dropped(1);
+        }
+        queue.push_back(command);
+        self.stats.set_replication_lag(queue.len() as u64);
+        drop(queue);
+        self.notify.notify_one();
+    }
+
+    /// Takes every queued command, returning them concatenated and how many
+    /// there were.
+    fn take(&self) -> (Bytes, u64) {
+        let mut queue = self.queue.lock();
+        let count = queue.len() as u64;
+        let mut batch = BytesMut::with_capacity(queue.iter().map(Bytes::len).sum());
+        for command in queue.drain(..) {
+            batch.put(command);
+        }
+        self.stats.set_replication_lag(0);
+        (batch.freeze(), count)
+    }
+
+    /// Stream the queued mutations to the replica at `addr`, reconnecting
+    /// whenever the connection is lost. Runs until the task is dropped.
+    pub(crate) async fn run(self: Arc<Self>, addr: String) {
+        loop {
+            match TcpStream::connect(&addr).await {
+                Ok(mut stream) => {
+                    info!("replicating to {}", addr);
+                    if let Err(err) = self.stream_to(&mut stream).await {
+                        warn!("replication to {} interrupted: {:#}", addr, err);
+                    }
+                }
+                Err(err) => warn!("failed to connect to replica {}: {}", addr, err),
+            }
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn stream_to(&self, stream: &mut TcpStream) -> Result<()> {
+        let (mut reader, mut writer) = stream.split();
+        let mut response = [0; 512];
+
+        loop {
+            let (batch, count) = self.take();
+            if count == 0 {
+                tokio::select! {
+                    _ = self.notify.notified() => {}
+                    // `noreply` commands are never answered, so anything
+                    // the replica sends is an error report.
+                    read = reader.read(&mut response) => {
+                        let read = read?;
+                        if read == 0 {
+                            bail!("closed by the replica");
+                        }
+                        warn!(
+                            "replica reported: {}",
+                            String::from_utf8_lossy(&response[..read]).trim_end()
+                        );
+                    }
+                }
+                continue;
+            }
+
+            if let Err(err) = writer.write_all(&batch).await {
+                self.stats.incr_replication_dropped(count);
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+/// Append `<name> <key> ` to `command`. Keys are raw bytes, so they are
+/// copied as is rather than formatted.
+fn put_line_start(command: &mut BytesMut, name: &str, key: &[u8]) {
+    command.put_slice(name.as_bytes());
+    command.put_u8(b' ');
+    command.put_slice(key);
+    command.put_u8(b' ');
+}
+
+/// A command without a data block: `<name> <key> <rest>`.
+fn command(name: &str, key: &[u8], rest: &str) -> Bytes {
+    let mut command = BytesMut::with_capacity(name.len() + key.len() + rest.len() + 2);
+    put_line_start(&mut command, name, key);
+    command.put_slice(rest.as_bytes());
+    command.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::net::TcpListener;
+
+    fn stat(stats: &ServerStats, name: &str) -> u64 {
+        stats
+            .snapshot()
+            .into_iter()
+            .find(|(stat, _)| stat == name)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn test_drops_oldest() {
+        let stats = Arc::new(ServerStats::new(1));
+        let replicator = Replicator::new(2, stats.clone());
+        for key in [b"a", b"b", b"c"] {
+            replicator.set(key, 0, None, b"1");
+        }
+        assert_eq!(stat(&stats, "replication_lag"), 2);
+        assert_eq!(stat(&stats, "replication_dropped"), 1);
+
+        let (batch, count) = replicator.take();
+        assert_eq!(count, 2);
+        assert_eq!(
+            &batch[..],
+            b"set b 0 0 1 noreply\r\n1\r\nset c 0 0 1 noreply\r\n1\r\n"
+        );
+        assert_eq!(stat(&stats, "replication_lag"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_streams_to_replica() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let replicator = Arc::new(Replicator::new(16, Arc::new(ServerStats::new(1))));
+        replicator.set(b"foo", 5, Some(60), b"bar");
+        let task = tokio::spawn(replicator.clone().run(addr));
+
+        let (replica, _) = listener.accept().await.unwrap();
+        let mut lines = tokio::io::BufReader::new(replica).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "set foo 5 60 3 noreply");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "bar");
+
+        // Mutations queued while connected follow
+        replicator.set(b"baz", 0, None, b"");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "set baz 0 0 0 noreply");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "");
+        replicator.delete(b"foo");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "delete foo noreply");
+        replicator.concat(b"baz", b"qux", true);
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "prepend baz 0 0 3 noreply");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "qux");
+        task.abort();
+    }
+}
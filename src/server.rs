@@ -1,24 +1,77 @@
 use crate::cache::Cache;
-use crate::{commands::Command, Connection, Shutdown};
+use crate::frame::{RequestFrame, ResponseFrame};
+use crate::limit::{self, IpLimiter, IpPermit, Rejected};
+use crate::registry::{Peer, Registration};
+use crate::replication::Replicator;
+use crate::settings::Settings;
+use crate::stats::ServerStats;
+use crate::health::{self, Readiness};
+use crate::{
+    acl, append_log, chunks, commands::{self, Command}, dump, expiry, handoff, integrity, overflow, proxy, reclaim, reload,
+    snapshot, tls, udp, watermark, Connection, Shutdown,
+};
 
-use anyhow::Result;
-use log::{debug, error, info};
+use anyhow::{bail, Context, Result};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use futures::FutureExt;
+use core_affinity::CoreId;
+use tracing::{debug, debug_span, error, info, info_span, warn, Instrument};
+use std::any::Any;
 use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc, Semaphore};
-use tokio::time::{self, Duration};
+use std::thread;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{broadcast, mpsc, oneshot, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
 
-const MAX_CONNECTIONS: usize = 250;
+/// Most connections served at once, the rest wait in the listen backlog.
+pub(crate) const MAX_CONNECTIONS: usize = 250;
 
-/// Accepts connections from the supplied listener. For each inbound connection,
+/// Accepts connections from the supplied listeners. For each inbound connection,
 /// a task is spawned to handle that connection. The server runs until the
 /// `shutdown` future completes, at which point the server shuts down
 /// gracefully.
 ///
 /// `tokio::signal::ctrl_c()` can be used as the `shutdown` argument. This will
 /// listen for a SIGINT signal.
-pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
+///
+/// Every listener in `listeners` gets its own accept loop, see `Server::run`.
+/// They are taken as non-blocking std sockets as with `--core-pinned` each
+/// one is registered with a runtime of its own.
+/// When `udp` is provided, requests arriving on it are served as well, sharing
+/// the same cache. When `health` is provided, it answers health checks until
+/// every connection has finished, reporting ready as of now, as `readiness`
+/// has it, and not ready from the moment the `shutdown` future completes.
+///
+/// Connections are served from `cache`, which may come preloaded.
+///
+/// On unix, SIGHUP reloads the settings from the config file, see
+/// `reload::reload`, and SIGUSR1 logs a diagnostic dump, see `dump::dump`.
+/// SIGUSR2 hands the cache and the sockets off to a new process, see
+/// `handoff`, after which the server shuts down as if `shutdown` completed.
+/// SIGWINCH starts or stops draining, see `health::drain`.
+///
+/// With `--data-dir`, a snapshot of the cache is saved once every connection
+/// has finished, unless the cache was handed off, and the append log emptied.
+///
+/// Fails before accepting anything if the TLS configuration in `settings`
+/// cannot be loaded.
+pub async fn run(
+    listeners: Vec<std::net::TcpListener>,
+    udp: Option<UdpSocket>,
+    health: Option<TcpListener>,
+    readiness: Arc<Readiness>,
+    cache: Cache,
+    settings: Settings,
+    shutdown: impl Future,
+) -> Result<()> {
     // When the provided `shutdown` future completes, we must send a shutdown
     // message to all active connections. We use a broadcast channel for this
     // purpose. The call below ignores the receiver of the broadcast pair, and when
@@ -27,16 +80,121 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
 
+    let ip_limiter = Arc::new(IpLimiter::default());
+    tokio::spawn(limit::prune_periodically(Arc::downgrade(&ip_limiter)));
+
+    // Taken before the sockets move into their tasks. They stay open until
+    // the server has shut down, so for as long as a handoff can happen.
+    #[cfg(unix)]
+    let sockets = {
+        use std::os::fd::AsRawFd;
+        handoff::Sockets {
+            tcp: listeners.iter().map(AsRawFd::as_raw_fd).collect(),
+            udp: udp.as_ref().map(AsRawFd::as_raw_fd),
+            health: health.as_ref().map(AsRawFd::as_raw_fd),
+        }
+    };
+
+    let stats = Arc::new(ServerStats::new(listeners.len()));
+    let replicator = settings.replica_of_mine.as_ref().map(|_| {
+        Arc::new(Replicator::new(
+            settings.replication_queue as usize,
+            stats.clone(),
+        ))
+    });
+
     // Initialize the listener state
     let mut server = Server {
-        listener,
-        cache: Cache::new(),
+        stats,
+        readiness,
+        replicator,
+        listeners,
+        tls: Arc::new(ArcSwapOption::new(tls::config(&settings)?)),
+        settings: Arc::new(ArcSwap::from_pointee(settings)),
+        cache,
         limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+        ip_limiter,
         notify_shutdown,
         shutdown_complete_tx,
         shutdown_complete_rx,
     };
 
+    if let Some(socket) = udp {
+        let mut listener = udp::Listener {
+            socket: Arc::new(socket),
+            cache: server.cache.clone(),
+            stats: server.stats.clone(),
+            settings: server.settings.clone(),
+            readiness: server.readiness.clone(),
+            replicator: server.replicator.clone(),
+            max_datagram: server.settings.load().udp_max_datagram.into(),
+            shutdown: Shutdown::new(server.notify_shutdown.subscribe()),
+            _shutdown_complete: server.shutdown_complete_tx.clone(),
+        };
+        tokio::spawn(async move { listener.run().await });
+    }
+
+    tokio::spawn(append_log::rewrite_when_grown(server.cache.clone(), server.settings.clone()));
+    tokio::spawn(overflow::compact_periodically(server.cache.clone()));
+    tokio::spawn(expiry::sweep_periodically(server.cache.clone()));
+    tokio::spawn(chunks::remove_orphans_periodically(server.cache.clone()));
+    tokio::spawn(reclaim::reclaim_periodically(server.cache.clone()));
+    tokio::spawn(integrity::audit_periodically(server.cache.clone(), server.settings.clone()));
+    let saving = Arc::new(snapshot::Saving::default());
+    tokio::spawn(snapshot::save_periodically(
+        server.cache.clone(),
+        server.settings.clone(),
+        server.stats.clone(),
+        saving.clone(),
+    ));
+    let keeping = Arc::new(watermark::Keeping::default());
+    tokio::spawn(watermark::save_periodically(
+        server.cache.clone(),
+        server.settings.clone(),
+        keeping.clone(),
+    ));
+
+    let replication = server.replicator.clone().map(|replicator| {
+        let addr = server.settings.load().replica_of_mine.clone().unwrap_or_default();
+        tokio::spawn(replicator.run(addr))
+    });
+
+    #[cfg(unix)]
+    let reloader = tokio::spawn(reload::on_sighup(
+        server.settings.clone(),
+        server.tls.clone(),
+        server.cache.clone(),
+    ));
+    #[cfg(unix)]
+    let dumper = tokio::spawn(dump::on_sigusr1(
+        server.stats.clone(),
+        server.cache.clone(),
+        server.settings.clone(),
+    ));
+    #[cfg(unix)]
+    let drainer = tokio::spawn(health::on_sigwinch(
+        server.readiness.clone(),
+        server.stats.clone(),
+    ));
+
+    // Started before anything else so probes see the server come up.
+    let readiness = server.readiness.clone();
+    let health = health.map(|listener| {
+        let health = health::Listener {
+            listener,
+            cache: server.cache.clone(),
+            readiness: readiness.clone(),
+        };
+        tokio::spawn(async move { health.run().await })
+    });
+
+    #[cfg(unix)]
+    let handed_off = handoff::on_sigusr2(server.cache.clone(), sockets);
+    #[cfg(not(unix))]
+    let handed_off = std::future::pending::<()>();
+
+    readiness.set_ready(true);
+
     // Concurrently run the server and listen for the `shutdown` signal. The
     // server task runs until an error is encountered, so under normal
     // circumstances, this `select!` statement runs until the `shutdown` signal
@@ -56,6 +214,7 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
     // asynchronous Rust. See the API docs for more details:
     //
     // https://docs.rs/tokio/*/tokio/macro.select.html
+    let mut handed_off_to_new_process = false;
     tokio::select! {
         res = server.run() => {
             // If an error is received here, accepting connections from the TCP
@@ -65,15 +224,28 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
             // Errors encountered when handling individual connections do not
             // bubble up to this point.
             if let Err(err) = res {
-                // error!(cause = %err, "failed to accept");
+                error!("failed to accept: {}", err);
             }
         }
         _ = shutdown => {
             // The shutdown signal has been received.
             info!("shutting down");
         }
+        _ = handed_off => {
+            // The new process accepts from here on
+            info!("handed off, shutting down");
+            handed_off_to_new_process = true;
+        }
     }
 
+    // Tell load balancers to stop sending traffic while connections drain.
+    readiness.set_ready(false);
+
+    // Acceptors running on their own threads with `--core-pinned` hold
+    // clones of `notify_shutdown`, so dropping it below is not enough to
+    // reach them.
+    let _ = server.notify_shutdown.send(());
+
     // Extract the `shutdown_complete` receiver and transmitter
     // explicitly drop `shutdown_transmitter`. This is important, as the
     // `.await` below would otherwise never complete.
@@ -81,6 +253,8 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
         mut shutdown_complete_rx,
         shutdown_complete_tx,
         notify_shutdown,
+        settings,
+        cache,
         ..
     } = server;
     // When `notify_shutdown` is dropped, all tasks which have `subscribe`d will
@@ -94,16 +268,72 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<()> {
     // `Sender` instances are held by connection handler tasks. When those drop,
     // the `mpsc` channel will close and `recv()` will return `None`.
     let _ = shutdown_complete_rx.recv().await;
+
+    // Not to save a periodic snapshot as well, or once handed off
+    let _ = tokio::task::spawn_blocking(move || {
+        saving.shut_down();
+        keeping.shut_down();
+    })
+    .await;
+    // Only now that nothing changes the cache anymore
+    let settings = settings.load_full();
+    if let (Some(dir), false) = (settings.data_dir.clone(), handed_off_to_new_process) {
+        info!("saving the snapshot");
+        let saved = tokio::task::spawn_blocking(move || {
+            // Its changes are in the snapshot once saved
+            if let Some(log) = cache.append_log() {
+                log.close();
+            }
+            let saved = snapshot::save(&dir, &cache, settings.snapshots_kept as usize)?;
+            if let Some(log) = cache.append_log() {
+                log.clear()?;
+            }
+            watermark::save(&dir, cache.watermark(), true)?;
+            Ok::<_, anyhow::Error>(saved)
+        });
+        match saved.await {
+            Ok(Ok(saved)) => info!(items = saved.items, "saved the snapshot"),
+            Ok(Err(err)) => error!("failed to save the snapshot: {:#}", err),
+            Err(err) => error!("failed to save the snapshot: {}", err),
+        }
+    }
+
+    if let Some(health) = health {
+        health.abort();
+    }
+    if let Some(replication) = replication {
+        replication.abort();
+    }
+    #[cfg(unix)]
+    {
+        reloader.abort();
+        dumper.abort();
+        drainer.abort();
+    }
     Ok(())
 }
 
 /// Server listener state. Created in the `run` call. It includes a `run` method
-/// which performs the TCP listening and initialization of per-connection state.
+/// which starts an `Acceptor` for each listener.
 #[derive(Debug)]
 struct Server {
+    /// Replaced as a whole when the settings are reloaded. Connections load
+    /// the current value when they need it.
+    settings: Arc<ArcSwap<Settings>>,
     cache: Cache,
-    listener: TcpListener,
+    stats: Arc<ServerStats>,
+    /// Reported by the health listener. New connections are refused while
+    /// draining.
+    readiness: Arc<Readiness>,
+    /// Forwards mutations to `--replica-of-mine`, when set
+    replicator: Option<Arc<Replicator>>,
+    listeners: Vec<std::net::TcpListener>,
+
+    /// Present when TLS is configured. Every accepted socket completes a
+    /// handshake before any protocol data is read. Rebuilt on reload.
+    tls: Arc<ArcSwapOption<ServerConfig>>,
     limit_connections: Arc<Semaphore>,
+    ip_limiter: Arc<IpLimiter>,
 
     /// Broadcasts a shutdown signal to all active connections.
     ///
@@ -130,6 +360,138 @@ struct Server {
 impl Server {
     /// Run the server
     ///
+    /// Spawns an `Acceptor` task per listener. All of them share the cache, the
+    /// connection limit and the shutdown signal. With `--reuseport` the kernel
+    /// balances incoming connections across the listeners.
+    ///
+    /// With `--core-pinned` every acceptor gets a thread pinned to a core and a
+    /// single threaded runtime instead, see `run_pinned`. The connections it
+    /// accepts are served on that same thread.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` as soon as any acceptor gives up.
+    async fn run(&mut self) -> Result<()> {
+        let mut acceptors = JoinSet::new();
+        let core_pinned = self.settings.load().core_pinned;
+        let cores = core_affinity::get_core_ids().unwrap_or_default();
+
+        for (id, listener) in std::mem::take(&mut self.listeners).into_iter().enumerate() {
+            if !core_pinned {
+                let mut acceptor = self.acceptor(id, TcpListener::from_std(listener)?);
+                acceptors.spawn(async move { acceptor.run().await });
+                continue;
+            }
+
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            // The listener must be registered with the runtime that polls it.
+            let listener = {
+                let _guard = runtime.enter();
+                TcpListener::from_std(listener)?
+            };
+            let acceptor = self.acceptor(id, listener);
+            let core = (!cores.is_empty()).then(|| cores[id % cores.len()]);
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let (result_tx, result_rx) = oneshot::channel();
+
+            thread::Builder::new()
+                .name(format!("sidica-core-{}", id))
+                .spawn(move || {
+                    run_pinned(runtime, acceptor, core, shutdown, result_tx);
+                    drop(shutdown_complete);
+                })?;
+            // A thread that went away without reporting did not fail.
+            acceptors.spawn(async move { result_rx.await.unwrap_or(Ok(())) });
+        }
+
+        // Dropping the `JoinSet`, either here or when `run` is cancelled by
+        // the shutdown signal, aborts the remaining acceptors.
+        while let Some(res) = acceptors.join_next().await {
+            res??;
+        }
+
+        Ok(())
+    }
+}
+
+impl Server {
+    fn acceptor(&self, id: usize, listener: TcpListener) -> Acceptor {
+        Acceptor {
+            id,
+            listener,
+            settings: self.settings.clone(),
+            cache: self.cache.clone(),
+            stats: self.stats.clone(),
+            readiness: self.readiness.clone(),
+            replicator: self.replicator.clone(),
+            tls: self.tls.clone(),
+            limit_connections: self.limit_connections.clone(),
+            ip_limiter: self.ip_limiter.clone(),
+            notify_shutdown: self.notify_shutdown.clone(),
+            shutdown_complete_tx: self.shutdown_complete_tx.clone(),
+        }
+    }
+}
+
+/// Run `acceptor` on `runtime` on the current thread, pinned to `core`, until
+/// the shutdown signal. The result of the accept loop is sent on `result`.
+///
+/// The connections accepted are spawned on `runtime` too. They report to a
+/// channel local to this thread, so the runtime is only dropped once every
+/// one of them has finished; dropping it earlier would cut them off.
+fn run_pinned(
+    runtime: Runtime,
+    mut acceptor: Acceptor,
+    core: Option<CoreId>,
+    mut shutdown: Shutdown,
+    result: oneshot::Sender<Result<()>>,
+) {
+    if let Some(core) = core {
+        if !core_affinity::set_for_current(core) {
+            warn!("acceptor {} could not be pinned to core {}", acceptor.id, core.id);
+        }
+    }
+
+    let (local_complete_tx, mut local_complete_rx) = mpsc::channel(1);
+    acceptor.shutdown_complete_tx = local_complete_tx;
+
+    runtime.block_on(async move {
+        let res = tokio::select! {
+            res = acceptor.run() => res,
+            _ = shutdown.recv() => Ok(()),
+        };
+        let _ = result.send(res);
+
+        drop(acceptor);
+        let _ = local_complete_rx.recv().await;
+    });
+}
+
+/// A single accept loop. There is one per listener, all sharing the state held
+/// by `Server`.
+#[derive(Debug)]
+struct Acceptor {
+    /// Index of this acceptor, used to report per-acceptor stats.
+    id: usize,
+    listener: TcpListener,
+    settings: Arc<ArcSwap<Settings>>,
+    cache: Cache,
+    stats: Arc<ServerStats>,
+    readiness: Arc<Readiness>,
+    replicator: Option<Arc<Replicator>>,
+    tls: Arc<ArcSwapOption<ServerConfig>>,
+    limit_connections: Arc<Semaphore>,
+    ip_limiter: Arc<IpLimiter>,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+impl Acceptor {
+    /// Run the accept loop
+    ///
     /// Listen for inbound connections. For each inbound connection, spawn a
     /// task to process that connection.
     ///
@@ -144,7 +506,7 @@ impl Server {
     /// itself. One strategy for handling this is to implement a back off
     /// strategy, which is what we do here.
     async fn run(&mut self) -> Result<()> {
-        info!("accepting inbound connections");
+        info!("acceptor {} accepting inbound connections", self.id);
 
         loop {
             // Wait for a permit to become available
@@ -157,36 +519,135 @@ impl Server {
             // "forget" the permit, which drops the permit value **without**
             // incrementing the semaphore's permits. Then, in the handler task
             // we manually add a new permit when processing completes.
-            self.limit_connections.acquire().await?.forget();
+            let permit = match self.limit_connections.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    // At the limit: new connections wait in the listen
+                    // backlog until a handler finishes.
+                    self.stats.incr_listen_disabled();
+                    self.limit_connections.acquire().await?
+                }
+            };
+            permit.forget();
 
             // Accept a new socket. This will attempt to perform error handling.
             // The `accept` method internally attempts to recover errors, so an
             // error here is non-recoverable.
-            let socket = self.accept().await?;
-
-            // Create the necessary per-connection handler state.
-            let mut handler = Handler {
-                cache: self.cache.clone(),
-                connection: Connection::new(socket),
-
-                // The connection state needs a handle to the max connections
-                // semaphore. When the handler is done processing the
-                // connection, a permit is added back to the semaphore.
-                limit_connections: self.limit_connections.clone(),
-                shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
-
-                // Notifies the receiver half once all clones are
-                // dropped.
-                _shutdown_complete: self.shutdown_complete_tx.clone(),
-            };
+            let (socket, addr) = self.accept().await?;
+            self.stats.incr_accepted(self.id);
+            // Responses are flushed once per batch of requests, so what is
+            // left of one once the write buffer has filled goes out at once
+            // rather than waiting on the peer to acknowledge the rest.
+            if let Err(err) = socket.set_nodelay(true) {
+                debug!(error = %err, "failed to set TCP_NODELAY");
+            }
+            // Moved into the task, so it is dropped however the task ends,
+            // panics and runtime shutdown included
+            let open = self.stats.open_connection();
+
+            let cache = self.cache.clone();
+            let stats = self.stats.clone();
+            let readiness = self.readiness.clone();
+            let replicator = self.replicator.clone();
+            let tls = self.tls.load_full();
+            let settings = self.settings.clone();
+            let limit_connections = self.limit_connections.clone();
+            let ip_limiter = self.ip_limiter.clone();
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
 
             // Spawn a new task to process the connections. Tokio tasks are like
             // asynchronous green threads and are executed concurrently.
             tokio::spawn(async move {
-                // Process the connection. If an error is encountered, log it.
-                if let Err(err) = handler.run().await {
-                    // error!(cause = err, "connection error");
+                let _open = open;
+                // The whole connection is set up under the settings current
+                // at accept time.
+                let current = settings.load_full();
+
+                // The handshake happens on the connection task so a slow or
+                // hostile client cannot stall the accept loop.
+                let (connection, peer) = match handshake(&current, &stats, tls, socket, addr).await {
+                    Ok(established) => established,
+                    Err(err) => {
+                        info!("handshake with {} failed: {}", addr, err);
+                        // No `Handler` owns the permit yet, so return it here.
+                        limit_connections.add_permits(1);
+                        return;
+                    }
+                };
+
+                // Create the necessary per-connection handler state.
+                let registration = stats.registry().register(peer.clone());
+                let mut handler = Handler {
+                    cache,
+                    stats,
+                    settings,
+                    readiness,
+                    replicator,
+                    writes_allowed: current.writes_allowed(peer.addr.ip()),
+                    connection,
+                    registration,
+                    peer: peer.addr,
+
+                    // The connection state needs a handle to the max connections
+                    // semaphore. When the handler is done processing the
+                    // connection, a permit is added back to the semaphore.
+                    limit_connections,
+                    _ip_permit: None,
+                    shutdown,
+
+                    // Notifies the receiver half once all clones are
+                    // dropped.
+                    _shutdown_complete: shutdown_complete,
+                };
+
+                // Everything logged from here on is attributed to the
+                // connection.
+                let span = info_span!(
+                    "connection",
+                    id = handler.registration.id(),
+                    peer = %handler.peer
+                );
+                async move {
+                    // Refused only now so the reply goes out over TLS too.
+                    if handler.readiness.is_draining() {
+                        info!("refusing connection while draining");
+                        handler.stats.incr_rejected_connections();
+                        let _ = handler
+                            .connection
+                            .write_and_flush(ResponseFrame::ServerError("draining".to_string()))
+                            .await;
+                        return;
+                    }
+
+                    // The per-IP limits are checked only now that the real
+                    // client address is known, and the rejection goes out over
+                    // TLS when it is enabled.
+                    match ip_limiter.acquire(handler.peer.ip(), &current) {
+                        Ok(permit) => handler._ip_permit = permit,
+                        Err(reason) => {
+                            handler.reject(reason).await;
+                            return;
+                        }
+                    }
+
+                    // Process the connection. If an error is encountered, log it.
+                    //
+                    // A panic only takes down this connection. It is caught here
+                    // so it gets reported; the handler is then dropped as usual,
+                    // returning its permit and leaving the registry.
+                    match AssertUnwindSafe(handler.run()).catch_unwind().await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(err)) if is_disconnect(&err) => debug!(error = %err, "peer went away"),
+                        Ok(Err(err)) => error!(error = %err, "connection error"),
+                        Err(panic) => {
+                            handler.stats.incr_handler_panics();
+                            error!(panic = panic_message(&panic), "connection panicked");
+                        }
+                    }
                 }
+                .instrument(span)
+                .await;
             });
         }
     }
@@ -198,7 +659,7 @@ impl Server {
     /// After the second failure, the task waits for 2 seconds. Each subsequent
     /// failure doubles the wait time. If accepting fails on the 6th try after
     /// waiting for 64 seconds, then this function returns with an error.
-    async fn accept(&mut self) -> Result<TcpStream> {
+    async fn accept(&mut self) -> Result<(TcpStream, SocketAddr)> {
         let mut backoff = 1;
 
         // Try to accept a few times
@@ -208,7 +669,7 @@ impl Server {
             match self.listener.accept().await {
                 Ok((socket, addr)) => {
                     info!("accepted connection from: {:?}", addr);
-                    return Ok(socket);
+                    return Ok((socket, addr));
                 }
                 Err(err) => {
                     if backoff > 64 {
@@ -232,8 +693,26 @@ impl Server {
 #[derive(Debug)]
 struct Handler {
     cache: Cache,
+    stats: Arc<ServerStats>,
+    settings: Arc<ArcSwap<Settings>>,
+    readiness: Arc<Readiness>,
+    replicator: Option<Arc<Replicator>>,
+
+    /// Whether this client may change the cache, see `--read-only`.
+    writes_allowed: bool,
     connection: Connection,
+
+    /// The client's address. Taken from the PROXY protocol header when
+    /// `--proxy-protocol` is enabled, otherwise the socket's peer address.
+    peer: SocketAddr,
+
+    /// Keeps this connection's entry in the `Registry` current.
+    registration: Registration,
     limit_connections: Arc<Semaphore>,
+
+    /// Not used directly. Holds this connection's slot in the per-IP limit
+    /// and frees it when the handler is dropped.
+    _ip_permit: Option<IpPermit>,
     shutdown: Shutdown,
 
     /// Not used directly. Instead, when `Handler` is dropped...?
@@ -244,7 +723,8 @@ impl Handler {
     /// Process a single connection.
     ///
     /// Request frames are read from the socket and processed. Responses are
-    /// written back to the socket.
+    /// written back to the socket. Requests pipelined into a single read are
+    /// processed as a batch, and their responses flushed together.
     ///
     /// Currently, pipelining is not implemented. Pipelining is the ability to
     /// process more than one request concurrently per connection without
@@ -253,35 +733,94 @@ impl Handler {
     ///
     /// When the shutdown signal is received, the connection is processed until
     /// it reaches a safe state, at which point it is terminated.
+    ///
+    /// The connection is also closed once it has been open for
+    /// `--max-connection-lifetime` or has served `--max-connection-requests`
+    /// commands. The command in flight is always answered first.
     async fn run(&mut self) -> Result<()> {
-        // As long as the shutdown signal has not been received, try to read a
-        // new request frame.
+        let deadline = self
+            .settings
+            .load()
+            .max_connection_lifetime
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        let mut served = 0;
+        let mut frames = Vec::new();
+
+        // As long as the shutdown signal has not been received, try to read
+        // new request frames.
         while !self.shutdown.is_shutdown() {
-            // While reading a request frame, also listen for the shutdown
-            // signal.
-            let maybe_frame = tokio::select! {
-                res = self.connection.read_frame() => res?,
+            // While reading request frames, also listen for the shutdown
+            // signal and the end of the connection's lifetime.
+            let open = tokio::select! {
+                res = self.connection.read_frames(&mut frames) => res?,
                 _ = self.shutdown.recv() => {
                     // If a shutdown signal is received, return from `run`.
                     // This will result in the task terminating.
                     return Ok(());
                 }
+                _ = expire(deadline) => {
+                    debug!("closing connection at --max-connection-lifetime");
+                    return Ok(());
+                }
             };
 
-            // If `None` is returned from `read_frame()` then the peer closed
+            // If `false` is returned from `read_frames()` then the peer closed
             // the socket. There is no further work to do and the task can be
             // terminated.
-            let frame = match maybe_frame {
-                Some(frame) => frame,
-                None => return Ok(()),
-            };
+            if !open {
+                return Ok(());
+            }
+
+            // Every request pipelined into the last read is answered before
+            // the socket is read again, and the responses flushed together.
+            self.connection.cork();
+            let keep_open = self.serve(&mut frames, &mut served, deadline).await;
+            self.connection.uncork().await?;
+            // Responses written out only once flushed
+            self.stats.add_traffic(self.connection.take_traffic());
+            if !keep_open? {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
 
+    /// Apply the commands of a batch of frames in order. Returns `false` when
+    /// the connection is to be closed, leaving the rest of the batch
+    /// unanswered.
+    async fn serve(
+        &mut self,
+        frames: &mut Vec<RequestFrame>,
+        served: &mut u64,
+        deadline: Option<Instant>,
+    ) -> Result<bool> {
+        for frame in frames.drain(..) {
             // Convert the redis frame into a command struct. This returns an
             // error if the frame is not a valid redis command or it is an
             // unsupported command.
-            let cmd = Command::from_frame(frame)?;
-
-            debug!("{:?}", cmd);
+            let cmd = match Command::from_frame(frame) {
+                Ok(cmd) => cmd,
+                Err(err) => match commands::client_error(&err) {
+                    Some(reason) => {
+                        let response = ResponseFrame::ClientError(reason.to_string());
+                        self.connection.write_and_flush(response).await?;
+                        continue;
+                    }
+                    None => {
+                        warn!(error = %err, "protocol error, closing connection");
+                        return Ok(false);
+                    }
+                },
+            };
+            self.stats.incr_tcp_requests();
+            self.registration.processing(cmd.get_name());
+            let span = debug_span!(
+                "command",
+                name = cmd.get_name(),
+                keys = cmd.key_count(),
+                bytes = cmd.data_len()
+            );
 
             // Perform the work needed to apply the command. This may mutate the
             // database state as a result.
@@ -290,17 +829,713 @@ impl Handler {
             // command to write response frames directly to the connection. In
             // the case of pub/sub, multiple frames may be send back to the
             // peer.
-            cmd.apply(&self.cache, &mut self.connection, &mut self.shutdown)
-                .await?;
+            let settings = self.settings.load_full();
+            cmd.apply(
+                &self.cache,
+                &self.stats,
+                &self.settings,
+                &self.readiness,
+                self.replicator.as_deref(),
+                self.writes_allowed,
+                &mut self.connection,
+            )
+            .instrument(span)
+            .await?;
+            self.registration.reading();
+            // Once per command, rather than per read or write
+            self.stats.add_traffic(self.connection.take_traffic());
+
+            // The batch is flushed once served, so closing here loses nothing.
+            *served += 1;
+            if settings
+                .max_connection_requests
+                .is_some_and(|max| *served >= max)
+            {
+                debug!(served = *served, "closing connection at --max-connection-requests");
+                return Ok(false);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                debug!("closing connection at --max-connection-lifetime");
+                return Ok(false);
+            }
         }
+        Ok(true)
+    }
 
-        Ok(())
+    /// Turn the connection away for exceeding a per-IP limit. The reply is
+    /// best effort; the connection is closed when the handler is dropped.
+    async fn reject(&mut self, reason: Rejected) {
+        info!(?reason, "rejecting connection");
+        self.stats.incr_ip_rejects(reason);
+
+        let message = match reason {
+            Rejected::TooManyConnections => "too many connections from your address",
+            Rejected::RateExceeded => "too many new connections from your address",
+        };
+        let _ = self
+            .connection
+            .write_and_flush(ResponseFrame::ServerError(message.to_string()))
+            .await;
+    }
+}
+
+/// Whether `err` is the peer closing the connection mid-request, or going
+/// away while being answered. Neither is the server's to report.
+fn is_disconnect(err: &anyhow::Error) -> bool {
+    use io::ErrorKind::*;
+
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| matches!(err.kind(), BrokenPipe | ConnectionAborted | ConnectionReset | UnexpectedEof))
+}
+
+/// Returns the message a panic was raised with, if it has one.
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Completes at `deadline`, or never without one.
+async fn expire(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
     }
 }
 
+/// Wrap an accepted socket in a `Connection`, returning it along with the
+/// client's address, the proxy's when it came through one, and the common
+/// name of the client's TLS certificate when it presented one.
+///
+/// With `--proxy-protocol` the stream must start with a PROXY protocol header,
+/// which is consumed and supplies the client address; connections without one
+/// are rejected. The TLS handshake, when TLS is configured, follows the header.
+///
+/// Clients refused by `--allow`/`--deny` are rejected once their address is
+/// known, before the TLS handshake.
+///
+/// With `--tls-client-ca` the handshake fails for clients that do not present
+/// a certificate signed by one of the configured CAs, so such connections
+/// never reach the protocol layer.
+///
+/// Clients get `--handshake-timeout` for the header and the TLS handshake
+/// together, so connections that send neither do not hold on to a permit.
+async fn handshake(
+    settings: &Settings,
+    stats: &ServerStats,
+    tls: Option<Arc<ServerConfig>>,
+    mut socket: TcpStream,
+    addr: SocketAddr,
+) -> Result<(Connection, Peer)> {
+    let mut peer = Peer::from(addr);
+    let deadline = Instant::now() + Duration::from_secs(settings.handshake_timeout);
+    let timed_out = || format!("no handshake within {}s", settings.handshake_timeout);
+
+    if settings.proxy_protocol {
+        // A header without an address (e.g. a health check by the proxy
+        // itself) leaves the proxy's address in place.
+        let header = time::timeout_at(deadline, proxy::read_header(&mut socket));
+        if let Some(client) = header.await.with_context(timed_out)?? {
+            debug!("connection from {} proxied for {}", addr, client);
+            peer = Peer {
+                addr: client,
+                proxy: Some(addr),
+                cn: None,
+            };
+        }
+    }
+
+    if !acl::permits(&settings.allow, &settings.deny, peer.addr.ip()) {
+        stats.incr_rejected_by_acl();
+        bail!("{} denied by ACL", peer.addr);
+    }
+
+    let config = match tls {
+        Some(config) => config,
+        None => return Ok((Connection::new(socket), peer)),
+    };
+
+    let accept = time::timeout_at(deadline, TlsAcceptor::from(config).accept(socket));
+    let stream = accept.await.with_context(timed_out)??;
+    if let Some(certs) = stream.get_ref().1.peer_certificates() {
+        peer.cn = tls::common_name(certs);
+        info!(
+            "client {} authenticated with certificate CN: {:?}",
+            peer.addr, peer.cn
+        );
+    }
+
+    Ok((Connection::new(stream), peer))
+}
+
 impl Drop for Handler {
     fn drop(&mut self) {
+        // What the last command left, however the connection ended
+        self.stats.add_traffic(self.connection.take_traffic());
         // Add a permit back to the semaphore.
         self.limit_connections.add_permits(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Start a server with the given command line options on an ephemeral
+    /// port, returning its address.
+    async fn start(args: &[&str]) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let settings = Settings::parse_from(["sidica"].iter().chain(args));
+        let cache = settings.cache_builder().build();
+        tokio::spawn(run(
+            vec![listener],
+            None,
+            None,
+            Default::default(),
+            cache,
+            settings,
+            std::future::pending::<()>(),
+        ));
+        addr
+    }
+
+    #[test]
+    fn test_is_disconnect() {
+        assert!(is_disconnect(&io::Error::from(io::ErrorKind::BrokenPipe).into()));
+        let reset = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(is_disconnect(&reset.context("writing a response")));
+        assert!(!is_disconnect(&io::Error::from(io::ErrorKind::PermissionDenied).into()));
+        assert!(!is_disconnect(&anyhow::anyhow!("connection reset by peer")));
+    }
+
+    /// Send commands on `stream` until the server closes it, up to `max`.
+    /// Returns how many were answered.
+    async fn count_until_closed(stream: &mut TcpStream, max: usize) -> usize {
+        let mut answered = 0;
+        let mut response = [0; 5];
+        while answered < max {
+            if stream.write_all(b"get foo\r\n").await.is_err() {
+                break;
+            }
+            if stream.read_exact(&mut response).await.is_err() {
+                break;
+            }
+            assert_eq!(&response, b"END\r\n");
+            answered += 1;
+        }
+        answered
+    }
+
+    #[tokio::test]
+    async fn test_max_connection_requests() {
+        let addr = start(&["--max-connection-requests", "3"]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(count_until_closed(&mut stream, 10).await, 3);
+
+        // A new connection starts counting again, and the limit is reported
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"stats settings\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("STAT max_connection_requests 3\r\n"));
+        assert!(response.contains("STAT max_connection_lifetime 0\r\n"));
+        assert!(response.contains("STAT tcp_backlog 1024\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let addr = start(&["--proxy-protocol", "--handshake-timeout", "1"]).await;
+
+        // Never sends the PROXY header, and is closed all the same
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut rest = Vec::new();
+        let read = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stats_conns() {
+        let addr = start(&["--proxy-protocol"]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let local = stream.local_addr().unwrap();
+        stream
+            .write_all(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 11211\r\nstats conns\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        let response = String::from_utf8(response).unwrap();
+
+        // The client as the header names it, and the proxy it came through
+        assert!(response.contains(":addr 192.0.2.1:56324\r\n"), "{}", response);
+        assert!(response.contains(&format!(":proxy {}\r\n", local)), "{}", response);
+        assert!(response.contains(":state processing\r\n"), "{}", response);
+        assert!(response.contains(":last_command stats\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_max_connection_lifetime() {
+        let addr = start(&["--max-connection-lifetime", "1"]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(count_until_closed(&mut stream, 2).await, 2);
+
+        // The idle connection is closed once its lifetime is up
+        let mut rest = Vec::new();
+        let read = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic_is_isolated() {
+        let addr = start(&[]).await;
+        let mut bystander = TcpStream::connect(addr).await.unwrap();
+
+        // More panics than there are connection permits, so leaking a permit
+        // per panic would stall the server.
+        for _ in 0..MAX_CONNECTIONS + 10 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"debug_panic\r\n").await.unwrap();
+            let mut rest = Vec::new();
+            // Reset or clean close, either way nothing is answered
+            let _ = stream.read_to_end(&mut rest).await;
+            assert!(rest.is_empty());
+        }
+
+        // Other connections are unaffected and the panics are counted
+        assert_eq!(count_until_closed(&mut bystander, 1).await, 1);
+        bystander.write_all(b"stats\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(bystander.read_buf(&mut response).await.unwrap(), 0);
+        }
+        let expected = format!("STAT handler_panics {}\r\n", MAX_CONNECTIONS + 10);
+        assert!(String::from_utf8(response).unwrap().contains(&expected));
+    }
+
+    /// The stats `stream` gets, by name.
+    async fn stats_of(stream: &mut TcpStream) -> std::collections::HashMap<String, u64> {
+        stream.write_all(b"stats\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        String::from_utf8(response)
+            .unwrap()
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.strip_prefix("STAT ")?.split(' ');
+                Some((fields.next()?.to_string(), fields.next()?.parse().ok()?))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_connection_counts_balance() {
+        let addr = start(&["--max-connections-per-ip", "201"]).await;
+        let mut watcher = TcpStream::connect(addr).await.unwrap();
+
+        // Closed every way a connection can end
+        for n in 0..2000 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            match n % 4 {
+                0 => {}
+                1 => assert_eq!(count_until_closed(&mut stream, 1).await, 1),
+                // Mid-command
+                2 => stream.write_all(b"get fo").await.unwrap(),
+                _ => {
+                    stream.write_all(b"debug_panic\r\n").await.unwrap();
+                    let _ = stream.read_to_end(&mut Vec::new()).await;
+                }
+            }
+        }
+        // Many at once, past the per-IP limit
+        let mut streams = Vec::new();
+        for _ in 0..200 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            assert_eq!(count_until_closed(&mut stream, 1).await, 1);
+            streams.push(stream);
+        }
+        for _ in 0..10 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        }
+        drop(streams);
+
+        let start = Instant::now();
+        let stats = loop {
+            let stats = stats_of(&mut watcher).await;
+            if stats["curr_connections"] == 1 {
+                break stats;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "{:?}", stats["curr_connections"]);
+            time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(stats["total_connections"], 1 + 2000 + 210);
+        assert_eq!(stats["rejected_connections"], 10);
+        assert_eq!(stats["ip_connection_rejects"], 10);
+        assert!(stats["connection_structures"] >= 201);
+    }
+
+    #[tokio::test]
+    async fn test_stats_report_the_process() {
+        let addr = start(&[]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"stats\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        let response = String::from_utf8(response).unwrap();
+        let stats: std::collections::HashMap<_, _> = response
+            .lines()
+            .filter_map(|line| line.strip_prefix("STAT ")?.split_once(' '))
+            .collect();
+
+        // First, in the order memcached reports them
+        let names: Vec<_> = response.lines().take(8).map(|line| line.split(' ').nth(1).unwrap()).collect();
+        assert_eq!(
+            names,
+            [
+                "uptime",
+                "time",
+                "version",
+                "pointer_size",
+                "rusage_user",
+                "rusage_system",
+                "max_connections",
+                "threads",
+            ]
+        );
+        assert!(stats["uptime"].parse::<u64>().unwrap() < 60);
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert!(now.as_secs().abs_diff(stats["time"].parse().unwrap()) <= 1);
+        assert_eq!(stats["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(stats["pointer_size"], usize::BITS.to_string());
+        for name in ["rusage_user", "rusage_system"] {
+            let (seconds, micros) = stats[name].split_once('.').unwrap();
+            assert!(seconds.parse::<u64>().is_ok() && micros.len() == 6 && micros.parse::<u32>().is_ok());
+        }
+        assert_eq!(stats["max_connections"], MAX_CONNECTIONS.to_string());
+        assert!(stats["threads"].parse::<usize>().unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_stats_items() {
+        let addr = start(&["--memory-limit", "1"]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut request = Vec::new();
+        for n in 0..20 {
+            request.extend_from_slice(format!("set key{} 0 0 100000\r\n", n).as_bytes());
+            request.extend_from_slice(&[b'x'; 100_000]);
+            request.extend_from_slice(b"\r\n");
+        }
+        // Expired as soon as stored, then replaced unread
+        request.extend_from_slice(b"set gone 0 -1 1\r\nx\r\nset gone 0 0 1\r\nx\r\n");
+        request.extend_from_slice(b"stats items\r\n");
+        stream.write_all(&request).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        let response = String::from_utf8(response).unwrap();
+        let stats: std::collections::HashMap<_, _> = response
+            .lines()
+            .filter_map(|line| line.strip_prefix("STAT ")?.split_once(' '))
+            .map(|(name, value)| (name, value.parse::<u64>().unwrap()))
+            .collect();
+
+        let evicted = stats["items:1:evicted"];
+        assert!(evicted >= 10, "{}", response);
+        assert_eq!(stats["items:1:evicted_unfetched"], evicted);
+        assert_eq!(stats["items:1:number"], 21 - evicted);
+        assert_eq!(stats["items:1:reclaimed"], 1);
+        assert_eq!(stats["items:1:expired_unfetched"], 1);
+        assert_eq!(stats["items:1:outofmemory"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_latency_of_a_slow_command() {
+        let addr = start(&[]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..3 {
+            stream.write_all(b"debug_sleep 20\r\n").await.unwrap();
+            let mut ok = [0; 4];
+            stream.read_exact(&mut ok).await.unwrap();
+            assert_eq!(&ok, b"OK\r\n");
+        }
+        stream.write_all(b"get foo\r\n").await.unwrap();
+        let mut end = [0; 5];
+        stream.read_exact(&mut end).await.unwrap();
+
+        async fn latency(stream: &mut TcpStream) -> String {
+            stream.write_all(b"stats latency\r\n").await.unwrap();
+            let mut response = Vec::new();
+            while !response.ends_with(b"END\r\n") {
+                assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+            }
+            String::from_utf8(response).unwrap()
+        }
+        let response = latency(&mut stream).await;
+        let stats: std::collections::HashMap<_, u64> = response
+            .lines()
+            .filter_map(|line| {
+                let (name, value) = line.strip_prefix("STAT ")?.split_once(' ')?;
+                Some((name, value.parse().unwrap()))
+            })
+            .collect();
+        assert_eq!(stats["debug_sleep:count"], 3);
+        // 20ms lands under 2^15 microseconds at the earliest
+        assert!((1 << 15..=1 << 20).contains(&stats["debug_sleep:p50_us"]));
+        assert!(stats["debug_sleep:p99_us"] >= stats["debug_sleep:p50_us"]);
+        assert_eq!(stats["get:count"], 1);
+        assert!(stats["get:p50_us"] < 1 << 15);
+        assert!(!stats.contains_key("set:count"));
+
+        stream.write_all(b"stats reset\r\n").await.unwrap();
+        let mut reset = [0; 7];
+        stream.read_exact(&mut reset).await.unwrap();
+        assert_eq!(&reset, b"RESET\r\n");
+        // Only the `stats latency` before, and the reset, since
+        let response = latency(&mut stream).await;
+        assert!(!response.contains("debug_sleep"), "{}", response);
+        assert!(response.contains("STAT stats:count 1\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_stats_detail() {
+        let addr = start(&["--detail-delimiter", "/", "--detail-max-prefixes", "2"]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        async fn ask(stream: &mut TcpStream, request: &str, until: &str) -> String {
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            while !response.ends_with(until.as_bytes()) {
+                assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+            }
+            String::from_utf8(response).unwrap()
+        }
+
+        assert_eq!(
+            ask(&mut stream, "stats detail\r\n", "\r\n").await,
+            "CLIENT_ERROR usage: stats detail on|off|dump\r\n"
+        );
+        ask(&mut stream, "set a/1 0 0 1\r\nx\r\n", "\r\n").await;
+        assert_eq!(ask(&mut stream, "stats detail on\r\n", "\r\n").await, "OK\r\n");
+        ask(&mut stream, "set a/1 0 0 1\r\nx\r\n", "\r\n").await;
+        ask(&mut stream, "get a/1 a/2 b/1\r\n", "END\r\n").await;
+        ask(&mut stream, "delete b/1\r\n", "\r\n").await;
+        ask(&mut stream, "get c/1\r\n", "END\r\n").await;
+        assert_eq!(ask(&mut stream, "stats detail off\r\n", "\r\n").await, "OK\r\n");
+        ask(&mut stream, "get a/1\r\n", "END\r\n").await;
+
+        let dump = ask(&mut stream, "stats detail dump\r\n", "END\r\n").await;
+        assert_eq!(
+            dump,
+            "PREFIX a get 2 hit 1 set 1 del 0\r\n\
+             PREFIX b get 1 hit 0 set 0 del 1\r\n\
+             OVERFLOW get 1 hit 0 set 0 del 0\r\n\
+             END\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traffic_counts_every_byte() {
+        let addr = start(&[]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // Larger than the buffers on both ends
+        let value = vec![b'x'; 100_000];
+        let mut set = b"set big 0 0 100000\r\n".to_vec();
+        set.extend_from_slice(&value);
+        set.extend_from_slice(b"\r\n");
+        stream.write_all(&set).await.unwrap();
+        let mut stored = [0; 8];
+        stream.read_exact(&mut stored).await.unwrap();
+        assert_eq!(&stored, b"STORED\r\n");
+
+        stream.write_all(b"get big\r\n").await.unwrap();
+        let mut expected = b"VALUE big 0 100000\r\n".to_vec();
+        expected.extend_from_slice(&value);
+        expected.extend_from_slice(b"\r\nEND\r\n");
+        let mut got = vec![0; expected.len()];
+        stream.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, expected);
+
+        // A command is counted once answered, so this one is
+        stream.write_all(b"stats\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        // And this one not yet
+        let stats = stats_of(&mut stream).await;
+        assert_eq!(stats["bytes_read"], (set.len() + b"get big\r\n".len() + b"stats\r\n".len()) as u64);
+        assert_eq!(stats["bytes_written"], (stored.len() + expected.len() + response.len()) as u64);
+    }
+
+    #[tokio::test]
+    async fn test_listen_disabled_at_connection_limit() {
+        let addr = start(&[]).await;
+
+        let mut streams = Vec::new();
+        for _ in 0..MAX_CONNECTIONS {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            assert_eq!(count_until_closed(&mut stream, 1).await, 1);
+            streams.push(stream);
+        }
+
+        // Waits in the backlog until a slot frees up
+        let mut waiting = TcpStream::connect(addr).await.unwrap();
+        waiting.write_all(b"stats\r\n").await.unwrap();
+        streams.pop();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(waiting.read_buf(&mut response).await.unwrap(), 0);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("STAT listen_disabled_num "));
+        assert!(!response.contains("STAT listen_disabled_num 0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_by_default() {
+        let addr = start(&[]).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(count_until_closed(&mut stream, 100).await, 100);
+    }
+
+    #[tokio::test]
+    async fn test_core_pinned_shares_cache() {
+        let addr = start(&["--core-pinned"]).await;
+
+        let mut writer = TcpStream::connect(addr).await.unwrap();
+        writer.write_all(b"set foo 0 0 3\r\nbar\r\n").await.unwrap();
+        let mut response = [0; 8];
+        writer.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"STORED\r\n");
+
+        let mut reader = TcpStream::connect(addr).await.unwrap();
+        reader.write_all(b"get foo\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(reader.read_buf(&mut response).await.unwrap(), 0);
+        }
+        assert_eq!(response, b"VALUE foo 0 3\r\nbar\r\nEND\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replicates_to_peer() {
+        let replica = start(&[]).await.to_string();
+        let primary = start(&["--replica-of-mine", &replica]).await;
+
+        let mut stream = TcpStream::connect(primary).await.unwrap();
+        stream.write_all(b"set foo 0 0 3\r\nbar\r\n").await.unwrap();
+        let mut response = [0; 8];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"STORED\r\n");
+
+        let mut stream = TcpStream::connect(&replica).await.unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            stream.write_all(b"get foo\r\n").await.unwrap();
+            let mut response = Vec::new();
+            while !response.ends_with(b"END\r\n") {
+                assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+            }
+            if response == b"VALUE foo 0 3\r\nbar\r\nEND\r\n" {
+                break;
+            }
+            assert!(Instant::now() < deadline, "set never reached the replica");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_accepts_replication_source_only() {
+        let replica = start(&["--read-only", "--replication-source", "127.0.0.2"]).await;
+        let set = b"set foo 0 0 3\r\nbar\r\n";
+
+        let mut client = TcpStream::connect(replica).await.unwrap();
+        client.write_all(set).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n") {
+            assert_ne!(client.read_buf(&mut response).await.unwrap(), 0);
+        }
+        assert_eq!(response, b"SERVER_ERROR writes not permitted on replica\r\n");
+
+        // The replication link connects from the designated address
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut link = socket.connect(replica).await.unwrap();
+        link.write_all(set).await.unwrap();
+        let mut response = [0; 8];
+        link.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"STORED\r\n");
+
+        // Reads are served to everyone
+        client.write_all(b"get foo\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(client.read_buf(&mut response).await.unwrap(), 0);
+        }
+        assert_eq!(response, b"VALUE foo 0 3\r\nbar\r\nEND\r\n");
+    }
+
+    /// Send `request` on `stream` and read the one line answer.
+    async fn answer(stream: &mut TcpStream, request: &[u8]) -> String {
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let addr = start(&[]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            answer(&mut stream, b"drain on\r\n").await,
+            "CLIENT_ERROR drain not enabled, see --enable-shutdown\r\n"
+        );
+
+        let addr = start(&["--enable-shutdown"]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(answer(&mut stream, b"drain on\r\n").await, "OK\r\n");
+
+        // New connections are turned away, the open one keeps working
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let mut response = Vec::new();
+        refused.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"SERVER_ERROR draining\r\n");
+        assert_eq!(count_until_closed(&mut stream, 1).await, 1);
+        stream.write_all(b"stats\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            assert_ne!(stream.read_buf(&mut response).await.unwrap(), 0);
+        }
+        assert!(String::from_utf8(response).unwrap().contains("STAT draining 1\r\n"));
+
+        assert_eq!(answer(&mut stream, b"drain off\r\n").await, "OK\r\n");
+        let mut accepted = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(count_until_closed(&mut accepted, 1).await, 1);
+    }
+}
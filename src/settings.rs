@@ -0,0 +1,761 @@
+use crate::acl::Cidr;
+use crate::bench;
+use crate::cache::{Cache, CacheBuilder, EvictionPolicy};
+use crate::clock::Jitter;
+use crate::expiry::ExpirySweep;
+use crate::id_generator::Layout;
+use crate::quota::{QuotaSpec, Quotas};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use std::ffi::OsString;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Server settings, read from the command line and the optional config file.
+#[derive(Parser, Debug, Clone)]
+#[command(
+    name = "sidica",
+    version,
+    about = "Simple Disk Cache",
+    args_override_self = true
+)]
+pub struct Settings {
+    /// Run a tool instead of the server
+    #[command(subcommand)]
+    pub command: Option<Subcommands>,
+
+    /// Config file to read settings from. Each line is `option = value`,
+    /// with `option` any long option below minus the leading `--`.
+    /// Options given on the command line take precedence.
+    #[arg(short = 'c', long = "config")]
+    pub config: Option<PathBuf>,
+
+    /// Format of the log on stderr
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// What to log: 0 for informational events, 1 to add a debug event per
+    /// command, 2 for everything. `RUST_LOG`, when set, is used instead
+    /// until the verbosity is changed with the `verbosity` command or a
+    /// reload.
+    #[arg(
+        short = 'v',
+        long = "verbosity",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=2)
+    )]
+    pub verbosity: u8,
+
+    /// OTLP/HTTP endpoint to export the command spans to, for example
+    /// `http://localhost:4318/v1/traces`. Nothing is exported unless set.
+    #[cfg(feature = "otel")]
+    #[arg(long = "otel-endpoint", value_name = "URL")]
+    pub otel_endpoint: Option<String>,
+
+    /// Fraction of the commands to export spans for, from 0 to 1
+    #[cfg(feature = "otel")]
+    #[arg(long = "otel-sample-rate", default_value_t = 0.01, value_parser = sample_rate)]
+    pub otel_sample_rate: f64,
+
+    /// Run in the background once the listeners are bound (unix only)
+    #[arg(short = 'd', long = "daemon")]
+    pub daemon: bool,
+
+    /// Write the server's pid to this file and remove it on shutdown.
+    /// Refuses to start if the file names a running process.
+    #[arg(short = 'P', long = "pid-file")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Once the listeners are bound, switch to this user and its groups.
+    /// Requires starting as root (unix only).
+    #[arg(short = 'u', long = "user")]
+    pub user: Option<String>,
+
+    /// Take over from a running server: load the cache from this handoff
+    /// file and use the sockets passed along with it. Set by the old process
+    /// on a warm restart (SIGUSR2), not meant to be given by hand.
+    #[arg(long = "load-handoff", value_name = "FILE")]
+    pub load_handoff: Option<PathBuf>,
+
+    /// Directory to keep the cache in across restarts. A snapshot of the
+    /// cache is written to it on shutdown and loaded from it on startup,
+    /// before any connection is accepted, and the last CAS handed out every
+    /// second, not to hand it out again. Must be writable by `--user`.
+    #[arg(long = "data-dir", value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Start with an empty cache when the snapshot in `--data-dir` cannot be
+    /// loaded, instead of refusing to start. The bad snapshot is replaced on
+    /// the next shutdown. With `--append-log`, also when the log cannot be
+    /// replayed; it is emptied. Also when the watermark of the last CAS
+    /// cannot be read; it is replaced within a second.
+    #[arg(long = "discard-bad-snapshot", requires = "data_dir")]
+    pub discard_bad_snapshot: bool,
+
+    /// Also save a snapshot every this many seconds, not only on shutdown.
+    #[arg(long = "snapshot-interval", value_name = "SECONDS", requires = "data_dir")]
+    pub snapshot_interval: Option<u64>,
+
+    /// Also save a snapshot after every this many changes to the cache:
+    /// items stored, deleted or touched, and flushes.
+    #[arg(long = "snapshot-after-changes", value_name = "N", requires = "data_dir")]
+    pub snapshot_after_changes: Option<u64>,
+
+    /// How many snapshots to keep in `--data-dir`, the newest as `snapshot`
+    /// and the ones before as `snapshot.1`, `snapshot.2` and so on. Only the
+    /// newest is loaded.
+    #[arg(
+        long = "snapshots-kept",
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub snapshots_kept: u32,
+
+    /// Also write every change to the cache to a log in `--data-dir` as it
+    /// is made, and replay it on top of the snapshot on startup, so changes
+    /// since the last snapshot survive a crash. Changes are reported done
+    /// once written to the log, which is synced to disk every second.
+    #[arg(long = "append-log", requires = "data_dir")]
+    pub append_log: bool,
+
+    /// Directory `export` and `import` write and read their files in,
+    /// named by the client. The commands are refused unless set.
+    #[arg(long = "dump-dir", value_name = "DIR")]
+    pub dump_dir: Option<PathBuf>,
+
+    /// Rewrite the append log, down to a record for each item, once it is
+    /// this many times the size of the items and at least 16 MiB. 0 for
+    /// only on `rewrite_log`.
+    #[arg(long = "log-rewrite-ratio", value_name = "N", default_value_t = 4)]
+    pub log_rewrite_ratio: u32,
+
+    /// Interface to listen on
+    #[arg(short = 'l', long = "listen", default_value = "127.0.0.1")]
+    pub listen: String,
+
+    /// TCP port to listen on
+    #[arg(short = 'p', long = "port", default_value_t = 8080)]
+    pub port: u16,
+
+    /// Length of the queue of connections waiting to be accepted, per TCP
+    /// listener. Connections beyond it are dropped by the kernel. Capped by
+    /// the system limit (net.core.somaxconn on Linux).
+    #[arg(
+        short = 'b',
+        long = "backlog",
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(i32).range(1..)
+    )]
+    pub backlog: i32,
+
+    /// Number of TCP accept loops. Above 1, each gets its own SO_REUSEPORT
+    /// listener on the same address and the kernel balances connections
+    /// between them.
+    #[arg(
+        long = "reuseport",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub reuseport: u16,
+
+    /// Run one single threaded runtime per CPU core, each pinned to its core
+    /// with its own SO_REUSEPORT listener, instead of one work-stealing
+    /// runtime. Connections stay on the core that accepted them.
+    #[arg(long = "core-pinned", conflicts_with = "reuseport")]
+    pub core_pinned: bool,
+
+    /// Expect every TCP connection to start with a PROXY protocol (v1 or v2)
+    /// header and use the client address it carries. Connections without
+    /// the header are closed.
+    #[arg(long = "proxy-protocol")]
+    pub proxy_protocol: bool,
+
+    /// Close connections whose PROXY protocol header and TLS handshake have
+    /// not both come through after this many seconds.
+    #[arg(
+        long = "handshake-timeout",
+        value_name = "SECONDS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub handshake_timeout: u64,
+
+    /// Most simultaneous connections allowed from a single client address.
+    /// Unlimited unless set.
+    #[arg(long = "max-connections-per-ip", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections_per_ip: Option<u32>,
+
+    /// Most new connections per second allowed from a single client
+    /// address. Unlimited unless set.
+    #[arg(long = "connection-rate-per-ip", value_parser = clap::value_parser!(u32).range(1..))]
+    pub connection_rate_per_ip: Option<u32>,
+
+    /// Close connections after this many seconds, once the command in
+    /// flight has been answered, so clients reconnect. Unlimited unless set.
+    #[arg(
+        long = "max-connection-lifetime",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_connection_lifetime: Option<u64>,
+
+    /// Close connections after they have served this many commands, so
+    /// clients reconnect. Unlimited unless set.
+    #[arg(
+        long = "max-connection-requests",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_connection_requests: Option<u64>,
+
+    /// Only accept clients in this address block (CIDR). Repeatable; when
+    /// none is given, every client not denied is accepted.
+    #[arg(long = "allow", value_name = "CIDR")]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse clients in this address block (CIDR). Repeatable; takes
+    /// precedence over `--allow`.
+    #[arg(long = "deny", value_name = "CIDR")]
+    pub deny: Vec<Cidr>,
+
+    /// Accept the admin commands that change how the server runs, such as
+    /// `drain`. Any client that can connect can use them.
+    #[arg(short = 'A', long = "enable-shutdown")]
+    pub enable_shutdown: bool,
+
+    /// Forward every successful mutation to the sidica at this address, which
+    /// should run with `--read-only` and this server as its
+    /// `--replication-source`. Forwarding never holds up clients; when the
+    /// replica falls too far behind the oldest mutations are lost.
+    #[arg(long = "replica-of-mine", value_name = "ADDR")]
+    pub replica_of_mine: Option<String>,
+
+    /// Run as a replica: refuse mutations with `SERVER_ERROR`, except from
+    /// `--replication-source`. Reads are served as usual.
+    #[arg(long = "read-only")]
+    pub read_only: bool,
+
+    /// Address block (CIDR) of the primary whose mutations a `--read-only`
+    /// replica applies. Repeatable.
+    #[arg(long = "replication-source", value_name = "CIDR")]
+    pub replication_source: Vec<Cidr>,
+
+    /// Most mutations queued for `--replica-of-mine` before the oldest are
+    /// dropped.
+    #[arg(
+        long = "replication-queue",
+        default_value_t = 65536,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub replication_queue: u32,
+
+    /// Most memory to use for items, in megabytes. Once reached, the least
+    /// recently used items are evicted to make room. Unlimited unless set.
+    #[arg(
+        short = 'm',
+        long = "memory-limit",
+        value_name = "MEGABYTES",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub memory_limit: Option<u64>,
+
+    /// Most items to hold. Once reached, items are evicted to make room like
+    /// with `--memory-limit`. Unlimited unless set.
+    #[arg(
+        long = "max-items",
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_items: Option<u64>,
+
+    /// Most memory to use for the items whose keys start with a prefix, as
+    /// `PREFIX=MEGABYTES`. Repeatable; each item counts against the longest
+    /// prefix it matches. Once a prefix reaches its quota, only its own
+    /// items are evicted to make room, or its stores refused under
+    /// `--eviction-policy none`.
+    #[arg(long = "quota", value_name = "PREFIX=MEGABYTES")]
+    pub quota: Vec<QuotaSpec>,
+
+    /// Largest value to accept, in megabytes, past the 1 MiB item size
+    /// limit. Such values are kept in 1 MiB chunks, all counted against
+    /// `--memory-limit`, and put back together when read. Larger values are
+    /// refused, and so are values past 1 MiB unless set.
+    #[arg(
+        long = "large-item-limit",
+        value_name = "MEGABYTES",
+        value_parser = clap::value_parser!(u64).range(2..)
+    )]
+    pub large_item_limit: Option<u64>,
+
+    /// Which items to evict once `--memory-limit` or `--max-items` is reached
+    #[arg(long = "eviction-policy", value_enum, default_value_t = EvictionPolicy::Lru)]
+    pub eviction_policy: EvictionPolicy,
+
+    /// How expired items nobody reads are found and removed. Without a
+    /// sweep they stay until evicted or come across.
+    #[arg(long = "expiry-sweep", value_enum, default_value_t = ExpirySweep::None)]
+    pub expiry_sweep: ExpirySweep,
+
+    /// Seconds to keep expired items past their expiration, served stale
+    /// by `mg` while one client is told to store a new value. Plain `get`
+    /// misses them all the same.
+    #[arg(long = "stale-grace", value_name = "SECONDS")]
+    pub stale_grace: Option<u64>,
+
+    /// Expire items stored with a time to live up to this much earlier,
+    /// picked at random for each store, so that items stored together do
+    /// not all expire together: either seconds (`30`) or a percentage of
+    /// the time to live (`10%`). Items are never made to expire right away.
+    #[arg(long = "ttl-jitter", value_name = "SECONDS|PERCENT%")]
+    pub ttl_jitter: Option<Jitter>,
+
+    /// Move expirations later by up to `--ttl-jitter` too, past what
+    /// clients asked for
+    #[arg(long = "ttl-jitter-both-ways", requires = "ttl_jitter")]
+    pub ttl_jitter_both_ways: bool,
+
+    /// Every this many seconds, look for keys whose item is missing and
+    /// items no key leads to, and remove them, see `stats integrity`. Off
+    /// unless set.
+    #[arg(
+        long = "integrity-audit",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub integrity_audit: Option<u64>,
+
+    /// How the ids of items are split between the time they were made at
+    /// and a count of those made in that time, as `UNIT:BITS`: the count
+    /// takes the low BITS, up to 32 for `seconds` or 22 for `milliseconds`.
+    /// Past that many ids in a second or millisecond, ids are borrowed from
+    /// the next, counted in the `id_counter_exhausted` stat.
+    #[arg(long = "id-layout", value_name = "UNIT:BITS", default_value_t = Layout::SECONDS)]
+    pub id_layout: Layout,
+
+    /// What ends the prefix of a key, for the counts per prefix of `stats
+    /// detail`. Read by `stats detail on`.
+    #[arg(long = "detail-delimiter", default_value_t = ':', value_parser = delimiter)]
+    pub detail_delimiter: char,
+
+    /// Most prefixes `stats detail` tells apart; keys of any more are
+    /// counted together. Read by `stats detail on`.
+    #[arg(
+        long = "detail-max-prefixes",
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub detail_max_prefixes: u32,
+
+    /// Directory to move values to rather than evict their items once
+    /// `--memory-limit` is reached. Only the keys stay in memory; a value is
+    /// read back, and moved to memory again, when its item is read. Not
+    /// kept across restarts.
+    #[arg(long = "overflow-dir", value_name = "DIR", requires = "memory_limit")]
+    pub overflow_dir: Option<PathBuf>,
+
+    /// Most disk space to use in `--overflow-dir`, in megabytes. Once it is
+    /// used up, the items whose values were moved there first are lost.
+    #[arg(
+        long = "overflow-limit",
+        value_name = "MEGABYTES",
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub overflow_limit: u64,
+
+    /// UDP port to listen on. UDP is disabled unless set.
+    #[arg(short = 'U', long = "udp-port")]
+    pub udp_port: Option<u16>,
+
+    /// Port for health checks. Every connection to it gets a one line status
+    /// and is closed. Disabled unless set.
+    #[arg(long = "health-port")]
+    pub health_port: Option<u16>,
+
+    /// Largest UDP response datagram to send, frame header included.
+    /// Responses are split into up to four datagrams; larger ones are
+    /// answered with `SERVER_ERROR` instead.
+    #[arg(
+        long = "udp-max-datagram",
+        default_value_t = 1400,
+        value_parser = clap::value_parser!(u16).range(16..=65507)
+    )]
+    pub udp_max_datagram: u16,
+
+    /// PEM encoded certificate chain. Enables TLS together with `--tls-key`.
+    #[arg(long = "tls-cert", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM encoded private key for `--tls-cert`
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM encoded CA bundle. When set, clients must present a certificate
+    /// signed by one of these CAs.
+    #[arg(long = "tls-client-ca", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+/// Log formats, see `--log-format`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Tools built into the binary.
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Subcommands {
+    /// Benchmark a running server
+    Bench(bench::Args),
+}
+
+impl Settings {
+    /// Read the settings from the command line and, when `--config` is given,
+    /// the config file.
+    ///
+    /// Exits the process on invalid arguments or `--help`, like
+    /// `Settings::parse`.
+    pub fn load() -> Result<Settings> {
+        let settings = Settings::parse();
+        match &settings.config {
+            Some(path) => Settings::with_config(path, std::env::args_os()),
+            None => Ok(settings),
+        }
+    }
+
+    /// Reset the settings that cannot change without a restart (sockets and
+    /// how they are served) to their values in `current`. Returns the names
+    /// of those that differed.
+    pub(crate) fn keep_restart_only(&mut self, current: &Settings) -> Vec<&'static str> {
+        let mut kept = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != current.$field {
+                        kept.push(stringify!($field));
+                        self.$field = current.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(
+            log_format,
+            daemon,
+            pid_file,
+            user,
+            load_handoff,
+            data_dir,
+            discard_bad_snapshot,
+            append_log,
+            listen,
+            port,
+            backlog,
+            reuseport,
+            core_pinned,
+            proxy_protocol,
+            replica_of_mine,
+            replication_queue,
+            quota,
+            large_item_limit,
+            eviction_policy,
+            expiry_sweep,
+            stale_grace,
+            ttl_jitter,
+            ttl_jitter_both_ways,
+            id_layout,
+            overflow_dir,
+            overflow_limit,
+            udp_port,
+            udp_max_datagram,
+            health_port
+        );
+        #[cfg(feature = "otel")]
+        keep!(otel_endpoint, otel_sample_rate);
+        kept
+    }
+
+    /// Returns a builder of the cache these settings describe. What is not
+    /// a setting, such as the overflow tier once opened, is left to add.
+    pub(crate) fn cache_builder(&self) -> CacheBuilder {
+        Cache::builder()
+            .memory_limit_bytes(self.memory_limit_bytes())
+            .max_items(self.max_items.map(|max| max as usize))
+            .quotas(Quotas::new(&self.quota))
+            .large_item_limit(self.large_item_limit.map(|megabytes| megabytes as usize * 1024 * 1024))
+            .eviction_policy(self.eviction_policy)
+            .expiry_sweep(self.expiry_sweep)
+            .stale_grace(self.stale_grace)
+            .ttl_jitter(self.ttl_jitter)
+            .ttl_jitter_both_ways(self.ttl_jitter_both_ways)
+            .id_layout(self.id_layout)
+    }
+
+    /// Returns `--memory-limit` in bytes.
+    pub(crate) fn memory_limit_bytes(&self) -> Option<usize> {
+        self.memory_limit.map(|megabytes| megabytes as usize * 1024 * 1024)
+    }
+
+    /// Returns `--overflow-limit` in bytes.
+    pub(crate) fn overflow_limit_bytes(&self) -> u64 {
+        self.overflow_limit * 1024 * 1024
+    }
+
+    /// Returns the settings as `(name, value)` pairs, reported by
+    /// `stats settings`. Limits that are not set are reported as 0.
+    pub(crate) fn snapshot(&self) -> Vec<(String, String)> {
+        let list = |cidrs: &[Cidr]| match cidrs {
+            [] => "none".to_string(),
+            cidrs => cidrs
+                .iter()
+                .map(|cidr| cidr.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        let yes_no = |enabled: bool| if enabled { "yes" } else { "no" }.to_string();
+
+        #[allow(unused_mut)]
+        let mut snapshot = vec![
+            ("verbosity".to_string(), self.verbosity.to_string()),
+            ("interface".to_string(), self.listen.clone()),
+            ("tcpport".to_string(), self.port.to_string()),
+            ("udpport".to_string(), self.udp_port.unwrap_or(0).to_string()),
+            ("udp_max_datagram".to_string(), self.udp_max_datagram.to_string()),
+            ("health_port".to_string(), self.health_port.unwrap_or(0).to_string()),
+            ("tcp_backlog".to_string(), self.backlog.to_string()),
+            ("reuseport".to_string(), self.reuseport.to_string()),
+            ("core_pinned".to_string(), yes_no(self.core_pinned)),
+            ("proxy_protocol".to_string(), yes_no(self.proxy_protocol)),
+            ("tls".to_string(), yes_no(self.tls_cert.is_some())),
+            ("tls_client_auth".to_string(), yes_no(self.tls_client_ca.is_some())),
+            (
+                "max_connections_per_ip".to_string(),
+                self.max_connections_per_ip.unwrap_or(0).to_string(),
+            ),
+            (
+                "connection_rate_per_ip".to_string(),
+                self.connection_rate_per_ip.unwrap_or(0).to_string(),
+            ),
+            (
+                "max_connection_lifetime".to_string(),
+                self.max_connection_lifetime.unwrap_or(0).to_string(),
+            ),
+            ("handshake_timeout".to_string(), self.handshake_timeout.to_string()),
+            (
+                "max_connection_requests".to_string(),
+                self.max_connection_requests.unwrap_or(0).to_string(),
+            ),
+            ("allow".to_string(), list(&self.allow)),
+            ("deny".to_string(), list(&self.deny)),
+            ("shutdown_enabled".to_string(), yes_no(self.enable_shutdown)),
+            ("read_only".to_string(), yes_no(self.read_only)),
+            ("replication_source".to_string(), list(&self.replication_source)),
+            (
+                "replica_of_mine".to_string(),
+                self.replica_of_mine.clone().unwrap_or_else(|| "none".to_string()),
+            ),
+            ("replication_queue".to_string(), self.replication_queue.to_string()),
+            ("maxbytes".to_string(), self.memory_limit_bytes().unwrap_or(0).to_string()),
+            ("max_items".to_string(), self.max_items.unwrap_or(0).to_string()),
+            (
+                "large_item_limit".to_string(),
+                self.large_item_limit.map_or(0, |megabytes| megabytes * 1024 * 1024).to_string(),
+            ),
+            (
+                "quotas".to_string(),
+                match &self.quota[..] {
+                    [] => "none".to_string(),
+                    quotas => quotas.iter().map(|quota| quota.to_string()).collect::<Vec<_>>().join(","),
+                },
+            ),
+            (
+                "data_dir".to_string(),
+                self.data_dir
+                    .as_ref()
+                    .map_or_else(|| "none".to_string(), |dir| dir.display().to_string()),
+            ),
+            (
+                "snapshot_interval".to_string(),
+                self.snapshot_interval.unwrap_or(0).to_string(),
+            ),
+            (
+                "snapshot_after_changes".to_string(),
+                self.snapshot_after_changes.unwrap_or(0).to_string(),
+            ),
+            ("snapshots_kept".to_string(), self.snapshots_kept.to_string()),
+            ("append_log".to_string(), yes_no(self.append_log)),
+            ("log_rewrite_ratio".to_string(), self.log_rewrite_ratio.to_string()),
+            (
+                "dump_dir".to_string(),
+                self.dump_dir
+                    .as_ref()
+                    .map_or_else(|| "none".to_string(), |dir| dir.display().to_string()),
+            ),
+            (
+                "eviction_policy".to_string(),
+                self.eviction_policy.to_possible_value().unwrap().get_name().to_string(),
+            ),
+            (
+                "expiry_sweep".to_string(),
+                self.expiry_sweep.to_possible_value().unwrap().get_name().to_string(),
+            ),
+            ("stale_grace".to_string(), self.stale_grace.unwrap_or(0).to_string()),
+            (
+                "ttl_jitter".to_string(),
+                self.ttl_jitter.map_or_else(|| "none".to_string(), |jitter| jitter.to_string()),
+            ),
+            ("ttl_jitter_both_ways".to_string(), yes_no(self.ttl_jitter_both_ways)),
+            ("integrity_audit".to_string(), self.integrity_audit.unwrap_or(0).to_string()),
+            ("id_layout".to_string(), self.id_layout.to_string()),
+            ("detail_delimiter".to_string(), self.detail_delimiter.to_string()),
+            ("detail_max_prefixes".to_string(), self.detail_max_prefixes.to_string()),
+            (
+                "overflow_dir".to_string(),
+                self.overflow_dir
+                    .as_ref()
+                    .map_or_else(|| "none".to_string(), |dir| dir.display().to_string()),
+            ),
+            ("overflow_limit".to_string(), self.overflow_limit_bytes().to_string()),
+        ];
+        #[cfg(feature = "otel")]
+        snapshot.extend([
+            (
+                "otel_endpoint".to_string(),
+                self.otel_endpoint.clone().unwrap_or_else(|| "none".to_string()),
+            ),
+            ("otel_sample_rate".to_string(), self.otel_sample_rate.to_string()),
+        ]);
+        snapshot
+    }
+
+    /// Whether a client at `ip` may change the cache, see `--read-only`.
+    pub(crate) fn writes_allowed(&self, ip: IpAddr) -> bool {
+        !self.read_only || self.replication_source.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Parse `args` with the options in the config file at `path` inserted
+    /// before them, so later command line options override the file.
+    pub(crate) fn with_config(path: &Path, args: impl IntoIterator<Item = OsString>) -> Result<Settings> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let options = config_args(&contents)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+
+        let mut args = args.into_iter();
+        let merged: Vec<OsString> = args
+            .next()
+            .into_iter()
+            .chain(options.into_iter().map(OsString::from))
+            .chain(args)
+            .collect();
+
+        Settings::try_parse_from(merged)
+            .with_context(|| format!("invalid config file {}", path.display()))
+    }
+}
+
+/// Parse a sampling rate, see `--otel-sample-rate`.
+#[cfg(feature = "otel")]
+fn sample_rate(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("`{}` is not a number from 0 to 1", arg)),
+    }
+}
+
+/// Parse a key prefix delimiter, see `--detail-delimiter`.
+fn delimiter(arg: &str) -> Result<char, String> {
+    let mut chars = arg.chars();
+    match (chars.next(), chars.next()) {
+        (Some(delimiter), None) if delimiter.is_ascii_graphic() => Ok(delimiter),
+        _ => Err(format!("`{}` is not a single printable ASCII character", arg)),
+    }
+}
+
+/// Turn config file lines into command line arguments.
+///
+/// Empty lines and lines starting with `#` are skipped. `name = value` becomes
+/// `--name value`; for flags, `name = true` or a bare `name` becomes `--name`
+/// and `name = false` is dropped.
+fn config_args(contents: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => (line, "true"),
+        };
+        if name.is_empty() || name.starts_with('-') || name == "config" {
+            bail!("line {}: invalid option `{}`", n + 1, name);
+        }
+
+        match value {
+            "true" => args.push(format!("--{}", name)),
+            "false" => {}
+            value => {
+                args.push(format!("--{}", name));
+                args.push(value.to_string());
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_args() {
+        let contents = "
+            # comment
+            port = 11211
+            proxy-protocol
+            reuseport=4
+            allow = 10.0.0.0/8
+            allow = 192.168.0.0/16
+
+            udp-port = false
+        ";
+        assert_eq!(
+            config_args(contents).unwrap(),
+            [
+                "--port",
+                "11211",
+                "--proxy-protocol",
+                "--reuseport",
+                "4",
+                "--allow",
+                "10.0.0.0/8",
+                "--allow",
+                "192.168.0.0/16",
+            ]
+        );
+
+        assert!(config_args("--port = 1").is_err());
+        assert!(config_args("config = other.conf").is_err());
+    }
+
+    #[test]
+    fn test_command_line_overrides_config() {
+        let path = std::env::temp_dir().join(format!("sidica-test-{}.conf", std::process::id()));
+        fs::write(&path, "port = 11211\nreuseport = 2\ndeny = 10.0.0.1\n").unwrap();
+
+        let args = ["sidica", "-c", path.to_str().unwrap(), "-p", "9000", "--deny", "::1"];
+        let settings = Settings::with_config(&path, args.map(OsString::from)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(settings.port, 9000);
+        assert_eq!(settings.reuseport, 2);
+        assert_eq!(settings.deny, ["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()]);
+    }
+}
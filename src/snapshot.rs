@@ -0,0 +1,516 @@
+//! Snapshots: the cache written to `--data-dir` on shutdown and loaded back
+//! on startup, so a restart does not start cold.
+//!
+//! A snapshot is a fixed header followed by the items and an end marker:
+//!
+//! ```text
+//! magic     8 bytes  "SIDICASN"
+//! version   u32      FORMAT_VERSION
+//! items     u64      how many items follow
+//! checksum  u32      CRC-32 of everything after the header
+//! item...            see `write_item`
+//! end       u32      END
+//! ```
+//!
+//! Numbers are big endian. The file is written under a temporary name and
+//! renamed into place once complete, so a crash while saving leaves the
+//! previous snapshot alone. With `--snapshots-kept` above 1 the previous ones
+//! are kept as `snapshot.1`, `snapshot.2` and so on, the oldest last.
+//!
+//! Besides on shutdown, snapshots can be saved every `--snapshot-interval`
+//! or `--snapshot-after-changes`, see `save_periodically`.
+
+use crate::cache::{Cache, Item};
+use crate::checksum::Checksummed;
+use crate::settings::Settings;
+use crate::stats::ServerStats;
+
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tracing::{error, info};
+
+/// Name of the snapshot in `--data-dir`.
+pub(crate) const FILE: &str = "snapshot";
+
+const MAGIC: &[u8; 8] = b"SIDICASN";
+
+/// Bumped whenever the layout changes. Snapshots of other versions are
+/// refused rather than misread.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 24;
+
+/// Key length marking the end of the items. A file without it was cut short.
+pub(crate) const END: u32 = u32::MAX;
+
+/// How often `save_periodically` checks whether a snapshot is due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Items written between checks for a save to give up.
+const ABORT_CHECK_EVERY: usize = 1024;
+
+/// Items loaded at a time, see `Cache::restore_batch`.
+const RESTORE_BATCH: usize = 1024;
+
+/// A snapshot saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Saved {
+    pub(crate) items: usize,
+    /// Size of the file
+    pub(crate) bytes: u64,
+}
+
+/// Write `item`: its key, flags, CAS, expiration and value.
+///
+/// ```text
+/// key length u32, key, flags u32, cas u64,
+/// expiration: 0 and 8 zero bytes for never, or 1 and a u64 unix timestamp,
+/// value length u32, value
+/// ```
+pub(crate) fn write_item(out: &mut impl Write, item: &Item) -> io::Result<()> {
+    out.write_all(&(item.key.len() as u32).to_be_bytes())?;
+    out.write_all(&item.key)?;
+    out.write_all(&item.flags.to_be_bytes())?;
+    out.write_all(&item.cas.to_be_bytes())?;
+    match item.expiration {
+        Some(expiration) => {
+            out.write_all(&[1])?;
+            out.write_all(&expiration.to_be_bytes())?;
+        }
+        None => out.write_all(&[0; 9])?,
+    }
+    out.write_all(&(item.data.len() as u32).to_be_bytes())?;
+    out.write_all(&item.data)
+}
+
+/// Read the next item written by `write_item`, or `None` at the end marker.
+pub(crate) fn read_item(reader: &mut impl Read) -> io::Result<Option<Item>> {
+    fn bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    fn vec(reader: &mut impl Read, len: u32) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        reader.take(len.into()).read_to_end(&mut buf)?;
+        if buf.len() != len as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+
+    let key_len = u32::from_be_bytes(bytes(reader)?);
+    if key_len == END {
+        return Ok(None);
+    }
+    let key = Bytes::from(vec(reader, key_len)?);
+    let flags = u32::from_be_bytes(bytes(reader)?);
+    let cas = u64::from_be_bytes(bytes(reader)?);
+    let [has_expiration, expiration @ ..] = bytes::<9>(reader)?;
+    let data_len = u32::from_be_bytes(bytes(reader)?);
+    let data = Bytes::from(vec(reader, data_len)?);
+
+    Ok(Some(Item {
+        key,
+        flags,
+        cas,
+        expiration: (has_expiration != 0).then(|| u64::from_be_bytes(expiration)),
+        data,
+    }))
+}
+
+/// Write every item in `cache` to the snapshot in `dir`, replacing the one
+/// there, or keeping it as one of the `kept` last.
+pub(crate) fn save(dir: &Path, cache: &Cache, kept: usize) -> Result<Saved> {
+    let saved = save_unless(dir, cache, kept, &AtomicBool::new(false))?;
+    Ok(saved.expect("never aborted"))
+}
+
+/// `save`, giving up once `abort` is set. Returns `None` then, leaving the
+/// snapshots as they were.
+fn save_unless(dir: &Path, cache: &Cache, kept: usize, abort: &AtomicBool) -> Result<Option<Saved>> {
+    let path = dir.join(FILE);
+    let temporary = dir.join(format!("{}.tmp", FILE));
+    let saved = write(&temporary, MAGIC, cache, abort)
+        .and_then(|items| {
+            let Some(items) = items else {
+                return Ok(None);
+            };
+            let bytes = fs::metadata(&temporary)?.len();
+            for n in (1..kept).rev() {
+                match fs::rename(name(dir, n - 1), name(dir, n)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+            fs::rename(&temporary, &path)?;
+            sync_dir(dir)?;
+            Ok(Some(Saved { items, bytes }))
+        })
+        .with_context(|| format!("saving snapshot {}", path.display()));
+    if !matches!(saved, Ok(Some(_))) {
+        let _ = fs::remove_file(&temporary);
+    }
+    saved
+}
+
+/// The `n`th last snapshot in `dir`, from 0.
+fn name(dir: &Path, n: usize) -> std::path::PathBuf {
+    match n {
+        0 => dir.join(FILE),
+        n => dir.join(format!("{}.{}", FILE, n)),
+    }
+}
+
+/// Write the snapshot, or another file of the same layout starting with
+/// `magic`, returning how many items it has or `None` if `abort` was set
+/// first.
+pub(crate) fn write(path: &Path, magic: &[u8; 8], cache: &Cache, abort: &AtomicBool) -> Result<Option<usize>> {
+    let mut file = File::create(path)?;
+    // Filled in once the items are written
+    file.write_all(&[0; HEADER_LEN])?;
+    let mut out = Checksummed::new(BufWriter::new(file));
+    let mut count = 0;
+    for item in cache.snapshot_iter() {
+        if count % ABORT_CHECK_EVERY == 0 && abort.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        write_item(&mut out, &item)?;
+        count += 1;
+    }
+    out.write_all(&END.to_be_bytes())?;
+    let crc = out.crc();
+
+    let mut file = out.into_inner().into_inner().map_err(|err| err.into_error())?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(magic);
+    header.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header.extend_from_slice(&(count as u64).to_be_bytes());
+    header.extend_from_slice(&crc.to_be_bytes());
+    file.rewind()?;
+    file.write_all(&header)?;
+    file.sync_all()?;
+    Ok(Some(count))
+}
+
+/// Make a rename in `dir` durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Load the snapshot in `dir` into `cache`, skipping items that have expired
+/// since. Returns how many items were loaded, `None` if there is no
+/// snapshot.
+///
+/// Items are stored as they are read, `RESTORE_BATCH` at a time, so on
+/// error `cache` holds those read up to the problem: a snapshot of another
+/// format version, one cut short, or one whose checksum does not match.
+pub(crate) fn load(dir: &Path, cache: &Cache) -> Result<Option<usize>> {
+    let path = dir.join(FILE);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("opening snapshot {}", path.display())),
+    };
+    let now = cache.now();
+    let mut loaded = 0;
+    let mut batch = Vec::with_capacity(RESTORE_BATCH);
+    let read = read(file, MAGIC, "a snapshot", |item| {
+        if item.expiration.is_none_or(|expiration| expiration > now) {
+            batch.push(item);
+            loaded += 1;
+        }
+        if batch.len() == RESTORE_BATCH {
+            cache.restore_batch(mem::replace(&mut batch, Vec::with_capacity(RESTORE_BATCH)));
+        }
+        Ok(())
+    });
+    cache.restore_batch(batch);
+    read.map(|_| Some(loaded))
+        .with_context(|| format!("loading snapshot {}", path.display()))
+}
+
+/// Read a file written by `write` with `magic`, calling `each` with every
+/// item in turn. Returns how many there were.
+///
+/// Items are passed on as they are read, so a damaged file is only found
+/// out once some of them have been; then or once `each` fails, the rest are
+/// not read. `what` names the kind of file in errors.
+pub(crate) fn read(
+    file: File,
+    magic: &[u8; 8],
+    what: &str,
+    mut each: impl FnMut(Item) -> Result<()>,
+) -> Result<usize> {
+    let mut reader = BufReader::new(file);
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header).context("no complete header")?;
+    let (start, rest) = header.split_at(magic.len());
+    if start != magic {
+        bail!("not {}", what);
+    }
+    let version = u32::from_be_bytes(rest[..4].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        bail!("format version {} is not supported, only {}", version, FORMAT_VERSION);
+    }
+    let count = u64::from_be_bytes(rest[4..12].try_into().unwrap());
+    let crc = u32::from_be_bytes(rest[12..].try_into().unwrap());
+
+    let mut reader = Checksummed::new(reader);
+    let mut read = 0;
+    while let Some(item) = read_item(&mut reader).context("cut short")? {
+        read += 1;
+        each(item)?;
+    }
+    if reader.crc() != crc {
+        bail!("checksum mismatch, the file is corrupt");
+    }
+    if read != count {
+        bail!("{} items instead of {}", read, count);
+    }
+    Ok(read as usize)
+}
+
+/// Takes turns saving the snapshots of a cache: periodic ones, see
+/// `save_periodically`, and the one on shutdown, which gives up a periodic
+/// one under way rather than wait for it.
+#[derive(Debug, Default)]
+pub(crate) struct Saving {
+    turn: Mutex<()>,
+    /// Set on shutdown, no periodic snapshot is saved after
+    shut_down: AtomicBool,
+}
+
+impl Saving {
+    /// Stop periodic snapshots, giving up one under way, and wait for it to
+    /// be. Blocks.
+    pub(crate) fn shut_down(&self) {
+        self.shut_down.store(true, Ordering::Relaxed);
+        drop(self.turn.lock());
+    }
+
+    /// Save a periodic snapshot, unless one is under way or the server is
+    /// shutting down. Returns `None` if it was not saved. Blocks.
+    fn save(&self, dir: &Path, cache: &Cache, kept: usize) -> Result<Option<Saved>> {
+        let Some(_turn) = self.turn.try_lock() else {
+            return Ok(None);
+        };
+        if self.shut_down.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        save_unless(dir, cache, kept, &self.shut_down)
+    }
+}
+
+/// Save a snapshot of `cache` every `--snapshot-interval`, and after every
+/// `--snapshot-after-changes` changes to it, whichever comes first; checked
+/// every `CHECK_INTERVAL`, for as long as the server runs. Their time,
+/// duration and size are reported in `stats`.
+///
+/// The snapshot has the cache as it was when the save started, see
+/// `Cache::snapshot_iter`; changes go on meanwhile. An append log is left as
+/// it is: replayed on top of the newer snapshot, it still ends in the same
+/// cache.
+pub(crate) async fn save_periodically(
+    cache: Cache,
+    settings: Arc<ArcSwap<Settings>>,
+    stats: Arc<ServerStats>,
+    saving: Arc<Saving>,
+) {
+    let mut interval = time::interval(CHECK_INTERVAL);
+    let (mut last, mut changes) = (Instant::now(), cache.changes());
+    loop {
+        interval.tick().await;
+        let settings = settings.load_full();
+        let Some(dir) = settings.data_dir.clone() else {
+            return;
+        };
+        let every = settings.snapshot_interval.map(Duration::from_secs);
+        let due = every.is_some_and(|every| last.elapsed() >= every)
+            || settings
+                .snapshot_after_changes
+                .is_some_and(|after| cache.changes() - changes >= after);
+        if !due {
+            continue;
+        }
+
+        let started = Instant::now();
+        let (cache, saving) = (cache.clone(), saving.clone());
+        let now = cache.changes();
+        let saved = tokio::task::spawn_blocking(move || {
+            saving.save(&dir, &cache, settings.snapshots_kept as usize)
+        });
+        match saved.await {
+            Ok(Ok(Some(saved))) => {
+                let took = started.elapsed();
+                info!(items = saved.items, bytes = saved.bytes, ?took, "saved a periodic snapshot");
+                let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                stats.set_last_snapshot(at.as_secs(), took.as_millis() as u64, saved.bytes);
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(err)) => error!("failed to save a periodic snapshot: {:#}", err),
+            Err(err) => error!("failed to save a periodic snapshot: {}", err),
+        }
+        // Tried either way, not to retry every check
+        (last, changes) = (Instant::now(), now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sidica-snapshot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn save_two(dir: &Path) {
+        let cache = Cache::new();
+        cache.set(Bytes::from("foo"), 5, None, Bytes::from("bar")).await;
+        cache.set(Bytes::from("empty"), 0, None, Bytes::new()).await;
+        assert_eq!(save(dir, &cache, 1).unwrap().items, 2);
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let dir = dir("round-trip");
+        let cache = Cache::new();
+        let expiration = Some(cache.now() + 60);
+        cache.set(Bytes::from("foo"), 5, expiration, Bytes::from("bar")).await;
+        cache.set(Bytes::from("empty"), 0, None, Bytes::new()).await;
+        // Expired by the time it is loaded
+        cache.set(Bytes::from("gone"), 0, Some(cache.now() + 1), Bytes::from("x")).await;
+        let foo = cache.get(b"foo").await.unwrap();
+        assert_eq!(save(&dir, &cache, 1).unwrap().items, 3);
+        assert!(!dir.join("snapshot.tmp").exists());
+
+        let clock = Clock::default();
+        clock.advance(1);
+        let loaded = Cache::builder().clock(clock).build();
+        assert_eq!(load(&dir, &loaded).unwrap(), Some(2));
+        let item = loaded.get(b"foo").await.unwrap();
+        assert_eq!(
+            (item.flags, item.cas, item.expiration, &item.data[..]),
+            (5, foo.cas, foo.expiration, &b"bar"[..])
+        );
+        assert_eq!(loaded.get(b"empty").await.unwrap().data.len(), 0);
+        assert!(loaded.get(b"gone").await.is_none());
+
+        // Saving again replaces the snapshot
+        assert_eq!(save(&dir, &Cache::new(), 1).unwrap().items, 0);
+        assert_eq!(load(&dir, &Cache::new()).unwrap(), Some(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loads_batches() {
+        let dir = dir("batches");
+        let cache = Cache::new();
+        let items = 2 * RESTORE_BATCH + 10;
+        for n in 0..items {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, Bytes::from(n.to_string())).await;
+        }
+        save(&dir, &cache, 1).unwrap();
+
+        let loaded = Cache::new();
+        assert_eq!(load(&dir, &loaded).unwrap(), Some(items));
+        assert_eq!(loaded.len(), items);
+        for n in 0..items {
+            let key = format!("key{}", n);
+            let item = loaded.get(key.as_bytes()).await.unwrap();
+            assert_eq!(item.data, n.to_string());
+            assert_eq!(item.cas, cache.get(key.as_bytes()).await.unwrap().cas);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keeps_the_last_snapshots() {
+        let dir = dir("kept");
+        let cache = Cache::new();
+        for n in 0..4 {
+            cache.set(Bytes::from(format!("key{}", n)), 0, None, Bytes::new()).await;
+            let saved = save(&dir, &cache, 3).unwrap();
+            assert_eq!(saved, Saved { items: n + 1, bytes: fs::metadata(dir.join(FILE)).unwrap().len() });
+        }
+        // Newest first
+        for (n, items) in [(0, 4), (1, 3), (2, 2)] {
+            fs::rename(name(&dir, n), dir.join(FILE)).unwrap();
+            assert_eq!(load(&dir, &Cache::new()).unwrap(), Some(items));
+        }
+        assert!(!name(&dir, 3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shut_down_gives_up_periodic_snapshots() {
+        let dir = dir("shut-down");
+        save_two(&dir).await;
+        let saving = Saving::default();
+        saving.shut_down();
+        let cache = Cache::new();
+        assert_eq!(saving.save(&dir, &cache, 1).unwrap(), None);
+        assert!(!dir.join("snapshot.tmp").exists());
+        // The snapshot before is left
+        assert_eq!(load(&dir, &cache).unwrap(), Some(2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_snapshot() {
+        let dir = dir("none");
+        assert_eq!(load(&dir, &Cache::new()).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refuses_damaged_snapshots() {
+        let dir = dir("damaged");
+        let path = dir.join(FILE);
+        let error = |dir: &Path| format!("{:#}", load(dir, &Cache::new()).unwrap_err());
+
+        save_two(&dir).await;
+        let mut bytes = fs::read(&path).unwrap();
+        // Items come in no particular order, so damage a value rather than
+        // whatever is last
+        let bar = bytes.windows(3).position(|window| window == b"bar").unwrap();
+        bytes[bar] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(error(&dir).contains("checksum mismatch"), "{}", error(&dir));
+
+        save_two(&dir).await;
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 6]).unwrap();
+        assert!(error(&dir).contains("cut short"), "{}", error(&dir));
+
+        save_two(&dir).await;
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[11] = 9;
+        fs::write(&path, &bytes).unwrap();
+        assert!(error(&dir).contains("format version 9 is not supported"), "{}", error(&dir));
+
+        fs::write(&path, b"some other file altogether\n").unwrap();
+        assert!(error(&dir).contains("not a snapshot"), "{}", error(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use crate::connection::Traffic;
+use crate::detail::Detail;
+use crate::latency::Latencies;
+use crate::limit::Rejected;
+use crate::registry::Registry;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Server wide counters, reported by the `stats` command.
+///
+/// Counters are only ever incremented with relaxed atomics; readers get a
+/// best-effort view. `curr_connections`, `replication_lag`, `draining` and
+/// the `snapshot_last_` ones are the exceptions, they are gauges that go
+/// down or are overwritten.
+#[derive(Debug, Default)]
+pub(crate) struct ServerStats {
+    /// Connections open, from accept to close, see `OpenConnection`
+    curr_connections: AtomicU64,
+    /// The most open at once
+    max_connections_open: AtomicU64,
+    /// Connections accepted, by all acceptors
+    total_connections: AtomicU64,
+    /// Connections closed without being served: refused by `--allow` and
+    /// `--deny`, the per-IP limits, or while draining
+    rejected_connections: AtomicU64,
+    /// Commands received over TCP
+    tcp_requests: AtomicU64,
+    /// Commands received over UDP
+    udp_requests: AtomicU64,
+    /// Bytes received from clients and sent to them, over TCP and UDP
+    /// alike, added up by `add_traffic`
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Connections accepted, per acceptor
+    accepted: Vec<AtomicU64>,
+    /// Connection handlers that panicked
+    handler_panics: AtomicU64,
+    /// Times accepting paused because the connection limit was reached.
+    /// Connections queue in the listen backlog meanwhile.
+    listen_disabled_num: AtomicU64,
+    /// Connections closed because of `--allow`/`--deny`
+    rejected_by_acl: AtomicU64,
+    /// Connections closed for exceeding `--max-connections-per-ip`
+    ip_connection_rejects: AtomicU64,
+    /// Connections closed for exceeding `--connection-rate-per-ip`
+    ip_rate_rejects: AtomicU64,
+    /// Mutations queued for the replica but not sent yet
+    replication_lag: AtomicU64,
+    /// Mutations never sent to the replica
+    replication_dropped: AtomicU64,
+    /// 1 while new connections are refused, see `health::drain`
+    draining: AtomicU64,
+    /// When the last periodic snapshot was saved, in seconds since the unix
+    /// epoch, 0 for none yet
+    snapshot_last_time: AtomicU64,
+    /// How long saving it took, in milliseconds
+    snapshot_last_duration_ms: AtomicU64,
+    /// Its size
+    snapshot_last_bytes: AtomicU64,
+    latency: Latencies,
+    detail: Detail,
+    registry: Arc<Registry>,
+    started: Started,
+}
+
+/// When the server started, see `ServerStats::uptime`.
+#[derive(Debug)]
+struct Started(Instant);
+
+impl Default for Started {
+    fn default() -> Started {
+        Started(Instant::now())
+    }
+}
+
+impl ServerStats {
+    /// Create the counters for a server running `acceptors` accept loops.
+    pub(crate) fn new(acceptors: usize) -> ServerStats {
+        ServerStats {
+            accepted: (0..acceptors).map(|_| AtomicU64::new(0)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// How long each command takes, see `Command::apply`.
+    pub(crate) fn latency(&self) -> &Latencies {
+        &self.latency
+    }
+
+    /// Counts per key prefix, see `stats detail`.
+    pub(crate) fn detail(&self) -> &Detail {
+        &self.detail
+    }
+
+    /// The open TCP connections, see `stats conns`.
+    pub(crate) fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    /// Seconds since the server started.
+    pub(crate) fn uptime(&self) -> u64 {
+        self.started.0.elapsed().as_secs()
+    }
+
+    /// Accept loops the server runs, see `new`.
+    pub(crate) fn acceptors(&self) -> usize {
+        self.accepted.len()
+    }
+
+    pub(crate) fn incr_accepted(&self, acceptor: usize) {
+        self.accepted[acceptor].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection just accepted as open until the returned guard is
+    /// dropped, however its handling ends.
+    pub(crate) fn open_connection(self: &Arc<Self>) -> OpenConnection {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        let open = self.curr_connections.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_connections_open.fetch_max(open, Ordering::Relaxed);
+        OpenConnection { stats: self.clone() }
+    }
+
+    /// Count a connection closed without being served, on top of the
+    /// counter of why.
+    pub(crate) fn incr_rejected_connections(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_tcp_requests(&self) {
+        self.tcp_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_udp_requests(&self) {
+        self.udp_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the bytes a connection read and wrote since last time, see
+    /// `Connection::take_traffic`.
+    pub(crate) fn add_traffic(&self, traffic: Traffic) {
+        if traffic.read > 0 {
+            self.bytes_read.fetch_add(traffic.read, Ordering::Relaxed);
+        }
+        if traffic.written > 0 {
+            self.bytes_written.fetch_add(traffic.written, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn incr_handler_panics(&self) {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_listen_disabled(&self) {
+        self.listen_disabled_num.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_rejected_by_acl(&self) {
+        self.rejected_by_acl.fetch_add(1, Ordering::Relaxed);
+        self.incr_rejected_connections();
+    }
+
+    pub(crate) fn incr_ip_rejects(&self, reason: Rejected) {
+        let counter = match reason {
+            Rejected::TooManyConnections => &self.ip_connection_rejects,
+            Rejected::RateExceeded => &self.ip_rate_rejects,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.incr_rejected_connections();
+    }
+
+    pub(crate) fn set_last_snapshot(&self, time: u64, duration_ms: u64, bytes: u64) {
+        self.snapshot_last_time.store(time, Ordering::Relaxed);
+        self.snapshot_last_duration_ms.store(duration_ms, Ordering::Relaxed);
+        self.snapshot_last_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_replication_lag(&self, queued: u64) {
+        self.replication_lag.store(queued, Ordering::Relaxed);
+    }
+
+    pub(crate) fn incr_replication_dropped(&self, dropped: u64) {
+        self.replication_dropped.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_draining(&self, draining: bool) {
+        self.draining.store(draining.into(), Ordering::Relaxed);
+    }
+
+    /// Returns every counter as a `(name, value)` pair in reporting order.
+    pub(crate) fn snapshot(&self) -> Vec<(String, u64)> {
+        let mut stats = vec![
+            (
+                "curr_connections".to_string(),
+                self.curr_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "total_connections".to_string(),
+                self.total_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "rejected_connections".to_string(),
+                self.rejected_connections.load(Ordering::Relaxed),
+            ),
+            // Memcached keeps the structures of closed connections for
+            // reuse, so they number the most connections open at once
+            (
+                "connection_structures".to_string(),
+                self.max_connections_open.load(Ordering::Relaxed),
+            ),
+            (
+                "tcp_requests".to_string(),
+                self.tcp_requests.load(Ordering::Relaxed),
+            ),
+            (
+                "udp_requests".to_string(),
+                self.udp_requests.load(Ordering::Relaxed),
+            ),
+            ("bytes_read".to_string(), self.bytes_read.load(Ordering::Relaxed)),
+            ("bytes_written".to_string(), self.bytes_written.load(Ordering::Relaxed)),
+            (
+                "handler_panics".to_string(),
+                self.handler_panics.load(Ordering::Relaxed),
+            ),
+            (
+                "listen_disabled_num".to_string(),
+                self.listen_disabled_num.load(Ordering::Relaxed),
+            ),
+            (
+                "rejected_by_acl".to_string(),
+                self.rejected_by_acl.load(Ordering::Relaxed),
+            ),
+            (
+                "ip_connection_rejects".to_string(),
+                self.ip_connection_rejects.load(Ordering::Relaxed),
+            ),
+            (
+                "ip_rate_rejects".to_string(),
+                self.ip_rate_rejects.load(Ordering::Relaxed),
+            ),
+            (
+                "replication_lag".to_string(),
+                self.replication_lag.load(Ordering::Relaxed),
+            ),
+            (
+                "replication_dropped".to_string(),
+                self.replication_dropped.load(Ordering::Relaxed),
+            ),
+            ("draining".to_string(), self.draining.load(Ordering::Relaxed)),
+            (
+                "snapshot_last_time".to_string(),
+                self.snapshot_last_time.load(Ordering::Relaxed),
+            ),
+            (
+                "snapshot_last_duration_ms".to_string(),
+                self.snapshot_last_duration_ms.load(Ordering::Relaxed),
+            ),
+            (
+                "snapshot_last_bytes".to_string(),
+                self.snapshot_last_bytes.load(Ordering::Relaxed),
+            ),
+        ];
+        for (id, accepted) in self.accepted.iter().enumerate() {
+            stats.push((
+                format!("acceptor_{}_accepts", id),
+                accepted.load(Ordering::Relaxed),
+            ));
+        }
+        stats
+    }
+}
+
+/// A connection counted in `curr_connections` until dropped, see
+/// `ServerStats::open_connection`.
+#[derive(Debug)]
+pub(crate) struct OpenConnection {
+    stats: Arc<ServerStats>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.stats.curr_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// CPU time the process has spent in user mode and in system mode, from
+/// `getrusage`. `None` if it failed.
+#[cfg(unix)]
+pub(crate) fn rusage() -> Option<(Duration, Duration)> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: `usage` is valid for writes of a `rusage`.
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } == -1 {
+        return None;
+    }
+    // SAFETY: filled in by `getrusage`, which succeeded.
+    let usage = unsafe { usage.assume_init() };
+    let time = |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    Some((time(usage.ru_utime), time(usage.ru_stime)))
+}
+
+/// Not known off unix.
+#[cfg(not(unix))]
+pub(crate) fn rusage() -> Option<(Duration, Duration)> {
+    None
+}
+
+/// Cache wide counters, and the bytes held, see `Cache::stats`.
+///
+/// Like `ServerStats`, only ever updated with relaxed atomics and without
+/// taking any lock; `bytes` and `evicted_time` are gauges, the rest count
+/// up.
+#[derive(Debug, Default)]
+pub(crate) struct CacheStats {
+    /// Bytes held by stored items, see `cache::footprint`. Expired items
+    /// count until they are removed.
+    pub(crate) bytes: AtomicUsize,
+    /// Items written, updates included
+    pub(crate) total_items: AtomicU64,
+    /// Items evicted to make room
+    pub(crate) evictions: AtomicU64,
+    /// Of those, items never read
+    pub(crate) evicted_unfetched: AtomicU64,
+    /// Seconds the item last evicted had gone unaccessed
+    pub(crate) evicted_time: AtomicU64,
+    /// Expired or flushed items removed as they were come across
+    pub(crate) expired: AtomicU64,
+    /// Expired or flushed items never read, removed or reclaimed
+    pub(crate) expired_unfetched: AtomicU64,
+    /// Map shards rebuilt smaller, and the slots that freed, see
+    /// `Cache::reclaim_memory`
+    pub(crate) map_shrinks: AtomicU64,
+    pub(crate) map_slots_freed: AtomicU64,
+    /// Reports of items leaving dropped for want of room, see
+    /// `CacheBuilder::removals`
+    pub(crate) removals_dropped: AtomicU64,
+    /// Expired or flushed items replaced by a store rather than removed
+    pub(crate) reclaimed: AtomicU64,
+    /// Stores refused for want of room
+    pub(crate) out_of_memory: AtomicU64,
+    /// Items removed by `flush_prefix`
+    pub(crate) prefix_flushed: AtomicU64,
+    /// Keys read that held an item, and that did not
+    pub(crate) get_hits: AtomicU64,
+    pub(crate) get_misses: AtomicU64,
+    /// Of the misses, keys whose item had expired or been flushed
+    pub(crate) get_expired: AtomicU64,
+    /// Of the hits, expired items served stale, see `Cache::get_stale`
+    pub(crate) get_stale: AtomicU64,
+    pub(crate) delete_hits: AtomicU64,
+    pub(crate) delete_misses: AtomicU64,
+    pub(crate) incr_hits: AtomicU64,
+    pub(crate) incr_misses: AtomicU64,
+    pub(crate) decr_hits: AtomicU64,
+    pub(crate) decr_misses: AtomicU64,
+    /// `cas` that stored, that found no item, and that found it changed
+    pub(crate) cas_hits: AtomicU64,
+    pub(crate) cas_misses: AtomicU64,
+    pub(crate) cas_badval: AtomicU64,
+    pub(crate) touch_hits: AtomicU64,
+    pub(crate) touch_misses: AtomicU64,
+    /// Keys asked for by `get` and `gets`, see `Cache::count_gets`
+    pub(crate) cmd_get: AtomicU64,
+    /// Stores tried by clients: `set`, `add`, `replace`, `append`, `prepend`
+    /// and `cas`, see `Cache::count_set`
+    pub(crate) cmd_set: AtomicU64,
+}
+
+impl CacheStats {
+    /// Count one more of `counter`.
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns `bytes` and every counter as `(name, value)` pairs in
+    /// reporting order.
+    pub(crate) fn snapshot(&self) -> Vec<(String, u64)> {
+        let counters = [
+            ("total_items", &self.total_items),
+            ("evictions", &self.evictions),
+            ("evicted_unfetched", &self.evicted_unfetched),
+            ("evicted_time", &self.evicted_time),
+            ("expired", &self.expired),
+            ("expired_unfetched", &self.expired_unfetched),
+            ("map_shrinks", &self.map_shrinks),
+            ("map_slots_freed", &self.map_slots_freed),
+            ("reclaimed", &self.reclaimed),
+            ("removals_dropped", &self.removals_dropped),
+            ("out_of_memory_errors", &self.out_of_memory),
+            ("prefix_flushed_items", &self.prefix_flushed),
+            ("get_hits", &self.get_hits),
+            ("get_misses", &self.get_misses),
+            ("get_expired", &self.get_expired),
+            ("get_stale", &self.get_stale),
+            ("delete_hits", &self.delete_hits),
+            ("delete_misses", &self.delete_misses),
+            ("incr_hits", &self.incr_hits),
+            ("incr_misses", &self.incr_misses),
+            ("decr_hits", &self.decr_hits),
+            ("decr_misses", &self.decr_misses),
+            ("cas_hits", &self.cas_hits),
+            ("cas_misses", &self.cas_misses),
+            ("cas_badval", &self.cas_badval),
+            ("touch_hits", &self.touch_hits),
+            ("touch_misses", &self.touch_misses),
+        ];
+        let mut stats = vec![("bytes".to_string(), self.bytes.load(Ordering::Relaxed) as u64)];
+        stats.extend(
+            counters
+                .into_iter()
+                .map(|(name, counter)| (name.to_string(), counter.load(Ordering::Relaxed))),
+        );
+        stats.push(("cmd_get".to_string(), self.cmd_get.load(Ordering::Relaxed)));
+        stats.push(("cmd_set".to_string(), self.cmd_set.load(Ordering::Relaxed)));
+        stats
+    }
+
+    /// Returns the counters memcached reports per slab class in `stats
+    /// items`, for `number` items. There are no slab classes, so all are
+    /// reported as class 1.
+    pub(crate) fn items(&self, number: u64) -> Vec<(String, u64)> {
+        let counters = [
+            ("evicted", &self.evictions),
+            ("evicted_unfetched", &self.evicted_unfetched),
+            ("evicted_time", &self.evicted_time),
+            ("expired_unfetched", &self.expired_unfetched),
+            ("reclaimed", &self.reclaimed),
+            ("outofmemory", &self.out_of_memory),
+        ];
+        let mut stats = vec![("items:1:number".to_string(), number)];
+        stats.extend(
+            counters
+                .into_iter()
+                .map(|(name, counter)| (format!("items:1:{}", name), counter.load(Ordering::Relaxed))),
+        );
+        stats
+    }
+}
+
+/// Index entries and items that have lost each other, see `Cache::verify`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Integrity {
+    /// Keys looked at
+    pub(crate) keys: u64,
+    /// Items looked at
+    pub(crate) items: u64,
+    /// Keys whose item is missing
+    pub(crate) dangling_keys: u64,
+    /// Items no key leads to
+    pub(crate) orphaned_items: u64,
+    /// Dangling keys and orphaned items removed
+    pub(crate) repaired: u64,
+}
+
+impl Integrity {
+    /// Returns the counts as `(name, value)` pairs in reporting order.
+    pub(crate) fn snapshot(&self) -> Vec<(String, u64)> {
+        vec![
+            ("integrity_keys".to_string(), self.keys),
+            ("integrity_items".to_string(), self.items),
+            ("integrity_dangling_keys".to_string(), self.dangling_keys),
+            ("integrity_orphaned_items".to_string(), self.orphaned_items),
+            ("integrity_repaired".to_string(), self.repaired),
+        ]
+    }
+}
+
+/// Upper bounds, in seconds, of the `TtlHistogram` buckets and their names.
+/// Items living longer than the last are in a bucket of their own.
+const TTL_BUCKETS: [(u64, &str); 4] = [
+    (60, "ttl_under_1m"),
+    (10 * 60, "ttl_under_10m"),
+    (60 * 60, "ttl_under_1h"),
+    (24 * 60 * 60, "ttl_under_1d"),
+];
+
+/// How long the items have left to live, see `Cache::ttl_histogram`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct TtlHistogram {
+    /// Items per bucket of `TTL_BUCKETS`, then those living longer
+    pub(crate) expiring: [u64; TTL_BUCKETS.len() + 1],
+    /// Items that never expire
+    pub(crate) never: u64,
+    /// Keys looked at, live or not
+    pub(crate) seen: u64,
+    /// Whether every key was looked at, rather than the work budget running out
+    pub(crate) complete: bool,
+}
+
+impl TtlHistogram {
+    /// Count an item with `ttl` seconds left, `None` if it never expires.
+    pub(crate) fn add(&mut self, ttl: Option<u64>) {
+        match ttl {
+            Some(ttl) => {
+                let bucket = TTL_BUCKETS.iter().take_while(|(bound, _)| ttl >= *bound).count();
+                self.expiring[bucket] += 1;
+            }
+            None => self.never += 1,
+        }
+    }
+
+    /// Returns the buckets, then the bookkeeping, as `(name, value)` pairs in
+    /// reporting order.
+    pub(crate) fn snapshot(&self) -> Vec<(String, u64)> {
+        let names = TTL_BUCKETS.iter().map(|(_, name)| *name).chain(["ttl_over_1d"]);
+        let mut stats: Vec<_> = names
+            .zip(self.expiring)
+            .map(|(name, items)| (name.to_string(), items))
+            .collect();
+        stats.push(("ttl_none".to_string(), self.never));
+        stats.push(("ttl_keys_seen".to_string(), self.seen));
+        stats.push(("ttl_complete".to_string(), u64::from(self.complete)));
+        stats
+    }
+}
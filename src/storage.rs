@@ -0,0 +1,394 @@
+//! What the item commands need of where items are kept, see `Storage`.
+
+use crate::cache::{Cache, Delta, Direction, ItemView, Outcome};
+use crate::clock;
+
+use bytes::Bytes;
+use std::future::Future;
+
+/// Where the item commands (`get`, `set`, `delete` and the like) keep
+/// items. The methods do what the `Cache` methods of the same name do.
+///
+/// `Cache` is the one the server uses. Commands take `&impl Storage`, so
+/// each is compiled for `Cache` and calls it directly, with no indirection
+/// on the way. Commands about the server or the cache as a whole, such as
+/// `stats` or `flush_all`, take the `Cache` itself.
+pub trait Storage: Send + Sync {
+    /// The current time, in seconds since the unix epoch.
+    fn now(&self) -> u64;
+
+    /// When an item stored under `key` with `exptime`, as sent by a client,
+    /// expires, see `clock::deadline`.
+    fn deadline(&self, _key: &[u8], exptime: i64) -> Option<u64> {
+        clock::deadline(exptime, self.now())
+    }
+
+    fn get(&self, key: &[u8]) -> impl Future<Output = Option<ItemView>> + Send;
+
+    fn get_multi(&self, keys: &[Bytes]) -> impl Future<Output = Vec<Option<ItemView>>> + Send;
+
+    fn set(
+        &self,
+        key: Bytes,
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+    ) -> impl Future<Output = Outcome> + Send;
+
+    fn add(
+        &self,
+        key: Bytes,
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+    ) -> impl Future<Output = Outcome> + Send;
+
+    fn replace(
+        &self,
+        key: &[u8],
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+    ) -> impl Future<Output = Outcome> + Send;
+
+    fn append(&self, key: &[u8], data: Bytes) -> impl Future<Output = Outcome> + Send;
+
+    fn prepend(&self, key: &[u8], data: Bytes) -> impl Future<Output = Outcome> + Send;
+
+    fn delete(&self, key: &[u8]) -> impl Future<Output = bool> + Send;
+
+    fn touch(&self, key: &[u8], expiration: Option<u64>) -> impl Future<Output = bool> + Send;
+
+    fn add_delta(&self, key: &[u8], delta: u64, direction: Direction) -> impl Future<Output = Delta> + Send;
+
+    fn check_and_set(
+        &self,
+        key: &[u8],
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+        cas: u64,
+    ) -> impl Future<Output = Outcome> + Send;
+}
+
+impl Storage for Cache {
+    fn now(&self) -> u64 {
+        Cache::now(self)
+    }
+
+    fn deadline(&self, key: &[u8], exptime: i64) -> Option<u64> {
+        Cache::deadline(self, key, exptime)
+    }
+
+    fn get(&self, key: &[u8]) -> impl Future<Output = Option<ItemView>> + Send {
+        Cache::get(self, key)
+    }
+
+    fn get_multi(&self, keys: &[Bytes]) -> impl Future<Output = Vec<Option<ItemView>>> + Send {
+        Cache::get_multi(self, keys)
+    }
+
+    fn set(
+        &self,
+        key: Bytes,
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+    ) -> impl Future<Output = Outcome> + Send {
+        Cache::set(self, key, flags, expiration, data)
+    }
+
+    fn add(
+        &self,
+        key: Bytes,
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+    ) -> impl Future<Output = Outcome> + Send {
+        Cache::add(self, key, flags, expiration, data)
+    }
+
+    fn replace(
+        &self,
+        key: &[u8],
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+    ) -> impl Future<Output = Outcome> + Send {
+        Cache::replace(self, key, flags, expiration, data)
+    }
+
+    fn append(&self, key: &[u8], data: Bytes) -> impl Future<Output = Outcome> + Send {
+        Cache::append(self, key, data)
+    }
+
+    fn prepend(&self, key: &[u8], data: Bytes) -> impl Future<Output = Outcome> + Send {
+        Cache::prepend(self, key, data)
+    }
+
+    fn delete(&self, key: &[u8]) -> impl Future<Output = bool> + Send {
+        Cache::delete(self, key)
+    }
+
+    fn touch(&self, key: &[u8], expiration: Option<u64>) -> impl Future<Output = bool> + Send {
+        Cache::touch(self, key, expiration)
+    }
+
+    fn add_delta(&self, key: &[u8], delta: u64, direction: Direction) -> impl Future<Output = Delta> + Send {
+        Cache::add_delta(self, key, delta, direction)
+    }
+
+    fn check_and_set(
+        &self,
+        key: &[u8],
+        flags: u32,
+        expiration: Option<u64>,
+        data: Bytes,
+        cas: u64,
+    ) -> impl Future<Output = Outcome> + Send {
+        Cache::check_and_set(self, key, flags, expiration, data, cas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Storage;
+    use crate::cache::{Delta, Direction, ItemView, Outcome};
+    use crate::clock::Clock;
+    use crate::commands::Command;
+    use crate::detail::Detail;
+    use crate::Connection;
+    use bytes::{Bytes, BytesMut};
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::future::{ready, Future};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Items in a `HashMap`, to run the commands against something other
+    /// than `Cache`. There is no limit on memory and nothing is evicted.
+    #[derive(Default)]
+    struct MapStorage {
+        clock: Clock,
+        items: Mutex<HashMap<Bytes, ItemView>>,
+        last_cas: Mutex<u64>,
+    }
+
+    impl MapStorage {
+        fn next_cas(&self) -> u64 {
+            let mut cas = self.last_cas.lock();
+            *cas += 1;
+            *cas
+        }
+
+        fn live(&self, key: &[u8]) -> Option<ItemView> {
+            let now = self.now();
+            let mut items = self.items.lock();
+            match items.get(key) {
+                Some(item) if item.expiration.is_some_and(|expiration| expiration <= now) => {
+                    items.remove(key);
+                    None
+                }
+                item => item.cloned(),
+            }
+        }
+
+        fn store(&self, key: Bytes, flags: u32, expiration: Option<u64>, data: Bytes) -> Outcome {
+            let item = ItemView { flags, cas: self.next_cas(), expiration, data };
+            self.items.lock().insert(key, item);
+            Outcome::Stored
+        }
+
+        fn concat(&self, key: &[u8], data: Bytes, before: bool) -> Outcome {
+            let Some(item) = self.live(key) else {
+                return Outcome::NotStored;
+            };
+            let mut joined = BytesMut::new();
+            if before {
+                joined.extend_from_slice(&data);
+                joined.extend_from_slice(&item.data);
+            } else {
+                joined.extend_from_slice(&item.data);
+                joined.extend_from_slice(&data);
+            }
+            self.store(Bytes::copy_from_slice(key), item.flags, item.expiration, joined.freeze())
+        }
+    }
+
+    impl Storage for MapStorage {
+        fn now(&self) -> u64 {
+            self.clock.now()
+        }
+
+        fn get(&self, key: &[u8]) -> impl Future<Output = Option<ItemView>> + Send {
+            ready(self.live(key))
+        }
+
+        fn get_multi(&self, keys: &[Bytes]) -> impl Future<Output = Vec<Option<ItemView>>> + Send {
+            ready(keys.iter().map(|key| self.live(key)).collect())
+        }
+
+        fn set(
+            &self,
+            key: Bytes,
+            flags: u32,
+            expiration: Option<u64>,
+            data: Bytes,
+        ) -> impl Future<Output = Outcome> + Send {
+            ready(self.store(key, flags, expiration, data))
+        }
+
+        fn add(
+            &self,
+            key: Bytes,
+            flags: u32,
+            expiration: Option<u64>,
+            data: Bytes,
+        ) -> impl Future<Output = Outcome> + Send {
+            ready(match self.live(&key) {
+                Some(_) => Outcome::NotStored,
+                None => self.store(key, flags, expiration, data),
+            })
+        }
+
+        fn replace(
+            &self,
+            key: &[u8],
+            flags: u32,
+            expiration: Option<u64>,
+            data: Bytes,
+        ) -> impl Future<Output = Outcome> + Send {
+            ready(match self.live(key) {
+                Some(_) => self.store(Bytes::copy_from_slice(key), flags, expiration, data),
+                None => Outcome::NotStored,
+            })
+        }
+
+        fn append(&self, key: &[u8], data: Bytes) -> impl Future<Output = Outcome> + Send {
+            ready(self.concat(key, data, false))
+        }
+
+        fn prepend(&self, key: &[u8], data: Bytes) -> impl Future<Output = Outcome> + Send {
+            ready(self.concat(key, data, true))
+        }
+
+        fn delete(&self, key: &[u8]) -> impl Future<Output = bool> + Send {
+            ready(self.live(key).is_some() && self.items.lock().remove(key).is_some())
+        }
+
+        fn touch(&self, key: &[u8], expiration: Option<u64>) -> impl Future<Output = bool> + Send {
+            let touched = self.live(key).is_some();
+            if let Some(item) = self.items.lock().get_mut(key) {
+                item.expiration = expiration;
+            }
+            ready(touched)
+        }
+
+        fn add_delta(&self, key: &[u8], delta: u64, direction: Direction) -> impl Future<Output = Delta> + Send {
+            let Some(item) = self.live(key) else {
+                return ready(Delta::NotFound);
+            };
+            let value = std::str::from_utf8(&item.data).ok().and_then(|data| data.parse::<u64>().ok());
+            let Some(value) = value else {
+                return ready(Delta::NonNumeric);
+            };
+            let value = match direction {
+                Direction::Incr => value.wrapping_add(delta),
+                Direction::Decr => value.saturating_sub(delta),
+            };
+            let data = Bytes::from(value.to_string());
+            self.store(Bytes::copy_from_slice(key), item.flags, item.expiration, data);
+            ready(Delta::Value(value))
+        }
+
+        fn check_and_set(
+            &self,
+            key: &[u8],
+            flags: u32,
+            expiration: Option<u64>,
+            data: Bytes,
+            cas: u64,
+        ) -> impl Future<Output = Outcome> + Send {
+            ready(match self.live(key) {
+                None => Outcome::NotFound,
+                Some(item) if item.cas != cas => Outcome::Exists,
+                Some(_) => self.store(Bytes::copy_from_slice(key), flags, expiration, data),
+            })
+        }
+    }
+
+    /// Send `request` and answer each command in it from `storage` the way
+    /// the server would. Returns the responses.
+    async fn run(storage: &impl Storage, request: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = Connection::new(server);
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut frames = Vec::new();
+        while conn.read_frames(&mut frames).await.unwrap() {
+            for frame in frames.drain(..) {
+                match Command::from_frame(frame).unwrap() {
+                    Command::Get(cmd) => cmd.apply(storage, &Detail::default(), &mut conn).await,
+                    Command::Set(cmd) => cmd.apply(storage, None, &Detail::default(), &mut conn).await,
+                    Command::Add(cmd) => cmd.apply(storage, None, &mut conn).await,
+                    Command::Replace(cmd) => cmd.apply(storage, None, &mut conn).await,
+                    Command::Append(cmd) => cmd.apply(storage, None, &mut conn).await,
+                    Command::Cas(cmd) => cmd.apply(storage, None, &mut conn).await,
+                    Command::Delete(cmd) => cmd.apply(storage, None, &Detail::default(), &mut conn).await,
+                    Command::Incr(cmd) => cmd.apply(storage, None, &mut conn).await,
+                    Command::Touch(cmd) => cmd.apply(storage, None, &mut conn).await,
+                    command => panic!("{} does not run on a Storage", command.get_name()),
+                }
+                .unwrap();
+            }
+        }
+        drop(conn);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_commands_run_on_any_storage() {
+        let storage = MapStorage::default();
+
+        let response = run(
+            &storage,
+            "set a 5 0 1\r\n1\r\n\
+             add a 0 0 1\r\n2\r\n\
+             replace b 0 0 1\r\n2\r\n\
+             append a 0 0 1\r\n0\r\n\
+             prepend a 0 0 1\r\n1\r\n\
+             incr a 5\r\n\
+             decr a 200\r\n\
+             get a b\r\n",
+        )
+        .await;
+        assert_eq!(
+            response,
+            "STORED\r\nNOT_STORED\r\nNOT_STORED\r\nSTORED\r\nSTORED\r\n115\r\n0\r\nVALUE a 5 1\r\n0\r\nEND\r\n"
+        );
+
+        let cas = storage.items.lock()[&b"a"[..]].cas;
+        let response = run(
+            &storage,
+            &format!(
+                "cas a 0 0 1 {}\r\nx\r\ncas a 0 0 1 {}\r\ny\r\ntouch a 100\r\ngets a\r\n",
+                cas + 1,
+                cas
+            ),
+        )
+        .await;
+        assert_eq!(
+            response,
+            format!("EXISTS\r\nSTORED\r\nTOUCHED\r\nVALUE a 0 1 {}\r\ny\r\nEND\r\n", cas + 1)
+        );
+        assert_eq!(storage.items.lock().len(), 1);
+
+        storage.clock.advance(100);
+        let response = run(&storage, "delete a\r\nget a\r\n").await;
+        assert_eq!(response, "NOT_FOUND\r\nEND\r\n");
+        assert!(storage.items.lock().is_empty());
+    }
+}
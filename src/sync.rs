@@ -0,0 +1,18 @@
+//! Atomics shared between threads, swapped for loom's with the `loom`
+//! feature so that model tests can run the code using them through every
+//! interleaving loom finds. Run them with
+//!
+//! ```text
+//! cargo test --release --lib --features loom loom
+//! ```
+//!
+//! and `LOOM_MAX_PREEMPTIONS=3` for a quicker, partial run. A feature
+//! rather than loom's usual `--cfg loom` in `RUSTFLAGS`, which tokio takes
+//! to leave out its networking. Only the `loom` tests work with it: loom
+//! types panic outside `loom::model`. There is a module of them in each
+//! module with some, see `id_generator` and `cache`.
+
+#[cfg(feature = "loom")]
+pub(crate) use loom::sync::atomic::AtomicU64;
+#[cfg(not(feature = "loom"))]
+pub(crate) use std::sync::atomic::AtomicU64;
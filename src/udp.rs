@@ -0,0 +1,389 @@
+use crate::cache::Cache;
+use crate::frame::ResponseFrame;
+use crate::health::Readiness;
+use crate::replication::Replicator;
+use crate::settings::Settings;
+use crate::stats::ServerStats;
+use crate::{commands::{self, Command}, Connection, Shutdown};
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use tracing::{debug, error, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Every datagram, in both directions, starts with an 8 byte frame header.
+const HEADER_LEN: usize = 8;
+
+/// Largest payload a single UDP datagram can carry.
+const MAX_DATAGRAM: usize = 65507;
+
+/// Most datagrams a response may take. A request fits in one datagram, and
+/// its source address is not checked, so anything more lets a spoofed request
+/// aim that much more traffic at someone else.
+const MAX_RESPONSE_DATAGRAMS: usize = 4;
+
+/// What is sent instead of a response that would take more datagrams.
+const RESPONSE_TOO_LARGE: &[u8] = b"SERVER_ERROR response too large for UDP\r\n";
+
+/// The memcached UDP frame header.
+///
+/// ```text
+/// 0-1 request id
+/// 2-3 sequence number
+/// 4-5 total number of datagrams in this message
+/// 6-7 reserved for future use; must be 0
+/// ```
+///
+/// All fields are big endian. Responses echo the request id so clients can
+/// match them to requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Header {
+    request_id: u16,
+    sequence: u16,
+    total: u16,
+}
+
+impl Header {
+    /// Read the header from the front of `datagram`. Returns `None` if the
+    /// datagram is too short or the reserved field is set.
+    fn parse(datagram: &[u8]) -> Option<Header> {
+        if datagram.len() < HEADER_LEN {
+            return None;
+        }
+        let field = |i: usize| u16::from_be_bytes([datagram[i], datagram[i + 1]]);
+        if field(6) != 0 {
+            return None;
+        }
+
+        Some(Header {
+            request_id: field(0),
+            sequence: field(2),
+            total: field(4),
+        })
+    }
+
+    fn encode(&self, dst: &mut Vec<u8>) {
+        dst.extend_from_slice(&self.request_id.to_be_bytes());
+        dst.extend_from_slice(&self.sequence.to_be_bytes());
+        dst.extend_from_slice(&self.total.to_be_bytes());
+        dst.extend_from_slice(&[0, 0]);
+    }
+}
+
+/// UDP listener state. Each received datagram is processed on its own task, so
+/// a slow request does not hold up the ones behind it.
+#[derive(Debug)]
+pub(crate) struct Listener {
+    pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) cache: Cache,
+    pub(crate) stats: Arc<ServerStats>,
+    pub(crate) settings: Arc<ArcSwap<Settings>>,
+    pub(crate) readiness: Arc<Readiness>,
+    pub(crate) replicator: Option<Arc<Replicator>>,
+
+    /// Upper bound on the size of each response datagram, header included.
+    pub(crate) max_datagram: usize,
+
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Dropped together with the listener to signal that
+    /// it has stopped.
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl Listener {
+    /// Receive datagrams until the shutdown signal is received.
+    pub(crate) async fn run(&mut self) {
+        info!("accepting UDP requests");
+
+        let mut buf = vec![0; MAX_DATAGRAM];
+        while !self.shutdown.is_shutdown() {
+            let (len, peer) = tokio::select! {
+                res = self.socket.recv_from(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(err) => {
+                        error!("UDP receive failed: {}", err);
+                        continue;
+                    }
+                },
+                _ = self.shutdown.recv() => return,
+            };
+
+            let header = match Header::parse(&buf[..len]) {
+                Some(header) => header,
+                None => {
+                    debug!("dropping datagram with invalid frame header from {}", peer);
+                    continue;
+                }
+            };
+            // Like memcached, requests spanning several datagrams are not
+            // supported.
+            if header.total != 1 {
+                debug!("dropping multi-datagram request from {}", peer);
+                continue;
+            }
+
+            let payload = buf[HEADER_LEN..len].to_vec();
+            let socket = self.socket.clone();
+            let cache = self.cache.clone();
+            let stats = self.stats.clone();
+            let settings = self.settings.clone();
+            let readiness = self.readiness.clone();
+            let replicator = self.replicator.clone();
+            let max_datagram = self.max_datagram;
+            let max_response = MAX_RESPONSE_DATAGRAMS * (max_datagram - HEADER_LEN);
+
+            tokio::spawn(async move {
+                let response = match respond(
+                    &cache,
+                    &stats,
+                    &settings,
+                    &readiness,
+                    replicator.as_deref(),
+                    &payload,
+                    max_response,
+                )
+                .await
+                {
+                    Ok(Some(response)) => response,
+                    Ok(None) => {
+                        debug!("UDP response to {} too large", peer);
+                        RESPONSE_TOO_LARGE.to_vec()
+                    }
+                    Err(err) => {
+                        debug!("UDP request from {} failed: {}", peer, err);
+                        return;
+                    }
+                };
+                if let Err(err) =
+                    send(&socket, peer, header.request_id, &response, max_datagram).await
+                {
+                    error!("UDP send to {} failed: {}", peer, err);
+                }
+            });
+        }
+    }
+}
+
+/// Run the commands in `payload` and collect the bytes that would have been
+/// written back to a TCP client.
+///
+/// The payload is written into one end of an in-memory pipe and read by a
+/// regular `Connection` on the other, so UDP goes through exactly the same
+/// `RequestFrame`/`Command` path as TCP. Returns `None` if the response grew
+/// beyond `max_response` bytes. The commands are run all the same.
+async fn respond(
+    cache: &Cache,
+    stats: &ServerStats,
+    settings: &ArcSwap<Settings>,
+    readiness: &Readiness,
+    replicator: Option<&Replicator>,
+    payload: &[u8],
+    max_response: usize,
+) -> Result<Option<Vec<u8>>> {
+    let (client, server) = tokio::io::duplex(payload.len().max(4096));
+    let (mut responses, mut requests) = tokio::io::split(client);
+    requests.write_all(payload).await?;
+    requests.shutdown().await?;
+
+    let process = async move {
+        let mut connection = Connection::new(server);
+        let mut frames = Vec::new();
+        while connection.read_frames(&mut frames).await? {
+            for frame in frames.drain(..) {
+                stats.incr_udp_requests();
+                let cmd = match Command::from_frame(frame) {
+                    Ok(cmd) => cmd,
+                    Err(err) => match commands::client_error(&err) {
+                        Some(reason) => {
+                            connection.write_and_flush(ResponseFrame::ClientError(reason.to_string())).await?;
+                            continue;
+                        }
+                        None => return Err(err),
+                    },
+                };
+                // The replication stream only arrives over TCP
+                let writes_allowed = !settings.load().read_only;
+                cmd.apply(cache, stats, settings, readiness, replicator, writes_allowed, &mut connection)
+                    .await?;
+            }
+        }
+        stats.add_traffic(connection.take_traffic());
+        Ok::<_, anyhow::Error>(())
+    };
+
+    // Keep draining past the limit so the writing side never blocks on a full
+    // pipe, but stop storing what is read.
+    let collect = async move {
+        let mut response = Vec::new();
+        let mut chunk = [0; 4096];
+        let mut oversized = false;
+        loop {
+            let n = responses.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            if response.len() + n > max_response {
+                oversized = true;
+            }
+            if !oversized {
+                response.extend_from_slice(&chunk[..n]);
+            }
+        }
+        Ok::<_, anyhow::Error>((!oversized).then_some(response))
+    };
+
+    let (processed, response) = tokio::join!(process, collect);
+    processed?;
+    response
+}
+
+/// Send `response` to `peer` split into sequenced datagrams of at most
+/// `max_datagram` bytes each.
+async fn send(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    request_id: u16,
+    response: &[u8],
+    max_datagram: usize,
+) -> Result<()> {
+    let chunks = response.chunks(max_datagram - HEADER_LEN);
+    let total = u16::try_from(chunks.len())?;
+
+    for (sequence, chunk) in chunks.enumerate() {
+        let header = Header {
+            request_id,
+            sequence: sequence as u16,
+            total,
+        };
+        let mut datagram = Vec::with_capacity(HEADER_LEN + chunk.len());
+        header.encode(&mut datagram);
+        datagram.extend_from_slice(chunk);
+        socket.send_to(&datagram, peer).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = Header {
+            request_id: 0x1234,
+            sequence: 2,
+            total: 3,
+        };
+        let mut encoded = Vec::new();
+        header.encode(&mut encoded);
+        assert_eq!(encoded, [0x12, 0x34, 0, 2, 0, 3, 0, 0]);
+        assert_eq!(Header::parse(&encoded), Some(header));
+    }
+
+    #[test]
+    fn test_header_invalid() {
+        assert_eq!(Header::parse(&[0, 1, 0, 0, 0, 1, 0]), None);
+        assert_eq!(Header::parse(&[0, 1, 0, 0, 0, 1, 0, 1]), None);
+    }
+
+    /// A listener sending datagrams of at most `max_datagram` bytes, and a
+    /// client connected to it.
+    async fn listen(max_datagram: usize) -> (UdpSocket, Arc<ServerStats>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let stats = Arc::new(ServerStats::default());
+
+        let mut listener = Listener {
+            socket: Arc::new(socket),
+            cache: Cache::new(),
+            stats: stats.clone(),
+            settings: Arc::new(ArcSwap::from_pointee(Settings::parse_from(["sidica"]))),
+            readiness: Arc::new(Readiness::default()),
+            replicator: None,
+            max_datagram,
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx,
+        };
+        tokio::spawn(async move {
+            // Kept alive for as long as the listener runs
+            let _notify_shutdown = notify_shutdown;
+            listener.run().await
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        (client, stats)
+    }
+
+    /// Send `payload` as request `request_id` and read the response, checking
+    /// the header of every datagram.
+    async fn ask(client: &UdpSocket, request_id: u16, payload: &[u8]) -> (Vec<u8>, usize) {
+        let mut request = Vec::new();
+        Header {
+            request_id,
+            sequence: 0,
+            total: 1,
+        }
+        .encode(&mut request);
+        request.extend_from_slice(payload);
+        client.send(&request).await.unwrap();
+
+        let mut response = Vec::new();
+        let mut buf = [0; 64];
+        let mut sequence = 0;
+        loop {
+            let n = client.recv(&mut buf).await.unwrap();
+            let header = Header::parse(&buf[..n]).unwrap();
+            assert_eq!(header.request_id, request_id);
+            assert_eq!(header.sequence, sequence);
+            response.extend_from_slice(&buf[HEADER_LEN..n]);
+            sequence += 1;
+            if sequence == header.total {
+                return (response, sequence as usize);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_response() {
+        let (client, stats) = listen(24).await;
+
+        // 16 byte payload per datagram
+        let (response, datagrams) = ask(&client, 7, b"set foo 5 0 11\r\nhello world\r\nget foo\r\n").await;
+        let expected = b"STORED\r\nVALUE foo 5 11\r\nhello world\r\nEND\r\n";
+        assert_eq!(response, expected);
+        assert_eq!(datagrams, expected.len().div_ceil(16));
+        assert!(stats.snapshot().contains(&("udp_requests".to_string(), 2)));
+    }
+
+    #[tokio::test]
+    async fn test_response_too_large() {
+        let (client, _) = listen(24).await;
+
+        // At most 4 datagrams of 16 bytes, and the value alone takes 5
+        let value = "x".repeat(80);
+        let set = format!("set foo 0 0 80\r\n{}\r\n", value);
+        assert_eq!(ask(&client, 1, set.as_bytes()).await.0, b"STORED\r\n");
+        let (response, datagrams) = ask(&client, 2, b"get foo\r\n").await;
+        assert_eq!(response, RESPONSE_TOO_LARGE);
+        assert_eq!(datagrams, RESPONSE_TOO_LARGE.len().div_ceil(16));
+
+        // Just fits
+        let value = "x".repeat(41);
+        let set = format!("set foo 0 0 41\r\n{}\r\n", value);
+        assert_eq!(ask(&client, 3, set.as_bytes()).await.0, b"STORED\r\n");
+        let (response, datagrams) = ask(&client, 4, b"get foo\r\n").await;
+        assert_eq!(response, format!("VALUE foo 0 41\r\n{}\r\nEND\r\n", value).as_bytes());
+        assert_eq!(datagrams, 4);
+    }
+}
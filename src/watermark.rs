@@ -0,0 +1,204 @@
+//! The watermark: the last id and CAS value the cache handed out, kept in
+//! `--data-dir` so that the next server started on it hands out only higher
+//! ones. The snapshot and the append log only have the CAS values of the
+//! items still there; without the watermark those of items deleted before
+//! the restart would be handed out again, and a client holding one could
+//! `cas` over an item it never read.
+//!
+//! ```text
+//! magic     8 bytes  "SIDICAWM"
+//! version   u32      VERSION
+//! id        u64      the last id handed out
+//! cas       u64      the last CAS handed out
+//! clean     u8       1 if written on shutdown, 0 if by `save_periodically`
+//! checksum  u32      CRC-32 of everything before
+//! ```
+//!
+//! Numbers are big endian. Like the snapshot, the file is written under a
+//! temporary name and renamed into place. One written while the server ran
+//! may be up to a `SAVE_INTERVAL` behind by the time it is killed, so its
+//! marks are read back `CRASH_GAP` higher.
+
+use crate::cache::Cache;
+use crate::checksum;
+use crate::settings::Settings;
+use crate::snapshot::sync_dir;
+
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tracing::{debug, error};
+
+/// Name of the watermark in `--data-dir`.
+pub(crate) const FILE: &str = "watermark";
+
+const MAGIC: &[u8; 8] = b"SIDICAWM";
+
+const VERSION: u32 = 1;
+
+const LEN: usize = 33;
+
+/// How often `save_periodically` saves the watermark, if it has moved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Added to both marks of a watermark not written on shutdown: more ids
+/// and CAS values than can be handed out in a `SAVE_INTERVAL`.
+pub(crate) const CRASH_GAP: u64 = 1 << 32;
+
+/// The last id and CAS value handed out, see `Cache::watermark`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Watermark {
+    pub(crate) id: u64,
+    pub(crate) cas: u64,
+}
+
+/// Write `mark` to `dir`, `clean` if nothing can be handed out after it.
+pub(crate) fn save(dir: &Path, mark: Watermark, clean: bool) -> Result<()> {
+    let mut buf = Vec::with_capacity(LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_be_bytes());
+    buf.extend_from_slice(&mark.id.to_be_bytes());
+    buf.extend_from_slice(&mark.cas.to_be_bytes());
+    buf.push(clean as u8);
+    buf.extend_from_slice(&checksum::update(0, &buf).to_be_bytes());
+
+    let path = dir.join(FILE);
+    let temp = dir.join(format!("{}.tmp", FILE));
+    let write = || {
+        let mut file = File::create(&temp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        sync_dir(dir)
+    };
+    write().with_context(|| format!("saving watermark {}", path.display()))
+}
+
+/// Read the watermark in `dir`, `CRASH_GAP` higher unless it was written on
+/// shutdown. Returns `None` if there is none.
+pub(crate) fn load(dir: &Path) -> Result<Option<Watermark>> {
+    let path = dir.join(FILE);
+    let buf = match fs::read(&path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("opening watermark {}", path.display())),
+    };
+    parse(&buf).map(Some).with_context(|| format!("loading watermark {}", path.display()))
+}
+
+fn parse(buf: &[u8]) -> Result<Watermark> {
+    if buf.len() != LEN {
+        bail!("{} bytes instead of {}", buf.len(), LEN);
+    }
+    let (body, crc) = buf.split_at(LEN - 4);
+    if !body.starts_with(MAGIC) {
+        bail!("not a watermark");
+    }
+    if checksum::update(0, body) != u32::from_be_bytes(crc.try_into().unwrap()) {
+        bail!("checksum mismatch, the file is corrupt");
+    }
+    let version = u32::from_be_bytes(body[8..12].try_into().unwrap());
+    if version != VERSION {
+        bail!("version {} is not supported, only {}", version, VERSION);
+    }
+    let id = u64::from_be_bytes(body[12..20].try_into().unwrap());
+    let cas = u64::from_be_bytes(body[20..28].try_into().unwrap());
+    let gap = if body[28] == 1 { 0 } else { CRASH_GAP };
+    Ok(Watermark { id: id.saturating_add(gap), cas: cas.saturating_add(gap) })
+}
+
+/// Takes turns saving the watermark of a cache: periodic ones, see
+/// `save_periodically`, and the clean one on shutdown, once they are
+/// stopped.
+#[derive(Debug, Default)]
+pub(crate) struct Keeping {
+    /// The watermark last saved
+    last: Mutex<Option<Watermark>>,
+    shut_down: AtomicBool,
+}
+
+impl Keeping {
+    /// Stop periodic saves, and wait for one under way. Blocks.
+    pub(crate) fn shut_down(&self) {
+        self.shut_down.store(true, Ordering::Relaxed);
+        drop(self.last.lock());
+    }
+
+    /// Save the watermark of `cache` if it has moved since the last time,
+    /// unless the server is shutting down. Blocks.
+    fn save(&self, dir: &Path, cache: &Cache) -> Result<()> {
+        let mut last = self.last.lock();
+        let mark = cache.watermark();
+        if self.shut_down.load(Ordering::Relaxed) || *last == Some(mark) {
+            return Ok(());
+        }
+        save(dir, mark, false)?;
+        *last = Some(mark);
+        Ok(())
+    }
+}
+
+/// Save the watermark of `cache` to `--data-dir` every `SAVE_INTERVAL` it
+/// has moved, for as long as the server runs.
+pub(crate) async fn save_periodically(cache: Cache, settings: Arc<ArcSwap<Settings>>, keeping: Arc<Keeping>) {
+    let mut interval = time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(dir) = settings.load().data_dir.clone() else {
+            continue;
+        };
+        let (cache, keeping) = (cache.clone(), keeping.clone());
+        match tokio::task::spawn_blocking(move || keeping.save(&dir, &cache)).await {
+            Ok(Ok(())) => debug!("saved the watermark"),
+            Ok(Err(err)) => error!("failed to save the watermark: {:#}", err),
+            Err(err) => error!("failed to save the watermark: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sidica-watermark-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_saved_on_shutdown_or_not() {
+        let dir = dir("load");
+        assert_eq!(load(&dir).unwrap(), None);
+
+        let mark = Watermark { id: 7 << 32 | 3, cas: 42 };
+        save(&dir, mark, true).unwrap();
+        assert_eq!(load(&dir).unwrap(), Some(mark));
+        // Possibly behind what the server handed out before it was killed
+        save(&dir, mark, false).unwrap();
+        let loaded = Watermark { id: mark.id + CRASH_GAP, cas: mark.cas + CRASH_GAP };
+        assert_eq!(load(&dir).unwrap(), Some(loaded));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_a_corrupt_watermark() {
+        let dir = dir("corrupt");
+        save(&dir, Watermark { id: 1, cas: 2 }, true).unwrap();
+        let mut buf = fs::read(dir.join(FILE)).unwrap();
+        buf[20] ^= 1;
+        fs::write(dir.join(FILE), &buf).unwrap();
+        assert!(format!("{:#}", load(&dir).unwrap_err()).contains("checksum mismatch"));
+        fs::write(dir.join(FILE), &buf[..10]).unwrap();
+        assert!(load(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+//! Clients that send a byte at a time, or go away at the worst moment: in
+//! the middle of a value they are sending, or of a response they are sent.
+#![cfg(unix)]
+
+mod common;
+
+use common::{Client, Server, TIMEOUT};
+use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The value of the stat `name`.
+fn stat(client: &mut Client, name: &str) -> String {
+    let stats = client.ask(b"stats\r\n", b"END\r\n");
+    let line = stats
+        .lines()
+        .find(|line| line.starts_with(&format!("STAT {} ", name)))
+        .unwrap_or_else(|| panic!("no {} in {}", name, stats));
+    line.rsplit(' ').next().unwrap().to_string()
+}
+
+/// Wait for the connections closed to be handled, leaving `client` the
+/// only one open, and check none of their handlers panicked.
+fn assert_only_connection(client: &mut Client) {
+    let start = Instant::now();
+    while stat(client, "curr_connections") != "1" {
+        assert!(start.elapsed() < TIMEOUT, "{} connections still open", stat(client, "curr_connections"));
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(stat(client, "handler_panics"), "0");
+}
+
+#[test]
+fn one_byte_per_write() {
+    let server = Server::start();
+    let mut client = server.connect();
+    client.stream.set_nodelay(true).unwrap();
+
+    let request = b"set foo 5 0 3 noreply\r\nbar\r\nincr n 1\r\nset n 0 0 1\r\n1\r\nincr n 41\r\ngets foo\r\n";
+    for byte in request {
+        client.send(&[*byte]);
+        thread::sleep(Duration::from_millis(1));
+    }
+    let response = client.read_until(b"END\r\n");
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("NOT_FOUND\r\nSTORED\r\n42\r\nVALUE foo 5 3 "), "{}", response);
+    assert!(response.ends_with("\r\nbar\r\nEND\r\n"), "{}", response);
+}
+
+#[test]
+fn close_in_the_data_block() {
+    let server = Server::start();
+    let mut client = server.connect();
+    client.ask(b"set foo 0 0 3\r\nbar\r\n", b"\r\n");
+
+    let value = vec![b'x'; 100_000];
+    for sent in [0, 1, 4096, 50_000, 99_999, 100_000, 100_001] {
+        let mut closing = server.connect();
+        closing.send(b"set foo 0 0 100000\r\n");
+        closing.send(&[&value[..], b"\r\n"].concat()[..sent]);
+        drop(closing);
+    }
+    assert_only_connection(&mut client);
+    // None of the sets was taken, even in part
+    assert_eq!(client.ask(b"get foo\r\n", b"END\r\n"), "VALUE foo 0 3\r\nbar\r\nEND\r\n");
+    assert_eq!(client.ask(b"set foo 0 0 3\r\nbaz\r\n", b"\r\n"), "STORED\r\n");
+}
+
+#[test]
+fn close_while_answered() {
+    let server = Server::start();
+    let mut client = server.connect();
+    let value = vec![b'x'; 500_000];
+    let mut keys = Vec::new();
+    for n in 0..20 {
+        let key = format!("key{}", n);
+        client.send(format!("set {} 0 0 {}\r\n", key, value.len()).as_bytes());
+        client.send(&value);
+        client.ask(b"\r\n", b"STORED\r\n");
+        keys.push(key);
+    }
+    // 10 MB of response, most of it still to be written when closed
+    let request = format!("get {}\r\n", keys.join(" "));
+
+    for read in [0, 1, 64 * 1024] {
+        let mut closing = server.connect();
+        closing.send(request.as_bytes());
+        let mut response = vec![0; read];
+        closing.stream.read_exact(&mut response).unwrap();
+        drop(closing);
+    }
+    assert_only_connection(&mut client);
+    let response = client.ask(b"get key19\r\n", b"END\r\n");
+    assert!(response.starts_with("VALUE key19 0 500000\r\n"));
+    assert_eq!(response.len(), "VALUE key19 0 500000\r\n\r\nEND\r\n".len() + value.len());
+}
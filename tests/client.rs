@@ -0,0 +1,58 @@
+//! `sidica::client` against a running server.
+#![cfg(unix)]
+
+mod common;
+
+use common::Server;
+use sidica::client::{Client, Response};
+
+#[tokio::test]
+async fn each_command() {
+    let server = Server::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    assert_eq!(client.get(b"a").await.unwrap(), None);
+    client.set(b"a", 7, 0, b"one").await.unwrap();
+    client.set(b"n", 0, 0, b"10").await.unwrap();
+    let a = client.get(b"a").await.unwrap().unwrap();
+    assert_eq!((&a.key[..], a.flags, a.cas, &a.data[..]), (&b"a"[..], 7, None, &b"one"[..]));
+
+    let found = client.get_multi(&[b"n", b"b", b"a"]).await.unwrap();
+    let keys: Vec<_> = found.iter().map(|value| &value.key[..]).collect();
+    assert_eq!(keys, [&b"n"[..], b"a"]);
+
+    assert_eq!(client.incr(b"n", 5).await.unwrap(), Some(15));
+    assert_eq!(client.decr(b"n", 20).await.unwrap(), Some(0));
+    assert_eq!(client.incr(b"b", 1).await.unwrap(), None);
+    assert!(client.incr(b"a", 1).await.is_err());
+
+    assert!(client.delete(b"a").await.unwrap());
+    assert!(!client.delete(b"a").await.unwrap());
+
+    let stats = client.stats(None).await.unwrap();
+    let stat = |name: &str| stats.iter().find(|(stat, _)| stat == name).map(|(_, value)| value.clone());
+    assert_eq!(stat("cmd_get").as_deref(), Some("5"));
+    assert_eq!(stat("cmd_set").as_deref(), Some("2"));
+    assert_eq!(stat("curr_items").as_deref(), Some("1"));
+    assert!(client.stats(Some("settings")).await.unwrap().iter().any(|(name, _)| name == "maxbytes"));
+}
+
+#[tokio::test]
+async fn raw_requests() {
+    let server = Server::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    client.send(b"set a 0 0 1 noreply\r\nx\r\n").await.unwrap();
+    let Response::Values(values) = client.request(b"gets a\r\n").await.unwrap() else {
+        panic!("no values");
+    };
+    let cas = values[0].cas.unwrap();
+    let request = format!("cas a 0 0 1 {}\r\ny\r\n", cas);
+    assert_eq!(client.request(request.as_bytes()).await.unwrap(), Response::Stored);
+    assert_eq!(client.request(request.as_bytes()).await.unwrap(), Response::Exists);
+    assert_eq!(
+        client.request(b"mg a v\r\n").await.unwrap(),
+        Response::Meta { flags: String::new(), data: Some("y".into()) }
+    );
+    assert_eq!(client.request(b"touch a 100\r\n").await.unwrap(), Response::Touched);
+}
@@ -0,0 +1,227 @@
+//! Runs the server binary for tests that talk to it over TCP.
+//!
+//! `Server::start` binds `127.0.0.1:0` and hands the listener to the server
+//! the way systemd socket activation does, so tests run side by side on
+//! ports of their own. The server is stopped with SIGINT, and has to exit
+//! cleanly, when the test ends. Servers that go to the background, or hand
+//! over to another process, are found through their `PidFile` instead.
+// Each test file uses only some of it
+#![allow(dead_code)]
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::process::{Child, Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait on the server before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server on a port of its own, stopped when dropped.
+pub struct Server {
+    process: Child,
+    addr: SocketAddr,
+}
+
+impl Server {
+    pub fn start() -> Server {
+        Server::with_args(&[])
+    }
+
+    pub fn with_args(args: &[&str]) -> Server {
+        let (mut command, listener) = activated(args);
+        let process = command.spawn().unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Connections queue in the backlog until the server accepts them
+        drop(listener);
+        Server { process, addr }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn id(&self) -> u32 {
+        self.process.id()
+    }
+
+    pub fn connect(&self) -> Client {
+        Client::connect(self.addr)
+    }
+
+    /// Send `request` over a connection of its own and read the response,
+    /// up to `end`.
+    pub fn ask(&self, request: &[u8], end: &[u8]) -> String {
+        self.connect().ask(request, end)
+    }
+
+    /// Whether the server has exited, e.g. after handing over to another
+    /// process. It still has to have exited cleanly.
+    pub fn exited(&mut self) -> bool {
+        self.process.try_wait().unwrap().is_some()
+    }
+
+    /// Stop the server the way ctrl-c does, and wait for it to exit.
+    pub fn stop(self) {
+        drop(self)
+    }
+
+    /// Kill the server, leaving it no chance to save anything.
+    pub fn kill(mut self) {
+        self.process.kill().unwrap();
+        self.process.wait().unwrap();
+        // Gone for good, so there is nothing left to stop
+        std::mem::forget(self);
+    }
+}
+
+/// Run the server with `args` to the end, for those that refuse to start.
+pub fn run(args: &[&str]) -> Output {
+    activated(args).0.output().unwrap()
+}
+
+/// A command running the server with `args` on a listener bound here and
+/// passed down as fd 3, returned with it to keep it open until the server
+/// has started.
+fn activated(args: &[&str]) -> (Command, TcpListener) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    socket2::SockRef::from(&listener).set_cloexec(false).unwrap();
+    // `exec` keeps the shell's pid, so `$$` is the server's pid. The port
+    // given on the command line is never bound.
+    let script = format!(
+        "exec 3<&{}; LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" -p 1 \"$@\"",
+        listener.as_raw_fd()
+    );
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(script)
+        .arg(env!("CARGO_BIN_EXE_sidica"))
+        .args(args);
+    (command, listener)
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Ok(Some(status)) = self.process.try_wait() {
+            if !thread::panicking() {
+                assert!(status.success(), "the server exited with {}", status);
+            }
+            return;
+        }
+        let stopped = Command::new("kill")
+            .args(["-INT", &self.process.id().to_string()])
+            .status()
+            .is_ok_and(|status| status.success());
+        let start = Instant::now();
+        while stopped && start.elapsed() < TIMEOUT {
+            match self.process.try_wait() {
+                Ok(Some(status)) => {
+                    if !thread::panicking() {
+                        assert!(status.success(), "the server exited with {}", status);
+                    }
+                    return;
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(20)),
+                Err(_) => break,
+            }
+        }
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        if !thread::panicking() {
+            panic!("the server did not stop on SIGINT");
+        }
+    }
+}
+
+/// A server found through the pid file it writes, killed when dropped.
+pub struct PidFile(pub PathBuf);
+
+impl PidFile {
+    /// A pid file of this test's own, named after `name`.
+    pub fn new(name: &str) -> PidFile {
+        PidFile(std::env::temp_dir().join(format!("sidica-{}-{}.pid", name, std::process::id())))
+    }
+
+    /// The pid written to the file, if it has been yet.
+    pub fn pid(&self) -> Option<String> {
+        let pid = std::fs::read_to_string(&self.0).ok()?;
+        Some(pid.trim().to_string()).filter(|pid| !pid.is_empty())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid() {
+            let _ = Command::new("kill").args(["-KILL", &pid]).status();
+        }
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A port nothing listens on, for servers that have to bind their own.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A fresh directory of this test's own, named after `name`.
+pub fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sidica-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Wait for `done`, failing the test after `TIMEOUT`.
+pub fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+pub struct Client {
+    pub stream: TcpStream,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        Client { stream }
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    /// Read until the response ends with `end`.
+    pub fn read_until(&mut self, end: &[u8]) -> Vec<u8> {
+        let mut response = Vec::new();
+        let mut buf = [0; 64 * 1024];
+        while !response.ends_with(end) {
+            let read = self.stream.read(&mut buf).unwrap();
+            assert_ne!(read, 0, "closed after {:?}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buf[..read]);
+        }
+        response
+    }
+
+    /// Send `request` and read the response, up to `end`.
+    pub fn ask(&mut self, request: &[u8], end: &[u8]) -> String {
+        self.send(request);
+        String::from_utf8(self.read_until(end)).unwrap()
+    }
+
+    /// Whether the server closed the connection, reading whatever is left.
+    pub fn closed(&mut self) -> bool {
+        let mut rest = Vec::new();
+        match self.stream.read_to_end(&mut rest) {
+            Ok(_) => true,
+            Err(err) => err.kind() == ErrorKind::ConnectionReset,
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! memcached conformance: the bytes clients send and those memcached answers
+//! them with, for every command's success and error forms, noreply,
+//! multiget order, CAS and expiration, like memcapable and memcached's own
+//! test scripts check.
+//!
+//! Each vector is an entry of `VECTORS`. Those sidica does not pass are
+//! marked `known` with why, and listed as a compatibility report by
+//! `cargo test --test conformance -- --nocapture`; one that starts to pass
+//! fails the suite until the mark is dropped.
+#![cfg(unix)]
+
+mod common;
+
+use common::{Client, Server};
+use std::io::{ErrorKind, Read};
+
+/// Sent after each step: `mg` answers `EN` for a key that is not there,
+/// which no other step answers, so it marks where the step's response ends.
+const SENTINEL: &[u8] = b"mg conformance:end\r\n";
+const SENTINEL_RESPONSE: &[u8] = b"EN\r\n";
+
+/// Ends a response after which the server closed the connection.
+const CLOSED: &[u8] = b"<closed>";
+
+/// In a response, matches a CAS value. In the requests after it, stands for
+/// the value matched.
+const CAS: &[u8] = b"<cas>";
+
+struct Vector {
+    name: &'static str,
+    /// Requests sent in turn on one connection, each with its response
+    steps: &'static [(&'static [u8], &'static [u8])],
+    /// Why sidica answers otherwise, for vectors it does not pass yet
+    known: Option<&'static str>,
+}
+
+const VECTORS: &[Vector] = &[
+    // Storage
+    Vector {
+        name: "set then get",
+        steps: &[(b"set set1 5 0 3\r\nbar\r\nget set1\r\n", b"STORED\r\nVALUE set1 5 3\r\nbar\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "set replaces",
+        steps: &[(
+            b"set set2 0 0 1\r\nx\r\nset set2 1 0 2\r\nyy\r\nget set2\r\n",
+            b"STORED\r\nSTORED\r\nVALUE set2 1 2\r\nyy\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "set noreply",
+        steps: &[(b"set set3 0 0 1 noreply\r\nx\r\nget set3\r\n", b"VALUE set3 0 1\r\nx\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "empty value",
+        steps: &[(b"set set4 0 0 0\r\n\r\nget set4\r\n", b"STORED\r\nVALUE set4 0 0\r\n\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "largest flags",
+        steps: &[(
+            b"set set5 4294967295 0 1\r\nx\r\nget set5\r\n",
+            b"STORED\r\nVALUE set5 4294967295 1\r\nx\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "flags past 32 bits",
+        steps: &[(b"set set6 4294967296 0 1\r\nx\r\n", b"CLIENT_ERROR bad command line format\r\n")],
+        known: Some("malformed command lines close the connection"),
+    },
+    Vector {
+        name: "longest key",
+        steps: &[(
+            b"set kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk 0 0 1\r\nx\r\n",
+            b"STORED\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "key past 250 bytes",
+        steps: &[(
+            b"set kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkk 0 0 1\r\nx\r\n",
+            b"CLIENT_ERROR bad command line format\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "set without a length",
+        steps: &[(b"set set7 0 0\r\n", b"ERROR\r\n")],
+        known: Some("malformed command lines close the connection"),
+    },
+    Vector {
+        name: "data block longer than declared",
+        steps: &[(b"set set8 0 0 3\r\nabcd\r\n", b"CLIENT_ERROR bad data chunk\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "data block holding a CRLF",
+        steps: &[(b"set set9 0 0 4\r\na\r\nb\r\nget set9\r\n", b"STORED\r\nVALUE set9 0 4\r\na\r\nb\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "add",
+        steps: &[(
+            b"add add1 0 0 1\r\nx\r\nadd add1 0 0 1\r\ny\r\nget add1\r\n",
+            b"STORED\r\nNOT_STORED\r\nVALUE add1 0 1\r\nx\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "add noreply",
+        steps: &[(b"add add2 0 0 1 noreply\r\nx\r\nget add2\r\n", b"VALUE add2 0 1\r\nx\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "replace",
+        steps: &[(
+            b"replace rep1 0 0 1\r\nx\r\nset rep1 0 0 1\r\nx\r\nreplace rep1 0 0 1\r\ny\r\nget rep1\r\n",
+            b"NOT_STORED\r\nSTORED\r\nSTORED\r\nVALUE rep1 0 1\r\ny\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "append and prepend keep the flags",
+        steps: &[(
+            b"append app1 0 0 1\r\nx\r\nset app1 7 0 1\r\na\r\nappend app1 0 0 1\r\nb\r\nprepend app1 0 0 1\r\nc\r\nget app1\r\n",
+            b"NOT_STORED\r\nSTORED\r\nSTORED\r\nSTORED\r\nVALUE app1 7 3\r\ncab\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    // Retrieval
+    Vector {
+        name: "get a missing key",
+        steps: &[(b"get get1\r\n", b"END\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "multiget answers the keys found, in the order asked",
+        steps: &[(
+            b"set get2 0 0 1\r\n2\r\nset get4 0 0 1\r\n4\r\nget get4 get3 get2 get4\r\n",
+            b"STORED\r\nSTORED\r\nVALUE get4 0 1\r\n4\r\nVALUE get2 0 1\r\n2\r\nVALUE get4 0 1\r\n4\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "get without a key",
+        steps: &[(b"get\r\n", b"ERROR\r\n")],
+        known: Some("malformed command lines close the connection"),
+    },
+    Vector {
+        name: "gets",
+        steps: &[(b"set gets1 3 0 1\r\nx\r\ngets gets1\r\n", b"STORED\r\nVALUE gets1 3 1 <cas>\r\nx\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "gat",
+        steps: &[(b"set gat1 0 0 1\r\nx\r\ngat 100 gat1\r\n", b"STORED\r\nVALUE gat1 0 1\r\nx\r\nEND\r\n")],
+        known: Some("gat and gats are not implemented"),
+    },
+    Vector {
+        name: "mg",
+        steps: &[(b"set mg1 3 0 1\r\nx\r\nmg mg1 v f\r\nmg mg1\r\n", b"STORED\r\nVA 1 f3\r\nx\r\nHD\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "mn",
+        steps: &[(b"mn\r\n", b"MN\r\n")],
+        known: Some("of the meta commands, only mg is implemented"),
+    },
+    Vector {
+        name: "ms",
+        steps: &[(b"ms ms1 1\r\nx\r\n", b"HD\r\n")],
+        known: Some("of the meta commands, only mg is implemented"),
+    },
+    // CAS
+    Vector {
+        name: "cas a missing key",
+        steps: &[(b"cas cas1 0 0 1 1\r\nx\r\n", b"NOT_FOUND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "cas with the value from gets, then again",
+        steps: &[
+            (b"set cas2 0 0 1\r\nx\r\ngets cas2\r\n", b"STORED\r\nVALUE cas2 0 1 <cas>\r\nx\r\nEND\r\n"),
+            (
+                b"cas cas2 5 0 1 <cas>\r\ny\r\ncas cas2 0 0 1 <cas>\r\nz\r\nget cas2\r\n",
+                b"STORED\r\nEXISTS\r\nVALUE cas2 5 1\r\ny\r\nEND\r\n",
+            ),
+        ],
+        known: None,
+    },
+    Vector {
+        name: "cas after another store",
+        steps: &[
+            (b"set cas3 0 0 1\r\nx\r\ngets cas3\r\n", b"STORED\r\nVALUE cas3 0 1 <cas>\r\nx\r\nEND\r\n"),
+            (b"set cas3 0 0 1\r\ny\r\ncas cas3 0 0 1 <cas>\r\nz\r\n", b"STORED\r\nEXISTS\r\n"),
+        ],
+        known: None,
+    },
+    Vector {
+        name: "cas noreply",
+        steps: &[
+            (b"set cas4 0 0 1\r\nx\r\ngets cas4\r\n", b"STORED\r\nVALUE cas4 0 1 <cas>\r\nx\r\nEND\r\n"),
+            (b"cas cas4 0 0 1 <cas> noreply\r\ny\r\nget cas4\r\n", b"VALUE cas4 0 1\r\ny\r\nEND\r\n"),
+        ],
+        known: None,
+    },
+    Vector {
+        name: "cas without a CAS value",
+        steps: &[(b"cas cas5 0 0 1\r\nx\r\n", b"ERROR\r\n")],
+        known: Some("malformed command lines close the connection"),
+    },
+    // Deletion
+    Vector {
+        name: "delete",
+        steps: &[(
+            b"delete del1\r\nset del1 0 0 1\r\nx\r\ndelete del1\r\nget del1\r\n",
+            b"NOT_FOUND\r\nSTORED\r\nDELETED\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "delete noreply",
+        steps: &[(b"set del2 0 0 1\r\nx\r\ndelete del2 noreply\r\nget del2\r\n", b"STORED\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "delete with the legacy time of 0",
+        steps: &[(b"set del3 0 0 1\r\nx\r\ndelete del3 0\r\n", b"STORED\r\nDELETED\r\n")],
+        known: Some("malformed command lines close the connection"),
+    },
+    // Increment and decrement
+    Vector {
+        name: "incr and decr",
+        steps: &[(
+            b"incr num1 1\r\nset num1 0 0 2\r\n10\r\nincr num1 5\r\ndecr num1 3\r\nget num1\r\n",
+            b"NOT_FOUND\r\nSTORED\r\n15\r\n12\r\nVALUE num1 0 2\r\n12\r\nEND\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "decr stops at 0",
+        steps: &[(b"set num2 0 0 1\r\n5\r\ndecr num2 100\r\n", b"STORED\r\n0\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "incr wraps at 64 bits",
+        steps: &[(b"set num3 0 0 20\r\n18446744073709551615\r\nincr num3 2\r\n", b"STORED\r\n1\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "incr noreply",
+        steps: &[(b"set num4 0 0 2\r\n10\r\nincr num4 1 noreply\r\nget num4\r\n", b"STORED\r\nVALUE num4 0 2\r\n11\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "incr a value that is not a number",
+        steps: &[(
+            b"set num5 0 0 1\r\nx\r\nincr num5 1\r\n",
+            b"STORED\r\nCLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+        )],
+        known: None,
+    },
+    Vector {
+        name: "incr by a delta that is not a number",
+        steps: &[(b"incr num6 x\r\n", b"CLIENT_ERROR invalid numeric delta argument\r\n")],
+        known: Some("malformed command lines close the connection"),
+    },
+    // Touch and expiration
+    Vector {
+        name: "touch",
+        steps: &[(b"touch exp1 10\r\nset exp1 0 0 1\r\nx\r\ntouch exp1 10\r\n", b"NOT_FOUND\r\nSTORED\r\nTOUCHED\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "touch noreply",
+        steps: &[(b"set exp2 0 0 1\r\nx\r\ntouch exp2 10 noreply\r\n", b"STORED\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "touch into the past expires",
+        steps: &[(b"set exp3 0 0 1\r\nx\r\ntouch exp3 -1\r\nget exp3\r\n", b"STORED\r\nTOUCHED\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "a negative exptime expires at once",
+        steps: &[(b"set exp4 0 -1 1\r\nx\r\nget exp4\r\n", b"STORED\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "30 days is still relative",
+        steps: &[(b"set exp5 0 2592000 1\r\nx\r\nget exp5\r\n", b"STORED\r\nVALUE exp5 0 1\r\nx\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "past 30 days is a unix time, long gone",
+        steps: &[(b"set exp6 0 2592001 1\r\nx\r\nget exp6\r\n", b"STORED\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "a unix time in the past",
+        steps: &[(b"set exp7 0 1000000000 1\r\nx\r\nget exp7\r\n", b"STORED\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "a unix time in the future",
+        steps: &[(b"set exp8 0 4000000000 1\r\nx\r\nget exp8\r\n", b"STORED\r\nVALUE exp8 0 1\r\nx\r\nEND\r\n")],
+        known: None,
+    },
+    // Everything else
+    Vector {
+        name: "flush_all later leaves items until then",
+        steps: &[(b"set fl1 0 0 1\r\nx\r\nflush_all 100\r\nget fl1\r\n", b"STORED\r\nOK\r\nVALUE fl1 0 1\r\nx\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "flush_all",
+        steps: &[(b"set fl2 0 0 1\r\nx\r\nflush_all\r\nget fl2\r\n", b"STORED\r\nOK\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "flush_all noreply",
+        steps: &[(b"set fl3 0 0 1\r\nx\r\nflush_all noreply\r\nget fl3\r\n", b"STORED\r\nEND\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "verbosity",
+        steps: &[(b"verbosity 1\r\nverbosity 0 noreply\r\n", b"OK\r\n")],
+        known: None,
+    },
+    Vector {
+        name: "version",
+        steps: &[(b"version\r\n", concat!("VERSION ", env!("CARGO_PKG_VERSION"), "\r\n").as_bytes())],
+        known: Some("version is not implemented"),
+    },
+    Vector {
+        name: "unknown command",
+        steps: &[(b"bogus\r\nget unk1\r\n", b"ERROR\r\nEND\r\n")],
+        known: Some("unknown commands close the connection"),
+    },
+    Vector {
+        name: "quit",
+        steps: &[(b"quit\r\n", CLOSED)],
+        known: None,
+    },
+];
+
+/// Whether `response` is `expected`, with the CAS value matched if there is
+/// one. Returns the CAS value, or an empty one if there is none.
+fn matches(expected: &[u8], response: &[u8]) -> Option<Vec<u8>> {
+    let Some(at) = expected.windows(CAS.len()).position(|window| window == CAS) else {
+        return (expected == response).then(Vec::new);
+    };
+    let (before, after) = (&expected[..at], &expected[at + CAS.len()..]);
+    let cas = response.strip_prefix(before)?.strip_suffix(after)?;
+    (!cas.is_empty() && cas.iter().all(u8::is_ascii_digit)).then(|| cas.to_vec())
+}
+
+/// `request` with the CAS placeholder replaced by `cas`.
+fn with_cas(request: &[u8], cas: &[u8]) -> Vec<u8> {
+    match request.windows(CAS.len()).position(|window| window == CAS) {
+        Some(at) => [&request[..at], cas, &with_cas(&request[at + CAS.len()..], cas)].concat(),
+        None => request.to_vec(),
+    }
+}
+
+/// Read the response to a step, up to the sentinel's, or until the server
+/// closes the connection.
+fn read_response(client: &mut Client) -> Vec<u8> {
+    let mut response = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let ends = response.ends_with(SENTINEL_RESPONSE);
+        let before = response.len().saturating_sub(SENTINEL_RESPONSE.len());
+        if ends && (before == 0 || response[..before].ends_with(b"\r\n")) {
+            response.truncate(before);
+            return response;
+        }
+        match client.stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => response.extend_from_slice(&buf[..read]),
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => break,
+            Err(err) => panic!("reading after {:?}: {}", response.escape_ascii().to_string(), err),
+        }
+    }
+    response.extend_from_slice(CLOSED);
+    response
+}
+
+/// Run `vector` on a connection of its own. Returns how the response
+/// differs from the one expected, if it does.
+fn run(server: &Server, vector: &Vector) -> Result<(), String> {
+    let mut client = server.connect();
+    let mut cas = Vec::new();
+    for (step, &(request, expected)) in vector.steps.iter().enumerate() {
+        client.send(&with_cas(request, &cas));
+        client.send(SENTINEL);
+        let response = read_response(&mut client);
+        match matches(expected, &response) {
+            Some(matched) if !matched.is_empty() => cas = matched,
+            Some(_) => {}
+            None => return Err(diff(step, expected, &response)),
+        }
+        if response.ends_with(CLOSED) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn diff(step: usize, expected: &[u8], response: &[u8]) -> String {
+    let same = expected.iter().zip(response).take_while(|(a, b)| a == b).count();
+    format!(
+        "step {}, first difference at byte {}\n  expected: \"{}\"\n       got: \"{}\"",
+        step + 1,
+        same,
+        expected.escape_ascii(),
+        response.escape_ascii()
+    )
+}
+
+#[test]
+fn conformance() {
+    let server = Server::start();
+    let mut failures = Vec::new();
+    let mut known = Vec::new();
+    for vector in VECTORS {
+        match (run(&server, vector), vector.known) {
+            (Ok(()), None) => {}
+            (Err(diff), None) => failures.push(format!("{}: {}", vector.name, diff)),
+            (Ok(()), Some(_)) => failures.push(format!("{}: passes now, drop its known mark", vector.name)),
+            (Err(_), Some(why)) => known.push(format!("{}: {}", vector.name, why)),
+        }
+    }
+
+    println!(
+        "{} of {} vectors answered like memcached does",
+        VECTORS.len() - known.len() - failures.len(),
+        VECTORS.len()
+    );
+    for line in &known {
+        println!("  known: {}", line);
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+fn test_matches() {
+    assert_eq!(matches(b"STORED\r\n", b"STORED\r\n"), Some(Vec::new()));
+    assert_eq!(matches(b"STORED\r\n", b"EXISTS\r\n"), None);
+    assert_eq!(matches(b"VALUE k 0 1 <cas>\r\n", b"VALUE k 0 1 42\r\n"), Some(b"42".to_vec()));
+    assert_eq!(matches(b"VALUE k 0 1 <cas>\r\n", b"VALUE k 0 1 \r\n"), None);
+    assert_eq!(with_cas(b"cas k 0 0 1 <cas>\r\nx\r\n", b"42"), b"cas k 0 0 1 42\r\nx\r\n");
+}
@@ -0,0 +1,47 @@
+//! Runs the server with `--daemon --pid-file` and drives it through its pid
+//! file, the way an init script would.
+#![cfg(unix)]
+
+mod common;
+
+use common::{free_port, wait_for, Client, PidFile};
+use std::process::Command;
+
+fn start(pid_file: &PidFile, port: u16) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_sidica"))
+        .args(["-d", "-P", pid_file.0.to_str().unwrap()])
+        .args(["-p", &port.to_string()])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn daemonizes_and_manages_pid_file() {
+    // Socket activation would not survive the fork into the background
+    let port = free_port();
+    // Kills the daemon when the test ends, pass or fail
+    let pid_file = PidFile::new("daemon-test");
+
+    // The launching process returns as soon as the server is in the background
+    assert!(start(&pid_file, port).status.success());
+    wait_for("the pid file", || pid_file.pid().is_some());
+    let pid = pid_file.pid().unwrap();
+
+    let mut client = Client::connect(("127.0.0.1", port));
+    assert_eq!(client.ask(b"set foo 0 0 3\r\nbar\r\n", b"\r\n"), "STORED\r\n");
+    drop(client);
+
+    // A second instance refuses to start while the first is alive
+    let second = start(&pid_file, port);
+    assert!(!second.status.success());
+    assert!(String::from_utf8_lossy(&second.stderr).contains("already running"));
+    assert_eq!(pid_file.pid().unwrap(), pid);
+
+    // A clean shutdown removes the pid file
+    assert!(Command::new("kill")
+        .args(["-INT", &pid])
+        .status()
+        .unwrap()
+        .success());
+    wait_for("the pid file to be removed", || !pid_file.0.exists());
+}
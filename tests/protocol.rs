@@ -0,0 +1,105 @@
+//! Talks to a running server over TCP, from the bytes a client sends to the
+//! bytes it gets back.
+#![cfg(unix)]
+
+mod common;
+
+use common::Server;
+use std::io::Read;
+use std::net::Shutdown;
+
+#[test]
+fn set_then_get() {
+    let server = Server::start();
+    let mut client = server.connect();
+
+    assert_eq!(client.ask(b"set foo 5 0 3\r\nbar\r\n", b"\r\n"), "STORED\r\n");
+    assert_eq!(client.ask(b"get foo\r\n", b"END\r\n"), "VALUE foo 5 3\r\nbar\r\nEND\r\n");
+    assert_eq!(client.ask(b"set foo 0 0 0\r\n\r\n", b"\r\n"), "STORED\r\n");
+    assert_eq!(client.ask(b"get foo\r\n", b"END\r\n"), "VALUE foo 0 0\r\n\r\nEND\r\n");
+    assert_eq!(client.ask(b"get missing\r\n", b"END\r\n"), "END\r\n");
+}
+
+#[test]
+fn multiget_answers_the_keys_found_in_order() {
+    let server = Server::start();
+    let mut client = server.connect();
+    client.ask(b"set a 0 0 1\r\n1\r\nset c 0 0 3\r\n333\r\n", b"STORED\r\nSTORED\r\n");
+
+    assert_eq!(
+        client.ask(b"get c b a\r\n", b"END\r\n"),
+        "VALUE c 0 3\r\n333\r\nVALUE a 0 1\r\n1\r\nEND\r\n"
+    );
+    let gets = client.ask(b"gets a\r\n", b"END\r\n");
+    assert!(gets.starts_with("VALUE a 0 1 "), "{}", gets);
+}
+
+#[test]
+fn pipelined_commands_are_answered_in_order() {
+    let server = Server::start();
+    let mut client = server.connect();
+
+    let mut request = Vec::new();
+    let mut expected = Vec::new();
+    for n in 0..500 {
+        let value = n.to_string();
+        request.extend_from_slice(format!("set key{} 0 0 {}\r\n{}\r\n", n, value.len(), value).as_bytes());
+        request.extend_from_slice(format!("get key{}\r\n", n).as_bytes());
+        request.extend_from_slice(format!("delete key{}\r\n", n).as_bytes());
+        expected.extend_from_slice(b"STORED\r\n");
+        let hit = format!("VALUE key{} 0 {}\r\n{}\r\nEND\r\n", n, value.len(), value);
+        expected.extend_from_slice(hit.as_bytes());
+        expected.extend_from_slice(b"DELETED\r\n");
+    }
+    // In small pieces, so commands are split across reads
+    for chunk in request.chunks(7) {
+        client.send(chunk);
+    }
+    client.stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = Vec::new();
+    client.stream.read_to_end(&mut response).unwrap();
+    assert_eq!(String::from_utf8(response).unwrap(), String::from_utf8(expected).unwrap());
+}
+
+#[test]
+fn unknown_command_closes_the_connection() {
+    let server = Server::start();
+    let mut client = server.connect();
+
+    client.send(b"bogus foo\r\nget foo\r\n");
+    assert!(client.closed());
+    // Other connections are served on
+    let mut other = server.connect();
+    assert_eq!(other.ask(b"get foo\r\n", b"END\r\n"), "END\r\n");
+}
+
+#[test]
+fn oversized_value_is_refused() {
+    let server = Server::start();
+    let mut client = server.connect();
+    client.ask(b"set big 0 0 1\r\nx\r\n", b"\r\n");
+
+    let mut request = b"set big 0 0 2000000\r\n".to_vec();
+    request.extend_from_slice(&vec![b'x'; 2_000_000]);
+    request.extend_from_slice(b"\r\n");
+    assert_eq!(
+        client.ask(&request, b"\r\n"),
+        "SERVER_ERROR object too large for cache\r\n"
+    );
+    // The value it was to replace is kept, and the connection goes on
+    assert_eq!(client.ask(b"get big\r\n", b"END\r\n"), "VALUE big 0 1\r\nx\r\nEND\r\n");
+}
+
+#[test]
+fn close_mid_frame_stores_nothing() {
+    let server = Server::start();
+
+    for partial in [&b"set foo 0 0 10\r\nabc"[..], b"set foo 0 0 3\r\nbar", b"set foo 0"] {
+        let mut client = server.connect();
+        client.send(partial);
+        drop(client);
+    }
+    let mut client = server.connect();
+    assert_eq!(client.ask(b"get foo\r\n", b"END\r\n"), "END\r\n");
+    assert_eq!(client.ask(b"set foo 0 0 3\r\nbar\r\n", b"\r\n"), "STORED\r\n");
+}
@@ -0,0 +1,194 @@
+//! Stops a server running with `--data-dir` and checks that the next one
+//! started on the same directory serves the same cache. With
+//! `--append-log` or periodic snapshots, also when the server is killed
+//! instead.
+#![cfg(unix)]
+
+mod common;
+
+use common::{data_dir, run, wait_for, Server};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn restart_keeps_the_cache() {
+    let dir = data_dir("snapshot-restart");
+    let args = ["--data-dir", dir.to_str().unwrap()];
+
+    let server = Server::with_args(&args);
+    assert_eq!(
+        server.ask(b"set foo 3 0 3\r\nbar\r\nset gone 0 1 1\r\nx\r\n", b"STORED\r\nSTORED\r\n"),
+        "STORED\r\nSTORED\r\n"
+    );
+    let gets = server.ask(b"gets foo\r\n", b"END\r\n");
+    server.stop();
+    assert!(dir.join("snapshot").exists());
+
+    // Long enough for `gone` to expire
+    thread::sleep(Duration::from_millis(1100));
+    let server = Server::with_args(&args);
+    // The CAS is kept too
+    assert_eq!(server.ask(b"gets foo\r\n", b"END\r\n"), gets);
+    assert_eq!(server.ask(b"get gone\r\n", b"END\r\n"), "END\r\n");
+    server.stop();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn append_log_keeps_changes_across_a_kill() {
+    let dir = data_dir("append-log");
+    let args = ["--data-dir", dir.to_str().unwrap(), "--append-log"];
+
+    let server = Server::with_args(&args);
+    assert_eq!(
+        server.ask(b"set foo 0 0 3\r\nbar\r\nset n 0 0 1\r\n1\r\n", b"STORED\r\nSTORED\r\n"),
+        "STORED\r\nSTORED\r\n"
+    );
+    server.stop();
+
+    // Changes on top of the snapshot, then no chance to save another
+    let server = Server::with_args(&args);
+    assert_eq!(server.ask(b"incr n 41\r\n", b"\r\n"), "42\r\n");
+    assert_eq!(server.ask(b"delete foo\r\n", b"\r\n"), "DELETED\r\n");
+    assert_eq!(server.ask(b"set bar 0 0 3\r\nbaz\r\n", b"\r\n"), "STORED\r\n");
+    let gets = server.ask(b"gets bar n\r\n", b"END\r\n");
+    // Rewritten or not, the log holds the same cache
+    assert_eq!(server.ask(b"rewrite_log\r\n", b"\r\n"), "OK\r\n");
+    wait_for("the log to be rewritten", || {
+        let stats = server.ask(b"stats\r\n", b"END\r\n");
+        stats.contains("STAT log_rewrite_in_progress 0\r\n")
+    });
+    assert_eq!(server.ask(b"set baz 0 0 1\r\nx\r\n", b"\r\n"), "STORED\r\n");
+    server.kill();
+
+    let server = Server::with_args(&args);
+    assert_eq!(server.ask(b"gets bar n\r\n", b"END\r\n"), gets);
+    assert_eq!(server.ask(b"get foo\r\n", b"END\r\n"), "END\r\n");
+    assert_eq!(server.ask(b"get baz\r\n", b"END\r\n"), "VALUE baz 0 1\r\nx\r\nEND\r\n");
+    // A clean stop saves a snapshot and empties the log
+    server.stop();
+    assert_eq!(std::fs::metadata(dir.join("log")).unwrap().len(), 0);
+    let server = Server::with_args(&args);
+    assert_eq!(server.ask(b"gets bar n\r\n", b"END\r\n"), gets);
+    server.stop();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn a_server_run_as_another_user_keeps_its_data_dir() {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: no preconditions
+    if unsafe { libc::geteuid() } != 0 {
+        // Only root can switch users
+        return;
+    }
+    let dir = data_dir("user");
+    let args = ["--data-dir", dir.to_str().unwrap(), "--append-log", "--user", "nobody"];
+
+    let server = Server::with_args(&args);
+    // Answered once privileges are dropped
+    assert_eq!(server.ask(b"set foo 0 0 3\r\nbar\r\n", b"\r\n"), "STORED\r\n");
+    let owner = |name: &str| std::fs::metadata(dir.join(name)).unwrap().uid();
+    let user = owner("");
+    assert_ne!(user, 0);
+    assert_eq!(owner("log"), user);
+    assert_eq!(owner("watermark"), user);
+    // Written next to the log, then renamed over it
+    assert_eq!(server.ask(b"rewrite_log\r\n", b"\r\n"), "OK\r\n");
+    wait_for("the log to be rewritten", || {
+        let stats = server.ask(b"stats\r\n", b"END\r\n");
+        stats.contains("STAT log_rewrite_in_progress 0\r\n")
+    });
+    assert!(!dir.join("log.tmp").exists());
+    assert_eq!(server.ask(b"set bar 0 0 3\r\nbaz\r\n", b"\r\n"), "STORED\r\n");
+    server.stop();
+    assert_eq!(owner("snapshot"), user);
+
+    let server = Server::with_args(&args);
+    assert_eq!(
+        server.ask(b"get foo bar\r\n", b"END\r\n"),
+        "VALUE foo 0 3\r\nbar\r\nVALUE bar 0 3\r\nbaz\r\nEND\r\n"
+    );
+    server.stop();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn periodic_snapshots_survive_a_kill() {
+    let dir = data_dir("periodic");
+    let args = ["--data-dir", dir.to_str().unwrap(), "--snapshot-after-changes", "2"];
+
+    let server = Server::with_args(&args);
+    assert_eq!(
+        server.ask(b"set foo 0 0 3\r\nbar\r\nset n 0 0 1\r\n1\r\n", b"STORED\r\nSTORED\r\n"),
+        "STORED\r\nSTORED\r\n"
+    );
+    wait_for("a periodic snapshot", || {
+        let stats = server.ask(b"stats\r\n", b"END\r\n");
+        !stats.contains("STAT snapshot_last_time 0\r\n")
+    });
+    server.kill();
+
+    let server = Server::with_args(&args);
+    assert_eq!(server.ask(b"get foo\r\n", b"END\r\n"), "VALUE foo 0 3\r\nbar\r\nEND\r\n");
+    server.stop();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The CAS in a `gets` response.
+fn cas_of(response: &str) -> u64 {
+    let line = response.lines().next().unwrap();
+    line.rsplit(' ').next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn restarts_never_hand_out_a_cas_again() {
+    let dir = data_dir("watermark");
+    let args = ["--data-dir", dir.to_str().unwrap()];
+
+    // Deleted, so not in the snapshot to go on from
+    let server = Server::with_args(&args);
+    server.ask(b"set foo 0 0 3\r\nbar\r\n", b"STORED\r\n");
+    let first = cas_of(&server.ask(b"gets foo\r\n", b"END\r\n"));
+    server.ask(b"delete foo\r\n", b"DELETED\r\n");
+    server.stop();
+
+    let server = Server::with_args(&args);
+    server.ask(b"set foo 0 0 3\r\nbaz\r\n", b"STORED\r\n");
+    let second = cas_of(&server.ask(b"gets foo\r\n", b"END\r\n"));
+    assert!(second > first, "{} after {}", second, first);
+    // Killed before the watermark saved every second has caught up, and
+    // without a snapshot
+    server.kill();
+
+    let server = Server::with_args(&args);
+    server.ask(b"set foo 0 0 3\r\nqux\r\n", b"STORED\r\n");
+    let third = cas_of(&server.ask(b"gets foo\r\n", b"END\r\n"));
+    assert!(third > second, "{} after {}", third, second);
+    server.stop();
+
+    // A corrupt watermark is refused like a corrupt snapshot
+    std::fs::write(dir.join("watermark"), b"SIDICAWM garbage").unwrap();
+    let output = run(&args);
+    assert!(!output.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn refuses_a_corrupt_snapshot() {
+    let dir = data_dir("snapshot-corrupt");
+    std::fs::write(Path::new(&dir).join("snapshot"), b"SIDICASN garbage").unwrap();
+
+    let output = run(&["--data-dir", dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--discard-bad-snapshot"), "{}", stderr);
+
+    // Unless told to start empty
+    let server = Server::with_args(&["--data-dir", dir.to_str().unwrap(), "--discard-bad-snapshot"]);
+    assert_eq!(server.ask(b"get foo\r\n", b"END\r\n"), "END\r\n");
+    server.stop();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
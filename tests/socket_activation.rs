@@ -0,0 +1,19 @@
+//! Simulates systemd socket activation: the test binds the listener and the
+//! server inherits it as fd 3 with `LISTEN_PID`/`LISTEN_FDS` set, exactly as
+//! systemd would launch it. `Server::start` does just that.
+#![cfg(unix)]
+
+mod common;
+
+use common::Server;
+
+#[test]
+fn serves_on_inherited_listener() {
+    // The port given on the command line is `1`, which is not what the
+    // server listens on
+    let server = Server::start();
+    assert_ne!(server.addr().port(), 1);
+
+    let response = server.ask(b"set foo 0 0 3\r\nbar\r\nget foo\r\n", b"END\r\n");
+    assert_eq!(response, "STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\n");
+}
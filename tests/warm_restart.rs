@@ -0,0 +1,31 @@
+//! Sends SIGUSR2 to a running server and checks that the process it hands
+//! off to serves the same cache on the same port.
+#![cfg(unix)]
+
+mod common;
+
+use common::{wait_for, PidFile, Server};
+use std::process::Command;
+
+#[test]
+fn hands_cache_to_new_process() {
+    // Names whichever process serves, to kill it when the test ends
+    let pid_file = PidFile::new("warm-restart");
+    let mut old = Server::with_args(&["-P", pid_file.0.to_str().unwrap()]);
+    wait_for("the pid file", || pid_file.pid().is_some());
+    assert_eq!(old.ask(b"set foo 3 0 3\r\nbar\r\n", b"\r\n"), "STORED\r\n");
+
+    assert!(Command::new("kill")
+        .args(["-USR2", &old.id().to_string()])
+        .status()
+        .unwrap()
+        .success());
+    // The old process exits once its connections are drained
+    wait_for("the old process to exit", || old.exited());
+    wait_for("the new pid file", || {
+        pid_file.pid().is_some_and(|pid| pid != old.id().to_string())
+    });
+
+    // Same listener, so same address
+    assert_eq!(old.ask(b"get foo\r\n", b"END\r\n"), "VALUE foo 3 3\r\nbar\r\nEND\r\n");
+}